@@ -0,0 +1,47 @@
+//! Everything that happens to the contents of a file: detection, decoding, the full-quality
+//! decode that replaces a preview or a reduced image, and every page of multi-page images.
+
+#![no_main]
+
+use std::path::Path;
+
+use libfuzzer_sys::fuzz_target;
+use reimv::hdr::ToneMapping;
+use reimv::limits::Limits;
+use reimv::{decode, format};
+
+/// Small enough to not run out of memory
+const LIMITS: Limits = Limits {
+    max_dimension: 4096,
+    max_bytes: 64 << 20,
+    max_svg_nodes: 10_000,
+};
+/// Small enough that reduced decodes are tried too
+const MAX_PIXELS: u64 = 1 << 16;
+
+fuzz_target!(|data: &[u8]| {
+    let Some(format) = format::detect(Path::new("image"), data) else {
+        return;
+    };
+    let tone_mapping = ToneMapping::default();
+    let Ok(decoded) = decode::decode(
+        data.to_vec(),
+        format,
+        None,
+        tone_mapping,
+        LIMITS,
+        true,
+        Some(MAX_PIXELS),
+    ) else {
+        return;
+    };
+    if let Some(deferred) = decoded.deferred {
+        let _ = deferred();
+    }
+    if let Some(full) = decoded.full {
+        let _ = full();
+    }
+    if let Some(mut pages) = decoded.pages {
+        while pages.turn(1).is_some() {}
+    }
+});
@@ -0,0 +1,17 @@
+//! JSON, as clients send it to the IPC socket. What is parsed must print as JSON which parses to the
+//! same value.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use reimv::json::Value;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    if let Ok(value) = Value::parse(text) {
+        let printed = value.to_string();
+        assert_eq!(Value::parse(&printed).unwrap().to_string(), printed);
+    }
+});
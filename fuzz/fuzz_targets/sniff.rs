@@ -0,0 +1,13 @@
+//! Format detection, with and without a misleading extension.
+
+#![no_main]
+
+use std::path::Path;
+
+use libfuzzer_sys::fuzz_target;
+use reimv::format;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = format::detect(Path::new("image"), data);
+    let _ = format::detect(Path::new("image.nef"), data);
+});
@@ -0,0 +1,28 @@
+//! SVG parsing, which is otherwise only reached by inputs that look like XML.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use reimv::decode;
+use reimv::format::Format;
+use reimv::hdr::ToneMapping;
+use reimv::limits::Limits;
+
+/// Small enough to not run out of memory
+const LIMITS: Limits = Limits {
+    max_dimension: 4096,
+    max_bytes: 64 << 20,
+    max_svg_nodes: 10_000,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode::decode(
+        data.to_vec(),
+        Format::Svg,
+        None,
+        ToneMapping::default(),
+        LIMITS,
+        false,
+        None,
+    );
+});
@@ -0,0 +1,323 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Context, Result};
+
+use crate::image::ImageTransform;
+
+/// How often the view is moved while it changes smoothly.
+const VIEW_FRAME_INTERVAL: Duration = Duration::from_millis(16);
+/// How long each image is shown in a slideshow started with `s` without `--slideshow`.
+pub const SLIDESHOW_INTERVAL: Duration = Duration::from_secs(5);
+/// How late a frame may be before the clock is taken to have stood still in between, like while
+/// reimv was stopped or the system was busy, rather than the animation jumping ahead.
+const MAX_LATENESS: Duration = Duration::from_secs(1);
+
+/// The clock of an animation, which says when to show the next frame.
+///
+/// It is monotonic, and stands still while the system is suspended, so waking it up continues
+/// animations, slideshows and held keys where they were instead of catching up on the time since.
+pub struct Playback {
+    start: Instant,
+    next_frame: Option<Instant>,
+    /// When the clock was paused, while it is
+    paused: Option<Instant>,
+}
+
+impl Playback {
+    /// Start at time zero, with the first frame due immediately.
+    pub fn start() -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            next_frame: Some(now),
+            paused: None,
+        }
+    }
+
+    /// The time since the start, in seconds, without the time spent paused.
+    pub fn elapsed(&self) -> f32 {
+        let now = self.paused.unwrap_or_else(Instant::now);
+        now.saturating_duration_since(self.start).as_secs_f32()
+    }
+
+    /// The duration until the next frame is due, or `None` if the animation has stopped or is
+    /// paused.
+    pub fn sleep(&self) -> Option<Duration> {
+        if self.paused.is_some() {
+            return None;
+        }
+        self.next_frame
+            .map(|next| next.saturating_duration_since(Instant::now()))
+    }
+
+    /// Whether the next frame is due. If it has been due for long, the time since is skipped like
+    /// a pause.
+    pub fn due(&mut self) -> bool {
+        let (None, Some(next)) = (self.paused, self.next_frame) else {
+            return false;
+        };
+        let now = Instant::now();
+        if next > now {
+            return false;
+        }
+        let late = now - next;
+        if late > MAX_LATENESS {
+            self.start += late;
+            self.next_frame = Some(now);
+        }
+        true
+    }
+
+    /// Stop the clock, or let it continue from where it was stopped.
+    pub fn set_paused(&mut self, paused: bool) {
+        match (self.paused, paused) {
+            (None, true) => self.paused = Some(Instant::now()),
+            (Some(since), false) => {
+                let pause = since.elapsed();
+                self.start += pause;
+                if let Some(next) = &mut self.next_frame {
+                    *next += pause;
+                }
+                self.paused = None;
+            }
+            _ => (),
+        }
+    }
+
+    /// Show the next frame `delay` after the current one. Frames which are late are not made up
+    /// for, so a slow frame does not cause a burst of frames after it.
+    pub fn schedule(&mut self, delay: Duration) {
+        let now = Instant::now();
+        let next = self.next_frame.unwrap_or(now) + delay;
+        self.next_frame = Some(if next < now { now + delay } else { next });
+    }
+
+    /// Show no more frames.
+    pub fn stop(&mut self) {
+        self.next_frame = None;
+    }
+}
+
+/// The clock of an image sequence given with `--sequence`, which has one file per frame.
+pub struct Sequence {
+    playback: Playback,
+    interval: Duration,
+    /// Whether the user has paused it
+    stopped: bool,
+}
+
+impl Sequence {
+    pub fn new(fps: f32, stopped: bool) -> Self {
+        let mut playback = Playback::start();
+        playback.set_paused(stopped);
+        Self {
+            playback,
+            interval: Duration::from_secs_f32(fps.recip()),
+            stopped,
+        }
+    }
+
+    pub fn sleep(&self) -> Option<Duration> {
+        self.playback.sleep()
+    }
+
+    /// Whether the next frame is due, scheduling the one after it if so.
+    pub fn tick(&mut self) -> bool {
+        if !self.playback.due() {
+            return false;
+        }
+        self.playback.schedule(self.interval);
+        true
+    }
+
+    /// Pause or continue, and return whether it is paused now.
+    pub fn toggle(&mut self) -> bool {
+        self.stopped = !self.stopped;
+        self.playback.set_paused(self.stopped);
+        self.stopped
+    }
+
+    /// Pause while nobody can see it, without continuing if the user has paused it.
+    pub fn hide(&mut self, hidden: bool) {
+        self.playback.set_paused(hidden || self.stopped);
+    }
+}
+
+/// Repeating the action of a key while it is held down.
+pub struct KeyRepeat {
+    playback: Playback,
+    interval: Duration,
+}
+
+impl KeyRepeat {
+    /// Repeat first after `delay`, and then every `interval`.
+    pub fn new(delay: Duration, interval: Duration) -> Self {
+        let mut playback = Playback::start();
+        playback.schedule(delay);
+        Self { playback, interval }
+    }
+
+    pub fn sleep(&self) -> Option<Duration> {
+        self.playback.sleep()
+    }
+
+    /// Whether the key repeats now, scheduling the next time if so. Repeats which are late are
+    /// not made up for, so a busy moment does not cause a burst of them.
+    pub fn tick(&mut self) -> bool {
+        if !self.playback.due() {
+            return false;
+        }
+        self.playback.schedule(self.interval);
+        true
+    }
+}
+
+/// Moving on to the next image after a while, see `--slideshow`.
+pub struct Slideshow {
+    playback: Playback,
+    interval: Duration,
+    /// Whether it is off, because it was not started or the user has stopped it
+    stopped: bool,
+}
+
+impl Slideshow {
+    pub fn new(interval: Duration, stopped: bool) -> Self {
+        let mut slideshow = Self {
+            playback: Playback::start(),
+            interval,
+            stopped,
+        };
+        slideshow.restart();
+        slideshow
+    }
+
+    pub fn sleep(&self) -> Option<Duration> {
+        self.playback.sleep()
+    }
+
+    /// Whether the next image is due, scheduling the one after it if so.
+    pub fn tick(&mut self) -> bool {
+        if !self.playback.due() {
+            return false;
+        }
+        self.playback.schedule(self.interval);
+        true
+    }
+
+    /// Show the current image for a whole interval from now, after the user has done something
+    /// with it.
+    pub fn restart(&mut self) {
+        self.playback = Playback::start();
+        self.playback.schedule(self.interval);
+        self.playback.set_paused(self.stopped);
+    }
+
+    /// Start or stop, and return whether it is stopped now.
+    pub fn toggle(&mut self) -> bool {
+        self.stopped = !self.stopped;
+        self.restart();
+        self.stopped
+    }
+
+    /// Stop after the last image.
+    pub fn stop(&mut self) {
+        self.stopped = true;
+        self.playback.set_paused(true);
+    }
+
+    /// Pause while nobody can see it, without continuing if it is stopped.
+    pub fn hide(&mut self, hidden: bool) {
+        self.playback.set_paused(hidden || self.stopped);
+    }
+}
+
+pub fn parse_interval(text: &str) -> Result<Duration> {
+    let secs: f64 = text.parse()?;
+    Duration::try_from_secs_f64(secs)
+        .ok()
+        .filter(|interval| !interval.is_zero())
+        .context("expected a positive number of seconds")
+}
+
+pub fn parse_fps(text: &str) -> Result<f32> {
+    let fps: f32 = text.parse()?;
+    ensure!(
+        fps.is_finite() && fps >= 0.01,
+        "expected at least 0.01 frames per second"
+    );
+    Ok(fps)
+}
+
+/// A smooth change of the view to a given zoom and center, as asked for over IPC.
+pub struct ViewAnimation {
+    /// The point of the image at the center of the window, and the scale, at the start
+    from: ((f32, f32), f32),
+    to: ((f32, f32), f32),
+    duration: Duration,
+    playback: Playback,
+    /// The view after the last frame. If it has changed since, the user has moved the image and
+    /// the animation stops.
+    last: ImageTransform,
+}
+
+impl ViewAnimation {
+    /// Move from `transform` in a window of size `window`. The center and the scale stay as they
+    /// are unless given.
+    pub fn new(
+        transform: ImageTransform,
+        window: (f32, f32),
+        center: Option<(f32, f32)>,
+        scale: Option<f32>,
+        duration: Duration,
+    ) -> Self {
+        let from = (
+            transform.image_point(window.0 / 2.0, window.1 / 2.0),
+            transform.scale,
+        );
+        Self {
+            from,
+            to: (center.unwrap_or(from.0), scale.unwrap_or(from.1)),
+            duration,
+            playback: Playback::start(),
+            last: transform,
+        }
+    }
+
+    pub fn sleep(&self) -> Option<Duration> {
+        self.playback.sleep()
+    }
+
+    /// Whether the view has reached its end, or the user has moved it.
+    pub fn finished(&self) -> bool {
+        self.playback.sleep().is_none()
+    }
+
+    /// Move the view if the next frame is due. Returns `true` if `transform` has changed.
+    pub fn advance(&mut self, transform: &mut ImageTransform, window: (f32, f32)) -> bool {
+        if *transform != self.last {
+            self.playback.stop();
+        }
+        if !self.playback.due() {
+            return false;
+        }
+        let t = match self.duration.is_zero() {
+            true => 1.0,
+            false => (self.playback.elapsed() / self.duration.as_secs_f32()).min(1.0),
+        };
+        if t < 1.0 {
+            self.playback.schedule(VIEW_FRAME_INTERVAL);
+        } else {
+            self.playback.stop();
+        }
+        // Ease in and out
+        let t = t * t * (3.0 - 2.0 * t);
+        let ((from_x, from_y), from_scale) = self.from;
+        let ((to_x, to_y), to_scale) = self.to;
+        let center = (from_x + (to_x - from_x) * t, from_y + (to_y - from_y) * t);
+        // Zooming by the same factor every frame looks steady, unlike adding the same amount
+        let scale = from_scale * (to_scale / from_scale).powf(t);
+        *transform = ImageTransform::showing(center, (window.0 / 2.0, window.1 / 2.0), scale);
+        self.last = *transform;
+        true
+    }
+}
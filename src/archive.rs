@@ -0,0 +1,389 @@
+//! Reading the members of zip and tar archives, such as CBZ comic books, one at a time.
+//!
+//! Only the list of members is read when an archive is opened: the central directory of zip
+//! archives and the headers of tar archives. Members are read and decompressed when they are
+//! needed, so large archives open quickly. Stored and deflated zip members are supported, which
+//! is what comic book archives use. Compressed tar archives cannot be read one member at a time
+//! and are not supported.
+
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context, Result};
+use flate2::read::DeflateDecoder;
+
+use crate::limits::Limits;
+
+const EOCD_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_EOCD_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const ZIP64_EOCD_SIGNATURE: u32 = 0x06064b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+/// The end of central directory record is 22 bytes, followed by a comment of up to 64 KiB.
+const MAX_EOCD_LEN: u64 = 22 + 0xffff;
+const TAR_BLOCK: u64 = 512;
+/// Long names and PAX headers are short, larger ones are broken.
+const MAX_TAR_EXTENSION: u64 = 1 << 16;
+
+pub struct Archive {
+    file: File,
+    len: u64,
+    members: Vec<Member>,
+}
+
+pub struct Member {
+    /// The path within the archive
+    pub name: String,
+    size: u64,
+    location: Location,
+}
+
+enum Location {
+    Zip {
+        method: u16,
+        crc: u32,
+        compressed_size: u64,
+        local_header: u64,
+    },
+    Tar {
+        offset: u64,
+    },
+}
+
+impl Archive {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).context("could not open archive")?;
+        let len = file.metadata()?.len();
+
+        let mut head = [0; 262];
+        let head_len = file
+            .read_at(&mut head, 0)
+            .context("could not read archive")?;
+        let head = &head[..head_len];
+        if head.starts_with(b"Rar!\x1a\x07") {
+            bail!("RAR archives are not supported, only zip and tar archives");
+        }
+        // gzip, bzip2, xz and zstd
+        if head.starts_with(&[0x1f, 0x8b])
+            || head.starts_with(b"BZh")
+            || head.starts_with(b"\xfd7zXZ\0")
+            || head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd])
+        {
+            bail!("compressed tar archives are not supported, only uncompressed ones");
+        }
+
+        let members = match head.get(257..262) == Some(b"ustar") {
+            true => tar_members(&file, len)?,
+            false => zip_members(&file, len)?,
+        };
+        Ok(Self { file, len, members })
+    }
+
+    pub fn members(&self) -> &[Member] {
+        &self.members
+    }
+
+    /// Read and decompress a member.
+    pub fn read(&self, index: usize, limits: &Limits) -> Result<Vec<u8>> {
+        let member = &self.members[index];
+        ensure!(
+            member.size <= limits.max_bytes,
+            "{} is larger than the limit of {} MiB",
+            member.name,
+            limits.max_bytes >> 20
+        );
+        match member.location {
+            Location::Zip {
+                method,
+                crc,
+                compressed_size,
+                local_header,
+            } => {
+                let header = read_at(&self.file, local_header, 30)?;
+                ensure!(
+                    le_u32(&header, 0) == LOCAL_HEADER_SIGNATURE,
+                    "broken local header of {}",
+                    member.name
+                );
+                // The lengths here may differ from the central directory
+                let offset =
+                    local_header + 30 + le_u16(&header, 26) as u64 + le_u16(&header, 28) as u64;
+                let compressed = self.read_data(member, offset, compressed_size)?;
+                let data = inflate(member, method, compressed)?;
+
+                let mut data_crc = flate2::Crc::new();
+                data_crc.update(&data);
+                ensure!(
+                    data.len() as u64 == member.size && data_crc.sum() == crc,
+                    "{} is corrupted",
+                    member.name
+                );
+                Ok(data)
+            }
+            Location::Tar { offset } => self.read_data(member, offset, member.size),
+        }
+    }
+
+    fn read_data(&self, member: &Member, offset: u64, len: u64) -> Result<Vec<u8>> {
+        ensure!(
+            offset.checked_add(len).is_some_and(|end| end <= self.len),
+            "{} is outside of the archive",
+            member.name
+        );
+        read_at(&self.file, offset, len)
+    }
+}
+
+fn inflate(member: &Member, method: u16, compressed: Vec<u8>) -> Result<Vec<u8>> {
+    match method {
+        0 => Ok(compressed),
+        8 => {
+            let mut data = Vec::with_capacity(member.size as usize);
+            DeflateDecoder::new(&compressed[..])
+                .take(member.size)
+                .read_to_end(&mut data)
+                .with_context(|| format!("could not decompress {}", member.name))?;
+            Ok(data)
+        }
+        method => bail!(
+            "{} uses compression method {method}, which is not supported",
+            member.name
+        ),
+    }
+}
+
+fn zip_members(file: &File, len: u64) -> Result<Vec<Member>> {
+    let tail_len = len.min(MAX_EOCD_LEN);
+    let tail = read_at(file, len - tail_len, tail_len)?;
+    let eocd = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&i| le_u32(&tail, i) == EOCD_SIGNATURE)
+        .context("not a zip or tar archive")?;
+    let mut count = le_u16(&tail, eocd + 10) as u64;
+    let mut dir_len = le_u32(&tail, eocd + 12) as u64;
+    let mut dir_offset = le_u32(&tail, eocd + 16) as u64;
+
+    // Archives with more than 65535 members or larger than 4 GiB have 64-bit fields elsewhere
+    let locator_pos = (len - tail_len + eocd as u64).checked_sub(20);
+    if let Some(locator_pos) = locator_pos {
+        let locator = read_at(file, locator_pos, 20)?;
+        if le_u32(&locator, 0) == ZIP64_EOCD_LOCATOR_SIGNATURE {
+            let eocd64 = read_at(file, le_u64(&locator, 8), 56)?;
+            ensure!(
+                le_u32(&eocd64, 0) == ZIP64_EOCD_SIGNATURE,
+                "broken zip64 end of central directory"
+            );
+            count = le_u64(&eocd64, 32);
+            dir_len = le_u64(&eocd64, 40);
+            dir_offset = le_u64(&eocd64, 48);
+        }
+    }
+    ensure!(
+        dir_offset
+            .checked_add(dir_len)
+            .is_some_and(|end| end <= len),
+        "the central directory is outside of the archive"
+    );
+
+    let dir = read_at(file, dir_offset, dir_len)?;
+    let mut members = Vec::new();
+    let mut pos = 0;
+    for _ in 0..count {
+        ensure!(
+            dir.len() >= pos + 46 && le_u32(&dir, pos) == CENTRAL_HEADER_SIGNATURE,
+            "broken central directory"
+        );
+        let header = &dir[pos..];
+        let flags = le_u16(header, 8);
+        let name_len = le_u16(header, 28) as usize;
+        let extra_len = le_u16(header, 30) as usize;
+        let comment_len = le_u16(header, 32) as usize;
+        ensure!(
+            header.len() >= 46 + name_len + extra_len,
+            "broken central directory"
+        );
+        // Names which are not UTF-8 are in code page 437, which is rare outside of old Windows
+        // tools
+        let name = member_name(&header[46..46 + name_len]);
+        let mut size = le_u32(header, 24) as u64;
+        let mut compressed_size = le_u32(header, 20) as u64;
+        let mut local_header = le_u32(header, 42) as u64;
+        read_zip64_extra(
+            &header[46 + name_len..46 + name_len + extra_len],
+            [&mut size, &mut compressed_size, &mut local_header],
+        );
+        pos += 46 + name_len + extra_len + comment_len;
+
+        // Encrypted members cannot be read, and directories are not images
+        if flags & 1 == 0 && !name.ends_with('/') {
+            members.push(Member {
+                name,
+                size,
+                location: Location::Zip {
+                    method: le_u16(header, 10),
+                    crc: le_u32(header, 16),
+                    compressed_size,
+                    local_header,
+                },
+            });
+        }
+    }
+    Ok(members)
+}
+
+/// Take the size, compressed size and local header offset which did not fit into 32 bits from
+/// the zip64 extra field.
+fn read_zip64_extra(mut extra: &[u8], mut values: [&mut u64; 3]) {
+    while extra.len() >= 4 {
+        let id = le_u16(extra, 0);
+        let len = (le_u16(extra, 2) as usize).min(extra.len() - 4);
+        if id == 0x0001 {
+            // Only the fields which are saturated in the central directory are present
+            let mut fields = extra[4..4 + len].chunks_exact(8).map(|f| le_u64(f, 0));
+            for value in values.iter_mut().filter(|v| ***v == u32::MAX as u64) {
+                match fields.next() {
+                    Some(field) => **value = field,
+                    None => break,
+                }
+            }
+        }
+        extra = &extra[4 + len..];
+    }
+}
+
+fn tar_members(file: &File, len: u64) -> Result<Vec<Member>> {
+    let mut members = Vec::new();
+    let mut pos = 0;
+    // Set by GNU and PAX headers for the member after them
+    let mut long_name: Option<String> = None;
+    while pos + TAR_BLOCK <= len {
+        let header = read_at(file, pos, TAR_BLOCK)?;
+        // The end is marked by empty blocks
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        ensure!(tar_checksum_ok(&header), "broken tar header at {pos}");
+        let size = tar_number(&header[124..136]).context("broken tar header")?;
+        let data = pos + TAR_BLOCK;
+
+        match header[156] {
+            // GNU long name of the next member
+            b'L' => {
+                ensure!(size <= MAX_TAR_EXTENSION, "broken tar header at {pos}");
+                long_name = Some(c_string(&read_at(file, data, size)?));
+            }
+            // PAX extended header, with the name as a `path` record
+            b'x' => {
+                ensure!(size <= MAX_TAR_EXTENSION, "broken tar header at {pos}");
+                let records = read_at(file, data, size)?;
+                long_name = pax_path(&records).or(long_name);
+            }
+            b'0' | 0 => {
+                let name = long_name.take().unwrap_or_else(|| {
+                    let name = c_string(&header[0..100]);
+                    match c_string(&header[345..500]) {
+                        prefix if prefix.is_empty() => name,
+                        prefix => format!("{prefix}/{name}"),
+                    }
+                });
+                members.push(Member {
+                    name,
+                    size,
+                    location: Location::Tar { offset: data },
+                });
+            }
+            // Directories, links and other special files
+            _ => long_name = None,
+        }
+
+        pos = size
+            .div_ceil(TAR_BLOCK)
+            .checked_mul(TAR_BLOCK)
+            .and_then(|padded| data.checked_add(padded))
+            .context("broken tar header")?;
+    }
+    Ok(members)
+}
+
+/// The checksum is the sum of the header's bytes, with the checksum field counted as spaces.
+fn tar_checksum_ok(header: &[u8]) -> bool {
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+        .sum();
+    tar_number(&header[148..156]) == Some(sum)
+}
+
+/// A number in a tar header: octal digits, or big-endian binary if the high bit is set.
+fn tar_number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        let bytes = &field[1..];
+        let (high, low) = bytes.split_at(bytes.len().saturating_sub(8));
+        if high.iter().any(|&b| b != 0) {
+            return None;
+        }
+        return Some(low.iter().fold(0, |n, &b| n << 8 | b as u64));
+    }
+    let text = std::str::from_utf8(field).ok()?;
+    match text.trim_matches(['\0', ' ']) {
+        "" => Some(0),
+        text => u64::from_str_radix(text, 8).ok(),
+    }
+}
+
+/// The `path` of PAX records, which are `<length> <key>=<value>\n`.
+fn pax_path(mut records: &[u8]) -> Option<String> {
+    let mut path = None;
+    while let Some(space) = records.iter().position(|&b| b == b' ') {
+        let len: usize = std::str::from_utf8(&records[..space]).ok()?.parse().ok()?;
+        if len <= space || len > records.len() {
+            break;
+        }
+        let record = &records[space + 1..len];
+        if let Some(value) = record.strip_prefix(b"path=") {
+            let value = value.strip_suffix(b"\n").unwrap_or(value);
+            path = Some(member_name(value));
+        }
+        records = &records[len..];
+    }
+    path
+}
+
+fn c_string(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    member_name(&field[..end])
+}
+
+/// The name of a member as it is shown, in the title among other places. Bytes which are not
+/// UTF-8 and control characters such as NUL are replaced, like invalid UTF-8 is.
+fn member_name(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes)
+        .chars()
+        .map(|c| match c.is_control() {
+            true => char::REPLACEMENT_CHARACTER,
+            false => c,
+        })
+        .collect()
+}
+
+fn read_at(file: &File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let mut buf = vec![0; len.try_into()?];
+    file.read_exact_at(&mut buf, offset)
+        .context("the archive is truncated")?;
+    Ok(buf)
+}
+
+fn le_u16(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes(data[pos..pos + 2].try_into().unwrap())
+}
+
+fn le_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+fn le_u64(data: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap())
+}
@@ -0,0 +1,79 @@
+//! How each kind of image is shown on the image surface. [`crate::image::Image`] decodes the
+//! images and keeps what they have in common, such as their metadata and pages, and hands the
+//! drawing to one of these.
+
+mod animation;
+mod clip;
+mod player;
+mod raster;
+mod svg;
+mod visible;
+
+use std::os::fd::RawFd;
+use std::time::Duration;
+
+use wayrs_client::protocol::*;
+use wayrs_client::Connection;
+use wayrs_protocols::viewporter::*;
+
+use resvg::tiny_skia;
+
+use crate::image::ImageTransform;
+use crate::shm::ShmAlloc;
+use crate::State;
+
+pub use animation::Animated;
+pub use clip::Clip;
+pub use player::Player;
+pub use raster::{upload, upload_deep, upload_hdr, Raster};
+pub use svg::Svg;
+pub use visible::{Region, View};
+
+/// The size in buffer pixels of a surface of `width` × `height` at `ui_scale120`, rounded
+/// halfway away from zero like compositors do for fractional scales.
+pub fn buffer_size(width: u32, height: u32, ui_scale120: u32) -> (u32, u32) {
+    let round = |len: u32| (len * ui_scale120 + 60) / 120;
+    (round(width), round(height))
+}
+
+/// The surface an image is shown on, which covers the window.
+pub struct Target<'a> {
+    pub conn: &'a mut Connection<State>,
+    pub shm: &'a mut ShmAlloc,
+    pub surface: WlSurface,
+    pub subsurface: WlSubsurface,
+    pub viewport: WpViewport,
+    /// The size of the window in surface local coordinates
+    pub width: u32,
+    pub height: u32,
+    /// The scale of buffers, times 120
+    pub ui_scale120: u32,
+}
+
+pub trait Render {
+    /// The size of the image at scale 1, in surface local coordinates.
+    fn natural_size(&self) -> (f32, f32);
+
+    /// Show the image on `target` with `transform`.
+    fn render(&mut self, transform: &ImageTransform, target: &mut Target);
+
+    /// Draw the image over `canvas`, the way the compositor shows it. `transform` maps the
+    /// image to buffer pixels.
+    fn draw(&self, canvas: &mut tiny_skia::PixmapMut, transform: tiny_skia::Transform);
+
+    /// Advance to the next frame if it is due. Returns `true` if the image has changed.
+    fn tick(&mut self) -> bool {
+        false
+    }
+
+    /// The duration until [`Self::tick`] has a new frame, or `None` if nothing is playing.
+    fn next_tick(&self) -> Option<Duration> {
+        None
+    }
+
+    /// A file descriptor which becomes readable when [`Self::tick`] may have a new frame, while
+    /// the frame is decoded in the background.
+    fn wakeup_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
@@ -0,0 +1,98 @@
+//! SVG images with SMIL animations, parsed again for every frame.
+
+use std::time::Duration;
+
+use resvg::tiny_skia;
+
+use super::{Render, Svg, Target};
+use crate::animation::Playback;
+use crate::decode::AnimatedSvg;
+use crate::image::ImageTransform;
+
+/// How often animated SVG images are parsed and drawn again.
+const SVG_FRAME_INTERVAL: Duration = Duration::from_millis(33);
+
+pub struct Animated {
+    /// The current frame
+    svg: Svg,
+    source: AnimatedSvg,
+    /// The clock, unless the animation is frozen at its start
+    playback: Option<Playback>,
+}
+
+impl Animated {
+    /// Play `source`, of which `svg` is the start, unless it is `frozen`.
+    pub fn new(svg: Svg, source: AnimatedSvg, frozen: bool) -> Self {
+        Self {
+            svg,
+            source,
+            playback: (!frozen).then(Playback::start),
+        }
+    }
+
+    /// Stop playing the animation where it is, or continue it.
+    pub fn set_paused(&mut self, paused: bool) {
+        if let Some(playback) = &mut self.playback {
+            playback.set_paused(paused);
+        }
+    }
+
+    /// Show the start only, or play the animation from the start again.
+    pub fn set_frozen(&mut self, frozen: bool) {
+        if !frozen {
+            self.playback = Some(Playback::start());
+            return;
+        }
+        self.playback = None;
+        match self.source.tree_at(0.0) {
+            Ok(tree) => self.svg.replace(tree),
+            Err(e) => eprintln!("reimv: could not show the start of the animation: {e:#}"),
+        }
+    }
+}
+
+impl Render for Animated {
+    fn natural_size(&self) -> (f32, f32) {
+        self.svg.natural_size()
+    }
+
+    fn render(&mut self, transform: &ImageTransform, target: &mut Target) {
+        self.svg.render(transform, target);
+    }
+
+    fn draw(&self, canvas: &mut tiny_skia::PixmapMut, transform: tiny_skia::Transform) {
+        self.svg.draw(canvas, transform);
+    }
+
+    fn tick(&mut self) -> bool {
+        let Some(playback) = &mut self.playback else {
+            return false;
+        };
+        if !playback.due() {
+            return false;
+        }
+        let mut t = playback.elapsed();
+        match self.source.end() {
+            Some(end) if t >= end => {
+                t = end;
+                playback.stop();
+            }
+            _ => playback.schedule(SVG_FRAME_INTERVAL),
+        }
+        match self.source.tree_at(t) {
+            Ok(tree) => {
+                self.svg.replace(tree);
+                true
+            }
+            Err(e) => {
+                eprintln!("reimv: stopping the animation: {e:#}");
+                playback.stop();
+                false
+            }
+        }
+    }
+
+    fn next_tick(&self) -> Option<Duration> {
+        self.playback.as_ref()?.sleep()
+    }
+}
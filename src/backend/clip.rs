@@ -0,0 +1,113 @@
+//! Short videos, played frame by frame in a loop.
+
+use std::os::fd::RawFd;
+use std::time::Duration;
+
+use reimv::video::Video;
+use resvg::tiny_skia;
+
+use super::{Raster, Render, Target};
+use crate::animation::Playback;
+use crate::image::ImageTransform;
+
+pub struct Clip {
+    /// The current frame, uploaded when it is rendered
+    frame: Raster,
+    video: Video,
+    playback: Playback,
+    /// Whether the user has paused it
+    stopped: bool,
+    /// Whether the next frame is due, or has been stepped to, but has not been decoded yet
+    waiting: bool,
+}
+
+impl Clip {
+    /// Play `video`, of which `first` is the first frame, unless it is `stopped`.
+    pub fn new(first: Raster, video: Video, stopped: bool) -> Self {
+        let mut playback = Playback::start();
+        playback.schedule(video.interval);
+        playback.set_paused(stopped);
+        Self {
+            frame: first,
+            video,
+            playback,
+            stopped,
+            waiting: false,
+        }
+    }
+
+    /// Pause or continue, and return whether it is paused now.
+    pub fn toggle(&mut self) -> bool {
+        self.stopped = !self.stopped;
+        self.playback.set_paused(self.stopped);
+        self.stopped
+    }
+
+    /// Pause while nobody can see it, without continuing if the user has paused it.
+    pub fn hide(&mut self, hidden: bool) {
+        self.playback.set_paused(hidden || self.stopped);
+    }
+
+    /// Pause and show the next frame. Returns `true` if it has been shown already, otherwise it
+    /// is shown by [`Render::tick`] once it is decoded.
+    pub fn step(&mut self) -> bool {
+        self.stopped = true;
+        self.playback.set_paused(true);
+        self.waiting = true;
+        self.next_frame()
+    }
+
+    /// Show the next frame if it has been decoded, and keep the current one otherwise.
+    fn next_frame(&mut self) -> bool {
+        match self.video.try_next_frame() {
+            Some(Ok(pixels)) => {
+                self.frame = Raster::frame(pixels);
+                self.waiting = false;
+                true
+            }
+            Some(Err(e)) => {
+                eprintln!("reimv: stopping the video: {e:#}");
+                self.playback.stop();
+                self.waiting = false;
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+impl Render for Clip {
+    fn natural_size(&self) -> (f32, f32) {
+        self.frame.natural_size()
+    }
+
+    fn render(&mut self, transform: &ImageTransform, target: &mut Target) {
+        self.frame.render(transform, target);
+    }
+
+    fn draw(&self, canvas: &mut tiny_skia::PixmapMut, transform: tiny_skia::Transform) {
+        self.frame.draw(canvas, transform);
+    }
+
+    fn tick(&mut self) -> bool {
+        if !self.waiting {
+            if !self.playback.due() {
+                return false;
+            }
+            self.playback.schedule(self.video.interval);
+            self.waiting = true;
+        }
+        self.next_frame()
+    }
+
+    fn next_tick(&self) -> Option<Duration> {
+        match self.waiting {
+            true => None,
+            false => self.playback.sleep(),
+        }
+    }
+
+    fn wakeup_fd(&self) -> Option<RawFd> {
+        self.waiting.then(|| self.video.wakeup_fd())
+    }
+}
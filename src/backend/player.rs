@@ -0,0 +1,417 @@
+//! GIF and APNG animations, drawn frame by frame. The frames are decoded in a background thread,
+//! a few ahead of the one shown, so that long animations start at once and only take memory for
+//! the frames about to be shown. Only the part of the image which has changed is copied to the
+//! buffer and damaged, which for most animations is a small part of each frame.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use wayrs_client::protocol::*;
+use wayrs_client::proxy::Proxy;
+use wayrs_utils::shm_alloc::BufferSpec;
+
+use anyhow::{anyhow, Result};
+use image::RgbaImage;
+use resvg::tiny_skia;
+
+use super::{Raster, Render, Target};
+use crate::animation::Playback;
+use crate::convert;
+use crate::frames::{Composer, Frames, Rect};
+use crate::image::ImageTransform;
+
+/// How many frames are decoded ahead of the one shown.
+const AHEAD: usize = 4;
+
+/// How many changes back a buffer can be brought up to date with only the parts which have
+/// changed, rather than copied whole.
+const HISTORY: usize = 8;
+
+pub struct Player {
+    /// The current frame, as it is shown
+    raster: Raster,
+    frames: Arc<Frames>,
+    composer: Composer,
+    /// The last frame drawn onto the image, unless it has been cleared since
+    drawn: Option<usize>,
+    /// The frame to show, which is drawn once the frames up to it have been decoded
+    target: usize,
+    stream: Option<Stream>,
+    /// How often the animation has been played to the end
+    played: u32,
+    /// The clock, unless the animation is frozen, stopped at a frame or over
+    playback: Option<Playback>,
+    changes: Changes,
+}
+
+/// Frames decoded in a background thread, in order and round and round, until it is dropped.
+struct Stream {
+    frames: mpsc::Receiver<Result<RgbaImage>>,
+    /// Becomes readable when a frame has been decoded, with a byte for each frame
+    wakeup: UnixStream,
+    /// The frame which comes next
+    next: usize,
+    count: usize,
+}
+
+/// What has changed on the image, so that a buffer which held it before only needs the parts
+/// which have changed since. The compositor releases buffers in the order they were attached, so
+/// the released one is usually a frame or two behind.
+struct Changes {
+    /// The number of changes so far
+    version: u64,
+    /// The parts changed by the most recent changes, the last one last
+    recent: VecDeque<Rect>,
+    /// The serials of the allocations of recent uploads, and the versions they hold
+    buffers: VecDeque<(u64, u64)>,
+    /// The version which the surface shows
+    attached: u64,
+}
+
+impl Player {
+    /// Play `frames`, of which `raster` shows the first one, unless it is `frozen`.
+    pub fn new(raster: Raster, frames: Frames, frozen: bool) -> Self {
+        let (width, height) = raster.pixels.dimensions();
+        let mut player = Self {
+            composer: Composer::after_first(frames.info(0), width, height),
+            raster,
+            frames: Arc::new(frames),
+            drawn: Some(0),
+            target: 0,
+            stream: None,
+            played: 0,
+            playback: None,
+            changes: Changes {
+                version: 0,
+                recent: VecDeque::new(),
+                buffers: VecDeque::new(),
+                attached: 0,
+            },
+        };
+        player.set_frozen(frozen);
+        player
+    }
+
+    pub fn pixels(&self) -> &RgbaImage {
+        &self.raster.pixels
+    }
+
+    /// The frame which is shown, or about to be, like `frame 3/12`.
+    pub fn label(&self) -> String {
+        format!("frame {}/{}", self.target + 1, self.frames.count())
+    }
+
+    /// Whether the animation is playing, even if it is paused.
+    pub fn playing(&self) -> bool {
+        self.playback.is_some()
+    }
+
+    /// Stop playing the animation where it is, or continue it.
+    pub fn set_paused(&mut self, paused: bool) {
+        if let Some(playback) = &mut self.playback {
+            playback.set_paused(paused);
+        }
+    }
+
+    /// Show the first frame only, or play the animation on from the frame which is shown.
+    pub fn set_frozen(&mut self, frozen: bool) {
+        if frozen {
+            self.playback = None;
+            self.go_to(0);
+            return;
+        }
+        if self.target == self.frames.count() - 1 {
+            self.go_to(0);
+        }
+        self.played = 0;
+        let mut playback = Playback::start();
+        playback.schedule(self.frames.info(self.target).delay);
+        self.playback = Some(playback);
+    }
+
+    /// Stop the animation and show the frame which is `delta` frames away from the current one,
+    /// stopping at the first and the last frame. Returns `false` if the frame stays.
+    pub fn turn(&mut self, delta: isize) -> bool {
+        let index = self
+            .target
+            .saturating_add_signed(delta)
+            .min(self.frames.count() - 1);
+        self.playback = None;
+        if index == self.target {
+            return false;
+        }
+        self.go_to(index);
+        true
+    }
+
+    /// Whether the frame to show is shown.
+    fn ready(&self) -> bool {
+        self.drawn == Some(self.target)
+    }
+
+    /// Show `index` from now on, starting over from a cleared image if it comes before the last
+    /// frame drawn. Returns `true` if the image has changed.
+    fn go_to(&mut self, index: usize) -> bool {
+        self.target = index;
+        let mut cleared = false;
+        if self.drawn.is_some_and(|drawn| drawn > index) {
+            let changed = self.composer.clear(&mut self.raster.pixels);
+            self.changes.push(changed);
+            self.drawn = None;
+            cleared = true;
+        }
+        self.draw_decoded() || cleared
+    }
+
+    /// Draw the frames up to the one to show, as far as they have been decoded. They are drawn
+    /// from a new stream if the current one is somewhere else. Returns `true` if the image has
+    /// changed.
+    fn draw_decoded(&mut self) -> bool {
+        let mut changed = Rect::default();
+        while !self.ready() {
+            let next = self.drawn.map_or(0, |drawn| drawn + 1);
+            if self.stream.as_ref().is_none_or(|s| s.next != next) {
+                match Stream::spawn(Arc::clone(&self.frames), next) {
+                    Ok(stream) => self.stream = Some(stream),
+                    Err(e) => {
+                        eprintln!("reimv: could not decode the animation: {e}");
+                        self.stop();
+                        break;
+                    }
+                }
+            }
+            match self.stream.as_mut().unwrap().try_next() {
+                Some(Ok(pixels)) => {
+                    let info = self.frames.info(next);
+                    let rect = self.composer.draw(&mut self.raster.pixels, info, &pixels);
+                    changed = changed.union(rect);
+                    self.drawn = Some(next);
+                }
+                Some(Err(e)) => {
+                    eprintln!("reimv: stopping the animation: {e:#}");
+                    self.stop();
+                    break;
+                }
+                // Not decoded yet
+                None => break,
+            }
+        }
+        self.changes.push(changed);
+        !changed.is_empty()
+    }
+
+    /// Stop at the last frame drawn, for good.
+    fn stop(&mut self) {
+        self.playback = None;
+        self.stream = None;
+        self.target = self.drawn.unwrap_or(0);
+        self.drawn = Some(self.target);
+    }
+
+    /// Bring a buffer up to date and attach it.
+    fn upload(&mut self, target: &mut Target) {
+        let image = &self.raster.pixels;
+        let (width, height) = image.dimensions();
+        let whole = Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        let (buffer, canvas) = target
+            .shm
+            .alloc_buffer(
+                target.conn,
+                BufferSpec {
+                    width,
+                    height,
+                    stride: width * 4,
+                    format: wl_shm::Format::Abgr8888,
+                },
+            )
+            .unwrap();
+        let copy = buffer
+            .previous()
+            .and_then(|serial| self.changes.held_by(serial))
+            .and_then(|version| self.changes.since(version))
+            .unwrap_or(whole);
+        for y in copy.y..copy.y + copy.height {
+            let start = (y * width + copy.x) as usize * 4;
+            let row = start..start + copy.width as usize * 4;
+            canvas[row.clone()].copy_from_slice(&image.as_raw()[row.clone()]);
+            convert::premultiply(&mut canvas[row]);
+        }
+        let damage = self.changes.since(self.changes.attached).unwrap_or(whole);
+        self.changes.uploaded(buffer.serial());
+
+        let (conn, surface) = (&mut *target.conn, target.surface);
+        surface.attach(conn, Some(buffer.into_wl_buffer()), 0, 0);
+        // Damage in buffer pixels needs version 4 of wl_surface. Before that it is in surface
+        // coordinates, which the viewport scales, so all of it is damaged.
+        match surface.version() >= 4 {
+            true => surface.damage_buffer(
+                conn,
+                damage.x as i32,
+                damage.y as i32,
+                damage.width as i32,
+                damage.height as i32,
+            ),
+            false => surface.damage(conn, 0, 0, i32::MAX, i32::MAX),
+        }
+    }
+}
+
+impl Changes {
+    fn push(&mut self, rect: Rect) {
+        if rect.is_empty() {
+            return;
+        }
+        self.version += 1;
+        self.recent.push_back(rect);
+        if self.recent.len() > HISTORY {
+            self.recent.pop_front();
+        }
+    }
+
+    /// The part which has changed since `version`, or `None` if that was too long ago to tell.
+    fn since(&self, version: u64) -> Option<Rect> {
+        let count = (self.version - version) as usize;
+        (count <= self.recent.len()).then(|| {
+            self.recent
+                .iter()
+                .rev()
+                .take(count)
+                .fold(Rect::default(), |changed, &rect| changed.union(rect))
+        })
+    }
+
+    /// The version in the buffer of the allocation `serial`, if it was one of ours.
+    fn held_by(&self, serial: u64) -> Option<u64> {
+        self.buffers
+            .iter()
+            .find(|&&(s, _)| s == serial)
+            .map(|&(_, version)| version)
+    }
+
+    /// Remember that the current version has been attached in the buffer of the allocation
+    /// `serial`.
+    fn uploaded(&mut self, serial: u64) {
+        self.buffers.push_back((serial, self.version));
+        if self.buffers.len() > HISTORY {
+            self.buffers.pop_front();
+        }
+        self.attached = self.version;
+    }
+}
+
+impl Render for Player {
+    fn natural_size(&self) -> (f32, f32) {
+        self.raster.natural_size()
+    }
+
+    fn render(&mut self, transform: &ImageTransform, target: &mut Target) {
+        if self.changes.attached != self.changes.version {
+            self.upload(target);
+        }
+        self.raster.render(transform, target);
+    }
+
+    fn draw(&self, canvas: &mut tiny_skia::PixmapMut, transform: tiny_skia::Transform) {
+        self.raster.draw(canvas, transform);
+    }
+
+    fn tick(&mut self) -> bool {
+        let due = self.playback.as_mut().is_some_and(Playback::due);
+        let changed = if due && self.ready() {
+            let mut next = self.target + 1;
+            if next == self.frames.count() {
+                self.played += 1;
+                if self
+                    .frames
+                    .plays()
+                    .is_some_and(|plays| self.played >= plays)
+                {
+                    // Nothing is decoded ahead any more
+                    self.playback = None;
+                    self.stream = None;
+                    return false;
+                }
+                next = 0;
+            }
+            self.go_to(next)
+        } else if !self.ready() {
+            self.draw_decoded()
+        } else {
+            return false;
+        };
+        // While the frame is decoded, the clock waits for it
+        if self.ready() {
+            let delay = self.frames.info(self.target).delay;
+            if let Some(playback) = &mut self.playback {
+                playback.schedule(delay);
+            }
+        }
+        changed
+    }
+
+    fn next_tick(&self) -> Option<Duration> {
+        match self.ready() {
+            true => self.playback.as_ref()?.sleep(),
+            false => None,
+        }
+    }
+
+    fn wakeup_fd(&self) -> Option<RawFd> {
+        match self.ready() {
+            true => None,
+            false => self.stream.as_ref().map(|s| s.wakeup.as_raw_fd()),
+        }
+    }
+}
+
+impl Stream {
+    /// Decode the frames from `start` on.
+    fn spawn(frames: Arc<Frames>, start: usize) -> io::Result<Self> {
+        let (tx, rx) = mpsc::sync_channel(AHEAD);
+        let (wakeup, mut wakeup_tx) = UnixStream::pair()?;
+        wakeup.set_nonblocking(true)?;
+        let count = frames.count();
+        std::thread::spawn(move || {
+            for index in (start..count).chain((0..count).cycle()) {
+                let frame = frames.decode(index);
+                let failed = frame.is_err();
+                // Until the stream is dropped
+                if tx.send(frame).is_err() {
+                    return;
+                }
+                let _ = wakeup_tx.write_all(&[0]);
+                if failed {
+                    return;
+                }
+            }
+        });
+        Ok(Self {
+            frames: rx,
+            wakeup,
+            next: start,
+            count,
+        })
+    }
+
+    /// The next frame, or `None` if it has not been decoded yet.
+    fn try_next(&mut self) -> Option<Result<RgbaImage>> {
+        // The bytes only wake the event loop up
+        while (&self.wakeup).read(&mut [0; 64]).is_ok_and(|len| len > 0) {}
+        let frame = match self.frames.try_recv() {
+            Ok(frame) => frame,
+            Err(mpsc::TryRecvError::Empty) => return None,
+            Err(mpsc::TryRecvError::Disconnected) => Err(anyhow!("the decoder has stopped")),
+        };
+        self.next = (self.next + 1) % self.count;
+        Some(frame)
+    }
+}
@@ -0,0 +1,242 @@
+//! Raster images, uploaded once and cropped and scaled by the compositor.
+
+use wayrs_client::protocol::*;
+use wayrs_client::Connection;
+use wayrs_utils::shm_alloc::BufferSpec;
+
+use half::f16;
+use image::{imageops, RgbaImage};
+use resvg::tiny_skia;
+
+use super::visible::View;
+use super::{Render, Target};
+use crate::convert;
+use crate::decode::Rgba16Image;
+use crate::hdr::HdrImage;
+use crate::image::ImageTransform;
+use crate::shm::ShmAlloc;
+use crate::State;
+
+pub struct Raster {
+    /// The pixels which have been uploaded, kept for inspection
+    pub pixels: RgbaImage,
+    upload: Upload,
+}
+
+/// When the pixels are uploaded.
+enum Upload {
+    /// Before the image is shown, all of them
+    Done,
+    /// For the frames of animations: when rendered, scaled down to the level of detail they are
+    /// seen at (see [`View::level`]), so that large frames shown zoomed out do not cost a full
+    /// upload each. `None` until the first render.
+    Reduced(Option<u32>),
+}
+
+impl Raster {
+    /// An image whose pixels have already been uploaded.
+    pub fn new(pixels: RgbaImage) -> Self {
+        Self {
+            pixels,
+            upload: Upload::Done,
+        }
+    }
+
+    /// A frame of an animation, uploaded when it is rendered.
+    pub fn frame(pixels: RgbaImage) -> Self {
+        Self {
+            pixels,
+            upload: Upload::Reduced(None),
+        }
+    }
+}
+
+impl Render for Raster {
+    fn natural_size(&self) -> (f32, f32) {
+        (self.pixels.width() as f32, self.pixels.height() as f32)
+    }
+
+    fn render(&mut self, img_transform: &ImageTransform, target: &mut Target) {
+        let (width, height) = self.pixels.dimensions();
+        let level = match &mut self.upload {
+            Upload::Done => 1,
+            Upload::Reduced(uploaded) => {
+                let view = View::new(
+                    target.width,
+                    target.height,
+                    target.ui_scale120,
+                    *img_transform,
+                );
+                let level = view.level(width.min(height));
+                if *uploaded != Some(level) {
+                    *uploaded = Some(level);
+                    let reduced = match level {
+                        1 => None,
+                        _ => Some(imageops::thumbnail(
+                            &self.pixels,
+                            width.div_ceil(level),
+                            height.div_ceil(level),
+                        )),
+                    };
+                    let pixels = reduced.as_ref().unwrap_or(&self.pixels);
+                    upload(target.conn, target.shm, target.surface, pixels);
+                }
+                level
+            }
+        };
+        // The viewport source is in buffer pixels
+        let (buffer_width, buffer_height) = (width.div_ceil(level), height.div_ceil(level));
+        let scale_x = buffer_width as f32 / width as f32;
+        let scale_y = buffer_height as f32 / height as f32;
+        let transform = tiny_skia::Transform::identity()
+            .post_scale(img_transform.scale, img_transform.scale)
+            .post_translate(img_transform.x, img_transform.y);
+        let transform_inv = tiny_skia::Transform::identity()
+            .pre_scale(img_transform.scale.recip(), img_transform.scale.recip())
+            .pre_translate(-img_transform.x, -img_transform.y);
+
+        let window =
+            tiny_skia::Rect::from_xywh(0.0, 0.0, target.width as f32, target.height as f32)
+                .unwrap();
+
+        let dst = tiny_skia::Rect::from_xywh(0.0, 0.0, width as f32, height as f32)
+            .unwrap()
+            .transform(transform)
+            .unwrap()
+            .intersect(&window);
+
+        let conn = &mut *target.conn;
+        match dst {
+            Some(dst) if dst.width() >= 1.0 && dst.height() >= 1.0 => {
+                let src = dst.transform(transform_inv).unwrap();
+                target
+                    .subsurface
+                    .set_position(conn, dst.x() as i32, dst.y() as i32);
+                target
+                    .viewport
+                    .set_destination(conn, dst.width() as i32, dst.height() as i32);
+                target.viewport.set_source(
+                    conn,
+                    (src.x() * scale_x).into(),
+                    (src.y() * scale_y).into(),
+                    (src.width() * scale_x)
+                        .clamp(1.0, buffer_width as f32)
+                        .into(),
+                    (src.height() * scale_y)
+                        .clamp(1.0, buffer_height as f32)
+                        .into(),
+                );
+            }
+            _ => {
+                // HACK
+                target.subsurface.set_position(conn, 0, 0);
+                target.viewport.set_destination(conn, 1, 1);
+            }
+        }
+    }
+
+    fn draw(&self, canvas: &mut tiny_skia::PixmapMut, transform: tiny_skia::Transform) {
+        let pixels = &self.pixels;
+        let mut data = pixels.as_raw().clone();
+        convert::premultiply(&mut data);
+        let size = tiny_skia::IntSize::from_wh(pixels.width(), pixels.height()).unwrap();
+        let pixmap = tiny_skia::Pixmap::from_vec(data, size).unwrap();
+        let paint = tiny_skia::PixmapPaint {
+            quality: tiny_skia::FilterQuality::Bilinear,
+            ..Default::default()
+        };
+        canvas.draw_pixmap(0, 0, pixmap.as_ref(), &paint, transform, None);
+    }
+}
+
+/// Upload 8-bit pixels, premultiplied.
+pub fn upload(
+    conn: &mut Connection<State>,
+    shm: &mut ShmAlloc,
+    surface: WlSurface,
+    image: &RgbaImage,
+) {
+    let width = image.width();
+    let height = image.height();
+
+    let (buffer, canvas) = shm
+        .alloc_buffer(
+            conn,
+            BufferSpec {
+                width,
+                height,
+                stride: width * 4,
+                format: wl_shm::Format::Abgr8888,
+            },
+        )
+        .unwrap();
+    canvas.copy_from_slice(image.as_raw());
+    convert::premultiply(canvas);
+    surface.attach(conn, Some(buffer.into_wl_buffer()), 0, 0);
+    surface.damage(conn, 0, 0, i32::MAX, i32::MAX);
+}
+
+/// Upload 16-bit pixels, premultiplied.
+pub fn upload_deep(
+    conn: &mut Connection<State>,
+    shm: &mut ShmAlloc,
+    surface: WlSurface,
+    image: &Rgba16Image,
+) {
+    let (width, height) = image.dimensions();
+
+    let (buffer, canvas) = shm
+        .alloc_buffer(
+            conn,
+            BufferSpec {
+                width,
+                height,
+                stride: width * 8,
+                format: wl_shm::Format::Abgr16161616,
+            },
+        )
+        .unwrap();
+    for (&[r, g, b, a], dst) in image.pixels().map(|p| &p.0).zip(canvas.chunks_exact_mut(8)) {
+        let premultiply = |c: u16| ((c as u32 * a as u32 + 32767) / 65535) as u16;
+        for (value, dst) in [premultiply(r), premultiply(g), premultiply(b), a]
+            .into_iter()
+            .zip(dst.chunks_exact_mut(2))
+        {
+            dst.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+    surface.attach(conn, Some(buffer.into_wl_buffer()), 0, 0);
+    surface.damage(conn, 0, 0, i32::MAX, i32::MAX);
+}
+
+/// Upload the linear pixels of an HDR image as premultiplied half floats.
+pub fn upload_hdr(
+    conn: &mut Connection<State>,
+    shm: &mut ShmAlloc,
+    surface: WlSurface,
+    image: &HdrImage,
+) {
+    let (width, height) = image.dimensions();
+
+    let (buffer, canvas) = shm
+        .alloc_buffer(
+            conn,
+            BufferSpec {
+                width,
+                height,
+                stride: width * 8,
+                format: wl_shm::Format::Abgr16161616f,
+            },
+        )
+        .unwrap();
+    for ([r, g, b, a], dst) in image.linear().zip(canvas.chunks_exact_mut(8)) {
+        for (value, dst) in [r * a, g * a, b * a, a]
+            .into_iter()
+            .zip(dst.chunks_exact_mut(2))
+        {
+            dst.copy_from_slice(&f16::from_f32(value).to_le_bytes());
+        }
+    }
+    surface.attach(conn, Some(buffer.into_wl_buffer()), 0, 0);
+    surface.damage(conn, 0, 0, i32::MAX, i32::MAX);
+}
@@ -0,0 +1,81 @@
+//! SVG images, drawn at the resolution of the window for every frame.
+
+use wayrs_client::protocol::*;
+use wayrs_utils::shm_alloc::BufferSpec;
+
+use resvg::{tiny_skia, usvg};
+
+use super::{buffer_size, Render, Target};
+use crate::image::ImageTransform;
+
+pub struct Svg {
+    tree: Box<usvg::Tree>,
+}
+
+impl Svg {
+    pub fn new(tree: Box<usvg::Tree>) -> Self {
+        Self { tree }
+    }
+
+    /// Show another document, such as the next frame of an animation.
+    pub fn replace(&mut self, tree: usvg::Tree) {
+        *self.tree = tree;
+    }
+}
+
+impl Render for Svg {
+    fn natural_size(&self) -> (f32, f32) {
+        (self.tree.size().width(), self.tree.size().height())
+    }
+
+    fn render(&mut self, transform: &ImageTransform, target: &mut Target) {
+        let ui_scale120 = target.ui_scale120;
+        let transform = tiny_skia::Transform::identity()
+            .post_scale(transform.scale, transform.scale)
+            .post_translate(transform.x, transform.y)
+            .post_scale(ui_scale120 as f32 / 120.0, ui_scale120 as f32 / 120.0);
+
+        let (pix_width, pix_height) = buffer_size(target.width, target.height, ui_scale120);
+
+        let (buffer, canvas) = target
+            .shm
+            .alloc_buffer(
+                target.conn,
+                BufferSpec {
+                    width: pix_width,
+                    height: pix_height,
+                    stride: pix_width * 4,
+                    format: wl_shm::Format::Abgr8888,
+                },
+            )
+            .unwrap();
+        canvas.fill(20);
+
+        let mut canvas = tiny_skia::PixmapMut::from_bytes(canvas, pix_width, pix_height).unwrap();
+
+        resvg::render(&self.tree, transform, &mut canvas);
+
+        target
+            .surface
+            .attach(target.conn, Some(buffer.into_wl_buffer()), 0, 0);
+        target
+            .viewport
+            .set_destination(target.conn, target.width as i32, target.height as i32);
+        target.surface.damage(target.conn, 0, 0, i32::MAX, i32::MAX);
+    }
+
+    fn draw(&self, canvas: &mut tiny_skia::PixmapMut, transform: tiny_skia::Transform) {
+        // The translucent canvas of `render`
+        let mut paint = tiny_skia::Paint::default();
+        paint.set_color_rgba8(255, 255, 255, 20);
+        let rect =
+            tiny_skia::Rect::from_xywh(0.0, 0.0, canvas.width() as f32, canvas.height() as f32);
+        canvas.fill_rect(
+            rect.unwrap(),
+            &paint,
+            tiny_skia::Transform::identity(),
+            None,
+        );
+        resvg::render(&self.tree, transform, canvas);
+    }
+}
@@ -0,0 +1,99 @@
+//! Which part of an image is in the window, and at which level of detail it is seen. Backends
+//! which hold an image in parts, such as [`crate::tiles`], use this to only read and upload the
+//! parts which are seen, at the resolution they are seen at.
+
+use crate::image::ImageTransform;
+
+/// How an image is shown in the window.
+#[derive(Debug, Clone, Copy)]
+pub struct View {
+    /// The size of the window in surface local coordinates
+    pub window: (f32, f32),
+    /// Buffer pixels per surface local unit
+    pub ui_scale: f32,
+    pub transform: ImageTransform,
+}
+
+/// A rectangle of image pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl View {
+    pub fn new(
+        win_width: u32,
+        win_height: u32,
+        ui_scale120: u32,
+        transform: ImageTransform,
+    ) -> Self {
+        Self {
+            window: (win_width as f32, win_height as f32),
+            ui_scale: ui_scale120 as f32 / 120.0,
+            transform,
+        }
+    }
+
+    /// The same view of a version of the image with `factor` times as many pixels in each
+    /// direction, e.g. of the full resolution of a reduced image.
+    pub fn of_larger(&self, factor: u32) -> Self {
+        let mut view = *self;
+        view.transform.scale /= factor as f32;
+        view
+    }
+
+    /// Buffer pixels per pixel of the image, in each direction. Above 1, each pixel is
+    /// stretched over several buffer pixels.
+    pub fn density(&self) -> f32 {
+        self.transform.scale * self.ui_scale
+    }
+
+    /// The pixels of an image of `size` which are in the window, or `None` if none are.
+    pub fn visible(&self, (width, height): (u32, u32)) -> Option<Region> {
+        let scale = self.transform.scale;
+        let span = |offset: f32, size: f32, len: u32| {
+            let start = (-offset / scale).clamp(0.0, len as f32);
+            let end = ((size - offset) / scale).clamp(0.0, len as f32);
+            (start < end).then_some((start as u32, end.ceil() as u32))
+        };
+        let (x0, x1) = span(self.transform.x, self.window.0, width)?;
+        let (y0, y1) = span(self.transform.y, self.window.1, height)?;
+        Some(Region {
+            x: x0,
+            y: y0,
+            width: x1 - x0,
+            height: y1 - y0,
+        })
+    }
+
+    /// The level of detail to show: every `level`th pixel of every `level`th row. This is the
+    /// number of pixels per buffer pixel rounded down to a power of two, so that the image is
+    /// never blurrier than the window, and at most `max`.
+    pub fn level(&self, max: u32) -> u32 {
+        let per_pixel = self.density().recip().max(1.0);
+        (1u32 << per_pixel.log2().floor() as u32).min(max)
+    }
+}
+
+impl Region {
+    /// The squares of a grid of `span` pixels over an image of `size`, which intersect this
+    /// region. The squares at the right and bottom edges are cut at the edges of the image.
+    pub fn tiles(&self, (width, height): (u32, u32), span: u32) -> impl Iterator<Item = Region> {
+        let (x0, x1) = (self.x / span, (self.x + self.width - 1) / span);
+        let (y0, y1) = (self.y / span, (self.y + self.height - 1) / span);
+        (y0..=y1).flat_map(move |row| {
+            (x0..=x1).map(move |col| {
+                let (x, y) = (col * span, row * span);
+                Region {
+                    x,
+                    y,
+                    width: span.min(width - x),
+                    height: span.min(height - y),
+                }
+            })
+        })
+    }
+}
@@ -0,0 +1,91 @@
+//! A client for the IPC socket of reimv, for scripts. It sends a single JSON-RPC request and prints
+//! its result as JSON, or after `subscribe`, the events until reimv exits:
+//!
+//! ```sh
+//! reimv-ctl view.set zoom=2 center=[1234,567]
+//! reimv-ctl subscribe 'events=["image"]'
+//! ```
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use reimv::json::Value;
+
+/// Send a request to reimv on its IPC socket and print the result
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct CliArgs {
+    /// The socket given to reimv with --ipc-socket
+    #[arg(long, env = "REIMV_IPC_SOCKET", value_name = "PATH")]
+    socket: PathBuf,
+    /// The method, like `image.get`, `view.set` or `subscribe`
+    method: String,
+    /// The parameters, like `zoom=2` or `center=[10,20]`. Values which are not JSON are strings,
+    /// so `path=/tmp/view.png` needs no quotes
+    #[arg(value_name = "NAME=VALUE", value_parser = parse_param)]
+    params: Vec<(String, Value)>,
+}
+
+fn parse_param(text: &str) -> Result<(String, Value)> {
+    let (name, value) = text
+        .split_once('=')
+        .context("expected NAME=VALUE, like zoom=2")?;
+    let value = Value::parse(value).unwrap_or_else(|_| value.into());
+    Ok((name.to_owned(), value))
+}
+
+fn main() -> Result<()> {
+    let cli_args = CliArgs::parse();
+
+    let mut request = vec![
+        ("jsonrpc", "2.0".into()),
+        ("id", 1_i64.into()),
+        ("method", cli_args.method.as_str().into()),
+    ];
+    if !cli_args.params.is_empty() {
+        request.push(("params", Value::Object(cli_args.params)));
+    }
+    let stream = UnixStream::connect(&cli_args.socket)
+        .with_context(|| format!("could not connect to {}", cli_args.socket.display()))?;
+    writeln!(&stream, "{}", Value::object(request)).context("could not send the request")?;
+
+    let mut lines = BufReader::new(&stream).lines();
+    let response = loop {
+        let line = lines
+            .next()
+            .context("reimv closed the connection")?
+            .context("could not read the response")?;
+        let message = Value::parse(&line).context("invalid response")?;
+        if message.get("id").is_some() {
+            break message;
+        }
+    };
+    if let Some(error) = response.get("error") {
+        let message = error.get("message").and_then(Value::as_str);
+        bail!("{}", message.unwrap_or("unknown error"));
+    }
+    match response.get("result") {
+        None | Some(Value::Null) => (),
+        Some(result) => println!("{result}"),
+    }
+
+    if cli_args.method == "subscribe" {
+        let mut stdout = std::io::stdout();
+        for line in lines {
+            let Ok(line) = line else {
+                break;
+            };
+            // Stop once the output is closed, like by `head`
+            if writeln!(stdout, "{line}")
+                .and_then(|()| stdout.flush())
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! Compositing premultiplied pixels, the way the compositor stacks the surfaces of the window.
+//!
+//! All buffers have premultiplied alpha, so a surface is blended over the ones below it with
+//! `dst = src + dst * (1 - src_alpha)`. Where that happens matters for translucent pixels, like
+//! the edges of text: sRGB surfaces are blended as they are encoded, while a compositor which
+//! shows an scRGB image, see [`crate::hdr_output`], blends in linear light. Snapshots do the
+//! same with [`over`], so that the overlay looks like it does on the screen.
+
+use crate::convert;
+use crate::hdr::{linear_to_srgb, srgb_to_linear};
+
+/// Where translucent pixels are blended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Space {
+    /// The sRGB encoded values
+    Srgb,
+    /// Linear light, with the sRGB primaries
+    Linear,
+}
+
+/// Premultiply a color with straight alpha.
+pub fn premultiply([r, g, b, a]: [u8; 4]) -> [u8; 4] {
+    let mul = |c: u8| convert::div255(c as u16 * a as u16);
+    [mul(r), mul(g), mul(b), a]
+}
+
+/// Composite the premultiplied RGBA pixels `src` over `dst`, four bytes each, in `space`.
+pub fn over(dst: &mut [u8], src: &[u8], space: Space) {
+    for (dst, src) in dst.chunks_exact_mut(4).zip(src.chunks_exact(4)) {
+        match src[3] {
+            0 => continue,
+            255 => dst.copy_from_slice(src),
+            src_alpha => match space {
+                Space::Srgb => {
+                    let rest = 255 - src_alpha as u16;
+                    for (d, &s) in dst.iter_mut().zip(src) {
+                        *d = s.saturating_add(convert::div255(*d as u16 * rest));
+                    }
+                }
+                Space::Linear => {
+                    let (s, d) = (to_linear(src), to_linear(dst));
+                    let rest = 1.0 - s[3];
+                    let blended = [0, 1, 2, 3].map(|i| s[i] + d[i] * rest);
+                    dst.copy_from_slice(&from_linear(blended));
+                }
+            },
+        }
+    }
+}
+
+/// A premultiplied sRGB pixel in premultiplied linear light.
+fn to_linear(pixel: &[u8]) -> [f32; 4] {
+    let a = pixel[3] as f32 / 255.0;
+    if a == 0.0 {
+        return [0.0; 4];
+    }
+    let channel = |c: u8| srgb_to_linear((c as f32 / 255.0 / a).min(1.0)) * a;
+    [channel(pixel[0]), channel(pixel[1]), channel(pixel[2]), a]
+}
+
+fn from_linear([r, g, b, a]: [f32; 4]) -> [u8; 4] {
+    if a <= 0.0 {
+        return [0; 4];
+    }
+    let channel = |c: f32| (linear_to_srgb((c / a).min(1.0)) * a * 255.0 + 0.5) as u8;
+    [channel(r), channel(g), channel(b), (a * 255.0 + 0.5) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+
+    fn half_red_over_blue(space: Space) -> [u8; 4] {
+        let mut dst = BLUE;
+        over(&mut dst, &premultiply([255, 0, 0, 128]), space);
+        dst
+    }
+
+    #[test]
+    fn blends_encoded_values() {
+        assert_eq!(half_red_over_blue(Space::Srgb), [128, 0, 127, 255]);
+    }
+
+    #[test]
+    fn blends_in_linear_light() {
+        assert_eq!(half_red_over_blue(Space::Linear), [188, 0, 187, 255]);
+    }
+
+    #[test]
+    fn opaque_and_transparent_sources() {
+        for space in [Space::Srgb, Space::Linear] {
+            let mut dst = BLUE;
+            over(&mut dst, &[0; 4], space);
+            assert_eq!(dst, BLUE);
+            over(&mut dst, &[10, 20, 30, 255], space);
+            assert_eq!(dst, [10, 20, 30, 255]);
+        }
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        for c in 0..=255 {
+            let pixel = [c, 255 - c, c / 2, 255];
+            assert_eq!(from_linear(to_linear(&pixel)), pixel);
+        }
+    }
+}
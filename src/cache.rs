@@ -0,0 +1,194 @@
+//! Large images scaled down, kept across sessions in `$XDG_CACHE_HOME/reimv/scaled/`.
+//!
+//! Decoding an 80 megapixel panorama takes a while, but most of the time it is only looked at as
+//! a whole. With `--disk-cache`, a version which fits [`CACHED_SIZE`] is stored after the first
+//! decode, and shown at once the next time until it is zoomed in. Every image gets its own file,
+//! named after a hash of its canonical path like in [`crate::persist`]. The file starts with the
+//! path, the modification time and the size of the image, to notice when it has changed, and
+//! ends with the raw pixels, which are read through a memory map. The images shown least recently
+//! are removed once the cache grows beyond its size.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use image::{imageops, RgbaImage};
+use memmap2::Mmap;
+
+use crate::metadata::Exif;
+use crate::persist;
+
+/// Cached images fit into this size, which covers most screens at their full resolution.
+const CACHED_SIZE: (u32, u32) = (3840, 2160);
+
+/// Which image a cache file is for, and how it looked when it was read.
+pub struct Key {
+    /// The canonical path of the image
+    path: PathBuf,
+    file: PathBuf,
+    /// The header lines up to the pixels, without the metadata of the image
+    stamp: String,
+    file_size: u64,
+}
+
+/// A cached image.
+pub struct Cached {
+    /// The scaled down pixels
+    pub pixels: RgbaImage,
+    pub dpi: Option<f32>,
+    pub exif: Exif,
+    pub file_size: u64,
+}
+
+impl Key {
+    /// The key of the image at `path`, which should be taken before it is read. Returns `None`
+    /// if it cannot be cached, e.g. without a cache directory.
+    pub fn new(path: &Path) -> Option<Self> {
+        if path == Path::new("-") {
+            return None;
+        }
+        let path = std::fs::canonicalize(path).ok()?;
+        let metadata = std::fs::metadata(&path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        let hash = persist::fnv1a(path.as_os_str().as_encoded_bytes());
+        let file = cache_dir()?.join(format!("{hash:016x}"));
+        let mut stamp = String::new();
+        let _ = writeln!(stamp, "reimv scaled 1");
+        let _ = writeln!(stamp, "path {}", path.display());
+        let _ = writeln!(
+            stamp,
+            "modified {}.{:09}",
+            modified.as_secs(),
+            modified.subsec_nanos()
+        );
+        let _ = writeln!(stamp, "size {}", metadata.len());
+        Some(Self {
+            path,
+            file,
+            stamp,
+            file_size: metadata.len(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The cached image, unless it is missing or was stored for an older version of the image.
+    pub fn load(&self) -> Option<Cached> {
+        let file = File::open(&self.file).ok()?;
+        // SAFETY: cache files are replaced by renaming, never written in place
+        let mmap = unsafe { Mmap::map(&file) }.ok()?;
+        let rest = mmap.strip_prefix(self.stamp.as_bytes())?;
+
+        let mut cached = Cached {
+            pixels: RgbaImage::default(),
+            dpi: None,
+            exif: Exif::default(),
+            file_size: self.file_size,
+        };
+        let mut rest = rest;
+        loop {
+            let end = rest.iter().position(|&b| b == b'\n')?;
+            let line = std::str::from_utf8(&rest[..end]).ok()?;
+            rest = &rest[end + 1..];
+            let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+            match name {
+                "dpi" => cached.dpi = value.parse().ok(),
+                "date" => cached.exif.date = Some(value.to_owned()),
+                "camera" => cached.exif.camera = Some(value.to_owned()),
+                "gps" => cached.exif.gps = true,
+                "pixels" => {
+                    let (width, height) = value.split_once(' ')?;
+                    let (width, height) = (width.parse().ok()?, height.parse().ok()?);
+                    cached.pixels = RgbaImage::from_raw(width, height, rest.to_vec())?;
+                    break;
+                }
+                _ => (),
+            }
+        }
+        // Shown most recently now, for removing the others first
+        let _ = file.set_modified(SystemTime::now());
+        Some(cached)
+    }
+
+    /// Store `pixels` scaled down, if it is much larger than the cached size, and remove the
+    /// images shown least recently beyond `max_bytes`. Writing happens in the background.
+    pub fn store(self, pixels: &RgbaImage, dpi: Option<f32>, exif: &Exif, max_bytes: u64) {
+        let (width, height) = pixels.dimensions();
+        let factor =
+            (CACHED_SIZE.0 as f64 / width as f64).min(CACHED_SIZE.1 as f64 / height as f64);
+        // Smaller images are decoded about as fast as they are read from the cache
+        if factor > 0.5 {
+            return;
+        }
+        let scaled_width = ((width as f64 * factor).round() as u32).max(1);
+        let scaled_height = ((height as f64 * factor).round() as u32).max(1);
+        let scaled = imageops::thumbnail(pixels, scaled_width, scaled_height);
+
+        let mut header = self.stamp;
+        if let Some(dpi) = dpi {
+            let _ = writeln!(header, "dpi {dpi}");
+        }
+        // The values are single lines, which is all the EXIF fields need
+        for (name, value) in [("date", &exif.date), ("camera", &exif.camera)] {
+            if let Some(value) = value.as_deref().filter(|v| !v.contains('\n')) {
+                let _ = writeln!(header, "{name} {value}");
+            }
+        }
+        if exif.gps {
+            let _ = writeln!(header, "gps");
+        }
+        let _ = writeln!(header, "pixels {scaled_width} {scaled_height}");
+
+        let file = self.file;
+        std::thread::spawn(move || {
+            let write = || {
+                std::fs::create_dir_all(file.parent().unwrap())?;
+                let tmp = file.with_extension("tmp");
+                let mut contents = header.into_bytes();
+                contents.extend_from_slice(scaled.as_raw());
+                std::fs::write(&tmp, contents)?;
+                std::fs::rename(tmp, &file)?;
+                prune(file.parent().unwrap(), max_bytes)
+            };
+            if let Err(e) = write() {
+                eprintln!("reimv: could not write to the disk cache: {e}");
+            }
+        });
+    }
+}
+
+pub fn cache_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("reimv/scaled")),
+        _ => {
+            let home = std::env::var_os("HOME")?;
+            Some(PathBuf::from(home).join(".cache/reimv/scaled"))
+        }
+    }
+}
+
+/// Remove the files modified least recently until the rest takes at most `max_bytes`.
+fn prune(dir: &Path, max_bytes: u64) -> io::Result<()> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        // Files being written have an extension
+        if metadata.is_file() && entry.path().extension().is_none() {
+            files.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+    }
+    files.sort_unstable_by_key(|(modified, _, _)| std::cmp::Reverse(*modified));
+    let mut total = 0;
+    for (_, len, path) in files {
+        total += len;
+        if total > max_bytes {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,65 @@
+//! CMYK and YCCK JPEG files, which come from print workflows.
+//!
+//! The `image` crate converts them to RGB naively and assumes that the inks are stored inverted,
+//! which only Adobe applications do. Files without the Adobe marker then have inverted colors.
+//! Here the inks are read the way the file stores them and converted with the embedded CMYK
+//! profile where possible.
+
+use std::io::Cursor;
+
+use anyhow::{ensure, Result};
+use image::RgbaImage;
+use jpeg_decoder::{Decoder, PixelFormat};
+
+use crate::color::CmykTransform;
+use crate::limits::Limits;
+use crate::metadata;
+
+/// Whether a JPEG file has four color components.
+pub fn is_cmyk(data: &[u8]) -> bool {
+    metadata::jpeg_segments(data)
+        .find(|(marker, _)| is_sof(*marker))
+        .is_some_and(|(_, segment)| segment.get(5) == Some(&4))
+}
+
+/// Start of frame markers, the others in this range are DHT, JPG and DAC.
+fn is_sof(marker: u8) -> bool {
+    matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC)
+}
+
+pub fn decode(data: &[u8], limits: &Limits, color_management: bool) -> Result<RgbaImage> {
+    let mut decoder = Decoder::new(Cursor::new(data));
+    decoder.read_info()?;
+    let info = decoder.info().unwrap();
+    ensure!(info.pixel_format == PixelFormat::CMYK32, "not a CMYK image");
+    let (width, height) = (info.width as u32, info.height as u32);
+    limits.check(width, height)?;
+    decoder.set_max_decoding_buffer_size(limits.max_bytes.try_into().unwrap_or(usize::MAX));
+
+    // The decoder inverts the samples as Adobe applications store them, so that 255 is full ink
+    let mut cmyk = decoder.decode()?;
+    let adobe = metadata::jpeg_segments(data)
+        .any(|(marker, segment)| marker == 0xEE && segment.starts_with(b"Adobe"));
+    if !adobe {
+        cmyk.iter_mut().for_each(|sample| *sample = 255 - *sample);
+    }
+
+    let mut image = RgbaImage::new(width, height);
+    let transform = color_management
+        .then(|| metadata::icc_profile(data))
+        .flatten()
+        .and_then(|profile| CmykTransform::new(&profile));
+    match transform {
+        Some(transform) => transform.apply(&cmyk, &mut image),
+        None => {
+            for (cmyk, rgba) in cmyk.chunks_exact(4).zip(image.chunks_exact_mut(4)) {
+                let white = 255 - cmyk[3] as u32;
+                for i in 0..3 {
+                    rgba[i] = ((255 - cmyk[i] as u32) * white / 255) as u8;
+                }
+                rgba[3] = 255;
+            }
+        }
+    }
+    Ok(image)
+}
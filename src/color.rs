@@ -0,0 +1,574 @@
+//! Converting images from the color space they were made for to sRGB, which is what we assume the
+//! display to be.
+//!
+//! The color space comes from the cICP chunk of PNG files or from an embedded ICC profile. Only
+//! ICC profiles made of tone curves and a matrix are supported, which covers the usual RGB working
+//! spaces (Display P3, Adobe RGB, ProPhoto RGB and so on) and grayscale profiles. Images with other
+//! profiles are shown as they are. CMYK images are the exception, see [`CmykTransform`].
+
+use image::{DynamicImage, RgbaImage};
+
+use crate::decode::Rgba16Image;
+use crate::metadata;
+
+pub type Matrix = [[f32; 3]; 3];
+
+/// The colorants of sRGB adapted to the D50 illuminant, as in the sRGB ICC profile.
+const SRGB_D50: Matrix = [
+    [0.436_08, 0.385_15, 0.143_09],
+    [0.222_49, 0.716_87, 0.060_62],
+    [0.013_92, 0.097_08, 0.714_10],
+];
+
+/// The white point of the profile connection space.
+const D50: [f32; 3] = [0.9642, 1.0, 0.8249];
+const D65: (f32, f32) = (0.3127, 0.3290);
+const BT709_PRIMARIES: [(f32, f32); 3] = [(0.64, 0.33), (0.30, 0.60), (0.15, 0.06)];
+
+/// The sRGB transfer function, as parameters of [`Curve::Parametric`].
+const SRGB_CURVE: [f32; 7] = [
+    2.4,
+    1.0 / 1.055,
+    0.055 / 1.055,
+    1.0 / 12.92,
+    0.04045,
+    0.0,
+    0.0,
+];
+
+/// Size of the table which encodes linear values back to sRGB.
+const ENCODE_LEN: usize = 4096;
+
+/// A function from encoded values to linear light, both in `0..=1`.
+enum Curve {
+    /// `(a * x + b)^g + e` from `d` on, and `c * x + f` below it, with the parameters in the order
+    /// `[g, a, b, c, d, e, f]`.
+    Parametric([f32; 7]),
+    /// Evenly spaced samples, which are interpolated linearly.
+    Table(Vec<f32>),
+}
+
+impl Curve {
+    fn gamma(g: f32) -> Self {
+        Self::Parametric([g, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0])
+    }
+
+    fn eval(&self, x: f32) -> f32 {
+        match self {
+            &Self::Parametric([g, a, b, c, d, e, f]) => {
+                if x >= d {
+                    (a * x + b).max(0.0).powf(g) + e
+                } else {
+                    c * x + f
+                }
+            }
+            Self::Table(samples) => {
+                let pos = x.clamp(0.0, 1.0) * (samples.len() - 1) as f32;
+                let i = (pos as usize).min(samples.len() - 2);
+                let t = pos - i as f32;
+                samples[i] * (1.0 - t) + samples[i + 1] * t
+            }
+        }
+    }
+}
+
+/// A conversion of pixels to sRGB.
+pub struct Transform {
+    curves: [Curve; 3],
+    /// Linear values of each channel, for 8-bit pixels
+    decode: [[f32; 256]; 3],
+    to_srgb: Matrix,
+    encode: Vec<u8>,
+}
+
+impl Transform {
+    /// The conversion for the color space of an image file. Returns `None` if the image does not
+    /// say what it is, if its color space is not supported, or if it is sRGB already.
+    pub fn of(data: &[u8]) -> Option<Self> {
+        let (curves, to_srgb) = match metadata::png_cicp(data) {
+            Some((primaries, transfer)) => from_cicp(primaries, transfer)?,
+            None => from_icc(&metadata::icc_profile(data)?)?,
+        };
+
+        let mut decode = [[0.0; 256]; 3];
+        for (table, curve) in decode.iter_mut().zip(&curves) {
+            for (i, value) in table.iter_mut().enumerate() {
+                *value = curve.eval(i as f32 / 255.0);
+            }
+        }
+
+        let srgb = Curve::Parametric(SRGB_CURVE);
+        let is_srgb = (0..256).all(|i| {
+            let expected = srgb.eval(i as f32 / 255.0);
+            decode
+                .iter()
+                .all(|table| (table[i] - expected).abs() < 0.002)
+        }) && (0..3).all(|row| {
+            (0..3).all(|col| (to_srgb[row][col] - (row == col) as u8 as f32).abs() < 0.002)
+        });
+        if is_srgb {
+            return None;
+        }
+
+        Some(Self {
+            curves,
+            decode,
+            to_srgb,
+            encode: encode_table(),
+        })
+    }
+
+    pub fn apply(&self, image: &mut RgbaImage) {
+        let m = &self.to_srgb;
+        let encode =
+            |x: f32| self.encode[(x.clamp(0.0, 1.0) * (ENCODE_LEN - 1) as f32 + 0.5) as usize];
+        for pixel in image.pixels_mut() {
+            let [r, g, b, _] = &mut pixel.0;
+            let lin = [
+                self.decode[0][*r as usize],
+                self.decode[1][*g as usize],
+                self.decode[2][*b as usize],
+            ];
+            let dot = |row: [f32; 3]| row[0] * lin[0] + row[1] * lin[1] + row[2] * lin[2];
+            *r = encode(dot(m[0]));
+            *g = encode(dot(m[1]));
+            *b = encode(dot(m[2]));
+        }
+    }
+
+    /// Like [`Self::apply`], with tables of all 16-bit values which are only built when needed.
+    pub fn apply16(&self, image: &mut Rgba16Image) {
+        let decode = self.curves.each_ref().map(|curve| {
+            (0..=u16::MAX)
+                .map(|i| curve.eval(i as f32 / 65535.0))
+                .collect::<Vec<_>>()
+        });
+        let encode: Vec<u16> = (0..=u16::MAX)
+            .map(|i| (encode_srgb(i as f32 / 65535.0) * 65535.0).round() as u16)
+            .collect();
+
+        let m = &self.to_srgb;
+        let encode = |x: f32| encode[(x.clamp(0.0, 1.0) * 65535.0 + 0.5) as usize];
+        for pixel in image.pixels_mut() {
+            let [r, g, b, _] = &mut pixel.0;
+            let lin = [
+                decode[0][*r as usize],
+                decode[1][*g as usize],
+                decode[2][*b as usize],
+            ];
+            let dot = |row: [f32; 3]| row[0] * lin[0] + row[1] * lin[1] + row[2] * lin[2];
+            *r = encode(dot(m[0]));
+            *g = encode(dot(m[1]));
+            *b = encode(dot(m[2]));
+        }
+    }
+}
+
+/// Convert a decoded image to sRGB according to the color space recorded in its file. HDR images
+/// are left alone, since they are linear already, and 16-bit images stay 16-bit.
+pub fn to_srgb(data: &[u8], image: DynamicImage) -> DynamicImage {
+    use DynamicImage::*;
+    if matches!(image, ImageRgb32F(_) | ImageRgba32F(_)) {
+        return image;
+    }
+    let Some(transform) = Transform::of(data) else {
+        return image;
+    };
+    match image {
+        ImageLuma16(_) | ImageLumaA16(_) | ImageRgb16(_) | ImageRgba16(_) => {
+            let mut image = image.into_rgba16();
+            transform.apply16(&mut image);
+            image.into()
+        }
+        _ => {
+            let mut image = image.into_rgba8();
+            transform.apply(&mut image);
+            image.into()
+        }
+    }
+}
+
+/// A conversion of CMYK pixels to sRGB through the lookup tables of an ICC profile.
+///
+/// Only version 2 tables (`mft1` and `mft2`) are supported, which is what the usual press profiles
+/// such as SWOP and FOGRA39 have.
+pub struct CmykTransform {
+    /// Positions on the grid for each ink amount
+    input: [[f32; 256]; 4],
+    grid: usize,
+    /// Output values at the grid points, in `0..=1`
+    clut: Vec<f32>,
+    output: [Curve; 3],
+    pcs: Pcs,
+    encode: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+enum Pcs {
+    Lab8,
+    /// The legacy 16-bit encoding of version 2 profiles, where 0xFF00 is L = 100
+    Lab16,
+    Xyz16,
+}
+
+impl CmykTransform {
+    pub fn new(profile: &[u8]) -> Option<Self> {
+        if profile.get(36..40)? != b"acsp" || profile.get(16..20)? != b"CMYK" {
+            return None;
+        }
+        let lut = icc_tag(profile, b"A2B0")?;
+        let (inputs, outputs, grid) = (*lut.get(8)?, *lut.get(9)?, *lut.get(10)? as usize);
+        if inputs != 4 || outputs != 3 || !(2..=64).contains(&grid) {
+            return None;
+        }
+        let sixteen_bit = match lut.get(..4)? {
+            b"mft1" => false,
+            b"mft2" => true,
+            _ => return None,
+        };
+        let pcs = match (profile.get(20..24)?, sixteen_bit) {
+            (b"Lab ", false) => Pcs::Lab8,
+            (b"Lab ", true) => Pcs::Lab16,
+            (b"XYZ ", true) => Pcs::Xyz16,
+            _ => return None,
+        };
+
+        // Input tables, the CLUT and output tables follow each other, with 8 or 16-bit entries
+        let (in_len, out_len, mut offset) = if sixteen_bit {
+            let be_u16 = |i: usize| Some(u16::from_be_bytes(lut.get(i..i + 2)?.try_into().ok()?));
+            (be_u16(48)? as usize, be_u16(50)? as usize, 52usize)
+        } else {
+            (256, 256, 48)
+        };
+        if in_len < 2 || out_len < 2 {
+            return None;
+        }
+        let size = if sixteen_bit { 2 } else { 1 };
+        let mut read = |count: usize| {
+            let bytes = lut.get(offset..offset.checked_add(count.checked_mul(size)?)?)?;
+            offset += count * size;
+            Some(match sixteen_bit {
+                true => bytes
+                    .chunks_exact(2)
+                    .map(|b| u16::from_be_bytes([b[0], b[1]]) as f32 / 65535.0)
+                    .collect::<Vec<_>>(),
+                false => bytes.iter().map(|&b| b as f32 / 255.0).collect(),
+            })
+        };
+
+        let mut input = [[0.0; 256]; 4];
+        for table in &mut input {
+            let curve = Curve::Table(read(in_len)?);
+            for (i, position) in table.iter_mut().enumerate() {
+                *position = curve.eval(i as f32 / 255.0) * (grid - 1) as f32;
+            }
+        }
+        let clut = read(grid.pow(4) * 3)?;
+        let output = [
+            Curve::Table(read(out_len)?),
+            Curve::Table(read(out_len)?),
+            Curve::Table(read(out_len)?),
+        ];
+
+        Some(Self {
+            input,
+            grid,
+            clut,
+            output,
+            pcs,
+            encode: encode_table(),
+        })
+    }
+
+    /// Convert CMYK samples, where 255 is full ink, to RGBA.
+    pub fn apply(&self, cmyk: &[u8], rgba: &mut [u8]) {
+        let to_srgb = invert(&SRGB_D50).unwrap();
+        let mut last = None;
+        for (src, dst) in cmyk.chunks_exact(4).zip(rgba.chunks_exact_mut(4)) {
+            // Print images have large areas of a single color
+            let rgb = match last {
+                Some((prev, rgb)) if prev == src => rgb,
+                _ => {
+                    let rgb = self.convert(src, &to_srgb);
+                    last = Some((src, rgb));
+                    rgb
+                }
+            };
+            dst.copy_from_slice(&[rgb[0], rgb[1], rgb[2], 255]);
+        }
+    }
+
+    fn convert(&self, cmyk: &[u8], to_srgb: &Matrix) -> [u8; 3] {
+        let g = self.grid;
+        let mut base = [0; 4];
+        let mut frac = [0.0; 4];
+        for i in 0..4 {
+            let position = self.input[i][cmyk[i] as usize];
+            base[i] = (position as usize).min(g - 2);
+            frac[i] = position - base[i] as f32;
+        }
+
+        // Interpolate between the 16 surrounding grid points
+        let mut pcs = [0.0; 3];
+        for corner in 0..16 {
+            let mut weight = 1.0;
+            let mut index = 0;
+            for i in 0..4 {
+                let bit = (corner >> (3 - i)) & 1;
+                weight *= if bit == 1 { frac[i] } else { 1.0 - frac[i] };
+                index = index * g + base[i] + bit;
+            }
+            if weight > 0.0 {
+                for (pcs, value) in pcs.iter_mut().zip(&self.clut[index * 3..index * 3 + 3]) {
+                    *pcs += weight * value;
+                }
+            }
+        }
+        let [a, b, c] = [0, 1, 2].map(|i| self.output[i].eval(pcs[i]));
+
+        let xyz = match self.pcs {
+            Pcs::Lab8 => lab_to_xyz(a * 100.0, b * 255.0 - 128.0, c * 255.0 - 128.0),
+            Pcs::Lab16 => {
+                let scale = 65535.0 / 65280.0;
+                lab_to_xyz(
+                    a * scale * 100.0,
+                    b * scale * 255.0 - 128.0,
+                    c * scale * 255.0 - 128.0,
+                )
+            }
+            Pcs::Xyz16 => [a, b, c].map(|v| v * 65535.0 / 32768.0),
+        };
+        to_srgb.map(|row| {
+            let linear = row[0] * xyz[0] + row[1] * xyz[1] + row[2] * xyz[2];
+            self.encode[(linear.clamp(0.0, 1.0) * (ENCODE_LEN - 1) as f32 + 0.5) as usize]
+        })
+    }
+}
+
+/// Convert CIELAB to XYZ, relative to D50.
+fn lab_to_xyz(l: f32, a: f32, b: f32) -> [f32; 3] {
+    let f_inv = |t: f32| {
+        if t > 6.0 / 29.0 {
+            t * t * t
+        } else {
+            3.0 * (6.0 / 29.0f32).powi(2) * (t - 4.0 / 29.0)
+        }
+    };
+    let fy = (l + 16.0) / 116.0;
+    let f = [fy + a / 500.0, fy, fy - b / 200.0];
+    [0, 1, 2].map(|i| D50[i] * f_inv(f[i]))
+}
+
+/// A table from linear light to 8-bit sRGB, indexed by `linear * (ENCODE_LEN - 1)`.
+fn encode_table() -> Vec<u8> {
+    (0..ENCODE_LEN)
+        .map(|i| {
+            let linear = i as f32 / (ENCODE_LEN - 1) as f32;
+            (encode_srgb(linear) * 255.0).round() as u8
+        })
+        .collect()
+}
+
+fn encode_srgb(linear: f32) -> f32 {
+    if linear <= 0.003_130_8 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Tone curves and a matrix to linear sRGB from coding points of ITU-T H.273.
+fn from_cicp(primaries: u8, transfer: u8) -> Option<([Curve; 3], Matrix)> {
+    let primaries = match primaries {
+        1 => BT709_PRIMARIES,
+        9 => [(0.708, 0.292), (0.170, 0.797), (0.131, 0.046)],
+        12 => [(0.680, 0.320), (0.265, 0.690), (0.150, 0.060)],
+        _ => return None,
+    };
+    let curve = || match transfer {
+        // The inverse of the BT.709 camera curve
+        1 | 6 | 14 | 15 => Some(Curve::Parametric([
+            1.0 / 0.45,
+            1.0 / 1.099,
+            0.099 / 1.099,
+            1.0 / 4.5,
+            0.081,
+            0.0,
+            0.0,
+        ])),
+        4 => Some(Curve::gamma(2.2)),
+        5 => Some(Curve::gamma(2.8)),
+        8 => Some(Curve::gamma(1.0)),
+        13 => Some(Curve::Parametric(SRGB_CURVE)),
+        // PQ and HLG are HDR transfer functions, which need tone mapping
+        _ => None,
+    };
+    let curves = [curve()?, curve()?, curve()?];
+    let srgb = rgb_to_xyz(BT709_PRIMARIES, D65)?;
+    let to_srgb = multiply(&invert(&srgb)?, &rgb_to_xyz(primaries, D65)?);
+    Some((curves, to_srgb))
+}
+
+/// Tone curves and a matrix to linear sRGB from an ICC profile.
+fn from_icc(profile: &[u8]) -> Option<([Curve; 3], Matrix)> {
+    if profile.get(36..40)? != b"acsp" {
+        return None;
+    }
+    let tag = |sig| icc_tag(profile, sig);
+
+    match profile.get(16..20)? {
+        b"RGB " => {
+            let curves = [
+                parse_curve(tag(b"rTRC")?)?,
+                parse_curve(tag(b"gTRC")?)?,
+                parse_curve(tag(b"bTRC")?)?,
+            ];
+            let [r, g, b] = [b"rXYZ", b"gXYZ", b"bXYZ"].map(|sig| tag(sig).and_then(parse_xyz));
+            let (r, g, b) = (r?, g?, b?);
+            let to_xyz = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+            Some((curves, multiply(&invert(&SRGB_D50)?, &to_xyz)))
+        }
+        // Gray values are taken to be the same in every channel
+        b"GRAY" => {
+            let curve = tag(b"kTRC")?;
+            let curves = [
+                parse_curve(curve)?,
+                parse_curve(curve)?,
+                parse_curve(curve)?,
+            ];
+            let identity = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+            Some((curves, identity))
+        }
+        _ => None,
+    }
+}
+
+/// The element of an ICC profile with the given tag signature.
+fn icc_tag<'a>(profile: &'a [u8], sig: &[u8; 4]) -> Option<&'a [u8]> {
+    let be_u32 = |offset: usize| {
+        let bytes = profile.get(offset..offset + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+    };
+    (0..be_u32(128)?.min(1024)).find_map(|i| {
+        let entry = 132 + i * 12;
+        if profile.get(entry..entry + 4)? != sig {
+            return None;
+        }
+        let offset = be_u32(entry + 4)?;
+        profile.get(offset..offset.checked_add(be_u32(entry + 8)?)?)
+    })
+}
+
+/// Parse a `curv` or `para` element.
+fn parse_curve(data: &[u8]) -> Option<Curve> {
+    let be_u16 = |offset: usize| {
+        Some(u16::from_be_bytes(
+            data.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+    let fixed = |offset: usize| {
+        let bytes = data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(i32::from_be_bytes(bytes) as f32 / 65536.0)
+    };
+    match data.get(..4)? {
+        b"curv" => {
+            let count = u32::from_be_bytes(data.get(8..12)?.try_into().ok()?) as usize;
+            match count {
+                0 => Some(Curve::gamma(1.0)),
+                1 => Some(Curve::gamma(be_u16(12)? as f32 / 256.0)),
+                _ => {
+                    let samples = (0..count.min(65536))
+                        .map(|i| Some(be_u16(12 + i * 2)? as f32 / 65535.0))
+                        .collect::<Option<_>>()?;
+                    Some(Curve::Table(samples))
+                }
+            }
+        }
+        b"para" => {
+            let kind = be_u16(8)?;
+            let param_count = [1, 3, 4, 5, 7];
+            let mut p = [0.0; 7];
+            for (i, p) in p
+                .iter_mut()
+                .enumerate()
+                .take(*param_count.get(kind as usize)?)
+            {
+                *p = fixed(12 + i * 4)?;
+            }
+            let [g, a, b, c, d, e, f] = p;
+            // Below `-b / a` the first three types are constant
+            let start = if a != 0.0 { -b / a } else { 0.0 };
+            Some(Curve::Parametric(match kind {
+                0 => [g, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+                1 => [g, a, b, 0.0, start, 0.0, 0.0],
+                2 => [g, a, b, 0.0, start, c, c],
+                3 => [g, a, b, c, d, 0.0, 0.0],
+                _ => [g, a, b, c, d, e, f],
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// Parse an `XYZ ` element with a single value.
+fn parse_xyz(data: &[u8]) -> Option<[f32; 3]> {
+    if data.get(..4)? != b"XYZ " {
+        return None;
+    }
+    let fixed = |i: usize| {
+        let bytes = data.get(8 + i * 4..12 + i * 4)?.try_into().ok()?;
+        Some(i32::from_be_bytes(bytes) as f32 / 65536.0)
+    };
+    Some([fixed(0)?, fixed(1)?, fixed(2)?])
+}
+
+/// The matrix from the white balanced colors of a camera to linear sRGB, given the color matrix
+/// of a DNG file, which goes from XYZ to the camera. Like dcraw, its rows are scaled so that camera
+/// white stays white.
+pub fn camera_to_srgb(xyz_to_camera: &Matrix) -> Option<Matrix> {
+    let srgb_to_camera = multiply(xyz_to_camera, &rgb_to_xyz(BT709_PRIMARIES, D65)?);
+    let mut scaled = [[0.0; 3]; 3];
+    for (row, scaled) in srgb_to_camera.iter().zip(&mut scaled) {
+        let sum: f32 = row.iter().sum();
+        if sum <= 0.0 {
+            return None;
+        }
+        *scaled = row.map(|x| x / sum);
+    }
+    invert(&scaled)
+}
+
+/// The matrix from linear RGB to XYZ for the given chromaticities of the primaries and white.
+fn rgb_to_xyz(primaries: [(f32, f32); 3], white: (f32, f32)) -> Option<Matrix> {
+    let xyz = |(x, y): (f32, f32)| [x / y, 1.0, (1.0 - x - y) / y];
+    let [r, g, b] = primaries.map(xyz);
+    let p = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+    let w = xyz(white);
+    let inv = invert(&p)?;
+    let s = [0, 1, 2].map(|row| inv[row][0] * w[0] + inv[row][1] * w[1] + inv[row][2] * w[2]);
+    Some(p.map(|row| [row[0] * s[0], row[1] * s[1], row[2] * s[2]]))
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut m = [[0.0; 3]; 3];
+    for (row, m_row) in m.iter_mut().enumerate() {
+        for (col, m) in m_row.iter_mut().enumerate() {
+            *m = (0..3).map(|k| a[row][k] * b[k][col]).sum();
+        }
+    }
+    m
+}
+
+fn invert(m: &Matrix) -> Option<Matrix> {
+    let cofactor = |r: usize, c: usize| {
+        let (r1, r2) = ((r + 1) % 3, (r + 2) % 3);
+        let (c1, c2) = ((c + 1) % 3, (c + 2) % 3);
+        m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+    };
+    let det = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum::<f32>();
+    if det.abs() < 1e-9 {
+        return None;
+    }
+    // The inverse is the transposed matrix of cofactors divided by the determinant
+    Some([0, 1, 2].map(|row| [0, 1, 2].map(|col| cofactor(col, row) / det)))
+}
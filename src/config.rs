@@ -0,0 +1,498 @@
+//! The configuration file, `$XDG_CONFIG_HOME/reimv/config.toml`, which changes settings for the
+//! images whose paths match a pattern:
+//!
+//! ```toml
+//! [override."*.svg"]
+//! background = "#ffffff"
+//!
+//! [override."~/scans/**"]
+//! fit = "contain"
+//! ```
+//!
+//! Keys before the first section set the defaults of command line options, which are overridden
+//! by `REIMV_*` environment variables and by the command line itself. Only this subset of TOML is
+//! understood.
+
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command, ValueEnum};
+
+use crate::blend;
+use crate::files::Entry;
+use crate::hdr::ToneMapping;
+use crate::image::DecodeOptions;
+
+/// How large an image is when it is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Fit {
+    /// At its natural size, in the top left corner
+    #[default]
+    None,
+    /// Scaled down to fit into the window if it is larger, and centered
+    Shrink,
+    /// Scaled up or down to fit into the window, and centered
+    Contain,
+}
+
+/// How an image smaller than the window is shown, instead of as [`Fit`] says.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Upscale {
+    /// At its natural size, and centered
+    Never,
+    /// Scaled up to fit into the window, and centered
+    Fit,
+    /// Scaled up by the largest whole number of display pixels per pixel which fits, so that
+    /// pixel art stays sharp, and centered
+    Integer,
+}
+
+/// What can be changed for each image.
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    pub decode: DecodeOptions,
+    pub fit: Fit,
+    /// How images smaller than the window are shown, or as `fit` says if `None`
+    pub upscale: Option<Upscale>,
+    /// What is around and behind the image, or the default
+    pub background: Option<Background>,
+}
+
+/// What the window shows around the image and through its transparent parts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Background {
+    /// Premultiplied RGBA
+    Color([u8; 4]),
+    /// Gray squares, which set transparent parts apart from dark and light ones
+    Checkerboard,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// The top-level keys, named like the long command line options
+    options: Vec<(String, Value)>,
+    overrides: Vec<Override>,
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Bool(bool),
+    /// A string or a number
+    Text(String),
+}
+
+/// A `[override."pattern"]` section.
+#[derive(Debug, Clone)]
+struct Override {
+    pattern: String,
+    /// The settings as they are written, for `--dump-config`
+    lines: Vec<String>,
+    fit: Option<Fit>,
+    upscale: Option<Upscale>,
+    background: Option<Background>,
+    tone_mapping: Option<ToneMapping>,
+    color_management: Option<bool>,
+}
+
+impl Config {
+    /// Load the configuration from `path`, or from the default path if it is `None`. Only the
+    /// default file may be missing.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, contents) = match path {
+            Some(path) => (path.to_owned(), std::fs::read_to_string(path)),
+            None => {
+                let Some(path) = default_path() else {
+                    return Ok(Self::default());
+                };
+                match std::fs::read_to_string(&path) {
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        return Ok(Self::default())
+                    }
+                    contents => (path, contents),
+                }
+            }
+        };
+        let contents = contents.with_context(|| format!("could not read {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for (i, line) in contents.lines().enumerate() {
+            parse_line(&mut config, line).with_context(|| format!("line {}", i + 1))?;
+        }
+        Ok(config)
+    }
+
+    /// Insert the options of the config file before the command line `args`, so that they can
+    /// be overridden there. Options set in the environment are left out, since the config file
+    /// would take precedence over them otherwise.
+    pub fn layer_args(&self, command: &Command, args: Vec<OsString>) -> Result<Vec<OsString>> {
+        let mut args = args.into_iter();
+        let mut layered: Vec<OsString> = args.next().into_iter().collect();
+        for (key, value) in &self.options {
+            let arg = command
+                .get_arguments()
+                .find(|arg| {
+                    arg.get_long() == Some(key) && !NOT_CONFIGURABLE.contains(&key.as_str())
+                })
+                .with_context(|| format!("unknown option `{key}` in the config file"))?;
+            if arg
+                .get_env()
+                .is_some_and(|var| std::env::var_os(var).is_some())
+            {
+                continue;
+            }
+            match (arg.get_action(), value) {
+                (ArgAction::SetTrue, Value::Bool(true)) => layered.push(format!("--{key}").into()),
+                (ArgAction::SetTrue, Value::Bool(false)) => (),
+                (ArgAction::SetTrue, Value::Text(_)) => bail!("`{key}` must be true or false"),
+                (_, Value::Bool(_)) => bail!("`{key}` must be a string or a number"),
+                (_, Value::Text(text)) => {
+                    // Checked on its own, so that errors are not blamed on the command line
+                    let mut check = Arg::new("value")
+                        .long(key.clone())
+                        .allow_hyphen_values(true)
+                        .value_parser(arg.get_value_parser().clone());
+                    if let Some(names) = arg.get_value_names() {
+                        check = check.value_names(names.to_vec());
+                    }
+                    let flag = format!("--{key}");
+                    if let Err(e) = Command::new("config")
+                        .no_binary_name(true)
+                        .arg(check)
+                        .try_get_matches_from([flag.as_str(), text])
+                    {
+                        let message = e.to_string();
+                        let message: Vec<&str> = message
+                            .lines()
+                            .map(str::trim)
+                            .take_while(|line| !line.starts_with("For more information"))
+                            .filter(|line| !line.is_empty())
+                            .collect();
+                        let message = message.join(" ");
+                        let message = message.strip_prefix("error: ").unwrap_or(&message);
+                        bail!("config file: {message}");
+                    }
+                    layered.push(format!("--{key}={text}").into());
+                }
+            }
+        }
+        layered.extend(args);
+        Ok(layered)
+    }
+
+    /// The effective configuration in the syntax of the config file, with where each value comes
+    /// from. `given` are the matches of the command line alone.
+    pub fn dump(&self, command: &Command, matches: &ArgMatches, given: &ArgMatches) -> String {
+        let mut dump = String::new();
+        for arg in command.get_arguments() {
+            let Some(key) = arg.get_long() else {
+                continue;
+            };
+            if NOT_CONFIGURABLE.contains(&key) {
+                continue;
+            }
+            let id = arg.get_id().as_str();
+            let source = match matches.value_source(id) {
+                Some(ValueSource::CommandLine)
+                    if given.value_source(id) == Some(ValueSource::CommandLine) =>
+                {
+                    "command line"
+                }
+                // The config file is layered as if it was written on the command line
+                Some(ValueSource::CommandLine) => "config file",
+                Some(ValueSource::EnvVariable) => "environment",
+                _ => "default",
+            };
+            let value = match arg.get_action() {
+                ArgAction::SetTrue => Some(matches.get_flag(id).to_string()),
+                _ => matches
+                    .get_raw(id)
+                    .and_then(|mut values| values.next())
+                    .map(|value| toml_value(&value.to_string_lossy())),
+            };
+            let _ = match value {
+                Some(value) => writeln!(dump, "{key} = {value}  # {source}"),
+                None => writeln!(dump, "# {key} is not set"),
+            };
+        }
+        for section in &self.overrides {
+            let _ = writeln!(dump, "\n[override.{}]", toml_value(&section.pattern));
+            for line in &section.lines {
+                let _ = writeln!(dump, "{line}");
+            }
+        }
+        dump
+    }
+
+    /// The settings for `entry`, starting from `defaults`. Sections later in the file take
+    /// precedence over earlier ones.
+    pub fn settings(&self, defaults: Settings, entry: &Entry) -> Settings {
+        let mut settings = defaults;
+        if self.overrides.is_empty() {
+            return settings;
+        }
+        let path = match entry {
+            Entry::File(path) => canonical(path),
+            Entry::Page { path, .. } => {
+                let name = entry.name();
+                format!("{}{}", canonical(path), &name[path.len()..])
+            }
+            Entry::Url { url, .. } => url.clone(),
+            Entry::Data { name, .. } => name.clone(),
+        };
+        for section in self.overrides.iter().filter(|o| o.matches(&path)) {
+            settings.fit = section.fit.unwrap_or(settings.fit);
+            settings.upscale = section.upscale.or(settings.upscale);
+            settings.background = section.background.or(settings.background);
+            if let Some(tone_mapping) = section.tone_mapping {
+                settings.decode.tone_mapping = tone_mapping;
+            }
+            if let Some(color_management) = section.color_management {
+                settings.decode.color_management = color_management;
+            }
+        }
+        settings
+    }
+}
+
+impl Override {
+    fn matches(&self, path: &str) -> bool {
+        matches_pattern(&self.pattern, path)
+    }
+}
+
+/// Whether `path` matches a glob pattern, see [`glob_match`]. Patterns without a slash match the
+/// file name, the others the whole path.
+pub fn matches_pattern(pattern: &str, path: &str) -> bool {
+    let text = match pattern.contains('/') {
+        true => path,
+        false => path.rsplit('/').next().unwrap_or(path),
+    };
+    glob_match(pattern.as_bytes(), text.as_bytes())
+}
+
+fn default_path() -> Option<PathBuf> {
+    match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("reimv/config.toml")),
+        _ => {
+            let home = std::env::var_os("HOME")?;
+            Some(PathBuf::from(home).join(".config/reimv/config.toml"))
+        }
+    }
+}
+
+/// The canonical form of `path` if it exists, so that relative paths match too.
+fn canonical(path: &str) -> String {
+    match std::fs::canonicalize(path) {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(_) => path.to_owned(),
+    }
+}
+
+/// Options which make no sense in the config file.
+const NOT_CONFIGURABLE: &[&str] = &[
+    "config",
+    "dump-config",
+    "help",
+    "version",
+    "sequence",
+    "stdin-files",
+    "null",
+];
+
+/// Write a value as a TOML string, unless it is a number.
+fn toml_value(text: &str) -> String {
+    if !text.is_empty() && text.parse::<f64>().is_ok_and(f64::is_finite) {
+        return text.to_owned();
+    }
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn parse_line(config: &mut Config, line: &str) -> Result<()> {
+    let line = strip_comment(line).trim();
+    if line.is_empty() {
+        return Ok(());
+    }
+
+    if let Some(header) = line.strip_prefix('[') {
+        let header = header
+            .strip_suffix(']')
+            .context("expected a section header")?;
+        let pattern = header
+            .trim()
+            .strip_prefix("override.")
+            .context("only [override.\"pattern\"] sections are supported")?;
+        let (pattern, rest) = parse_string(pattern.trim())?;
+        ensure!(rest.trim().is_empty(), "unexpected text after the pattern");
+        config.overrides.push(Override {
+            pattern: expand_home(&pattern),
+            lines: Vec::new(),
+            fit: None,
+            upscale: None,
+            background: None,
+            tone_mapping: None,
+            color_management: None,
+        });
+        return Ok(());
+    }
+
+    let (key, value) = line.split_once('=').context("expected `key = value`")?;
+    let (key, value) = (key.trim(), value.trim());
+    let Some(section) = config.overrides.last_mut() else {
+        let value = match value {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ if value.starts_with(['"', '\'']) => Value::Text(parse_whole_string(value)?),
+            _ if value.parse::<f64>().is_ok() => Value::Text(value.to_owned()),
+            _ => bail!("expected a string, a number, true or false"),
+        };
+        config.options.push((key.to_owned(), value));
+        return Ok(());
+    };
+    section.lines.push(format!("{key} = {value}"));
+    match key {
+        "fit" => section.fit = Some(parse_enum(value)?),
+        "upscale-small-images" => section.upscale = Some(parse_enum(value)?),
+        "background" => section.background = Some(parse_background(&parse_whole_string(value)?)?),
+        "tone-mapping" => section.tone_mapping = Some(parse_enum(value)?),
+        "color-management" => {
+            section.color_management = Some(match value {
+                "true" => true,
+                "false" => false,
+                _ => bail!("expected true or false"),
+            })
+        }
+        "filter" => bail!("the filter cannot be chosen, since the compositor scales images"),
+        key => bail!("unknown setting `{key}`"),
+    }
+    Ok(())
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (c, quote) {
+            ('#', None) => return &line[..i],
+            ('"' | '\'', None) => quote = Some(c),
+            ('\\', Some('"')) => {
+                chars.next();
+            }
+            (c, Some(q)) if c == q => quote = None,
+            _ => (),
+        }
+    }
+    line
+}
+
+/// Parse a basic `"..."` or literal `'...'` TOML string at the start of `text`, returning it and
+/// the rest of the text.
+fn parse_string(text: &str) -> Result<(String, &str)> {
+    if let Some(literal) = text.strip_prefix('\'') {
+        let (value, rest) = literal.split_once('\'').context("unclosed string")?;
+        return Ok((value.to_owned(), rest));
+    }
+    let basic = text.strip_prefix('"').context("expected a quoted string")?;
+    let mut value = String::new();
+    let mut chars = basic.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &basic[i + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('"') => value.push('"'),
+                Some('\\') => value.push('\\'),
+                Some('t') => value.push('\t'),
+                _ => bail!("unsupported escape sequence"),
+            },
+            c => value.push(c),
+        }
+    }
+    bail!("unclosed string")
+}
+
+fn parse_whole_string(text: &str) -> Result<String> {
+    let (value, rest) = parse_string(text)?;
+    ensure!(rest.trim().is_empty(), "unexpected text after the value");
+    Ok(value)
+}
+
+fn parse_enum<T: ValueEnum>(text: &str) -> Result<T> {
+    let value = parse_whole_string(text)?;
+    T::from_str(&value, false).map_err(|_| {
+        let expected: Vec<String> = T::value_variants()
+            .iter()
+            .filter_map(|v| Some(v.to_possible_value()?.get_name().to_owned()))
+            .collect();
+        anyhow::anyhow!("expected one of {}", expected.join(", "))
+    })
+}
+
+/// Parse `checkerboard` or a color, see [`parse_color`].
+pub fn parse_background(text: &str) -> Result<Background> {
+    match text {
+        "checkerboard" => Ok(Background::Checkerboard),
+        _ => parse_color(text)
+            .map(Background::Color)
+            .context("expected checkerboard or a color like #rrggbb or #rrggbbaa"),
+    }
+}
+
+/// Parse `#rrggbb` or `#rrggbbaa` into premultiplied RGBA.
+pub fn parse_color(text: &str) -> Result<[u8; 4]> {
+    let hex = text
+        .strip_prefix('#')
+        .filter(|hex| matches!(hex.len(), 6 | 8) && hex.is_ascii())
+        .context("expected a color like #rrggbb or #rrggbbaa")?;
+    let mut rgba = [255; 4];
+    for (i, c) in rgba.iter_mut().enumerate().take(hex.len() / 2) {
+        *c = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).context("invalid hex digits")?;
+    }
+    Ok(blend::premultiply(rgba))
+}
+
+/// Replace a leading `~/` with the home directory.
+pub fn expand_home(pattern: &str) -> String {
+    match (pattern.strip_prefix("~/"), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}/{rest}", home.trim_end_matches('/')),
+        _ => pattern.to_owned(),
+    }
+}
+
+/// Match a glob pattern: `?` and `*` stand for one and any number of characters other than `/`,
+/// and `**` also for slashes.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+                // `a/**/b` also matches `a/b`
+                || rest.strip_prefix(b"/").is_some_and(|rest| glob_match(rest, text))
+        }
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| glob_match(rest, &text[i..])),
+        [b'?', rest @ ..] => match text {
+            [c, text @ ..] if *c != b'/' => glob_match(rest, text),
+            _ => false,
+        },
+        [p, rest @ ..] => match text {
+            [c, text @ ..] if c == p => glob_match(rest, text),
+            _ => false,
+        },
+    }
+}
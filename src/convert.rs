@@ -0,0 +1,150 @@
+//! Preparing pixels for upload.
+//!
+//! SHM buffers are expected to have premultiplied alpha, while decoded images don't. The
+//! conversion touches every pixel of potentially very large images, so it has vectorized
+//! versions for x86_64, where SSE2 is always available and AVX2 is detected at runtime, and for
+//! aarch64, where NEON is always available.
+
+/// Premultiply RGBA pixels, four bytes each, in place.
+pub fn premultiply(pixels: &mut [u8]) {
+    #[cfg(target_arch = "x86_64")]
+    let pixels = {
+        let rest = if is_x86_feature_detected!("avx2") {
+            // SAFETY: AVX2 is supported
+            unsafe { x86::premultiply_avx2(pixels) }
+        } else {
+            x86::premultiply_sse2(pixels)
+        };
+        let done = pixels.len() - rest;
+        &mut pixels[done..]
+    };
+
+    #[cfg(target_arch = "aarch64")]
+    let pixels = {
+        let rest = neon::premultiply_neon(pixels);
+        let done = pixels.len() - rest;
+        &mut pixels[done..]
+    };
+
+    premultiply_scalar(pixels);
+}
+
+fn premultiply_scalar(pixels: &mut [u8]) {
+    for pixel in pixels.chunks_exact_mut(4) {
+        let a = pixel[3] as u16;
+        for c in &mut pixel[..3] {
+            *c = div255(*c as u16 * a);
+        }
+    }
+}
+
+/// Exact rounding division by 255 for values up to 255 * 255.
+pub fn div255(x: u16) -> u8 {
+    let t = x + 128;
+    ((t + (t >> 8)) >> 8) as u8
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    /// Returns the number of trailing bytes which were not processed.
+    pub fn premultiply_sse2(pixels: &mut [u8]) -> usize {
+        let mut chunks = pixels.chunks_exact_mut(16);
+        for chunk in &mut chunks {
+            // SAFETY: SSE2 is part of the x86_64 baseline and the chunk is 16 bytes long
+            unsafe {
+                let ptr = chunk.as_mut_ptr() as *mut __m128i;
+                let src = _mm_loadu_si128(ptr);
+                let zero = _mm_setzero_si128();
+                let lo = multiply_sse2(_mm_unpacklo_epi8(src, zero));
+                let hi = multiply_sse2(_mm_unpackhi_epi8(src, zero));
+                let alpha_mask = _mm_set1_epi32(0xFF00_0000_u32 as i32);
+                let dst = _mm_or_si128(
+                    _mm_andnot_si128(alpha_mask, _mm_packus_epi16(lo, hi)),
+                    _mm_and_si128(alpha_mask, src),
+                );
+                _mm_storeu_si128(ptr, dst);
+            }
+        }
+        chunks.into_remainder().len()
+    }
+
+    /// Two pixels, with 16-bit channels.
+    #[inline(always)]
+    unsafe fn multiply_sse2(x: __m128i) -> __m128i {
+        let a = _mm_shufflehi_epi16(_mm_shufflelo_epi16(x, 0xFF), 0xFF);
+        let t = _mm_add_epi16(_mm_mullo_epi16(x, a), _mm_set1_epi16(128));
+        _mm_srli_epi16(_mm_add_epi16(t, _mm_srli_epi16(t, 8)), 8)
+    }
+
+    /// Returns the number of trailing bytes which were not processed.
+    ///
+    /// # Safety
+    ///
+    /// The CPU must support AVX2.
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn premultiply_avx2(pixels: &mut [u8]) -> usize {
+        let mut chunks = pixels.chunks_exact_mut(32);
+        for chunk in &mut chunks {
+            let ptr = chunk.as_mut_ptr() as *mut __m256i;
+            let src = _mm256_loadu_si256(ptr);
+            let zero = _mm256_setzero_si256();
+            // Unpacking and packing both work within 128-bit lanes, so the order is preserved
+            let lo = multiply_avx2(_mm256_unpacklo_epi8(src, zero));
+            let hi = multiply_avx2(_mm256_unpackhi_epi8(src, zero));
+            let alpha_mask = _mm256_set1_epi32(0xFF00_0000_u32 as i32);
+            let dst = _mm256_or_si256(
+                _mm256_andnot_si256(alpha_mask, _mm256_packus_epi16(lo, hi)),
+                _mm256_and_si256(alpha_mask, src),
+            );
+            _mm256_storeu_si256(ptr, dst);
+        }
+        chunks.into_remainder().len()
+    }
+
+    /// Four pixels, with 16-bit channels.
+    #[inline(always)]
+    unsafe fn multiply_avx2(x: __m256i) -> __m256i {
+        let a = _mm256_shufflehi_epi16(_mm256_shufflelo_epi16(x, 0xFF), 0xFF);
+        let t = _mm256_add_epi16(_mm256_mullo_epi16(x, a), _mm256_set1_epi16(128));
+        _mm256_srli_epi16(_mm256_add_epi16(t, _mm256_srli_epi16(t, 8)), 8)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    /// Returns the number of trailing bytes which were not processed.
+    pub fn premultiply_neon(pixels: &mut [u8]) -> usize {
+        let mut chunks = pixels.chunks_exact_mut(64);
+        for chunk in &mut chunks {
+            // SAFETY: NEON is part of the aarch64 baseline and the chunk is 64 bytes long
+            unsafe {
+                let ptr = chunk.as_mut_ptr();
+                // Each channel of 16 pixels in a register of its own, so alpha stays
+                let mut src = vld4q_u8(ptr);
+                src.0 = multiply_neon(src.0, src.3);
+                src.1 = multiply_neon(src.1, src.3);
+                src.2 = multiply_neon(src.2, src.3);
+                vst4q_u8(ptr, src);
+            }
+        }
+        chunks.into_remainder().len()
+    }
+
+    /// One channel of 16 pixels times their alpha.
+    #[inline(always)]
+    unsafe fn multiply_neon(c: uint8x16_t, a: uint8x16_t) -> uint8x16_t {
+        let lo = vmull_u8(vget_low_u8(c), vget_low_u8(a));
+        let hi = vmull_high_u8(c, a);
+        vcombine_u8(div255_neon(lo), div255_neon(hi))
+    }
+
+    /// (x + ((x + 128) >> 8) + 128) >> 8 on eight values, the same rounding as `div255`.
+    #[inline(always)]
+    unsafe fn div255_neon(x: uint16x8_t) -> uint8x8_t {
+        vraddhn_u16(x, vrshrq_n_u16::<8>(x))
+    }
+}
@@ -0,0 +1,107 @@
+//! Bug report information printed when we panic.
+//!
+//! Most panics happen in Wayland callbacks and say little on their own, so the panic hook adds
+//! what is needed to make sense of them: the compositor, the bound protocol versions, the file
+//! and the view. The report is also saved to `crash.txt` in the state directory.
+
+use std::backtrace::Backtrace;
+use std::fmt::Write;
+use std::os::fd::RawFd;
+use std::sync::Mutex;
+
+use crate::globals::Globals;
+use crate::image::ImageTransform;
+use crate::persist;
+
+static CONTEXT: Mutex<Context> = Mutex::new(Context {
+    compositor: None,
+    globals: Vec::new(),
+    path: None,
+    transform: None,
+});
+
+struct Context {
+    compositor: Option<String>,
+    globals: Vec<(String, u32)>,
+    path: Option<String>,
+    transform: Option<ImageTransform>,
+}
+
+pub fn install_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let mut report = format!("reimv {} {info}\n", env!("CARGO_PKG_VERSION"));
+        if let Ok(cx) = CONTEXT.try_lock() {
+            let unknown = || "unknown".to_owned();
+            let _ = writeln!(
+                report,
+                "compositor: {}",
+                cx.compositor.clone().unwrap_or_else(unknown)
+            );
+            let _ = writeln!(report, "file: {}", cx.path.clone().unwrap_or_else(unknown));
+            if let Some(t) = cx.transform {
+                let _ = writeln!(report, "transform: x={} y={} scale={}", t.x, t.y, t.scale);
+            }
+            let _ = writeln!(report, "globals:");
+            for (name, version) in &cx.globals {
+                let _ = writeln!(report, "  {name} v{version}");
+            }
+        }
+        let _ = write!(report, "backtrace:\n{}", Backtrace::force_capture());
+
+        eprintln!("{report}");
+        if let Some(dir) = persist::state_dir() {
+            let path = dir.join("crash.txt");
+            if std::fs::create_dir_all(&dir)
+                .and_then(|()| std::fs::write(&path, &report))
+                .is_ok()
+            {
+                eprintln!("This report was saved to {}", path.display());
+            }
+        }
+        eprintln!("Please include it when reporting a bug");
+    }));
+}
+
+/// Find out which process is on the other side of the Wayland socket.
+pub fn set_compositor(socket: RawFd) {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `cred` and `len` are valid for writes and `len` is the size of `cred`
+    let res = unsafe {
+        libc::getsockopt(
+            socket,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    let name = if res == 0 && cred.pid > 0 {
+        std::fs::read_to_string(format!("/proc/{}/comm", cred.pid))
+            .map(|comm| format!("{} (pid {})", comm.trim_end(), cred.pid))
+            .ok()
+    } else {
+        None
+    };
+    CONTEXT.lock().unwrap().compositor = name;
+}
+
+pub fn set_globals(globals: &Globals) {
+    CONTEXT.lock().unwrap().globals = globals
+        .versions()
+        .into_iter()
+        .map(|(name, version)| (name.to_string_lossy().into_owned(), version))
+        .collect();
+}
+
+pub fn set_path(path: &str) {
+    CONTEXT.lock().unwrap().path = Some(path.to_owned());
+}
+
+pub fn set_transform(transform: ImageTransform) {
+    CONTEXT.lock().unwrap().transform = Some(transform);
+}
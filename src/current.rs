@@ -0,0 +1,84 @@
+//! A symlink to the current image, for scripts which only need to know what is shown, like one
+//! which makes it the wallpaper. See `--current-link`.
+//!
+//! A new link is renamed over the old one, so that scripts never find it missing, and watching its
+//! directory for `moved_to` tells when it changes. It is removed while the current image is not a
+//! file, like a download, and on exit.
+
+use std::fs::{self, DirBuilder};
+use std::io::ErrorKind;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+pub struct CurrentLink {
+    path: PathBuf,
+    /// What the link points to, if we have made it
+    target: Option<PathBuf>,
+    /// Whether updating it has failed, which is reported only once
+    failed: bool,
+}
+
+impl CurrentLink {
+    /// The link at `$XDG_RUNTIME_DIR/reimv/current`, whose directory is created.
+    pub fn new() -> Result<Self> {
+        let dir = dir().context("XDG_RUNTIME_DIR is not set")?;
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("could not create {}", dir.display()))?;
+        Ok(Self {
+            path: dir.join("current"),
+            target: None,
+            failed: false,
+        })
+    }
+
+    /// Point the link at `file`, or remove it if the current image is not a file.
+    pub fn update(&mut self, file: Option<&str>) {
+        // Relative to the working directory of reimv, not to the directory of the link
+        let target = file.and_then(|file| std::path::absolute(file).ok());
+        if target == self.target {
+            return;
+        }
+        let result = match &target {
+            Some(target) => {
+                let temp = self
+                    .path
+                    .with_file_name(format!("current.{}", std::process::id()));
+                let _ = fs::remove_file(&temp);
+                std::os::unix::fs::symlink(target, &temp)
+                    .and_then(|()| fs::rename(&temp, &self.path))
+            }
+            None => match fs::remove_file(&self.path) {
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+                result => result,
+            },
+        };
+        match result {
+            Ok(()) => self.target = target,
+            Err(e) if !self.failed => {
+                eprintln!("reimv: could not update {}: {e}", self.path.display());
+                self.failed = true;
+            }
+            Err(_) => (),
+        }
+    }
+}
+
+impl Drop for CurrentLink {
+    fn drop(&mut self) {
+        // Unless another instance has replaced it since
+        if self.target.is_some() && fs::read_link(&self.path).ok() == self.target {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// The directory of the link, `$XDG_RUNTIME_DIR/reimv`.
+pub fn dir() -> Option<PathBuf> {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty())?;
+    Some(Path::new(&runtime_dir).join("reimv"))
+}
@@ -0,0 +1,515 @@
+//! Decoding images from memory, independently of how they are shown.
+
+use std::io::{Cursor, Read};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use anyhow::{bail, ensure, Context, Result};
+use flate2::read::GzDecoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage, ImageBuffer, RgbImage, Rgba, RgbaImage};
+use jpeg_decoder::PixelFormat;
+use resvg::usvg;
+use usvg::{fontdb, roxmltree};
+
+use crate::cmyk;
+use crate::color;
+use crate::djvu;
+use crate::format::Format;
+use crate::frames::Frames;
+use crate::hdr::{HdrImage, ToneMapping};
+use crate::isolate;
+use crate::jpeg2000;
+use crate::limits::Limits;
+use crate::metadata;
+use crate::pages::Pages;
+use crate::pdf;
+use crate::pnm;
+use crate::psd;
+use crate::pyramid::Pyramid;
+use crate::raw;
+use crate::smil::Animation;
+use crate::video::Video;
+use crate::{xbm, xpm};
+
+/// JPEG files of at least this many pixels first show their EXIF thumbnail, if they have one.
+const THUMBNAIL_MIN_PIXELS: u64 = 12_000_000;
+
+pub struct Decoded {
+    pub content: Content,
+    /// A full-quality decode to run in the background, while the content is only a preview
+    pub deferred: Option<Deferred>,
+    /// A decode at full resolution, if the content has been reduced to fewer pixels. It is only
+    /// needed once the image is zoomed in beyond them.
+    pub full: Option<Deferred>,
+    /// Physical resolution, in dots per inch
+    pub dpi: Option<f32>,
+    /// The source of the content for HDR formats
+    pub hdr: Option<HdrImage>,
+    /// The source of the content for images with more than 8 bits per channel
+    pub deep: Option<Rgba16Image>,
+    pub pages: Option<Pages>,
+    /// The levels of a pyramidal TIFF file, of which the content is the current one
+    pub pyramid: Option<Pyramid>,
+    /// The SMIL animations of an SVG document, with the content showing its start
+    pub animation: Option<AnimatedSvg>,
+    /// The frames of a GIF or APNG animation, with the content showing the first one
+    pub frames: Option<Frames>,
+    /// The frames after the first one, which is the content
+    pub video: Option<Video>,
+}
+
+pub enum Content {
+    Svg(Box<usvg::Tree>),
+    Raster(RgbaImage),
+}
+
+/// An image with 16-bit channels, which the `image` crate has no name for.
+pub type Rgba16Image = ImageBuffer<Rgba<u16>, Vec<u16>>;
+
+pub type Deferred = Box<dyn FnOnce() -> Result<RgbaImage> + Send>;
+
+impl Decoded {
+    /// A raster image without anything else.
+    pub fn raster(image: RgbaImage, dpi: Option<f32>) -> Self {
+        Self {
+            content: Content::Raster(image),
+            deferred: None,
+            full: None,
+            dpi,
+            hdr: None,
+            deep: None,
+            pages: None,
+            pyramid: None,
+            animation: None,
+            frames: None,
+            video: None,
+        }
+    }
+}
+
+/// An SVG document with SMIL animations, which is parsed again for every frame.
+pub struct AnimatedSvg {
+    animation: Animation,
+    options: usvg::Options,
+    limits: Limits,
+}
+
+impl AnimatedSvg {
+    /// The image `t` seconds after the start.
+    pub fn tree_at(&self, t: f32) -> Result<usvg::Tree> {
+        parse_svg_text(&self.animation.frame(t), &self.options, &self.limits)
+    }
+
+    /// The time in seconds after which the image stays the same, or `None` if it never does.
+    pub fn end(&self) -> Option<f32> {
+        self.animation.end()
+    }
+}
+
+/// Decode an image of a known format. Files an SVG document refers to are looked up in
+/// `resources_dir`. With `color_management`, raster images are converted to sRGB. Raster images
+/// with more than `max_pixels` pixels are reduced to that many, with a decode at full resolution
+/// for later.
+pub fn decode(
+    data: Vec<u8>,
+    format: Format,
+    resources_dir: Option<PathBuf>,
+    tone_mapping: ToneMapping,
+    limits: Limits,
+    color_management: bool,
+    max_pixels: Option<u64>,
+) -> Result<Decoded> {
+    let to_srgb = move |data: &[u8], image| match color_management {
+        true => color::to_srgb(data, image),
+        false => image,
+    };
+
+    // Pages and the sources of HDR images are kept at full resolution
+    let reduce_to = match format {
+        Format::Raster(
+            image::ImageFormat::Tiff
+            | image::ImageFormat::Ico
+            | image::ImageFormat::Gif
+            | image::ImageFormat::OpenExr
+            | image::ImageFormat::Hdr,
+        ) => None,
+        Format::Raster(format) => max_pixels.filter(|&max| {
+            image::io::Reader::with_format(Cursor::new(&data), format)
+                .into_dimensions()
+                .is_ok_and(|(width, height)| width as u64 * height as u64 > max)
+        }),
+        Format::Svg
+        | Format::Raw
+        | Format::Psd
+        | Format::Djvu
+        | Format::Pdf
+        | Format::Jpeg2000
+        | Format::Video
+        | Format::Xbm
+        | Format::Xpm => None,
+    };
+    let full = reduce_to.map(|_| -> Deferred {
+        let data = data.clone();
+        Box::new(move || {
+            isolate::run(&limits, || {
+                let image = decode_raster(&data, format, &limits, color_management, None)?;
+                Ok(to_srgb(&data, image))
+            })
+            .map(DynamicImage::into_rgba8)
+            .context("could not decode the image at full resolution")
+        })
+    });
+
+    // Large JPEG files take a while to decode, so their EXIF thumbnail is shown meanwhile
+    if format == Format::Raster(image::ImageFormat::Jpeg) {
+        if let Some(thumbnail) = jpeg_thumbnail(&data, &limits) {
+            // The thumbnail is in the color space of the image
+            let thumbnail = to_srgb(&data, thumbnail.into()).into_rgba8();
+            let dpi = metadata::dpi(&data);
+            return Ok(Decoded {
+                deferred: Some(Box::new(move || {
+                    isolate::run(&limits, || {
+                        let image =
+                            decode_raster(&data, format, &limits, color_management, reduce_to)?;
+                        Ok(to_srgb(&data, image))
+                    })
+                    .map(DynamicImage::into_rgba8)
+                    .context("could not decode image")
+                })),
+                full,
+                ..Decoded::raster(thumbnail, dpi)
+            });
+        }
+    }
+
+    match format {
+        Format::Svg => {
+            let mut opt = usvg::Options::default();
+            opt.resources_dir = resources_dir;
+            let text = svg_text(&data, &limits)?;
+            let (tree, animation) = match Animation::parse(&text, &limits) {
+                Some(animation) => {
+                    let animation = AnimatedSvg {
+                        animation,
+                        options: opt,
+                        limits,
+                    };
+                    (animation.tree_at(0.0)?, Some(animation))
+                }
+                None => (parse_svg_text(&text, &opt, &limits)?, None),
+            };
+            Ok(Decoded {
+                content: Content::Svg(Box::new(tree)),
+                deferred: None,
+                full: None,
+                // SVG user units are CSS pixels
+                dpi: Some(96.0),
+                hdr: None,
+                deep: None,
+                pages: None,
+                pyramid: None,
+                animation,
+                frames: None,
+                video: None,
+            })
+        }
+        Format::Raw => {
+            let dpi = metadata::dpi(&data);
+            let Some(preview) = raw::embedded_preview(&data) else {
+                let image = isolate::run(&limits, || raw::decode(&data, &limits).map(Into::into))
+                    .context("could not decode raw image")?
+                    .into_rgba8();
+                return Ok(Decoded::raster(image, dpi));
+            };
+
+            let preview =
+                isolate::run(&limits, || limits.decode(preview, image::ImageFormat::Jpeg))
+                    .context("could not decode embedded preview")?
+                    .into_rgba8();
+
+            Ok(Decoded {
+                deferred: Some(Box::new(move || {
+                    isolate::run(&limits, || raw::decode(&data, &limits).map(Into::into))
+                        .map(DynamicImage::into_rgba8)
+                })),
+                ..Decoded::raster(preview, dpi)
+            })
+        }
+        Format::Djvu => {
+            let image = djvu::render(&data, 0, &limits)
+                .context("could not render the first page")?
+                .into_rgba8();
+            let dpi = djvu::dpi(&data, 0);
+            Ok(Decoded {
+                pages: Pages::djvu(data, limits),
+                ..Decoded::raster(image, dpi)
+            })
+        }
+        Format::Pdf => {
+            let image = pdf::render(&data, 0, &limits)
+                .context("could not render the first page")?
+                .into_rgba8();
+            Ok(Decoded {
+                pages: Pages::pdf(data, limits),
+                ..Decoded::raster(image, Some(pdf::DPI))
+            })
+        }
+        Format::Jpeg2000 => {
+            let image = jpeg2000::decode(&data, &limits)
+                .context("could not decode image")?
+                .into_rgba8();
+            Ok(Decoded::raster(image, None))
+        }
+        Format::Video if cfg!(feature = "ffmpeg") => {
+            let (first, video) = Video::open(&data, &limits).context("could not play the video")?;
+            Ok(Decoded {
+                video: Some(video),
+                ..Decoded::raster(first, None)
+            })
+        }
+        Format::Video => {
+            bail!("videos are only played when reimv is built with the ffmpeg feature")
+        }
+        Format::Psd | Format::Xbm | Format::Xpm | Format::Raster(_) => {
+            // Only the level closest to the size it is shown at is decoded
+            if format == Format::Raster(image::ImageFormat::Tiff) {
+                if let Some(pyramid) = Pyramid::tiff(&data, limits, color_management) {
+                    let image = pyramid.decode(pyramid.current())?.into_rgba8();
+                    return Ok(Decoded {
+                        pyramid: Some(pyramid),
+                        ..Decoded::raster(image, metadata::dpi(&data))
+                    });
+                }
+            }
+            // Animations are drawn from their frames, at full resolution
+            let frames = match format {
+                Format::Raster(image::ImageFormat::Gif) => Frames::gif(&data, limits),
+                Format::Raster(image::ImageFormat::Png) => {
+                    Frames::apng(&data, limits, color_management)
+                }
+                _ => None,
+            };
+            if let Some(frames) = frames {
+                let first = frames.first()?;
+                return Ok(Decoded {
+                    frames: Some(frames),
+                    ..Decoded::raster(first, metadata::dpi(&data))
+                });
+            }
+            let image = isolate::run(&limits, || {
+                let image = decode_raster(&data, format, &limits, color_management, reduce_to)?;
+                Ok(to_srgb(&data, image))
+            })
+            .context("could not decode image")?;
+
+            let dpi = metadata::dpi(&data);
+            let mut pages = match format {
+                Format::Raster(image::ImageFormat::Tiff) => {
+                    Pages::tiff(data, limits, color_management)
+                }
+                Format::Raster(image::ImageFormat::Ico) => {
+                    Pages::ico(data, limits, color_management)
+                }
+                Format::Raster(image::ImageFormat::Gif) => Pages::gif(data, limits),
+                _ => None,
+            };
+            // Make sure that the image matches the current page
+            let image = match (format, &mut pages) {
+                (Format::Raster(image::ImageFormat::Ico), Some(pages)) => pages.turn(0).unwrap()?,
+                _ => image,
+            };
+
+            let (image, hdr, deep) = match format {
+                Format::Raster(image::ImageFormat::OpenExr | image::ImageFormat::Hdr) => {
+                    let hdr = HdrImage::new(image.into_rgba32f(), tone_mapping);
+                    (hdr.tone_map(), Some(hdr), None)
+                }
+                _ => {
+                    let (image, deep) = split_depth(image);
+                    (image, None, deep)
+                }
+            };
+
+            Ok(Decoded {
+                hdr,
+                deep,
+                pages,
+                full,
+                ..Decoded::raster(image, dpi)
+            })
+        }
+    }
+}
+
+/// The 8-bit pixels of an image, and the 16-bit ones if it has more than 8 bits per channel.
+pub fn split_depth(image: DynamicImage) -> (RgbaImage, Option<Rgba16Image>) {
+    match image {
+        DynamicImage::ImageLuma16(_)
+        | DynamicImage::ImageLumaA16(_)
+        | DynamicImage::ImageRgb16(_)
+        | DynamicImage::ImageRgba16(_) => (image.to_rgba8(), Some(image.into_rgba16())),
+        image => (image.into_rgba8(), None),
+    }
+}
+
+/// Decode a PSD file, an X bitmap or pixmap, or a raster image, reduced to at most `max_pixels` pixels if given.
+fn decode_raster(
+    data: &[u8],
+    format: Format,
+    limits: &Limits,
+    color_management: bool,
+    max_pixels: Option<u64>,
+) -> Result<DynamicImage> {
+    let image = match format {
+        Format::Psd => psd::decode(data, limits).map(Into::into),
+        Format::Xbm => xbm::decode(data, limits).map(Into::into),
+        Format::Xpm => xpm::decode(data, limits).map(Into::into),
+        Format::Raster(image::ImageFormat::Pnm) => pnm::decode(data, limits).map(Into::into),
+        Format::Raster(image::ImageFormat::Jpeg) => match (cmyk::is_cmyk(data), max_pixels) {
+            (true, _) => cmyk::decode(data, limits, color_management).map(Into::into),
+            (false, Some(max)) => decode_jpeg_scaled(data, limits, max),
+            (false, None) => limits.decode(data, image::ImageFormat::Jpeg),
+        },
+        Format::Raster(format) => limits.decode(data, format),
+        Format::Svg
+        | Format::Raw
+        | Format::Djvu
+        | Format::Pdf
+        | Format::Jpeg2000
+        | Format::Video => {
+            unreachable!()
+        }
+    }?;
+    Ok(match max_pixels {
+        Some(max) => reduce(image, max),
+        None => image,
+    })
+}
+
+/// The factor by which an image of `width` by `height` pixels is scaled to have at most
+/// `max_pixels` pixels.
+fn reduction(width: u32, height: u32, max_pixels: u64) -> f64 {
+    (max_pixels as f64 / (width as f64 * height as f64))
+        .sqrt()
+        .min(1.0)
+}
+
+/// Scale an image down to at most `max_pixels` pixels.
+fn reduce(image: DynamicImage, max_pixels: u64) -> DynamicImage {
+    let factor = reduction(image.width(), image.height(), max_pixels);
+    if factor >= 1.0 {
+        return image;
+    }
+    let width = ((image.width() as f64 * factor) as u32).max(1);
+    let height = ((image.height() as f64 * factor) as u32).max(1);
+    image.resize(width, height, FilterType::Triangle)
+}
+
+/// Decode a JPEG image at a fraction of its size, which skips most of the work for large
+/// reductions. The result has at least `max_pixels` pixels where the fractions allow it.
+fn decode_jpeg_scaled(data: &[u8], limits: &Limits, max_pixels: u64) -> Result<DynamicImage> {
+    let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(data));
+    decoder.read_info()?;
+    let info = decoder.info().unwrap();
+    // 12-bit images are rare, and decoded as they are
+    if !matches!(info.pixel_format, PixelFormat::L8 | PixelFormat::RGB24) {
+        return limits.decode(data, image::ImageFormat::Jpeg);
+    }
+    let factor = reduction(info.width as u32, info.height as u32, max_pixels);
+    let requested = |size: u16| (size as f64 * factor).ceil() as u16;
+    let (width, height) = decoder.scale(requested(info.width), requested(info.height))?;
+    let (width, height) = (width as u32, height as u32);
+    limits.check(width, height)?;
+    decoder.set_max_decoding_buffer_size(limits.max_bytes.try_into().unwrap_or(usize::MAX));
+    let pixels = decoder.decode()?;
+    let image = match info.pixel_format {
+        PixelFormat::L8 => GrayImage::from_raw(width, height, pixels).map(DynamicImage::from),
+        _ => RgbImage::from_raw(width, height, pixels).map(DynamicImage::from),
+    };
+    image.context("the decoder returned too few pixels")
+}
+
+/// The text of an SVG document, which may be compressed, within the limits.
+fn svg_text(data: &[u8], limits: &Limits) -> Result<String> {
+    let data = if data.starts_with(&[0x1f, 0x8b]) {
+        // One byte more than the limit is enough to tell that it was exceeded
+        let mut buf = Vec::new();
+        let mut decoder = GzDecoder::new(data).take(limits.max_bytes.saturating_add(1));
+        decoder
+            .read_to_end(&mut buf)
+            .context("could not decompress SVG document")?;
+        buf
+    } else {
+        data.to_vec()
+    };
+    limits.check_svg_len(data.len())?;
+    String::from_utf8(data).context("SVG document is not UTF-8")
+}
+
+/// Parse the text of an SVG document within the limits.
+fn parse_svg_text(text: &str, opt: &usvg::Options, limits: &Limits) -> Result<usvg::Tree> {
+    let xml_opt = roxmltree::ParsingOptions {
+        allow_dtd: true,
+        nodes_limit: limits.max_svg_nodes,
+    };
+    let doc = match roxmltree::Document::parse_with_options(text, xml_opt) {
+        Err(roxmltree::Error::NodesLimitReached) => bail!(
+            "the SVG document has more than {} nodes",
+            limits.max_svg_nodes
+        ),
+        res => res?,
+    };
+    let tree = match usvg::Tree::from_xmltree(&doc, opt, fonts()) {
+        // usvg has a fixed limit of its own
+        Err(usvg::Error::ParsingFailed(roxmltree::Error::NodesLimitReached)) => {
+            bail!("the SVG image has more than a million elements")
+        }
+        res => res?,
+    };
+    // References with <use> are expanded, so the tree can be much larger than the document
+    ensure!(
+        count_nodes(tree.root()) <= limits.max_svg_nodes as usize,
+        "the SVG image has more than {} elements",
+        limits.max_svg_nodes
+    );
+    Ok(tree)
+}
+
+fn count_nodes(group: &usvg::Group) -> usize {
+    group
+        .children()
+        .iter()
+        .map(|node| match node {
+            usvg::Node::Group(group) => 1 + count_nodes(group),
+            _ => 1,
+        })
+        .sum()
+}
+
+/// The system fonts, which are only loaded once.
+fn fonts() -> &'static fontdb::Database {
+    static FONTS: OnceLock<fontdb::Database> = OnceLock::new();
+    FONTS.get_or_init(|| {
+        let mut fontdb = fontdb::Database::new();
+        fontdb.load_system_fonts();
+        fontdb
+    })
+}
+
+/// The EXIF thumbnail of a JPEG file large enough to need one.
+fn jpeg_thumbnail(data: &[u8], limits: &Limits) -> Option<RgbaImage> {
+    let (width, height) =
+        image::io::Reader::with_format(Cursor::new(data), image::ImageFormat::Jpeg)
+            .into_dimensions()
+            .ok()?;
+    if (width as u64 * height as u64) < THUMBNAIL_MIN_PIXELS {
+        return None;
+    }
+    let thumbnail = raw::embedded_preview(metadata::jpeg_exif(data)?)?;
+    isolate::run(limits, || {
+        limits.decode(thumbnail, image::ImageFormat::Jpeg)
+    })
+    .ok()
+    .map(DynamicImage::into_rgba8)
+}
@@ -0,0 +1,108 @@
+//! DjVu documents, such as scanned books, rendered page by page by `ddjvu` from djvulibre.
+//!
+//! The layers of a DjVu page are compressed with wavelets and JB2, which only djvulibre decodes
+//! faithfully, so the pages are rendered by its command line tool into PPM images. The document
+//! structure is read here, to count the pages and to know their size before rendering them.
+//!
+//! A DjVu file is an IFF container: a `FORM:DJVU` chunk for a single page, or a `FORM:DJVM`
+//! chunk with a directory followed by one `FORM:DJVU` chunk per page. Indirect documents, whose
+//! pages are files of their own next to the index, are not supported.
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, ensure, Context, Result};
+use image::DynamicImage;
+
+use crate::isolate;
+use crate::limits::Limits;
+use crate::pnm;
+
+pub fn is_djvu(data: &[u8]) -> bool {
+    data.starts_with(b"AT&TFORM") && matches!(data.get(12..16), Some(b"DJVU" | b"DJVM"))
+}
+
+/// The number of pages in the file.
+pub fn page_count(data: &[u8]) -> usize {
+    pages(data).len()
+}
+
+/// The resolution of a page, in dots per inch.
+pub fn dpi(data: &[u8], page: usize) -> Option<f32> {
+    let info = info(data, page)?;
+    let dpi = u16::from_le_bytes(info.get(6..8)?.try_into().ok()?);
+    // Some writers leave it at 0
+    (dpi > 0).then_some(dpi as f32)
+}
+
+/// Render a page at its full resolution.
+pub fn render(data: &[u8], page: usize, limits: &Limits) -> Result<DynamicImage> {
+    let info = info(data, page).context("the page is missing")?;
+    ensure!(info.len() >= 4, "the page information is truncated");
+    let width = u16::from_be_bytes([info[0], info[1]]) as u32;
+    let height = u16::from_be_bytes([info[2], info[3]]) as u32;
+    limits.check(width, height)?;
+
+    let mut child = Command::new("ddjvu")
+        .args(["-format=ppm", &format!("-page={}", page + 1), "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("could not run ddjvu, which is needed to show DjVu documents")?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    let mut ppm = Vec::new();
+    let mut errors = String::new();
+    // The pipes are used at the same time, so that none of them fills up
+    let read = std::thread::scope(|scope| {
+        scope.spawn(move || stdin.write_all(data));
+        scope.spawn(|| stderr.read_to_string(&mut errors));
+        // A rotated page has as many pixels, and the header is short
+        let max_len = 64 + width as u64 * height as u64 * 3;
+        stdout.take(max_len).read_to_end(&mut ppm)
+    });
+    let status = child.wait()?;
+    read.context("could not read the output of ddjvu")?;
+    if !status.success() {
+        match errors.lines().map(str::trim).find(|line| !line.is_empty()) {
+            Some(line) => bail!("ddjvu failed: {line}"),
+            None => bail!("ddjvu failed: {status}"),
+        }
+    }
+    isolate::run(limits, || pnm::decode(&ppm, limits).map(Into::into))
+}
+
+/// The `FORM:DJVU` chunks of the pages, starting with their type.
+fn pages(data: &[u8]) -> Vec<&[u8]> {
+    let Some((b"FORM", form)) = chunks(data.get(4..).unwrap_or_default()).next() else {
+        return Vec::new();
+    };
+    match form.split_at_checked(4) {
+        Some((b"DJVU", _)) => vec![form],
+        Some((b"DJVM", components)) => chunks(components)
+            .filter(|&(id, chunk)| id == b"FORM" && chunk.starts_with(b"DJVU"))
+            .map(|(_, chunk)| chunk)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The `INFO` chunk of a page, with its size, version and resolution.
+fn info(data: &[u8], page: usize) -> Option<&[u8]> {
+    let form = *pages(data).get(page)?;
+    chunks(&form[4..]).find_map(|(id, chunk)| (id == b"INFO").then_some(chunk))
+}
+
+/// The chunks of an IFF container: their IDs and contents. Each one is padded to an even length.
+fn chunks(mut data: &[u8]) -> impl Iterator<Item = (&[u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let id: &[u8; 4] = data.get(..4)?.try_into().ok()?;
+        let len = u32::from_be_bytes(data.get(4..8)?.try_into().ok()?) as usize;
+        let chunk = data.get(8..8usize.checked_add(len)?)?;
+        data = data.get(8 + len + len % 2..).unwrap_or_default();
+        Some((id, chunk))
+    })
+}
@@ -0,0 +1,226 @@
+//! Downloading images from http(s) URLs in the background, with curl.
+
+use std::cell::OnceCell;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::process::{Child, Command, Stdio};
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, ensure, Context, Result};
+
+/// How often the progress is reported while the data arrives.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+const CONNECT_TIMEOUT_SECS: &str = "30";
+
+pub fn is_url(path: &str) -> bool {
+    ["http://", "https://"].iter().any(|scheme| {
+        path.get(..scheme.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(scheme))
+    })
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Progress {
+    pub received: u64,
+    /// The size given by the server, if any
+    pub total: Option<u64>,
+}
+
+pub struct Download {
+    /// Where the data is stored once it has arrived, shared with the file list
+    data: Rc<OnceCell<Vec<u8>>>,
+    progress: Arc<Mutex<Progress>>,
+    result: mpsc::Receiver<Result<Vec<u8>>>,
+    /// Becomes readable when there is progress, and when the download has finished. Closing it
+    /// cancels the download.
+    wakeup: UnixStream,
+}
+
+impl Download {
+    /// Start downloading `url` into `data`. Responses larger than `max_bytes` are refused.
+    pub fn start(url: &str, data: Rc<OnceCell<Vec<u8>>>, max_bytes: u64) -> Result<Self> {
+        let mut child = Command::new("curl")
+            .args(["--silent", "--show-error", "--fail", "--location"])
+            // Redirects must not lead to local files
+            .args(["--proto", "=http,https", "--proto-redir", "=http,https"])
+            .args(["--connect-timeout", CONNECT_TIMEOUT_SECS])
+            .args(["--max-filesize", &max_bytes.to_string()])
+            // The headers come first in the output, for the size of the body
+            .args(["--dump-header", "-", "--url", url])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("could not run curl, which is needed to download images")?;
+
+        let progress = Arc::new(Mutex::new(Progress::default()));
+        let (tx, result) = mpsc::channel();
+        let (wakeup, mut wakeup_tx) = UnixStream::pair()?;
+        wakeup.set_nonblocking(true)?;
+        let thread_progress = progress.clone();
+        std::thread::spawn(move || {
+            let mut report = |p: Option<Progress>| {
+                if let Some(p) = p {
+                    *thread_progress.lock().unwrap() = p;
+                }
+                wakeup_tx.write_all(&[0]).is_ok()
+            };
+            let result = receive(&mut child, max_bytes, &mut |p| report(Some(p)));
+            if result.is_err() {
+                let _ = child.kill();
+            }
+            let _ = child.wait();
+            let _ = tx.send(result);
+            report(None);
+        });
+
+        Ok(Self {
+            data,
+            progress,
+            result,
+            wakeup,
+        })
+    }
+
+    /// Whether this download is for the image which stores its data in `data`.
+    pub fn is_for(&self, data: &Rc<OnceCell<Vec<u8>>>) -> bool {
+        Rc::ptr_eq(&self.data, data)
+    }
+
+    pub fn progress(&self) -> Progress {
+        *self.progress.lock().unwrap()
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.wakeup.as_raw_fd()
+    }
+
+    /// Store the data once it has arrived. Returns `None` while the download is running.
+    ///
+    /// Call this when [`Self::fd`] becomes readable.
+    pub fn finish(&mut self) -> Option<Result<()>> {
+        let mut buf = [0; 64];
+        while matches!(self.wakeup.read(&mut buf), Ok(1..)) {}
+        let result = match self.result.try_recv() {
+            Ok(result) => result,
+            Err(mpsc::TryRecvError::Empty) => return None,
+            Err(mpsc::TryRecvError::Disconnected) => Err(anyhow::anyhow!("the download stopped")),
+        };
+        Some(result.map(|data| {
+            let _ = self.data.set(data);
+        }))
+    }
+}
+
+/// Read the response from curl. `report` is called with the progress, and returns `false` once
+/// nobody waits for the download anymore.
+fn receive(
+    child: &mut Child,
+    max_bytes: u64,
+    report: &mut impl FnMut(Progress) -> bool,
+) -> Result<Vec<u8>> {
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut progress = Progress {
+        received: 0,
+        total: body_size(&mut stdout)?,
+    };
+    if let Some(total) = progress.total {
+        ensure!(
+            total <= max_bytes,
+            "the image is larger than {} MiB",
+            max_bytes >> 20
+        );
+    }
+
+    let mut data = Vec::new();
+    let mut buf = vec![0; 64 << 10];
+    let mut reported = Instant::now();
+    loop {
+        let n = match stdout.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context("could not read the download"),
+        };
+        data.extend_from_slice(&buf[..n]);
+        progress.received = data.len() as u64;
+        ensure!(
+            progress.received <= max_bytes,
+            "the image is larger than {} MiB",
+            max_bytes >> 20
+        );
+        if reported.elapsed() >= PROGRESS_INTERVAL {
+            reported = Instant::now();
+            if !report(progress) {
+                bail!("cancelled");
+            }
+        }
+    }
+
+    let status = child.wait()?;
+    if !status.success() {
+        let mut message = String::new();
+        if let Some(mut stderr) = child.stderr.take() {
+            let _ = stderr.read_to_string(&mut message);
+        }
+        let message = message.trim();
+        let message = message.strip_prefix("curl: ").unwrap_or(message);
+        // Without the exit code, as in "(22) The requested URL returned error: 404"
+        let message = match message.split_once(") ") {
+            Some((code, rest)) if code.starts_with('(') => rest,
+            _ => message,
+        };
+        bail!(
+            "{}",
+            if message.is_empty() {
+                "curl failed"
+            } else {
+                message
+            }
+        );
+    }
+    Ok(data)
+}
+
+/// Skip the headers of every response up to the final one, returning the size of its body if
+/// the server has given it.
+fn body_size(stdout: &mut impl BufRead) -> Result<Option<u64>> {
+    loop {
+        let mut status = None;
+        let mut redirect = false;
+        let mut size = None;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if stdout.read_line(&mut line)? == 0 {
+                // curl failed before there was a response
+                return Ok(None);
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if status.is_none() {
+                status = line.split_whitespace().nth(1).and_then(|s| s.parse().ok());
+                continue;
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            if name.eq_ignore_ascii_case("location") {
+                redirect = true;
+            } else if name.eq_ignore_ascii_case("content-length") {
+                size = value.trim().parse().ok();
+            }
+        }
+        match status {
+            // Informational responses and redirects, which curl follows
+            Some(100..=199) => (),
+            Some(300..=399) if redirect => (),
+            _ => return Ok(size),
+        }
+    }
+}
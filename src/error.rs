@@ -0,0 +1,97 @@
+//! Errors which are handled differently from the rest.
+//!
+//! Most errors are simply reported with `anyhow`. The ones here are recoverable: the window can
+//! stay open without an image, and a lost compositor connection can be re-established.
+
+use std::env;
+use std::fmt;
+use std::io;
+
+use wayrs_client::ConnectError;
+
+/// The image could not be read or decoded. This is shown in the window.
+#[derive(Debug)]
+pub struct DecodeError {
+    pub path: String,
+    pub source: anyhow::Error,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {:#}", self.path, self.source)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Talking to the compositor failed. We try to reconnect.
+#[derive(Debug)]
+pub enum WaylandError {
+    /// Could not connect at all
+    Connect(ConnectError),
+    /// The connection broke, or the compositor sent a protocol error
+    Lost(io::Error),
+}
+
+impl WaylandError {
+    /// What the user can do when we could not connect, judging by the environment.
+    pub fn hint(&self) -> Option<String> {
+        let Self::Connect(err) = self else {
+            return None;
+        };
+        let set = |var: &str| env::var_os(var).is_some_and(|v| !v.is_empty());
+        let session_type = env::var("XDG_SESSION_TYPE").unwrap_or_default();
+
+        if !set("WAYLAND_DISPLAY") {
+            return Some(if session_type == "x11" || set("DISPLAY") {
+                "reimv only supports Wayland, and this is an X11 session. Log in to a Wayland \
+                 session, or run reimv in a nested Wayland compositor, e.g. `cage -- reimv FILE`"
+                    .into()
+            } else {
+                "no Wayland session was found (WAYLAND_DISPLAY is not set). Run reimv from a \
+                 terminal inside your Wayland compositor"
+                    .into()
+            });
+        }
+        if !set("XDG_RUNTIME_DIR") {
+            return Some(
+                "XDG_RUNTIME_DIR is not set, which happens under `su` or `sudo`. Run reimv as the \
+                 user who is logged in"
+                    .into(),
+            );
+        }
+        match err {
+            ConnectError::Io(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                let display = env::var("WAYLAND_DISPLAY").unwrap_or_default();
+                Some(format!(
+                    "no compositor is listening on WAYLAND_DISPLAY={display}. Check that it is \
+                     still running, and that WAYLAND_DISPLAY belongs to the current session"
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for WaylandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connect(_) => write!(f, "could not connect to the compositor"),
+            Self::Lost(_) => write!(f, "lost connection to the compositor"),
+        }
+    }
+}
+
+impl std::error::Error for WaylandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Connect(e) => Some(e),
+            Self::Lost(e) => Some(e),
+        }
+    }
+}
@@ -0,0 +1,957 @@
+//! The images given on the command line, and moving through them.
+
+use std::cell::OnceCell;
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
+
+use anyhow::{bail, ensure, Context, Result};
+use clap::ValueEnum;
+
+use crate::config;
+use crate::download;
+
+use reimv::archive::Archive;
+use reimv::format;
+use reimv::limits::Limits;
+
+/// What happens when moving past the last image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum AtEnd {
+    /// Stay on the last image and say so
+    #[default]
+    Stop,
+    /// Continue with the first image
+    Wrap,
+    /// Close the window
+    Quit,
+    /// Stay on the last image and run the `--end-hook` command
+    Hook,
+}
+
+/// The order of the images given on the command line and of those in directories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Sort {
+    /// By path, character by character
+    Name,
+    /// By path, with numbers by their value so that `img2` comes before `img10`
+    #[default]
+    Natural,
+    /// By the time of the last change, oldest first
+    Mtime,
+    /// By file size, smallest first
+    Size,
+    /// In the order they are given, with the images in a directory by name
+    None,
+}
+
+/// Which files of a directory are shown.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Whether the files of subdirectories are shown too
+    pub recursive: bool,
+    /// Patterns of the files to show. Without any, the images are found by their extensions
+    pub include: Vec<String>,
+    /// Patterns of the files and directories to leave out
+    pub exclude: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct FileList {
+    entries: Vec<Entry>,
+    cursor: Cursor,
+    /// The indices of the images shown so far, to go back and forward like in a web browser
+    history: Vec<usize>,
+    /// The directories the files were found in, to follow files being added and removed
+    directories: Vec<String>,
+    filter: Filter,
+    /// The order the files were put in and whether it is reversed, or `None` after shuffling
+    order: Option<(Sort, bool)>,
+    /// The names of the marked images
+    marked: HashSet<String>,
+}
+
+/// The current image and its place in the history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    current: usize,
+    history: usize,
+}
+
+#[derive(Clone)]
+pub enum Entry {
+    /// A file, or - for stdin
+    File(String),
+    /// An image in an archive
+    Page {
+        path: String,
+        archive: Rc<Archive>,
+        index: usize,
+    },
+    /// An image on the web, downloaded when it is shown for the first time
+    Url {
+        url: String,
+        data: Rc<OnceCell<Vec<u8>>>,
+    },
+    /// An image given inline as a `data:` URI
+    Data {
+        /// `data:` and the media type, since the whole URI is too long to show
+        name: String,
+        data: Rc<Vec<u8>>,
+    },
+}
+
+/// Random numbers for shuffling, which need not be good ones.
+pub struct Random(u64);
+
+impl Random {
+    /// Seeded differently every time, or always the same if `deterministic`.
+    pub fn new(deterministic: bool) -> Self {
+        match deterministic {
+            true => Self(0x2545_f491_4f6c_dd1d),
+            false => Self(RandomState::new().build_hasher().finish() | 1),
+        }
+    }
+
+    /// A number in `0..n`, with xorshift64*.
+    pub fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % n as u64) as usize
+    }
+}
+
+/// Where a step through the list leads.
+pub enum Step {
+    /// To another image, which is now the current one
+    Moved,
+    /// Past the last image, with the current one unchanged
+    PastEnd,
+    /// Before the first image, with the current one unchanged
+    PastStart,
+}
+
+impl FileList {
+    /// Archives are opened and replaced by their images, and directories by the files in them
+    /// which pass `filter`. The others are only read when they are shown. The images are put in
+    /// the order of `sort`, where those of an archive stay together in the order of their names.
+    pub fn new(paths: &[String], filter: &Filter, sort: Sort, reverse: bool) -> Result<Self> {
+        // The images of each archive, and the other images on their own
+        let mut groups = Vec::new();
+        let mut directories = Vec::new();
+        for path in paths {
+            if is_data_uri(path) {
+                match data_uri(path) {
+                    Ok(entry) => groups.push(vec![entry]),
+                    Err(e) => eprintln!("reimv: {e:#}"),
+                }
+                continue;
+            }
+            if download::is_url(path) {
+                groups.push(vec![Entry::Url {
+                    url: path.clone(),
+                    data: Rc::default(),
+                }]);
+                continue;
+            }
+            if Path::new(path).is_dir() {
+                let mut files = Vec::new();
+                directory(path, filter, &mut files, &mut directories);
+                groups.extend(files.into_iter().map(|file| vec![Entry::File(file)]));
+                continue;
+            }
+            if !is_archive(path) {
+                groups.push(vec![Entry::File(path.clone())]);
+                continue;
+            }
+            match Archive::open(Path::new(path)) {
+                Ok(archive) => groups.push(pages(path, archive)),
+                Err(e) => eprintln!("reimv: {path}: {e:#}"),
+            }
+        }
+        sort_groups(&mut groups, sort);
+        if reverse {
+            groups.reverse();
+        }
+        let entries: Vec<Entry> = groups.into_iter().flatten().collect();
+        ensure!(!entries.is_empty(), "there are no images to show");
+        Ok(Self {
+            entries,
+            cursor: Cursor {
+                current: 0,
+                history: 0,
+            },
+            history: vec![0],
+            directories,
+            filter: filter.clone(),
+            order: Some((sort, reverse)),
+            marked: HashSet::new(),
+        })
+    }
+
+    /// Put the images in a random order, starting with the first one of it.
+    pub fn shuffle(&mut self, random: &mut Random) {
+        for i in (1..self.entries.len()).rev() {
+            self.entries.swap(i, random.below(i + 1));
+        }
+        self.order = None;
+    }
+
+    /// The step to a random image other than the current one, or 0 if there is no other one.
+    pub fn random_step(&self, random: &mut Random) -> isize {
+        let len = self.entries.len();
+        if len < 2 {
+            return 0;
+        }
+        let other = (self.cursor.current + 1 + random.below(len - 1)) % len;
+        other as isize - self.cursor.current as isize
+    }
+
+    pub fn current(&self) -> &Entry {
+        &self.entries[self.cursor.current]
+    }
+
+    /// The paths of all files, without duplicates from archives. URLs are included.
+    pub fn paths(&self) -> Vec<&str> {
+        let mut paths: Vec<&str> = self.entries.iter().map(Entry::path).collect();
+        paths.dedup();
+        paths
+    }
+
+    /// The directories the files were found in, including subdirectories with `recursive`.
+    pub fn directories(&self) -> &[String] {
+        &self.directories
+    }
+
+    /// Add a file which has appeared in one of the directories, where the order of the list puts
+    /// it, or at the end after shuffling. Returns `false` if the filter leaves it out or it is
+    /// already there.
+    pub fn add(&mut self, path: String) -> bool {
+        let Some(name) = Path::new(&path).file_name().and_then(|name| name.to_str()) else {
+            return false;
+        };
+        if !self.filter.shows(&path, name) || self.index_of(&path).is_some() {
+            return false;
+        }
+        let index = match self.order {
+            Some((sort, reverse)) => {
+                // Whether the new file comes before `entry`
+                let before = |entry: &Entry| {
+                    let ordering = compare(sort, &path, entry.path());
+                    match reverse {
+                        true => ordering.is_gt(),
+                        false => ordering.is_lt(),
+                    }
+                };
+                match sort {
+                    // Among the other files of the directory
+                    Sort::None => {
+                        let dir = Path::new(&path).parent();
+                        let same_dir = |entry: &Entry| matches!(entry, Entry::File(file) if Path::new(file).parent() == dir);
+                        let next = self.entries.iter().position(|e| same_dir(e) && before(e));
+                        let last = self.entries.iter().rposition(same_dir);
+                        next.or(last.map(|i| i + 1)).unwrap_or(self.entries.len())
+                    }
+                    _ => self.entries.partition_point(|e| !before(e)),
+                }
+            }
+            None => self.entries.len(),
+        };
+        self.entries.insert(index, Entry::File(path));
+        for i in self.history.iter_mut().chain([&mut self.cursor.current]) {
+            if *i >= index {
+                *i += 1;
+            }
+        }
+        true
+    }
+
+    /// Add the images of `paths`, which have been chosen while running, at the end of the list.
+    /// They are ordered among themselves, but the list keeps no order afterwards, so files which
+    /// appear later go to the end too. Returns the index of the first one, which may have been
+    /// in the list already.
+    pub fn open(&mut self, paths: &[String]) -> Result<usize> {
+        let (sort, reverse) = self.order.unwrap_or((Sort::None, false));
+        let opened = FileList::new(paths, &self.filter, sort, reverse)?;
+        let mut first = None;
+        for entry in opened.entries {
+            let known = match &entry {
+                // Which the portal gives as absolute paths
+                Entry::File(path) => self.find(Path::new(path)),
+                _ => None,
+            };
+            let index = known.unwrap_or_else(|| {
+                self.entries.push(entry);
+                self.order = None;
+                self.entries.len() - 1
+            });
+            first.get_or_insert(index);
+        }
+        Ok(first.unwrap())
+    }
+
+    /// Remove a file which has been deleted or moved away. The current image stays, since it is
+    /// still shown.
+    pub fn remove(&mut self, path: &str) {
+        let Some(index) = self.index_of(path) else {
+            return;
+        };
+        if index != self.cursor.current {
+            self.remove_index(index);
+        }
+    }
+
+    /// Remove the current image, after its file has been deleted. The next image becomes the
+    /// current one, or the previous one after the last. Returns `false` if it is the only one,
+    /// which stays.
+    pub fn remove_current(&mut self) -> bool {
+        if self.entries.len() == 1 {
+            return false;
+        }
+        let index = self.cursor.current;
+        self.remove_index(index);
+        self.cursor.current = index.min(self.entries.len() - 1);
+        true
+    }
+
+    fn remove_index(&mut self, index: usize) {
+        self.entries.remove(index);
+        let mut history = Vec::with_capacity(self.history.len());
+        for (i, &entry) in self.history.iter().enumerate() {
+            match entry.cmp(&index) {
+                Ordering::Less => history.push(entry),
+                Ordering::Equal if i <= self.cursor.history => {
+                    self.cursor.history = self.cursor.history.saturating_sub(1);
+                }
+                Ordering::Equal => (),
+                Ordering::Greater => history.push(entry - 1),
+            }
+        }
+        self.history = history;
+        if self.cursor.current > index {
+            self.cursor.current -= 1;
+        }
+    }
+
+    /// Make the image at `index` the current one, to be recorded in the history once shown.
+    pub fn go_to(&mut self, index: usize) {
+        self.cursor.current = index;
+    }
+
+    /// Follow a file which has been renamed from `old` to `new`. It keeps its place in the list,
+    /// and its mark.
+    pub fn rename(&mut self, old: &str, new: &str) {
+        for entry in &mut self.entries {
+            if matches!(entry, Entry::File(path) if path == old) {
+                *entry = Entry::File(new.to_owned());
+            }
+        }
+        if self.marked.remove(old) {
+            self.marked.insert(new.to_owned());
+        }
+    }
+
+    /// Mark the current image, or unmark it if it is marked. Returns whether it is marked now.
+    pub fn toggle_mark(&mut self) -> bool {
+        let name = self.current().name();
+        let marked = !self.marked.remove(&name);
+        if marked {
+            self.marked.insert(name);
+        }
+        marked
+    }
+
+    pub fn is_marked(&self) -> bool {
+        !self.marked.is_empty() && self.marked.contains(&self.current().name())
+    }
+
+    /// The names of the marked images in the order of the list, which is the path for files.
+    pub fn marked(&self) -> Vec<String> {
+        // Files which are given twice are only listed once
+        let mut listed = HashSet::new();
+        self.entries
+            .iter()
+            .map(Entry::name)
+            .filter(|name| self.marked.contains(name) && listed.insert(name.clone()))
+            .collect()
+    }
+
+    /// The index of the file at `path`, if it is in the list.
+    pub fn index_of(&self, path: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| matches!(entry, Entry::File(file) if file == path))
+    }
+
+    /// The index of the file at `path`, even if it is given relative to another directory than
+    /// the list.
+    pub fn find(&self, path: &Path) -> Option<usize> {
+        let path = std::path::absolute(path).ok()?;
+        self.entries.iter().position(|entry| {
+            matches!(entry, Entry::File(file)
+                if std::path::absolute(file).is_ok_and(|file| file == path))
+        })
+    }
+
+    /// Start at the image at `index` instead of the first one, before any has been shown.
+    pub fn start_at(&mut self, index: usize) {
+        self.cursor = Cursor {
+            current: index,
+            history: 0,
+        };
+        self.history = vec![index];
+    }
+
+    pub fn cursor(&self) -> Cursor {
+        self.cursor
+    }
+
+    /// Go back to an earlier cursor, after moving to an image which could not be shown.
+    pub fn restore(&mut self, cursor: Cursor) {
+        self.cursor = cursor;
+    }
+
+    /// Add the current image to the history, after moving to it through the list. Anything
+    /// after the previous image in the history is forgotten.
+    pub fn record(&mut self) {
+        self.history.truncate(self.cursor.history + 1);
+        self.history.push(self.cursor.current);
+        self.cursor.history = self.history.len() - 1;
+    }
+
+    /// Move `delta` images forward through the history. Returns `false` if it ends before.
+    pub fn step_history(&mut self, delta: isize) -> bool {
+        let Some(history) = self
+            .cursor
+            .history
+            .checked_add_signed(delta)
+            .filter(|&i| i < self.history.len())
+        else {
+            return false;
+        };
+        self.cursor = Cursor {
+            current: self.history[history],
+            history,
+        };
+        true
+    }
+
+    /// The position of the current image, counting from 1, and the number of images.
+    pub fn position(&self) -> (usize, usize) {
+        (self.cursor.current + 1, self.entries.len())
+    }
+
+    /// The next and the previous image, wrapping around the ends if `wrap` is set.
+    pub fn neighbours(&self, wrap: bool) -> Vec<&Entry> {
+        let (current, len) = (self.cursor.current, self.entries.len());
+        let next = match current + 1 {
+            next if next < len => Some(next),
+            _ if wrap => Some(0),
+            _ => None,
+        };
+        let previous = match current.checked_sub(1) {
+            Some(previous) => Some(previous),
+            None if wrap => Some(len - 1),
+            None => None,
+        };
+        let previous = previous.filter(|&previous| Some(previous) != next);
+        [next, previous]
+            .into_iter()
+            .flatten()
+            .filter(|&i| i != current)
+            .map(|i| &self.entries[i])
+            .collect()
+    }
+
+    /// Move `delta` images forward, wrapping around the ends if `wrap` is set.
+    pub fn step(&mut self, delta: isize, wrap: bool) -> Step {
+        let len = self.entries.len() as isize;
+        let next = self.cursor.current as isize + delta;
+        let next = match next {
+            _ if wrap => next.rem_euclid(len),
+            ..0 => return Step::PastStart,
+            _ if next >= len => return Step::PastEnd,
+            _ => next,
+        };
+        self.cursor.current = next as usize;
+        Step::Moved
+    }
+}
+
+impl Entry {
+    /// The path of the file, which is the archive for images in one, or the URL.
+    pub fn path(&self) -> &str {
+        match self {
+            Self::File(path) | Self::Page { path, .. } | Self::Url { url: path, .. } => path,
+            Self::Data { name, .. } => name,
+        }
+    }
+
+    /// The path of the file, followed by the image's path within the archive for images in one.
+    pub fn name(&self) -> String {
+        match self {
+            Self::File(path) | Self::Url { url: path, .. } | Self::Data { name: path, .. } => {
+                path.clone()
+            }
+            Self::Page {
+                path,
+                archive,
+                index,
+            } => format!("{path}/{}", archive.members()[*index].name),
+        }
+    }
+
+    pub fn read(&self, limits: &Limits) -> Result<Vec<u8>> {
+        match self {
+            Self::File(path) => format::read(Path::new(path)).context("could not read file"),
+            Self::Page { archive, index, .. } => archive.read(*index, limits),
+            Self::Url { data, .. } => data
+                .get()
+                .cloned()
+                .context("the image has not been downloaded yet"),
+            Self::Data { data, .. } => Ok(data.to_vec()),
+        }
+    }
+}
+
+pub fn is_data_uri(path: &str) -> bool {
+    path.get(..5)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("data:"))
+}
+
+/// Decode a `data:` URI, which is base64 or percent encoded.
+fn data_uri(uri: &str) -> Result<Entry> {
+    let url = data_url::DataUrl::process(uri)
+        .map_err(|e| anyhow::anyhow!("{e}"))
+        .context("invalid data URI")?;
+    let (data, _) = url
+        .decode_to_vec()
+        .map_err(|e| anyhow::anyhow!("{e}"))
+        .context("invalid data URI")?;
+    let mime = url.mime_type();
+    Ok(Entry::Data {
+        name: format!("data:{}/{}", mime.type_, mime.subtype),
+        data: Rc::new(data),
+    })
+}
+
+/// Sort groups of entries by their first one. Stdin, URLs and `data:` URIs have no time or size,
+/// so they come last when sorting by those.
+fn sort_groups(groups: &mut [Vec<Entry>], sort: Sort) {
+    fn path(group: &[Entry]) -> &str {
+        group.first().map_or("", Entry::path)
+    }
+    let metadata = |group: &[Entry]| std::fs::metadata(path(group)).ok();
+    match sort {
+        Sort::Name => groups.sort_by(|a, b| path(a).cmp(path(b))),
+        Sort::Natural => groups.sort_by(|a, b| natural_cmp(path(a), path(b))),
+        Sort::Mtime => groups.sort_by_cached_key(|group| {
+            let time = metadata(group).and_then(|m| m.modified().ok());
+            (time.is_none(), time)
+        }),
+        Sort::Size => groups.sort_by_cached_key(|group| {
+            let size = metadata(group).map(|m| m.len());
+            (size.is_none(), size)
+        }),
+        Sort::None => (),
+    }
+}
+
+/// The order of two paths in the file list, where `Sort::None` is the one of a directory.
+fn compare(sort: Sort, a: &str, b: &str) -> Ordering {
+    let metadata = |path: &str| std::fs::metadata(path).ok();
+    match sort {
+        Sort::Name => a.cmp(b),
+        Sort::Natural | Sort::None => natural_cmp(a, b),
+        Sort::Mtime => {
+            let time = |path| metadata(path).and_then(|m| m.modified().ok());
+            let (a, b) = (time(a), time(b));
+            (a.is_none(), a).cmp(&(b.is_none(), b))
+        }
+        Sort::Size => {
+            let size = |path| metadata(path).map(|m| m.len());
+            let (a, b) = (size(a), size(b));
+            (a.is_none(), a).cmp(&(b.is_none(), b))
+        }
+    }
+}
+
+impl Filter {
+    /// Whether the file at `path` in a directory, which is called `name`, is shown.
+    fn shows(&self, path: &str, name: &str) -> bool {
+        if name.starts_with('.') || self.excludes(path) {
+            return false;
+        }
+        match self.include.is_empty() {
+            true => format::has_image_extension(Path::new(name)),
+            false => matches_any(&self.include, path),
+        }
+    }
+
+    fn excludes(&self, path: &str) -> bool {
+        matches_any(&self.exclude, path)
+    }
+}
+
+fn matches_any(patterns: &[String], path: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| config::matches_pattern(pattern, path))
+}
+
+/// Add the paths of the files in a directory which pass `filter` to `files`, in the order of
+/// their names, and the directory and the subdirectories which are searched to `directories`.
+/// Hidden files and directories are left out. Directories which cannot be read are reported and
+/// skipped.
+fn directory(dir: &str, filter: &Filter, files: &mut Vec<String>, directories: &mut Vec<String>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("reimv: {dir}: could not read the directory: {e}");
+            return;
+        }
+    };
+    let mut entries: Vec<(String, bool)> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            // Links to directories are not followed, so that there are no loops
+            let is_dir = entry.file_type().ok()?.is_dir();
+            Some((entry.file_name().into_string().ok()?, is_dir))
+        })
+        .filter(|(name, _)| !name.starts_with('.'))
+        .collect();
+    entries.sort_by(|(a, _), (b, _)| natural_cmp(a, b));
+
+    let dir = dir
+        .strip_suffix('/')
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or(dir);
+    directories.push(dir.to_owned());
+    for (name, is_dir) in entries {
+        let path = join(dir, &name);
+        if !is_dir {
+            if filter.shows(&path, &name) {
+                files.push(path);
+            }
+        } else if filter.recursive && !filter.excludes(&path) {
+            directory(&path, filter, files, directories);
+        }
+    }
+}
+
+/// The path of the file called `name` in the directory at `dir`, like
+/// [`FileList::directories`] and the files found in them.
+pub fn join(dir: &str, name: &str) -> String {
+    match dir {
+        "/" => format!("/{name}"),
+        dir => format!("{dir}/{name}"),
+    }
+}
+
+/// The paths listed in `reader`, like the output of `find`: one per line, or separated by NUL
+/// characters with `nul`, like the output of `find -print0`. Empty lines are left out, and paths
+/// which are not valid UTF-8 are skipped with a notice.
+pub fn read_list(mut reader: impl Read, nul: bool) -> Result<Vec<String>> {
+    let mut data = Vec::new();
+    reader
+        .read_to_end(&mut data)
+        .context("could not read the list of files")?;
+    let separator = match nul {
+        true => b'\0',
+        false => b'\n',
+    };
+    let paths = data
+        .split(|&byte| byte == separator)
+        .filter(|path| !path.is_empty())
+        .filter_map(|path| match String::from_utf8(path.to_vec()) {
+            Ok(path) => Some(path),
+            Err(_) => {
+                let path = String::from_utf8_lossy(path);
+                eprintln!("reimv: {path}: skipping a path which is not valid UTF-8");
+                None
+            }
+        });
+    Ok(paths.collect())
+}
+
+/// Write `paths` to `writer` like [`read_list`] reads them, one per line or each followed by a
+/// NUL character with `nul`.
+pub fn write_list(mut writer: impl Write, paths: &[String], nul: bool) -> io::Result<()> {
+    let separator = match nul {
+        true => b'\0',
+        false => b'\n',
+    };
+    for path in paths {
+        writer.write_all(path.as_bytes())?;
+        writer.write_all(&[separator])?;
+    }
+    writer.flush()
+}
+
+/// The files matching `pattern`, the frames of an image sequence, in the order of their numbers.
+/// The file name may contain a printf-style number like `%04d`, or `*` and `?` wildcards.
+pub fn sequence(pattern: &str) -> Result<Vec<String>> {
+    let (dir, name) = match pattern.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((dir, name)) => (dir, name),
+        None => (".", pattern),
+    };
+    let tokens = sequence_tokens(name)?;
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .with_context(|| format!("could not read {dir}"))?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| {
+            let name: Vec<char> = name.chars().collect();
+            matches_sequence(&name, &tokens)
+        })
+        .collect();
+    ensure!(!names.is_empty(), "no files match {pattern}");
+    names.sort_by(|a, b| natural_cmp(a, b));
+    Ok(match pattern.rsplit_once('/') {
+        Some((dir, _)) => names.iter().map(|name| format!("{dir}/{name}")).collect(),
+        None => names,
+    })
+}
+
+enum SequenceToken {
+    Char(char),
+    /// `?`
+    AnyChar,
+    /// `*`
+    AnyChars,
+    /// `%d`, with at least this many digits for `%04d`
+    Number(usize),
+}
+
+fn sequence_tokens(pattern: &str) -> Result<Vec<SequenceToken>> {
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '?' => SequenceToken::AnyChar,
+            '*' => SequenceToken::AnyChars,
+            '%' if chars.as_str().starts_with('%') => {
+                chars.next();
+                SequenceToken::Char('%')
+            }
+            '%' => {
+                let rest = chars.as_str();
+                let end = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                ensure!(
+                    rest[end..].starts_with('d'),
+                    "expected a number like %04d in the pattern"
+                );
+                // Without a leading zero, the number is padded with spaces, which file names
+                // rarely have
+                let width = match rest[..end].strip_prefix('0') {
+                    Some(width) => width.parse().unwrap_or(1),
+                    None => 1,
+                };
+                chars = rest[end + 1..].chars();
+                SequenceToken::Number(width)
+            }
+            c => SequenceToken::Char(c),
+        });
+    }
+    Ok(tokens)
+}
+
+fn matches_sequence(name: &[char], tokens: &[SequenceToken]) -> bool {
+    let Some((token, rest)) = tokens.split_first() else {
+        return name.is_empty();
+    };
+    match token {
+        SequenceToken::Char(c) => name.first() == Some(c) && matches_sequence(&name[1..], rest),
+        SequenceToken::AnyChar => !name.is_empty() && matches_sequence(&name[1..], rest),
+        SequenceToken::AnyChars => (0..=name.len()).any(|i| matches_sequence(&name[i..], rest)),
+        SequenceToken::Number(width) => {
+            let digits = name.iter().take_while(|c| c.is_ascii_digit()).count();
+            ((*width).max(1)..=digits).any(|i| matches_sequence(&name[i..], rest))
+        }
+    }
+}
+
+/// Zip and tar archives, and comic book archives which are either.
+fn is_archive(path: &str) -> bool {
+    let ext = Path::new(path).extension().and_then(|ext| ext.to_str());
+    // Many CBR files are zip archives with the wrong extension, and the others are refused
+    // when they are opened
+    ext.is_some_and(|ext| {
+        ["zip", "cbz", "cbr", "tar", "cbt"]
+            .iter()
+            .any(|archive| ext.eq_ignore_ascii_case(archive))
+    })
+}
+
+/// The images in an archive, in the order of their names.
+fn pages(path: &str, archive: Archive) -> Vec<Entry> {
+    let archive = Rc::new(archive);
+    let mut indices: Vec<usize> = (0..archive.members().len())
+        .filter(|&i| format::has_image_extension(Path::new(&archive.members()[i].name)))
+        .collect();
+    indices.sort_by(|&a, &b| natural_cmp(&archive.members()[a].name, &archive.members()[b].name));
+    if indices.is_empty() {
+        eprintln!("reimv: {path}: the archive contains no images");
+    }
+    indices
+        .into_iter()
+        .map(|index| Entry::Page {
+            path: path.to_owned(),
+            archive: archive.clone(),
+            index,
+        })
+        .collect()
+}
+
+/// Compare names like people do, with numbers by their value, so that `page2` comes before
+/// `page10`. Letters are compared ignoring case.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a_rest, mut b_rest) = (a, b);
+    while let (Some(ac), Some(bc)) = (a_rest.chars().next(), b_rest.chars().next()) {
+        let ordering = if ac.is_ascii_digit() && bc.is_ascii_digit() {
+            let a_len = a_rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(a_rest.len());
+            let b_len = b_rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(b_rest.len());
+            let a_num = a_rest[..a_len].trim_start_matches('0');
+            let b_num = b_rest[..b_len].trim_start_matches('0');
+            let ordering = a_num.len().cmp(&b_num.len()).then(a_num.cmp(b_num));
+            a_rest = &a_rest[a_len..];
+            b_rest = &b_rest[b_len..];
+            ordering
+        } else {
+            a_rest = &a_rest[ac.len_utf8()..];
+            b_rest = &b_rest[bc.len_utf8()..];
+            ac.to_lowercase().cmp(bc.to_lowercase())
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a_rest.len().cmp(&b_rest.len()).then(a.cmp(b))
+}
+
+/// Copy the file at `path` into the directory `dir`, which is created if needed, or move it there
+/// unless `copy`. Returns the new path. A file of the same name which is already there is not
+/// replaced. Copies keep the permissions and the modification time, so that sorting by time still
+/// works, and files are copied too when they are moved to another file system.
+pub fn copy_or_move(path: &str, dir: &str, copy: bool) -> Result<PathBuf> {
+    let name = Path::new(path).file_name().context("not a file")?;
+    std::fs::create_dir_all(dir).with_context(|| format!("could not create {dir}"))?;
+    let target = Path::new(dir).join(name);
+    ensure!(
+        std::fs::symlink_metadata(&target).is_err(),
+        "{} already exists",
+        target.display()
+    );
+    if !copy {
+        match rename_noreplace(Path::new(path), &target) {
+            Ok(()) => return Ok(target),
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => (),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                bail!("{} already exists", target.display())
+            }
+            Err(e) => return Err(e).context("could not move the file"),
+        }
+    }
+    copy_file(Path::new(path), &target)?;
+    if !copy {
+        std::fs::remove_file(path).context("copied the file, but could not remove it")?;
+    }
+    Ok(target)
+}
+
+/// Rename the file at `path` to `name`, in the same directory. Returns the new path. A file which
+/// is already called `name` is not replaced.
+pub fn rename(path: &str, name: &str) -> Result<String> {
+    ensure!(
+        !matches!(name, "" | "." | "..") && !name.contains('/'),
+        "{name:?} is not a file name"
+    );
+    let target = match Path::new(path).parent().and_then(Path::to_str) {
+        None | Some("") => name.to_owned(),
+        Some(dir) => join(dir, name),
+    };
+    match rename_noreplace(Path::new(path), Path::new(&target)) {
+        Ok(()) => Ok(target),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => bail!("{target} already exists"),
+        Err(e) => Err(e).context("could not rename the file"),
+    }
+}
+
+/// Rename `from` to `to` unless there is a file at `to`, which may appear at any moment, so it is
+/// checked by the rename itself. Fails with `AlreadyExists` then.
+fn rename_noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let from_c = CString::new(from.as_os_str().as_bytes())?;
+    let to_c = CString::new(to.as_os_str().as_bytes())?;
+    // SAFETY: both paths are NUL-terminated strings which outlive the call
+    let result = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from_c.as_ptr(),
+            libc::AT_FDCWD,
+            to_c.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if result == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    // Linux before 3.15, or a file system which cannot rename without replacing
+    if !matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EINVAL)) {
+        return Err(e);
+    }
+    // Linking fails as well if there is a file already
+    std::fs::hard_link(from, to)?;
+    std::fs::remove_file(from)
+}
+
+fn copy_file(source: &Path, target: &Path) -> Result<()> {
+    let mut source = File::open(source).context("could not read the file")?;
+    let metadata = source.metadata().context("could not read the file")?;
+    let mut file = File::options()
+        .write(true)
+        .create_new(true)
+        .open(target)
+        .with_context(|| format!("could not create {}", target.display()))?;
+    let result =
+        io::copy(&mut source, &mut file).and_then(|_| file.set_permissions(metadata.permissions()));
+    if let Err(e) = result {
+        // Not half of it
+        let _ = std::fs::remove_file(target);
+        return Err(e).context("could not copy the file");
+    }
+    if let Ok(modified) = metadata.modified() {
+        let _ = file.set_modified(modified);
+    }
+    Ok(())
+}
+
+/// Run `command` with the shell in the background. It gets the path of the current image in
+/// `$REIMV_FILE`.
+pub fn run_hook(command: &str, current: &str) -> Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("REIMV_FILE", current)
+        .spawn()
+        .context("could not run the end hook")?;
+    // Reap it once it exits
+    std::thread::spawn(move || child.wait());
+    Ok(())
+}
@@ -0,0 +1,140 @@
+//! Detecting the format of an image file, by content first and by extension second.
+
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use flate2::read::GzDecoder;
+use image::ImageFormat;
+
+use crate::{djvu, jpeg2000, pdf, psd, raw, video, xbm, xpm};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Svg,
+    Raw,
+    Psd,
+    Djvu,
+    Pdf,
+    Jpeg2000,
+    Video,
+    Xbm,
+    Xpm,
+    Raster(ImageFormat),
+}
+
+/// Read the file at `path`, where `-` stands for stdin.
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    // Stdin can only be read once, but we may need to load the image again after reconnecting
+    static STDIN: OnceLock<Vec<u8>> = OnceLock::new();
+    if path != Path::new("-") {
+        return std::fs::read(path);
+    }
+    if let Some(data) = STDIN.get() {
+        return Ok(data.clone());
+    }
+    let mut data = Vec::new();
+    io::stdin().lock().read_to_end(&mut data)?;
+    Ok(STDIN.get_or_init(|| data).clone())
+}
+
+pub fn detect(path: &Path, data: &[u8]) -> Option<Format> {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    let raw_ext = ext.as_deref().is_some_and(raw::is_raw_extension);
+
+    if is_svg(data) || is_svgz(data) {
+        return Some(Format::Svg);
+    }
+    if psd::is_psd(data) {
+        return Some(Format::Psd);
+    }
+    if raw::is_raw(data) {
+        return Some(Format::Raw);
+    }
+    if djvu::is_djvu(data) {
+        return Some(Format::Djvu);
+    }
+    if pdf::is_pdf(data) {
+        return Some(Format::Pdf);
+    }
+    if jpeg2000::is_jpeg2000(data) {
+        return Some(Format::Jpeg2000);
+    }
+    if video::is_video(data) {
+        return Some(Format::Video);
+    }
+    if xpm::is_xpm(data) {
+        return Some(Format::Xpm);
+    }
+    if xbm::is_xbm(data) {
+        return Some(Format::Xbm);
+    }
+    match image::guess_format(data) {
+        // Many RAW formats are indistinguishable from plain TIFF files
+        Ok(ImageFormat::Tiff) if raw_ext => return Some(Format::Raw),
+        Ok(format) => return Some(Format::Raster(format)),
+        Err(_) => (),
+    }
+
+    // Formats without a signature, such as TGA, and broken compressed SVG
+    match ext.as_deref() {
+        Some("svg" | "svgz") => Some(Format::Svg),
+        Some("xbm") => Some(Format::Xbm),
+        Some("xpm") => Some(Format::Xpm),
+        _ if raw_ext => Some(Format::Raw),
+        _ => ImageFormat::from_path(path).ok().map(Format::Raster),
+    }
+}
+
+/// Whether `path` has the extension of an image format, for picking out the images from the
+/// files of a directory or an archive.
+pub fn has_image_extension(path: &Path) -> bool {
+    let Some(ext) = path.extension().and_then(|ext| ext.to_str()) else {
+        return false;
+    };
+    let ext = ext.to_ascii_lowercase();
+    matches!(
+        ext.as_str(),
+        "svg"
+            | "svgz"
+            | "psd"
+            | "djvu"
+            | "djv"
+            | "pdf"
+            | "jp2"
+            | "j2k"
+            | "j2c"
+            | "jpc"
+            | "mp4"
+            | "m4v"
+            | "webm"
+            | "xbm"
+            | "xpm"
+    ) || raw::is_raw_extension(&ext)
+        || ImageFormat::from_extension(&ext).is_some()
+}
+
+/// Look for an `<svg` element near the start of an XML document.
+fn is_svg(data: &[u8]) -> bool {
+    let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+    let head = &data[..data.len().min(4096)];
+    let text = String::from_utf8_lossy(head);
+    let text = text.trim_start();
+    (text.starts_with("<?xml") || text.starts_with("<!") || text.starts_with("<svg"))
+        && text.contains("<svg")
+}
+
+/// Look for an SVG document in gzip-compressed data, so that SVGZ files are found without their
+/// extension, e.g. on stdin.
+fn is_svgz(data: &[u8]) -> bool {
+    if !data.starts_with(&[0x1f, 0x8b]) {
+        return false;
+    }
+    let mut head = Vec::new();
+    // Whatever could be decompressed before an error is enough
+    let _ = GzDecoder::new(data).take(4096).read_to_end(&mut head);
+    is_svg(&head)
+}
@@ -0,0 +1,148 @@
+//! Scheduling of frames.
+
+use wayrs_client::protocol::*;
+use wayrs_client::Connection;
+
+use crate::EventCtx;
+use crate::State;
+
+/// Coalesces redraw requests so that at most one frame is drawn per frame callback.
+///
+/// Requests only mark a frame as wanted. The event loop draws it after it has handled everything
+/// that woke it up, like key repeats, pointer motion, gesture updates and IPC commands, so that
+/// all changes which arrive together make a single frame even when no callback is pending.
+///
+/// The image and the overlay are sub-surfaces in synchronized mode, so their pending state is
+/// only applied together with the next commit of the main surface. [`Self::present`] is the only
+/// place where surfaces are committed, which makes every frame a single atomic update of all
+/// layers.
+#[derive(Default)]
+pub struct FrameScheduler {
+    /// A frame has been presented and its callback has not arrived yet
+    waiting: bool,
+    /// A frame has been requested and not drawn yet
+    wanted: bool,
+    /// Close the window when the callback of the current frame arrives
+    pub close_after: bool,
+}
+
+impl FrameScheduler {
+    /// Ask for a frame, see [`Self::due`].
+    pub fn request(&mut self) {
+        self.wanted = true;
+    }
+
+    /// Returns `true` if a frame has been requested and may be drawn right now. Otherwise it
+    /// stays requested until the compositor asks for the next one.
+    pub fn due(&mut self) -> bool {
+        !self.waiting && std::mem::take(&mut self.wanted)
+    }
+
+    /// Note that a frame has been committed, so the next one waits for its callback.
+    fn presented(&mut self) {
+        self.waiting = true;
+    }
+
+    /// Note that the compositor is ready for the next frame.
+    fn done(&mut self) {
+        self.waiting = false;
+    }
+
+    /// Commit the `layers` and then the `main` surface, and ask for a frame callback.
+    pub fn present(&mut self, conn: &mut Connection<State>, main: WlSurface, layers: &[WlSurface]) {
+        for layer in layers {
+            layer.commit(conn);
+        }
+        self.presented();
+        main.frame_with_cb(conn, |ctx: EventCtx<WlCallback>| {
+            let frames = &mut ctx.state.window.frames;
+            frames.done();
+            if frames.close_after {
+                ctx.state.window.closed = true;
+                ctx.conn.break_dispatch_loop();
+            }
+        });
+        main.commit(conn);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::animation::KeyRepeat;
+
+    #[test]
+    fn one_frame_per_callback() {
+        let mut frames = FrameScheduler::default();
+        let mut drawn = 0;
+        for round in 0..100 {
+            // A flood of input, which asks for several frames in each round of the event loop
+            for _ in 0..10 {
+                frames.request();
+            }
+            if frames.due() {
+                drawn += 1;
+                frames.presented();
+            }
+            assert!(!frames.due());
+            // The compositor is ready for the next frame every ten rounds
+            if round % 10 == 9 {
+                frames.done();
+            }
+        }
+        assert_eq!(drawn, 10);
+    }
+
+    /// Rounds of the event loop, with everything that asks for frames through `Window::frame`
+    /// in the order `event_loop` handles it: IPC commands, the key repeat timer, and then the
+    /// Wayland events, where the frame callback can arrive between pointer motion and pinch
+    /// updates. The frame is drawn at the end of the round.
+    #[test]
+    fn flood_from_every_source() {
+        let mut frames = FrameScheduler::default();
+        let mut repeat = KeyRepeat::new(Duration::ZERO, Duration::ZERO);
+        let (mut drawn, mut callbacks) = (0, 0);
+        for round in 0..100 {
+            // `view set` commands of a script
+            for _ in 0..3 {
+                frames.request();
+            }
+            // A held arrow key
+            assert!(repeat.tick());
+            frames.request();
+            // Pointer motion while dragging, then pinch updates
+            for _ in 0..20 {
+                frames.request();
+            }
+            let callback = round % 4 == 3;
+            if callback {
+                frames.done();
+                callbacks += 1;
+            }
+            for _ in 0..5 {
+                frames.request();
+            }
+
+            let due = frames.due();
+            // The first frame, and then one right after each callback
+            assert_eq!(due, round == 0 || callback);
+            if due {
+                drawn += 1;
+                frames.presented();
+            }
+            assert!(!frames.due());
+        }
+        assert_eq!(drawn, callbacks + 1);
+    }
+
+    #[test]
+    fn nothing_to_draw_without_a_request() {
+        let mut frames = FrameScheduler::default();
+        assert!(!frames.due());
+        frames.request();
+        assert!(frames.due());
+        assert!(!frames.due());
+    }
+}
@@ -0,0 +1,548 @@
+//! The frames of GIF and APNG animations.
+//!
+//! A frame usually covers only the part of the image which changes, and says what becomes of that
+//! part before the next frame is drawn: whether it stays, is cleared or goes back to what was
+//! there before. The `image` crate only hands out whole frames, so to decode frame N we hand it a
+//! copy of the file which contains only frame N, the size of its part, like [`crate::pages`] does
+//! for TIFF pages, and draw it onto the image ourselves with a [`Composer`].
+
+use std::ops::Range;
+use std::time::Duration;
+
+use anyhow::{ensure, Context, Result};
+use image::{ImageFormat, Rgba, RgbaImage};
+
+use crate::color;
+use crate::isolate;
+use crate::limits::Limits;
+
+/// Frames with shorter delays are shown for [`DEFAULT_DELAY`], like browsers do, since such
+/// files usually expect that.
+const MIN_DELAY: Duration = Duration::from_millis(20);
+const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// The frames of an animation, which are decoded one at a time.
+pub struct Frames {
+    data: Vec<u8>,
+    kind: Kind,
+    width: u32,
+    height: u32,
+    frames: Vec<FrameInfo>,
+    /// How often the animation is played, or `None` if it loops forever
+    plays: Option<u32>,
+    limits: Limits,
+    color_management: bool,
+}
+
+enum Kind {
+    /// With the header, the screen descriptor and the global color table
+    Gif { header: Range<usize> },
+    /// With the data of the IHDR chunk, and the chunks before the image data which every frame
+    /// shares, like the palette and the color profile
+    Png {
+        ihdr: Range<usize>,
+        shared: Vec<Range<usize>>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct FrameInfo {
+    /// The part of the image the frame covers
+    pub rect: Rect,
+    /// How long the frame is shown
+    pub delay: Duration,
+    pub dispose: Dispose,
+    /// Whether the frame is blended over the image, rather than replacing its part
+    pub blend: bool,
+    /// Where the frame is in the file: the graphic control extension and the image of a GIF
+    /// frame, or the image data of an APNG frame
+    parts: Vec<Range<usize>>,
+}
+
+/// What becomes of the part of a frame before the next one is drawn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dispose {
+    Keep,
+    /// Cleared to transparent
+    Clear,
+    /// Put back the way it was before the frame was drawn
+    Restore,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// The smallest rectangle containing both.
+    pub fn union(self, other: Self) -> Self {
+        if self.is_empty() {
+            return other;
+        }
+        if other.is_empty() {
+            return self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Self {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+
+    /// The part within an image of `width` × `height`.
+    fn clip(self, width: u32, height: u32) -> Self {
+        let x = self.x.min(width);
+        let y = self.y.min(height);
+        Self {
+            x,
+            y,
+            width: self.width.min(width - x),
+            height: self.height.min(height - y),
+        }
+    }
+}
+
+impl Frames {
+    /// Returns `None` if this is not a GIF file with several frames, or if the image is too
+    /// large.
+    pub fn gif(data: &[u8], limits: Limits) -> Option<Self> {
+        Self::new(data, parse_gif(data)?, limits, false)
+    }
+
+    /// Returns `None` if this is not an animated PNG file with several frames, or if the image
+    /// is too large.
+    pub fn apng(data: &[u8], limits: Limits, color_management: bool) -> Option<Self> {
+        Self::new(data, parse_apng(data)?, limits, color_management)
+    }
+
+    fn new(
+        data: &[u8],
+        structure: Structure,
+        limits: Limits,
+        color_management: bool,
+    ) -> Option<Self> {
+        limits.check(structure.width, structure.height).ok()?;
+        Some(Self {
+            data: data.to_vec(),
+            kind: structure.kind,
+            width: structure.width,
+            height: structure.height,
+            frames: structure.frames,
+            plays: structure.plays,
+            limits,
+            color_management,
+        })
+    }
+
+    pub fn count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The size of the image the frames are drawn onto.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn info(&self, index: usize) -> &FrameInfo {
+        &self.frames[index]
+    }
+
+    /// How often the animation is played, or `None` if it loops forever.
+    pub fn plays(&self) -> Option<u32> {
+        self.plays
+    }
+
+    /// The image after the first frame.
+    pub fn first(&self) -> Result<RgbaImage> {
+        let mut image = RgbaImage::new(self.width, self.height);
+        Composer::default().draw(&mut image, self.info(0), &self.decode(0)?);
+        Ok(image)
+    }
+
+    /// Decode the pixels of the part of the image a frame covers.
+    pub fn decode(&self, index: usize) -> Result<RgbaImage> {
+        let rect = self.frames[index].rect;
+        let (copy, format) = self.copy_of(index);
+        let image = isolate::run(&self.limits, || {
+            let image = self.limits.decode(&copy, format)?;
+            // The profile of an APNG file is in the copy as well
+            Ok(match self.color_management {
+                true => color::to_srgb(&copy, image),
+                false => image,
+            })
+        })
+        .with_context(|| format!("could not decode frame {}", index + 1))?
+        .into_rgba8();
+        ensure!(
+            image.dimensions() == (rect.width, rect.height),
+            "frame {} has the wrong size",
+            index + 1
+        );
+        Ok(image)
+    }
+
+    /// A file with only one frame, the size of its part.
+    fn copy_of(&self, index: usize) -> (Vec<u8>, ImageFormat) {
+        let frame = &self.frames[index];
+        let (width, height) = (frame.rect.width, frame.rect.height);
+        match &self.kind {
+            Kind::Gif { header } => {
+                let mut copy = self.data[header.clone()].to_vec();
+                copy[6..8].copy_from_slice(&(width as u16).to_le_bytes());
+                copy[8..10].copy_from_slice(&(height as u16).to_le_bytes());
+                for part in &frame.parts {
+                    copy.extend_from_slice(&self.data[part.clone()]);
+                }
+                // The image descriptor comes last, and its position becomes the top left corner
+                let descriptor = copy.len() - frame.parts.last().unwrap().len();
+                copy[descriptor + 1..descriptor + 5].fill(0);
+                copy.push(0x3B);
+                (copy, ImageFormat::Gif)
+            }
+            Kind::Png { ihdr, shared } => {
+                let mut copy = PNG_SIGNATURE.to_vec();
+                let mut header = self.data[ihdr.clone()].to_vec();
+                header[0..4].copy_from_slice(&width.to_be_bytes());
+                header[4..8].copy_from_slice(&height.to_be_bytes());
+                push_chunk(&mut copy, b"IHDR", &header);
+                for chunk in shared {
+                    copy.extend_from_slice(&self.data[chunk.clone()]);
+                }
+                for part in &frame.parts {
+                    push_chunk(&mut copy, b"IDAT", &self.data[part.clone()]);
+                }
+                push_chunk(&mut copy, b"IEND", &[]);
+                (copy, ImageFormat::Png)
+            }
+        }
+    }
+}
+
+/// Draws the frames of an animation onto an image, one after the other.
+#[derive(Default)]
+pub struct Composer {
+    /// The part of the last frame, and what becomes of it before the next one
+    last: Option<(Rect, Dispose)>,
+    /// The pixels under the last frame, if they are put back
+    saved: Option<RgbaImage>,
+}
+
+impl Composer {
+    /// Go on from an image of `width` by `height` pixels on which only the first frame, `info`,
+    /// has been drawn.
+    pub fn after_first(info: &FrameInfo, width: u32, height: u32) -> Self {
+        let rect = info.rect.clip(width, height);
+        Self {
+            last: Some((rect, info.dispose)),
+            saved: (info.dispose == Dispose::Restore)
+                .then(|| RgbaImage::new(rect.width, rect.height)),
+        }
+    }
+
+    /// Draw a frame onto `image`, after disposing of the last one. Returns the part of the image
+    /// which has changed.
+    pub fn draw(&mut self, image: &mut RgbaImage, info: &FrameInfo, pixels: &RgbaImage) -> Rect {
+        let mut changed = Rect::default();
+        match (self.last.take(), self.saved.take()) {
+            (Some((rect, Dispose::Clear)), _) => {
+                put(image, rect, &RgbaImage::new(rect.width, rect.height), false);
+                changed = rect;
+            }
+            (Some((rect, Dispose::Restore)), Some(saved)) => {
+                put(image, rect, &saved, false);
+                changed = rect;
+            }
+            _ => (),
+        }
+        let rect = info.rect.clip(image.width(), image.height());
+        if info.dispose == Dispose::Restore {
+            let saved = image::imageops::crop_imm(image, rect.x, rect.y, rect.width, rect.height);
+            self.saved = Some(saved.to_image());
+        }
+        put(image, rect, pixels, info.blend);
+        self.last = Some((rect, info.dispose));
+        changed.union(rect)
+    }
+
+    /// Clear `image` to start the animation over. Returns the part of the image which has
+    /// changed.
+    pub fn clear(&mut self, image: &mut RgbaImage) -> Rect {
+        *self = Self::default();
+        image.fill(0);
+        Rect {
+            x: 0,
+            y: 0,
+            width: image.width(),
+            height: image.height(),
+        }
+    }
+}
+
+/// Draw `pixels` onto the part `rect` of `image`, blended over it or replacing it.
+fn put(image: &mut RgbaImage, rect: Rect, pixels: &RgbaImage, blend: bool) {
+    for y in 0..rect.height {
+        for x in 0..rect.width {
+            let src = *pixels.get_pixel(x, y);
+            let dst = image.get_pixel_mut(rect.x + x, rect.y + y);
+            *dst = match blend {
+                true => over(src, *dst),
+                false => src,
+            };
+        }
+    }
+}
+
+/// Blend `src` over `dst`, both with straight alpha.
+fn over(src: Rgba<u8>, dst: Rgba<u8>) -> Rgba<u8> {
+    let src_alpha = src[3] as u32;
+    match src_alpha {
+        0 => return dst,
+        255 => return src,
+        _ => (),
+    }
+    let dst_alpha = dst[3] as u32 * (255 - src_alpha) / 255;
+    let alpha = src_alpha + dst_alpha;
+    let mix = |i: usize| {
+        ((src[i] as u32 * src_alpha + dst[i] as u32 * dst_alpha + alpha / 2) / alpha) as u8
+    };
+    Rgba([mix(0), mix(1), mix(2), alpha as u8])
+}
+
+/// The delay of a frame, with short ones made longer.
+fn delay(delay: Duration) -> Duration {
+    match delay < MIN_DELAY {
+        true => DEFAULT_DELAY,
+        false => delay,
+    }
+}
+
+/// What the blocks or chunks of a file say about its frames.
+struct Structure {
+    kind: Kind,
+    width: u32,
+    height: u32,
+    frames: Vec<FrameInfo>,
+    plays: Option<u32>,
+}
+
+/// The size, frames, plays and header of a GIF file, read from its blocks without decoding
+/// them.
+fn parse_gif(data: &[u8]) -> Option<Structure> {
+    // Skip the sub-blocks of an extension or of image data, returning the position after them
+    let skip_sub_blocks = |mut pos: usize| -> Option<usize> {
+        loop {
+            let len = *data.get(pos)? as usize;
+            pos += 1 + len;
+            if len == 0 {
+                return Some(pos);
+            }
+        }
+    };
+    let color_table_len = |flags: u8| match flags & 0x80 {
+        0 => 0,
+        _ => 3 << ((flags & 7) + 1),
+    };
+    let u16_at = |pos: usize| Some(u16::from_le_bytes(data.get(pos..pos + 2)?.try_into().ok()?));
+
+    if !data.starts_with(b"GIF") {
+        return None;
+    }
+    let width = u16_at(6)? as u32;
+    let height = u16_at(8)? as u32;
+    let header = 0..13 + color_table_len(*data.get(10)?);
+    let mut pos = header.end;
+    let mut frames = Vec::new();
+    let mut control = None;
+    // Without a loop count, the animation is played once
+    let mut plays = Some(1);
+    loop {
+        let start = pos;
+        match data.get(pos) {
+            Some(0x21) => {
+                let label = *data.get(pos + 1)?;
+                pos = skip_sub_blocks(pos + 2)?;
+                let block = data.get(start..pos)?;
+                match label {
+                    0xF9 if block.len() >= 8 && block[2] == 4 => control = Some(start..pos),
+                    0xFF if block.len() >= 19
+                        && block[2] == 11
+                        && matches!(&block[3..14], b"NETSCAPE2.0" | b"ANIMEXTS1.0")
+                        && block[14] == 3
+                        && block[15] == 1 =>
+                    {
+                        // The number of times it is repeated after the first play
+                        plays = match u16::from_le_bytes([block[16], block[17]]) {
+                            0 => None,
+                            repeats => Some(repeats as u32 + 1),
+                        };
+                    }
+                    _ => (),
+                }
+            }
+            Some(0x2C) => {
+                let rect = Rect {
+                    x: u16_at(pos + 1)? as u32,
+                    y: u16_at(pos + 3)? as u32,
+                    width: u16_at(pos + 5)? as u32,
+                    height: u16_at(pos + 7)? as u32,
+                };
+                let flags = *data.get(pos + 9)?;
+                // The local color table and the LZW code size come before the image data
+                let Some(end) = skip_sub_blocks(pos + 10 + color_table_len(flags) + 1) else {
+                    break;
+                };
+                pos = end;
+                let control = control.take();
+                let (dispose, centiseconds) = match &control {
+                    Some(control) => {
+                        let block = &data[control.clone()];
+                        let dispose = match (block[3] >> 2) & 7 {
+                            2 => Dispose::Clear,
+                            3 => Dispose::Restore,
+                            _ => Dispose::Keep,
+                        };
+                        (dispose, u16::from_le_bytes([block[4], block[5]]))
+                    }
+                    None => (Dispose::Keep, 0),
+                };
+                if rect.is_empty() {
+                    continue;
+                }
+                frames.push(FrameInfo {
+                    rect,
+                    delay: delay(Duration::from_millis(centiseconds as u64 * 10)),
+                    dispose,
+                    // Transparent pixels let the image show through, and the others are opaque
+                    blend: true,
+                    parts: control.into_iter().chain(Some(start..end)).collect(),
+                });
+            }
+            _ => break,
+        }
+    }
+    (frames.len() >= 2).then_some(Structure {
+        kind: Kind::Gif { header },
+        width,
+        height,
+        frames,
+        plays,
+    })
+}
+
+/// The size, frames, plays, IHDR data and shared chunks of an APNG file, read from its chunks
+/// without decoding them.
+fn parse_apng(data: &[u8]) -> Option<Structure> {
+    let u32_at = |pos: usize| Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?));
+    let u16_at = |pos: usize| Some(u16::from_be_bytes(data.get(pos..pos + 2)?.try_into().ok()?));
+
+    if !data.starts_with(PNG_SIGNATURE) {
+        return None;
+    }
+    let mut pos = PNG_SIGNATURE.len();
+    let mut ihdr = None;
+    let mut animated = false;
+    let mut plays = None;
+    let mut shared = Vec::new();
+    let mut frames: Vec<FrameInfo> = Vec::new();
+    let mut image_data = false;
+    while let Some(len) = u32_at(pos) {
+        let body = pos + 8..(pos + 8).checked_add(len as usize)?;
+        let end = body.end + 4;
+        if end > data.len() {
+            break;
+        }
+        match &data[pos + 4..pos + 8] {
+            b"IHDR" if len >= 13 => ihdr = Some(body),
+            b"acTL" if len >= 8 => {
+                animated = true;
+                plays = match u32_at(body.start + 4)? {
+                    0 => None,
+                    plays => Some(plays),
+                };
+            }
+            b"fcTL" if len >= 26 => {
+                let at = body.start;
+                let rect = Rect {
+                    width: u32_at(at + 4)?,
+                    height: u32_at(at + 8)?,
+                    x: u32_at(at + 12)?,
+                    y: u32_at(at + 16)?,
+                };
+                let (numerator, denominator) = (u16_at(at + 20)?, u16_at(at + 22)?);
+                let denominator = match denominator {
+                    0 => 100,
+                    d => d,
+                };
+                let dispose = match data[at + 24] {
+                    1 => Dispose::Clear,
+                    // The first frame has nothing under it to put back
+                    2 if !frames.is_empty() => Dispose::Restore,
+                    2 => Dispose::Clear,
+                    _ => Dispose::Keep,
+                };
+                frames.push(FrameInfo {
+                    rect,
+                    delay: delay(Duration::from_secs_f64(
+                        numerator as f64 / denominator as f64,
+                    )),
+                    dispose,
+                    blend: data[at + 25] == 1,
+                    parts: Vec::new(),
+                });
+            }
+            b"IDAT" => {
+                image_data = true;
+                // Without a frame control chunk before it, the default image is not part of
+                // the animation
+                if let [frame] = &mut frames[..] {
+                    frame.parts.push(body);
+                }
+            }
+            b"fdAT" if len > 4 => {
+                if let Some(frame) = frames.last_mut() {
+                    frame.parts.push(body.start + 4..body.end);
+                }
+            }
+            b"IEND" => break,
+            b"fcTL" | b"fdAT" | b"acTL" | b"IHDR" => (),
+            _ if !image_data => shared.push(pos..end),
+            _ => (),
+        }
+        pos = end;
+    }
+    let ihdr = ihdr?;
+    let width = u32_at(ihdr.start)?;
+    let height = u32_at(ihdr.start + 4)?;
+    frames.retain(|frame| !frame.rect.is_empty() && !frame.parts.is_empty());
+    (animated && frames.len() >= 2).then_some(Structure {
+        kind: Kind::Png { ihdr, shared },
+        width,
+        height,
+        frames,
+        plays,
+    })
+}
+
+/// Append a PNG chunk with its checksum.
+fn push_chunk(png: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    png.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(body);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(body);
+    png.extend_from_slice(&crc.finalize().to_be_bytes());
+}
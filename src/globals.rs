@@ -1,40 +1,95 @@
+use std::ffi::CStr;
+
 use wayrs_client::global::{BindError, Global, GlobalsExt};
+use wayrs_client::interface::Interface;
 use wayrs_client::protocol::*;
+use wayrs_client::proxy::Proxy;
 use wayrs_client::{Connection, EventCtx};
 use wayrs_protocols::fractional_scale_v1::*;
 use wayrs_protocols::pointer_gestures_unstable_v1::*;
 use wayrs_protocols::single_pixel_buffer_v1::*;
 use wayrs_protocols::viewporter::*;
+use wayrs_protocols::wlr_output_power_management_unstable_v1::*;
 use wayrs_protocols::xdg_decoration_unstable_v1::*;
 use wayrs_protocols::xdg_shell::*;
 
+use crate::hdr_output;
+use crate::protocols::color_management_v1::*;
+use crate::shm;
+use crate::State;
+
 pub struct Globals {
     pub wl_compositor: WlCompositor,
     pub wl_subcompositor: WlSubcompositor,
+    pub wl_shm: WlShm,
     pub xdg_wm_base: XdgWmBase,
     pub wp_viewporter: WpViewporter,
     pub single_pixel_buffer_manager: WpSinglePixelBufferManagerV1,
     pub wp_fractional_scale_manager: Option<WpFractionalScaleManagerV1>,
     pub xdg_decoration_manager: Option<ZxdgDecorationManagerV1>,
     pub pointer_gestures: Option<ZwpPointerGesturesV1>,
+    pub wp_color_manager: Option<WpColorManagerV1>,
+    pub output_power_manager: Option<ZwlrOutputPowerManagerV1>,
 }
 
 impl Globals {
-    pub fn bind<D: 'static>(
-        conn: &mut Connection<D>,
-        globals: &[Global],
-    ) -> Result<Self, BindError> {
+    pub fn bind(conn: &mut Connection<State>, globals: &[Global]) -> Result<Self, BindError> {
         Ok(Self {
             wl_compositor: globals.bind(conn, 1..=5)?,
             wl_subcompositor: globals.bind(conn, 1..=1)?,
+            wl_shm: globals.bind_with_cb(conn, 1..=1, shm::wl_shm_cb)?,
             xdg_wm_base: globals.bind_with_cb(conn, 1..=5, xdg_wm_base_cb)?,
             wp_viewporter: globals.bind(conn, 1..=1)?,
             single_pixel_buffer_manager: globals.bind(conn, 1..=1)?,
             wp_fractional_scale_manager: globals.bind(conn, 1..=1).ok(),
             xdg_decoration_manager: globals.bind(conn, 1..=1).ok(),
             pointer_gestures: globals.bind(conn, 1..=3).ok(),
+            wp_color_manager: globals
+                .bind_with_cb(conn, 1..=1, hdr_output::color_manager_cb)
+                .ok(),
+            output_power_manager: globals.bind(conn, 1..=1).ok(),
         })
     }
+
+    /// Interface names and versions of the bound globals.
+    pub fn versions(&self) -> Vec<(&'static CStr, u32)> {
+        fn entry<P: Proxy>(proxy: &P) -> (&'static CStr, u32) {
+            (P::INTERFACE.name, proxy.version())
+        }
+        let mut versions = vec![
+            entry(&self.wl_compositor),
+            entry(&self.wl_subcompositor),
+            entry(&self.wl_shm),
+            entry(&self.xdg_wm_base),
+            entry(&self.wp_viewporter),
+            entry(&self.single_pixel_buffer_manager),
+        ];
+        versions.extend(self.wp_fractional_scale_manager.as_ref().map(entry));
+        versions.extend(self.xdg_decoration_manager.as_ref().map(entry));
+        versions.extend(self.pointer_gestures.as_ref().map(entry));
+        versions.extend(self.wp_color_manager.as_ref().map(entry));
+        versions.extend(self.output_power_manager.as_ref().map(entry));
+        versions
+    }
+}
+
+/// The interfaces of all globals which reimv binds, including seats and outputs.
+pub fn interfaces() -> [&'static Interface; 13] {
+    [
+        WlCompositor::INTERFACE,
+        WlSubcompositor::INTERFACE,
+        WlShm::INTERFACE,
+        XdgWmBase::INTERFACE,
+        WpViewporter::INTERFACE,
+        WpSinglePixelBufferManagerV1::INTERFACE,
+        WpFractionalScaleManagerV1::INTERFACE,
+        ZxdgDecorationManagerV1::INTERFACE,
+        ZwpPointerGesturesV1::INTERFACE,
+        WpColorManagerV1::INTERFACE,
+        ZwlrOutputPowerManagerV1::INTERFACE,
+        WlSeat::INTERFACE,
+        WlOutput::INTERFACE,
+    ]
 }
 
 fn xdg_wm_base_cb<D>(ctx: EventCtx<D, XdgWmBase>) {
@@ -0,0 +1,77 @@
+//! Horizontal and vertical guides, dragged out of the rulers.
+
+use crate::image::ImageTransform;
+use crate::overlay::RULER_SIZE;
+
+/// How close to a guide, in surface local coordinates, the pointer has to be to grab it
+const GRAB_DISTANCE: f32 = 3.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    Horizontal,
+    Vertical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guide {
+    pub orientation: Orientation,
+    /// The image pixel boundary the guide is snapped to
+    pub pos: i32,
+}
+
+impl Guide {
+    /// Position of the guide in surface local coordinates.
+    pub fn surface_pos(&self, transform: &ImageTransform) -> f32 {
+        match self.orientation {
+            Orientation::Horizontal => transform.y + self.pos as f32 * transform.scale,
+            Orientation::Vertical => transform.x + self.pos as f32 * transform.scale,
+        }
+    }
+
+    /// Snap the guide to the pixel boundary closest to the pointer.
+    pub fn move_to(&mut self, transform: &ImageTransform, x: f32, y: f32) {
+        self.pos = match self.orientation {
+            Orientation::Horizontal => ((y - transform.y) / transform.scale).round() as i32,
+            Orientation::Vertical => ((x - transform.x) / transform.scale).round() as i32,
+        };
+    }
+
+    /// Whether the pointer is over the ruler this guide was dragged from. Guides dropped there
+    /// are removed.
+    pub fn over_ruler(&self, x: f32, y: f32) -> bool {
+        match self.orientation {
+            Orientation::Horizontal => y < RULER_SIZE,
+            Orientation::Vertical => x < RULER_SIZE,
+        }
+    }
+}
+
+/// Find the guide to drag when the pointer is pressed at `(x, y)`: either an existing guide
+/// under the pointer or a new one, if the pointer is over a ruler. Returns the index of the guide.
+pub fn grab(guides: &mut Vec<Guide>, transform: &ImageTransform, x: f32, y: f32) -> Option<usize> {
+    let new = |orientation| {
+        let mut guide = Guide {
+            orientation,
+            pos: 0,
+        };
+        guide.move_to(transform, x, y);
+        guide
+    };
+
+    if y < RULER_SIZE && x >= RULER_SIZE {
+        guides.push(new(Orientation::Horizontal));
+        return Some(guides.len() - 1);
+    }
+    if x < RULER_SIZE && y >= RULER_SIZE {
+        guides.push(new(Orientation::Vertical));
+        return Some(guides.len() - 1);
+    }
+
+    guides.iter().position(|g| {
+        let pointer = match g.orientation {
+            Orientation::Horizontal => y,
+            Orientation::Vertical => x,
+        };
+        (g.surface_pos(transform) - pointer).abs() <= GRAB_DISTANCE
+    })
+}
@@ -0,0 +1,93 @@
+//! Tone mapping of high dynamic range images.
+
+use clap::ValueEnum;
+use image::{Rgba32FImage, RgbaImage};
+
+/// How scene-linear values are mapped to the displayable range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ToneMapping {
+    /// Clip values above 1.0
+    Clamp,
+    /// Simple Reinhard operator, x / (1 + x)
+    #[default]
+    Reinhard,
+    /// Krzysztof Narkowicz's fit of the ACES filmic curve
+    Aces,
+}
+
+/// A floating point image, kept around so that it can be re-mapped when the exposure changes.
+pub struct HdrImage {
+    pixels: Rgba32FImage,
+    tone_mapping: ToneMapping,
+    /// In stops
+    exposure: f32,
+}
+
+impl HdrImage {
+    pub fn new(pixels: Rgba32FImage, tone_mapping: ToneMapping) -> Self {
+        Self {
+            pixels,
+            tone_mapping,
+            exposure: 0.0,
+        }
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.pixels.dimensions()
+    }
+
+    pub fn adjust_exposure(&mut self, stops: f32) {
+        self.exposure += stops;
+    }
+
+    /// The pixels in linear light with the exposure applied, where 1.0 is the reference white.
+    /// Alpha is not premultiplied.
+    pub fn linear(&self) -> impl Iterator<Item = [f32; 4]> + '_ {
+        let gain = self.exposure.exp2();
+        self.pixels.pixels().map(move |p| {
+            let map = |x: f32| (x * gain).max(0.0);
+            [map(p[0]), map(p[1]), map(p[2]), p[3].clamp(0.0, 1.0)]
+        })
+    }
+
+    /// Produce an 8-bit sRGB image.
+    pub fn tone_map(&self) -> RgbaImage {
+        let gain = self.exposure.exp2();
+        let map = |x: f32| {
+            let x = (x * gain).max(0.0);
+            let y = match self.tone_mapping {
+                ToneMapping::Clamp => x,
+                ToneMapping::Reinhard => x / (1.0 + x),
+                ToneMapping::Aces => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+            };
+            (linear_to_srgb(y.min(1.0)) * 255.0 + 0.5) as u8
+        };
+
+        let mut out = RgbaImage::new(self.pixels.width(), self.pixels.height());
+        for (src, dst) in self.pixels.pixels().zip(out.pixels_mut()) {
+            dst.0 = [
+                map(src[0]),
+                map(src[1]),
+                map(src[2]),
+                (src[3].clamp(0.0, 1.0) * 255.0 + 0.5) as u8,
+            ];
+        }
+        out
+    }
+}
+
+pub fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        x * 12.92
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
@@ -0,0 +1,93 @@
+//! Showing HDR images without tone mapping, on compositors which support the color management
+//! protocol.
+//!
+//! HDR images are uploaded as half floats with an scRGB image description: sRGB primaries and
+//! linear light, where 1.0 is the reference white and larger values are brighter than it. The
+//! compositor maps that to whatever the output can show. This needs both the protocol and the
+//! half float shm format. Otherwise, and for everything else, images are shown as sRGB.
+
+use wayrs_client::protocol::*;
+use wayrs_client::Connection;
+
+use crate::protocols::color_management_v1::*;
+use crate::shm::ShmAlloc;
+use crate::window::Window;
+use crate::{EventCtx, State};
+
+#[derive(Default)]
+pub struct HdrOutput {
+    features: Vec<u32>,
+    transfer_functions: Vec<u32>,
+    primaries: Vec<u32>,
+}
+
+impl HdrOutput {
+    /// Ask the compositor for an scRGB image description, if it supports one.
+    fn describe_scrgb(
+        &self,
+        conn: &mut Connection<State>,
+        manager: WpColorManagerV1,
+        shm: &ShmAlloc,
+    ) {
+        use wp_color_manager_v1::{Feature, Primaries, TransferFunction};
+
+        let has_feature = |f: Feature| self.features.contains(&f.into());
+        // The formats are announced right after wl_shm is bound, which is before the manager
+        if !shm.supports(wl_shm::Format::Abgr16161616f) {
+            return;
+        }
+        if has_feature(Feature::WindowsScrgb) {
+            manager.create_windows_scrgb_with_cb(conn, image_description_cb);
+        } else if has_feature(Feature::Parametric)
+            && self
+                .transfer_functions
+                .contains(&TransferFunction::ExtLinear.into())
+            && self.primaries.contains(&Primaries::Srgb.into())
+        {
+            let creator = manager.create_parametric_creator(conn);
+            creator.set_tf_named(conn, TransferFunction::ExtLinear);
+            creator.set_primaries_named(conn, Primaries::Srgb);
+            creator.create_with_cb(conn, image_description_cb);
+        }
+    }
+}
+
+pub fn color_manager_cb(ctx: EventCtx<WpColorManagerV1>) {
+    let State {
+        hdr_output,
+        shm_alloc,
+        ..
+    } = ctx.state;
+    match ctx.event {
+        wp_color_manager_v1::Event::SupportedFeature(feature) => hdr_output.features.push(feature),
+        wp_color_manager_v1::Event::SupportedTfNamed(tf) => hdr_output.transfer_functions.push(tf),
+        wp_color_manager_v1::Event::SupportedPrimariesNamed(primaries) => {
+            hdr_output.primaries.push(primaries)
+        }
+        wp_color_manager_v1::Event::Done => {
+            hdr_output.describe_scrgb(ctx.conn, ctx.proxy, shm_alloc)
+        }
+        _ => (),
+    }
+}
+
+fn image_description_cb(ctx: EventCtx<WpImageDescriptionV1>) {
+    match ctx.event {
+        wp_image_description_v1::Event::Ready(_) => {
+            let manager = ctx.state.globals.wp_color_manager.unwrap();
+            let State {
+                backend, shm_alloc, ..
+            } = ctx.state;
+            if backend.enable_hdr_output(ctx.conn, shm_alloc, manager, ctx.proxy) {
+                Window::frame(ctx.state);
+            }
+        }
+        wp_image_description_v1::Event::Failed(args) => {
+            eprintln!(
+                "reimv: HDR output is not available: {}",
+                args.msg.to_string_lossy()
+            );
+            ctx.proxy.destroy(ctx.conn);
+        }
+    }
+}
@@ -1,30 +1,202 @@
+use std::io::Write;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
 use std::path::Path;
+use std::sync::mpsc;
+use std::time::Duration;
 
 use wayrs_client::protocol::*;
 use wayrs_client::Connection;
 use wayrs_protocols::viewporter::*;
-use wayrs_utils::shm_alloc::{BufferSpec, ShmAlloc};
 
 use anyhow::{Context, Result};
-use resvg::{tiny_skia, usvg};
-use usvg::fontdb;
+use image::{DynamicImage, RgbaImage};
+use resvg::tiny_skia;
 
+use crate::backend::{
+    upload, upload_deep, upload_hdr, Animated, Clip, Player, Raster, Render, Svg, Target, View,
+};
+use crate::cache;
+use crate::decode::{self, AnimatedSvg, Content, Decoded, Deferred, Rgba16Image};
+use crate::error::DecodeError;
+use crate::files::Entry;
+use crate::format;
+use crate::frames::Frames;
 use crate::globals::Globals;
+use crate::hdr::{HdrImage, ToneMapping};
+use crate::limits::Limits;
+use crate::metadata::{self, Exif};
+use crate::pages::Pages;
+use crate::protocols::color_management_v1::*;
+use crate::pyramid::Pyramid;
+use crate::recent::{self, Recent};
+use crate::shm::ShmAlloc;
+use crate::tiles::{Layer, Tiles};
 use crate::State;
 
+use reimv::video::Video;
+
 pub struct Image {
-    surface: WlSurface,
+    pub surface: WlSurface,
     subsurface: WlSubsurface,
     viewport: WpViewport,
     kind: ImageKind,
+    pending: Option<PendingDecode>,
+    /// The decode at full resolution of an image shown with fewer pixels, until it is zoomed in
+    full: Option<Deferred>,
+    /// Physical resolution, in dots per inch
+    dpi: Option<f32>,
+    /// The source of ImageKind::Raster for HDR formats
+    hdr: Option<HdrImage>,
+    /// The source of ImageKind::Raster for images with more than 8 bits per channel, uploaded as
+    /// they are if the compositor supports 16-bit buffers
+    deep: Option<Rgba16Image>,
+    pages: Option<Pages>,
+    /// The levels of a pyramidal TIFF file, one of which is shown
+    pyramid: Option<Pyramid>,
+    /// The size of the file, or of the image in an archive, in bytes
+    file_size: Option<u64>,
+    exif: Exif,
+    /// Set once the compositor can show HDR images, see [`crate::hdr_output`]
+    hdr_output: Option<(WpColorManagementSurfaceV1, WpImageDescriptionV1)>,
+    /// Show animations at their start only, which is kept for the following images
+    freeze_animations: bool,
+    /// Raster images are the frames of an animation, uploaded at the size they are seen at
+    frames: bool,
+    /// Keep images at the reduced resolution they were decoded at while the system is low on
+    /// memory, which is kept for the following images
+    low_memory: bool,
+    /// The parts of an image too large to decode, read at higher resolutions when zoomed in
+    tiles: Option<Tiles>,
+    layer: Layer,
+    /// The images shown before, which are kept for the following images
+    recent: Recent<Kept>,
+    /// Which image is shown, to keep it by once another one is
+    key: Option<recent::Key>,
+}
+
+/// An image which has been shown, kept to be shown again without decoding it.
+struct Kept {
+    /// A static raster or SVG image
+    kind: ImageKind,
+    full: Option<Deferred>,
+    dpi: Option<f32>,
+    hdr: Option<HdrImage>,
+    deep: Option<Rgba16Image>,
+    pages: Option<Pages>,
+    pyramid: Option<Pyramid>,
+    file_size: Option<u64>,
+    exif: Exif,
+}
+
+impl Kept {
+    /// About how much memory the image takes.
+    fn bytes(&self) -> u64 {
+        let pixels = match &self.kind {
+            ImageKind::Raster(raster) => raster.pixels.len() as u64,
+            _ => 0,
+        };
+        let hdr = self.hdr.as_ref().map_or(0, |hdr| {
+            let (width, height) = hdr.dimensions();
+            width as u64 * height as u64 * 16
+        });
+        let deep = self.deep.as_ref().map_or(0, |deep| deep.len() as u64 * 2);
+        // SVG documents, and the files which pages and levels are decoded from
+        let file = match (&self.kind, &self.pages, &self.pyramid) {
+            (ImageKind::Svg(_), _, _) | (_, Some(_), _) | (_, _, Some(_)) => {
+                self.file_size.unwrap_or(0)
+            }
+            _ => 0,
+        };
+        pixels + hdr + deep + file
+    }
+}
+
+/// A full-quality decode running in a background thread, while a preview or thumbnail is shown.
+struct PendingDecode {
+    result: mpsc::Receiver<Result<RgbaImage>>,
+    /// Becomes readable when the result is ready
+    wakeup: UnixStream,
+    /// What is shown if the decode fails
+    fallback: &'static str,
+}
+
+impl PendingDecode {
+    fn spawn(
+        decode: impl FnOnce() -> Result<RgbaImage> + Send + 'static,
+        fallback: &'static str,
+    ) -> Result<Self> {
+        let (tx, result) = mpsc::channel();
+        let (wakeup, mut wakeup_tx) = UnixStream::pair()?;
+        std::thread::spawn(move || {
+            let _ = tx.send(decode());
+            let _ = wakeup_tx.write_all(&[0]);
+        });
+        Ok(Self {
+            result,
+            wakeup,
+            fallback,
+        })
+    }
 }
 
 enum ImageKind {
-    Svg { tree: usvg::Tree },
-    Image { width: u32, height: u32 },
+    /// Nothing has been loaded
+    Empty,
+    Svg(Svg),
+    Animated(Box<Animated>),
+    Raster(Raster),
+    /// A GIF or APNG animation
+    Player(Box<Player>),
+    Clip(Box<Clip>),
 }
 
+impl ImageKind {
+    fn as_render(&self) -> Option<&dyn Render> {
+        match self {
+            Self::Empty => None,
+            Self::Svg(svg) => Some(svg),
+            Self::Animated(animated) => Some(&**animated),
+            Self::Raster(raster) => Some(raster),
+            Self::Player(player) => Some(&**player),
+            Self::Clip(clip) => Some(&**clip),
+        }
+    }
+
+    fn as_render_mut(&mut self) -> Option<&mut dyn Render> {
+        match self {
+            Self::Empty => None,
+            Self::Svg(svg) => Some(svg),
+            Self::Animated(animated) => Some(&mut **animated),
+            Self::Raster(raster) => Some(raster),
+            Self::Player(player) => Some(&mut **player),
+            Self::Clip(clip) => Some(&mut **clip),
+        }
+    }
+}
+
+/// An image which has been read and decoded, but not shown yet, like one decoded in the
+/// background by [`crate::preload`].
+pub struct Prepared {
+    decoded: Decoded,
+    file_size: u64,
+    exif: Exif,
+}
+
+/// How images are decoded, the same for every image.
 #[derive(Debug, Clone, Copy)]
+pub struct DecodeOptions {
+    pub tone_mapping: ToneMapping,
+    pub limits: Limits,
+    /// Convert images to sRGB from their embedded profile
+    pub color_management: bool,
+    /// Decode raster images with more pixels than this at a reduced resolution first
+    pub max_pixels: Option<u64>,
+    /// Keep large images scaled down in a cache on disk of at most this many bytes
+    pub disk_cache: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct ImageTransform {
     /// Y-offset in surface local coordinates
     pub x: f32,
@@ -34,14 +206,117 @@ pub struct ImageTransform {
     pub scale: f32,
 }
 
+impl ImageTransform {
+    /// Show the point `image_point` of the image, in its own pixels, at `point` in surface local
+    /// coordinates.
+    pub fn showing(image_point: (f32, f32), point: (f32, f32), scale: f32) -> Self {
+        Self {
+            x: point.0 - image_point.0 * scale,
+            y: point.1 - image_point.1 * scale,
+            scale,
+        }
+    }
+
+    /// The point of the image, in its own pixels, which is shown at `(x, y)` in surface local
+    /// coordinates.
+    pub fn image_point(&self, x: f32, y: f32) -> (f32, f32) {
+        ((x - self.x) / self.scale, (y - self.y) / self.scale)
+    }
+
+    /// Set the scale, keeping the point `(x, y)` (in surface local coordinates) in place.
+    pub fn zoom_to(&mut self, x: f32, y: f32, scale: f32) {
+        self.x = x + (self.x - x) * scale / self.scale;
+        self.y = y + (self.y - y) * scale / self.scale;
+        self.scale = scale;
+    }
+}
+
+/// Read and decode the image of `entry`, and store it in the disk cache under `cache_key` if it
+/// can be shown the same from there.
+pub fn prepare(
+    entry: &Entry,
+    options: DecodeOptions,
+    cache_key: Option<cache::Key>,
+) -> Result<Prepared> {
+    let data = entry.read(&options.limits)?;
+    let file_size = data.len() as u64;
+    let exif = metadata::exif(&data);
+    let format = format::detect(Path::new(&entry.name()), &data).context("unknown image format")?;
+    // Archives, web servers and data URIs are not searched for the files an SVG image refers to
+    let resources_dir = match entry {
+        Entry::File(path) => std::fs::canonicalize(path)
+            .ok()
+            .and_then(|p| p.parent().map(Into::into)),
+        Entry::Page { .. } | Entry::Url { .. } | Entry::Data { .. } => None,
+    };
+    let decoded = decode::decode(
+        data,
+        format,
+        resources_dir,
+        options.tone_mapping,
+        options.limits,
+        options.color_management,
+        options.max_pixels,
+    )?;
+    if let (Some(key), Some(max_bytes), Content::Raster(pixels)) =
+        (cache_key, options.disk_cache, &decoded.content)
+    {
+        // Only images which are shown the same from the cache
+        if decoded.deferred.is_none()
+            && decoded.hdr.is_none()
+            && decoded.deep.is_none()
+            && decoded.pages.is_none()
+            && decoded.pyramid.is_none()
+            && decoded.frames.is_none()
+            && decoded.video.is_none()
+        {
+            key.store(pixels, decoded.dpi, &exif, max_bytes);
+        }
+    }
+    Ok(Prepared {
+        decoded,
+        file_size,
+        exif,
+    })
+}
+
+/// The zoom level of `--integer-zoom` closest to `scale`, in device pixels per pixel of the image:
+/// a whole number, or one over a whole number when zoomed out.
+pub fn integer_scale(scale: f32) -> f32 {
+    match scale >= 1.0 {
+        true => scale.round(),
+        false => 1.0 / (1.0 / scale).round(),
+    }
+}
+
+/// The zoom level of `--integer-zoom` after `scale`, further in if `zoom_in`.
+pub fn next_integer_scale(scale: f32, zoom_in: bool) -> f32 {
+    let level = integer_scale(scale);
+    // Between two levels, the closer one may already be the next one
+    match zoom_in {
+        true if level > scale * 1.001 => level,
+        true if level >= 1.0 => level + 1.0,
+        true => 1.0 / (1.0 / level - 1.0),
+        false if level < scale * 0.999 => level,
+        false if level > 1.0 => level - 1.0,
+        false => 1.0 / (1.0 / level + 1.0),
+    }
+}
+
 impl Image {
-    pub fn from_file(
-        path: impl AsRef<Path>,
+    /// Create the surfaces, with nothing shown yet. With `freeze_animations`, animated images
+    /// only show their first frame until [`Self::toggle_animation`]. With `frames`, raster images
+    /// are the frames of an animation, which are scaled down before they are uploaded while
+    /// zoomed out. Static images which have been shown are kept decoded in up to `memory_cache`
+    /// bytes.
+    pub fn new(
         main_surface: WlSurface,
         globals: &Globals,
-        shm: &mut ShmAlloc,
         conn: &mut Connection<State>,
-    ) -> Result<Self> {
+        freeze_animations: bool,
+        frames: bool,
+        memory_cache: u64,
+    ) -> Self {
         let surface = globals.wl_compositor.create_surface(conn);
         let subsurface = globals
             .wl_subcompositor
@@ -52,56 +327,506 @@ impl Image {
         surface.set_input_region(conn, Some(empty_reg));
         empty_reg.destroy(conn);
 
-        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
-            Some("svg") => {
-                let mut opt = usvg::Options::default();
-                opt.resources_dir = std::fs::canonicalize(path.as_ref())
-                    .ok()
-                    .and_then(|p| p.parent().map(Into::into));
-
-                let mut fontdb = fontdb::Database::new();
-                fontdb.load_system_fonts();
-
-                let buf = std::fs::read(path).context("could not read file")?;
-                let tree = usvg::Tree::from_data(&buf, &usvg::Options::default(), &fontdb)?;
-
-                Ok(Self {
-                    surface,
-                    subsurface,
-                    viewport,
-                    kind: ImageKind::Svg { tree },
-                })
+        Self {
+            surface,
+            subsurface,
+            viewport,
+            kind: ImageKind::Empty,
+            pending: None,
+            full: None,
+            dpi: None,
+            hdr: None,
+            deep: None,
+            pages: None,
+            pyramid: None,
+            file_size: None,
+            exif: Exif::default(),
+            hdr_output: None,
+            freeze_animations,
+            frames,
+            low_memory: false,
+            tiles: None,
+            layer: Layer::new(globals, main_surface, surface),
+            recent: Recent::new(memory_cache),
+            key: None,
+        }
+    }
+
+    /// Show the image of `entry`, or `preloaded` if it has been decoded already, unless it has
+    /// been kept since it was shown. On failure, the current image is kept.
+    pub fn load(
+        &mut self,
+        entry: &Entry,
+        shm: &mut ShmAlloc,
+        conn: &mut Connection<State>,
+        options: DecodeOptions,
+        preloaded: Option<Result<Prepared>>,
+    ) -> Result<(), DecodeError> {
+        let key = recent::Key::new(entry);
+        let mut image = match self.recent.take(&key) {
+            Some(kept) => self.restore(kept, shm, conn),
+            None => self
+                .decode(entry, shm, conn, options, preloaded)
+                .map_err(|source| DecodeError {
+                    path: entry.name(),
+                    source,
+                })?,
+        };
+        if let Some(tiles) = &mut self.tiles {
+            tiles.clear(conn);
+        }
+        image.key = Some(key);
+        std::mem::swap(&mut image.recent, &mut self.recent);
+        let previous = std::mem::replace(self, image);
+        if !self.low_memory {
+            if let Some((key, kept)) = previous.into_kept() {
+                let bytes = kept.bytes();
+                self.recent.insert(key, kept, bytes);
             }
-            _ => {
-                let image = image::io::Reader::open(path)
-                    .context("could not open file")?
-                    .decode()
-                    .context("could not decode image")?
-                    .into_rgba8();
-                let width = image.width();
-                let height = image.height();
-
-                let (buffer, canvas) = shm
-                    .alloc_buffer(
-                        conn,
-                        BufferSpec {
-                            width,
-                            height,
-                            stride: width * 4,
-                            format: wl_shm::Format::Abgr8888,
-                        },
-                    )
-                    .unwrap();
-                canvas.copy_from_slice(image.as_raw());
-                surface.attach(conn, Some(buffer.into_wl_buffer()), 0, 0);
-
-                Ok(Self {
-                    surface,
-                    subsurface,
-                    viewport,
-                    kind: ImageKind::Image { width, height },
-                })
+        }
+        Ok(())
+    }
+
+    /// Whether the image of `entry` has been kept since it was shown, so that it need not be
+    /// decoded again.
+    pub fn is_kept(&self, entry: &Entry) -> bool {
+        self.recent.contains(&recent::Key::new(entry))
+    }
+
+    /// What to keep of the image once another one is shown. Animations, videos and images read
+    /// tile by tile are not kept, nor images whose decode has not finished.
+    fn into_kept(self) -> Option<(recent::Key, Kept)> {
+        let static_image = matches!(self.kind, ImageKind::Raster(_) | ImageKind::Svg(_));
+        if !static_image || self.pending.is_some() || self.tiles.is_some() {
+            return None;
+        }
+        let kept = Kept {
+            kind: self.kind,
+            full: self.full,
+            dpi: self.dpi,
+            hdr: self.hdr,
+            deep: self.deep,
+            pages: self.pages,
+            pyramid: self.pyramid,
+            file_size: self.file_size,
+            exif: self.exif,
+        };
+        Some((self.key?, kept))
+    }
+
+    /// Show an image which has been kept, with new buffers.
+    fn restore(&self, kept: Kept, shm: &mut ShmAlloc, conn: &mut Connection<State>) -> Self {
+        let mut image = Self {
+            surface: self.surface,
+            subsurface: self.subsurface,
+            viewport: self.viewport,
+            kind: ImageKind::Empty,
+            pending: None,
+            full: kept.full,
+            dpi: kept.dpi,
+            hdr: kept.hdr,
+            deep: kept.deep,
+            pages: kept.pages,
+            pyramid: kept.pyramid,
+            file_size: kept.file_size,
+            exif: kept.exif,
+            hdr_output: self.hdr_output,
+            freeze_animations: self.freeze_animations,
+            frames: self.frames,
+            low_memory: self.low_memory,
+            tiles: None,
+            layer: self.layer,
+            recent: Recent::new(0),
+            key: None,
+        };
+        match kept.kind {
+            ImageKind::Raster(raster) => image.show(conn, shm, Content::Raster(raster.pixels)),
+            kind => image.kind = kind,
+        }
+        image
+    }
+
+    fn decode(
+        &self,
+        entry: &Entry,
+        shm: &mut ShmAlloc,
+        conn: &mut Connection<State>,
+        options: DecodeOptions,
+        preloaded: Option<Result<Prepared>>,
+    ) -> Result<Self> {
+        let (surface, subsurface, viewport) = (self.surface, self.subsurface, self.viewport);
+
+        let tiled = match (entry, options.max_pixels) {
+            (Entry::File(path), Some(max_pixels)) if path != "-" => {
+                Tiles::open(Path::new(path), max_pixels, &options.limits)?
+            }
+            _ => None,
+        };
+        let cache_key = match (entry, options.disk_cache) {
+            (Entry::File(path), Some(_)) if tiled.is_none() => cache::Key::new(Path::new(path)),
+            _ => None,
+        };
+        // At full quality, unlike the cached image, but files read tile by tile are never decoded
+        // as a whole
+        let preloaded = preloaded.filter(|_| tiled.is_none()).transpose()?;
+        let cached = match preloaded {
+            Some(_) => None,
+            None => cache_key.as_ref().and_then(cache::Key::load),
+        };
+        let (decoded, file_size, exif, tiles) = match (tiled, preloaded, cached) {
+            (Some((tiles, reduced, file_size)), _, _) => (
+                Decoded::raster(reduced, None),
+                file_size,
+                Exif::default(),
+                Some(tiles),
+            ),
+            (None, Some(prepared), _) => {
+                (prepared.decoded, prepared.file_size, prepared.exif, None)
             }
+            (None, None, Some(cached)) => {
+                let path = cache_key.unwrap().path().to_owned();
+                let mut decoded = Decoded::raster(cached.pixels, cached.dpi);
+                decoded.full = Some(Box::new(move || decode_full(&path, options)));
+                (decoded, cached.file_size, cached.exif, None)
+            }
+            (None, None, None) => {
+                let prepared = prepare(entry, options, cache_key)?;
+                (prepared.decoded, prepared.file_size, prepared.exif, None)
+            }
+        };
+
+        let mut image = Self {
+            surface,
+            subsurface,
+            viewport,
+            kind: ImageKind::Empty,
+            pending: decoded
+                .deferred
+                .map(|deferred| PendingDecode::spawn(deferred, "the embedded preview"))
+                .transpose()?,
+            full: decoded.full,
+            dpi: decoded.dpi,
+            hdr: decoded.hdr,
+            deep: decoded.deep,
+            pages: decoded.pages,
+            pyramid: decoded.pyramid,
+            file_size: Some(file_size),
+            exif,
+            hdr_output: self.hdr_output,
+            freeze_animations: self.freeze_animations,
+            frames: self.frames,
+            low_memory: self.low_memory,
+            tiles,
+            layer: self.layer,
+            // Moved over by `Self::load`
+            recent: Recent::new(0),
+            key: None,
+        };
+        image.show(conn, shm, decoded.content);
+        if let Some(source) = decoded.animation {
+            image.animate(source);
+        }
+        if let Some(frames) = decoded.frames {
+            image.play(frames);
+        }
+        if let Some(video) = decoded.video {
+            image.play_video(video);
+        }
+        Ok(image)
+    }
+
+    /// Show new content, with the HDR or 16-bit source of raster images if possible.
+    fn show(&mut self, conn: &mut Connection<State>, shm: &mut ShmAlloc, content: Content) {
+        let hdr = self.hdr.as_ref().zip(self.hdr_output);
+        if let (None, Some((color_surface, _))) = (hdr, self.hdr_output) {
+            color_surface.unset_image_description(conn);
+        }
+        self.kind = match (content, hdr) {
+            (Content::Svg(tree), _) => ImageKind::Svg(Svg::new(tree)),
+            (Content::Raster(pixels), Some((hdr, (color_surface, description)))) => {
+                upload_hdr(conn, shm, self.surface, hdr);
+                color_surface.set_image_description(
+                    conn,
+                    description,
+                    wp_color_manager_v1::RenderIntent::Perceptual,
+                );
+                // The tone mapped pixels are still used for inspection
+                ImageKind::Raster(Raster::new(pixels))
+            }
+            (Content::Raster(pixels), None) => ImageKind::Raster(match &self.deep {
+                Some(deep) if shm.supports(wl_shm::Format::Abgr16161616) => {
+                    upload_deep(conn, shm, self.surface, deep);
+                    Raster::new(pixels)
+                }
+                _ if self.frames => Raster::frame(pixels),
+                _ => {
+                    upload(conn, shm, self.surface, &pixels);
+                    Raster::new(pixels)
+                }
+            }),
+        };
+    }
+
+    /// Play the animation of the SVG image which is shown, unless animations are frozen.
+    fn animate(&mut self, source: AnimatedSvg) {
+        if let ImageKind::Svg(svg) = std::mem::replace(&mut self.kind, ImageKind::Empty) {
+            let animated = Animated::new(svg, source, self.freeze_animations);
+            self.kind = ImageKind::Animated(Box::new(animated));
+        }
+    }
+
+    /// Play the frames of the GIF or APNG image which is shown, unless animations are frozen.
+    /// Images which are themselves the frames of an animation are only shown as they are.
+    fn play(&mut self, frames: Frames) {
+        if self.frames {
+            return;
+        }
+        if let ImageKind::Raster(raster) = std::mem::replace(&mut self.kind, ImageKind::Empty) {
+            let player = Player::new(raster, frames, self.freeze_animations);
+            self.kind = ImageKind::Player(Box::new(player));
+        }
+    }
+
+    /// Play the video whose first frame is shown, unless animations are frozen.
+    fn play_video(&mut self, video: Video) {
+        if let ImageKind::Raster(first) = std::mem::replace(&mut self.kind, ImageKind::Empty) {
+            let clip = Clip::new(first, video, self.freeze_animations);
+            self.kind = ImageKind::Clip(Box::new(clip));
+        }
+    }
+
+    /// Upload the 16-bit source of the current image, now that the compositor has said that it
+    /// supports 16-bit buffers. Returns `true` if the current image has changed.
+    pub fn enable_deep_output(&mut self, conn: &mut Connection<State>, shm: &mut ShmAlloc) -> bool {
+        let (Some(_), ImageKind::Raster(raster)) = (&self.deep, &mut self.kind) else {
+            return false;
+        };
+        let pixels = std::mem::take(&mut raster.pixels);
+        self.show(conn, shm, Content::Raster(pixels));
+        true
+    }
+
+    /// Show HDR images with `description` from now on. Returns `true` if the current image has
+    /// changed.
+    pub fn enable_hdr_output(
+        &mut self,
+        conn: &mut Connection<State>,
+        shm: &mut ShmAlloc,
+        manager: WpColorManagerV1,
+        description: WpImageDescriptionV1,
+    ) -> bool {
+        let color_surface = manager.get_surface(conn, self.surface);
+        self.hdr_output = Some((color_surface, description));
+        let Some(hdr) = &self.hdr else {
+            return false;
+        };
+        let pixels = hdr.tone_map();
+        self.show(conn, shm, Content::Raster(pixels));
+        true
+    }
+
+    /// Don't decode the full resolution of reduced images, nor keep the images which have been
+    /// shown, while the system is low on memory, see [`crate::pressure`].
+    pub fn set_low_memory(&mut self, low_memory: bool) {
+        self.low_memory = low_memory;
+        if low_memory {
+            self.recent.clear();
+        }
+    }
+
+    /// Whether the image is shown in scRGB, so that the compositor blends the surfaces above it
+    /// in linear light.
+    pub fn shows_hdr(&self) -> bool {
+        self.hdr.is_some() && self.hdr_output.is_some()
+    }
+
+    /// The natural size of the image.
+    pub fn size(&self) -> (f32, f32) {
+        self.kind
+            .as_render()
+            .map_or((0.0, 0.0), |image| image.natural_size())
+    }
+
+    /// The decoded pixels of a raster image.
+    pub fn pixels(&self) -> Option<&RgbaImage> {
+        match &self.kind {
+            ImageKind::Raster(raster) => Some(&raster.pixels),
+            ImageKind::Player(player) => Some(player.pixels()),
+            _ => None,
+        }
+    }
+
+    /// The physical resolution of the image in dots per inch, if known.
+    pub fn dpi(&self) -> Option<f32> {
+        // The resolution is that of the whole pyramid
+        match (&self.pyramid, &self.kind) {
+            (Some(pyramid), ImageKind::Raster(raster)) => {
+                let scale = raster.pixels.width() as f32 / pyramid.dimensions().0 as f32;
+                self.dpi.map(|dpi| dpi * scale)
+            }
+            _ => self.dpi,
+        }
+    }
+
+    /// Change the exposure of an HDR image by a number of stops. Returns `false` if this is not
+    /// an HDR image.
+    pub fn adjust_exposure(
+        &mut self,
+        conn: &mut Connection<State>,
+        shm: &mut ShmAlloc,
+        stops: f32,
+    ) -> bool {
+        let Some(hdr) = &mut self.hdr else {
+            return false;
+        };
+        hdr.adjust_exposure(stops);
+        let pixels = hdr.tone_map();
+        self.show(conn, shm, Content::Raster(pixels));
+        true
+    }
+
+    pub fn file_size(&self) -> Option<u64> {
+        self.file_size
+    }
+
+    pub fn exif(&self) -> &Exif {
+        &self.exif
+    }
+
+    /// Which page or frame is shown, if this is a multi-page image or an animation.
+    pub fn page_label(&self) -> Option<String> {
+        match &self.kind {
+            ImageKind::Player(player) => Some(player.label()),
+            _ => self.pages.as_ref().map(Pages::label),
+        }
+    }
+
+    /// Move `delta` pages forward, stopping at the first and the last page. Animations stop and
+    /// move `delta` frames forward instead. Returns `false` if the page stays.
+    pub fn turn_page(
+        &mut self,
+        conn: &mut Connection<State>,
+        shm: &mut ShmAlloc,
+        delta: isize,
+    ) -> Result<bool> {
+        if let ImageKind::Player(player) = &mut self.kind {
+            return Ok(player.turn(delta));
+        }
+        if let ImageKind::Clip(clip) = &mut self.kind {
+            // Videos are only decoded forwards
+            return Ok(delta > 0 && clip.step());
+        }
+        let Some(page) = self.pages.as_mut().and_then(|p| p.turn(delta)) else {
+            return Ok(false);
+        };
+        let (pixels, deep) = decode::split_depth(page?);
+        self.deep = deep;
+        self.show(conn, shm, Content::Raster(pixels));
+        Ok(true)
+    }
+
+    /// The duration until the next frame of an animation is due, or `None` if nothing is
+    /// playing.
+    pub fn animation_timeout(&self) -> Option<Duration> {
+        self.kind.as_render()?.next_tick()
+    }
+
+    /// A file descriptor which becomes readable when the next frame of an animation may have
+    /// been decoded.
+    pub fn animation_fd(&self) -> Option<RawFd> {
+        self.kind.as_render()?.wakeup_fd()
+    }
+
+    /// Show the next frame of an animation if it is due. Returns `true` if the image has
+    /// changed.
+    pub fn advance_animation(&mut self) -> bool {
+        self.kind.as_render_mut().is_some_and(|image| image.tick())
+    }
+
+    /// Stop playing the animation where it is, or continue it.
+    pub fn pause_animation(&mut self, paused: bool) {
+        match &mut self.kind {
+            ImageKind::Animated(animated) => animated.set_paused(paused),
+            ImageKind::Player(player) => player.set_paused(paused),
+            ImageKind::Clip(clip) => clip.hide(paused),
+            _ => (),
+        }
+    }
+
+    /// Pause or continue a video. Returns whether it is paused now, or `None` if the image is
+    /// not a video.
+    pub fn toggle_video(&mut self) -> Option<bool> {
+        match &mut self.kind {
+            ImageKind::Clip(clip) => Some(clip.toggle()),
+            _ => None,
+        }
+    }
+
+    /// Freeze animations at their start, or play them from the start again. Returns whether
+    /// they are frozen now, or `None` if the image is not animated.
+    pub fn toggle_animation(&mut self) -> Option<bool> {
+        match &mut self.kind {
+            ImageKind::Animated(animated) => {
+                self.freeze_animations = !self.freeze_animations;
+                animated.set_frozen(self.freeze_animations);
+            }
+            ImageKind::Player(player) => {
+                // After turning to a frame with [ and ], the animation plays on from there
+                self.freeze_animations = player.playing();
+                player.set_frozen(self.freeze_animations);
+            }
+            _ => return None,
+        }
+        Some(self.freeze_animations)
+    }
+
+    /// The surface of the image, followed by those of its tiles.
+    pub fn surfaces(&self) -> Vec<WlSurface> {
+        let mut surfaces = vec![self.surface];
+        surfaces.extend(self.tiles.iter().flat_map(Tiles::surfaces));
+        surfaces
+    }
+
+    /// A file descriptor which becomes readable when a background decode finishes.
+    pub fn pending_fd(&self) -> Option<RawFd> {
+        self.pending.as_ref().map(|p| p.wakeup.as_raw_fd())
+    }
+
+    /// Replace the preview with the result of the background decode. Returns `true` if the image
+    /// has changed.
+    ///
+    /// Call this when [`Self::pending_fd`] becomes readable.
+    pub fn finish_pending(&mut self, conn: &mut Connection<State>, shm: &mut ShmAlloc) -> bool {
+        let Some(pending) = self.pending.take() else {
+            return false;
+        };
+        match pending.result.recv() {
+            Ok(Ok(image)) => {
+                self.deep = None;
+                self.show(conn, shm, Content::Raster(image));
+                true
+            }
+            Ok(Err(e)) => {
+                eprintln!("reimv: showing {} only: {e:#}", pending.fallback);
+                false
+            }
+            Err(mpsc::RecvError) => false,
+        }
+    }
+
+    /// Draw the image over `canvas`, which covers the window, the way the compositor shows it.
+    pub fn draw(
+        &self,
+        canvas: &mut tiny_skia::PixmapMut,
+        ui_scale120: u32,
+        img_transform: &ImageTransform,
+    ) {
+        let transform = tiny_skia::Transform::identity()
+            .post_scale(img_transform.scale, img_transform.scale)
+            .post_translate(img_transform.x, img_transform.y)
+            .post_scale(ui_scale120 as f32 / 120.0, ui_scale120 as f32 / 120.0);
+        if let Some(image) = self.kind.as_render() {
+            image.draw(canvas, transform);
         }
     }
 
@@ -114,86 +839,67 @@ impl Image {
         ui_scale120: u32,
         img_transform: &ImageTransform,
     ) {
-        match &mut self.kind {
-            ImageKind::Svg { tree } => {
-                let transform = tiny_skia::Transform::identity()
-                    .post_scale(img_transform.scale, img_transform.scale)
-                    .post_translate(img_transform.x, img_transform.y)
-                    .post_scale(ui_scale120 as f32 / 120.0, ui_scale120 as f32 / 120.0);
-
-                // Round halfway away from zero
-                let pix_width = (win_width * ui_scale120 + 60) / 120;
-                let pix_height = (win_height * ui_scale120 + 60) / 120;
-
-                let (buffer, canvas) = shm
-                    .alloc_buffer(
-                        conn,
-                        BufferSpec {
-                            width: pix_width,
-                            height: pix_height,
-                            stride: pix_width * 4,
-                            format: wl_shm::Format::Abgr8888,
-                        },
-                    )
-                    .unwrap();
-                canvas.fill(20);
-
-                let mut canvas =
-                    tiny_skia::PixmapMut::from_bytes(canvas, pix_width, pix_height).unwrap();
-
-                resvg::render(tree, transform, &mut canvas);
-
-                self.surface
-                    .attach(conn, Some(buffer.into_wl_buffer()), 0, 0);
-                self.viewport
-                    .set_destination(conn, win_width as i32, win_height as i32);
-                self.surface.damage(conn, 0, 0, i32::MAX, i32::MAX);
+        let view = View::new(win_width, win_height, ui_scale120, *img_transform);
+        if let ImageKind::Raster(raster) = &self.kind {
+            // Each pixel covers more than one buffer pixel, so the reduced image looks blurry
+            let zoomed_in = view.density() > 1.0;
+            let wanted = zoomed_in && self.pending.is_none() && !self.low_memory;
+            if let Some(full) = self.full.take_if(|_| wanted) {
+                match PendingDecode::spawn(full, "a reduced resolution") {
+                    Ok(pending) => self.pending = Some(pending),
+                    Err(e) => eprintln!("reimv: could not decode at full resolution: {e:#}"),
+                }
             }
-            ImageKind::Image { width, height } => {
-                let transform = tiny_skia::Transform::identity()
-                    .post_scale(img_transform.scale, img_transform.scale)
-                    .post_translate(img_transform.x, img_transform.y);
-                let transform_inv = tiny_skia::Transform::identity()
-                    .pre_scale(img_transform.scale.recip(), img_transform.scale.recip())
-                    .pre_translate(-img_transform.x, -img_transform.y);
-
-                let window =
-                    tiny_skia::Rect::from_xywh(0.0, 0.0, win_width as f32, win_height as f32)
-                        .unwrap();
-
-                let dst = tiny_skia::Rect::from_xywh(0.0, 0.0, *width as f32, *height as f32)
-                    .unwrap()
-                    .transform(transform)
-                    .unwrap()
-                    .intersect(&window);
-
-                match dst {
-                    Some(dst) if dst.width() >= 1.0 && dst.height() >= 1.0 => {
-                        let src = dst.transform(transform_inv).unwrap();
-                        self.subsurface
-                            .set_position(conn, dst.x() as i32, dst.y() as i32);
-                        self.viewport.set_destination(
-                            conn,
-                            dst.width() as i32,
-                            dst.height() as i32,
-                        );
-                        self.viewport.set_source(
-                            conn,
-                            src.x().into(),
-                            src.y().into(),
-                            src.width().clamp(1.0, *width as f32).into(),
-                            src.height().clamp(1.0, *height as f32).into(),
-                        );
-                    }
-                    _ => {
-                        // HACK
-                        self.subsurface.set_position(conn, 0, 0);
-                        self.viewport.set_destination(conn, 1, 1);
+            if let Some(tiles) = &mut self.tiles {
+                tiles.render(conn, shm, &self.layer, &view);
+            }
+            if let Some(pyramid) = self.pyramid.as_mut().filter(|_| self.pending.is_none()) {
+                // The level with about as many pixels as the window shows
+                let level = pyramid.level_for_width(raster.pixels.width() as f32 * view.density());
+                if level != pyramid.current() {
+                    pyramid.set_current(level);
+                    let pyramid = pyramid.clone();
+                    let decode = move || pyramid.decode(level).map(DynamicImage::into_rgba8);
+                    match PendingDecode::spawn(decode, "the previous level") {
+                        Ok(pending) => self.pending = Some(pending),
+                        Err(e) => eprintln!("reimv: could not decode another level: {e:#}"),
                     }
                 }
             }
         }
 
-        self.surface.commit(conn);
+        let Some(image) = self.kind.as_render_mut() else {
+            return;
+        };
+        let mut target = Target {
+            conn,
+            shm,
+            surface: self.surface,
+            subsurface: self.subsurface,
+            viewport: self.viewport,
+            width: win_width,
+            height: win_height,
+            ui_scale120,
+        };
+        image.render(img_transform, &mut target);
+    }
+}
+
+/// Decode the image at `path` at full resolution, after it was shown from the disk cache.
+fn decode_full(path: &Path, options: DecodeOptions) -> Result<RgbaImage> {
+    let data = format::read(path).context("could not read file")?;
+    let format = format::detect(path, &data).context("unknown image format")?;
+    let decoded = decode::decode(
+        data,
+        format,
+        None,
+        options.tone_mapping,
+        options.limits,
+        options.color_management,
+        None,
+    )?;
+    match decoded.content {
+        Content::Raster(pixels) => Ok(pixels),
+        Content::Svg(_) => anyhow::bail!("the image is no longer a raster image"),
     }
 }
@@ -0,0 +1,96 @@
+//! Reading pixel values under the pointer and statistics of rectangular regions.
+
+use std::fmt::Write;
+
+use image::RgbaImage;
+
+/// Coordinates are in image space, so that the selection follows panning and zooming.
+#[derive(Debug, Default)]
+pub struct Inspect {
+    /// The corner where the selection drag started
+    pub anchor: Option<(f32, f32)>,
+    /// The opposite corner
+    pub cursor: Option<(f32, f32)>,
+    /// Whether the selection is being dragged
+    pub dragging: bool,
+}
+
+/// A rectangle of whole pixels, `x0..x1` by `y0..y1`.
+#[derive(Debug, Clone, Copy)]
+pub struct PixelRect {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+impl Inspect {
+    /// The selected pixels, clamped to the image.
+    pub fn selection(&self, width: u32, height: u32) -> Option<PixelRect> {
+        let (a, b) = (self.anchor?, self.cursor?);
+        let clamp = |v: f32, max: u32| v.round().clamp(0.0, max as f32) as u32;
+        let rect = PixelRect {
+            x0: clamp(a.0.min(b.0), width),
+            y0: clamp(a.1.min(b.1), height),
+            x1: clamp(a.0.max(b.0), width),
+            y1: clamp(a.1.max(b.1), height),
+        };
+        (rect.x0 < rect.x1 && rect.y0 < rect.y1).then_some(rect)
+    }
+}
+
+/// Describe the pixel at image coordinates `(x, y)`.
+pub fn describe_pixel(image: &RgbaImage, x: f32, y: f32) -> Option<String> {
+    if x < 0.0 || y < 0.0 {
+        return None;
+    }
+    let (x, y) = (x as u32, y as u32);
+    let [r, g, b, a] = image.get_pixel_checked(x, y)?.0;
+    Some(format!(
+        "{x}, {y}: rgba({r}, {g}, {b}, {a}) #{r:02x}{g:02x}{b:02x}"
+    ))
+}
+
+/// Per-channel minimum, maximum and mean of the pixels in `rect`.
+pub fn describe_region(image: &RgbaImage, rect: PixelRect) -> String {
+    let mut min = [u8::MAX; 4];
+    let mut max = [u8::MIN; 4];
+    let mut sum = [0u64; 4];
+    for y in rect.y0..rect.y1 {
+        for x in rect.x0..rect.x1 {
+            let pixel = image.get_pixel(x, y).0;
+            for c in 0..4 {
+                min[c] = min[c].min(pixel[c]);
+                max[c] = max[c].max(pixel[c]);
+                sum[c] += pixel[c] as u64;
+            }
+        }
+    }
+    let count = (rect.x1 - rect.x0) as u64 * (rect.y1 - rect.y0) as u64;
+
+    // Only mention alpha if the region is not fully opaque
+    let channels = if min[3] == u8::MAX { 3 } else { 4 };
+    let list = |values: &mut dyn Iterator<Item = String>| {
+        values.take(channels).collect::<Vec<_>>().join(", ")
+    };
+
+    let mut text = format!(
+        "{}x{} at {}, {}:",
+        rect.x1 - rect.x0,
+        rect.y1 - rect.y0,
+        rect.x0,
+        rect.y0
+    );
+    let _ = write!(text, " min ({})", list(&mut min.iter().map(u8::to_string)));
+    let _ = write!(text, " max ({})", list(&mut max.iter().map(u8::to_string)));
+    let _ = write!(
+        text,
+        " mean ({})",
+        list(
+            &mut sum
+                .iter()
+                .map(|s| format!("{:.1}", *s as f64 / count as f64))
+        )
+    );
+    text
+}
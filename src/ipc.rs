@@ -0,0 +1,578 @@
+//! Commands from other programs, received on a Unix socket.
+//!
+//! With `--ipc-socket`, we listen on a stream socket at the given path. Clients speak JSON-RPC 2.0
+//! with one message per line: they send requests, or batches of them, and get a response line for
+//! every request with an `id`, in order. Connections stay open, so that a client can send more
+//! requests and subscribe to events, which arrive as notifications. The README lists the methods,
+//! and `reimv-ctl` is a client for scripts.
+//!
+//! [`PROTOCOL_VERSION`], which the `version` method returns, only changes with changes that break
+//! existing clients. Methods, parameters, members of results and events are added without changing
+//! it, so clients should ignore members they do not know. Unknown parameters are rejected, so that a
+//! misspelled one is noticed.
+//!
+//! Lines which do not start with `{` or `[` are the plain commands from before, which get back `ok`
+//! or `error: <message>`, after which the connection is closed:
+//!
+//! - `snapshot <path>` saves what the window shows, the image together with the overlay, as a PNG
+//!   file. This works without a screenshot tool and when the window is hidden, e.g. on a headless
+//!   compositor. Relative paths are relative to the working directory of reimv.
+//! - `view set [--zoom SCALE] [--center X,Y] [--animate DURATION]` shows the point `X,Y` of the
+//!   image, in its pixels, at the center of the window, with each image pixel taking `SCALE`
+//!   logical pixels. What is not given stays as it is. With `--animate 300ms` (or `0.5s`), the
+//!   view moves there smoothly, until the user moves it.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use reimv::json::Value;
+use resvg::tiny_skia;
+
+use crate::animation::ViewAnimation;
+use crate::backend;
+use crate::blend::{self, Space};
+use crate::overlay::Overlay;
+use crate::State;
+
+/// Increased only by changes which break existing clients.
+const PROTOCOL_VERSION: u32 = 1;
+/// A longer line ends the connection, so that a client cannot make us buffer without limit.
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// Further connections are closed right away, since clients which stay silent are kept.
+const MAX_CLIENTS: usize = 64;
+
+// The error codes of JSON-RPC
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// A valid request which could not be carried out, e.g. a snapshot which could not be saved
+const FAILED: i64 = -32000;
+
+pub struct Ipc {
+    listener: UnixListener,
+    path: PathBuf,
+    /// Ready when the listener or a client is, so that the event loop polls a single descriptor
+    epoll: OwnedFd,
+    clients: Vec<Client>,
+}
+
+struct Client {
+    stream: UnixStream,
+    /// The start of a line which has not been received in full yet
+    pending: Vec<u8>,
+    /// Whether the client subscribed to `image` events
+    image_events: bool,
+    /// The parameters of the last `image` event sent to the client
+    last_image: Option<Value>,
+}
+
+/// An error response.
+struct Error {
+    code: i64,
+    message: String,
+}
+
+impl Ipc {
+    pub fn bind(path: &Path) -> Result<Self> {
+        // A socket left behind by a crashed instance, but never a file of some other kind
+        let stale = std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket())
+            && UnixStream::connect(path).is_err();
+        if stale {
+            let _ = std::fs::remove_file(path);
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("could not bind IPC socket {}", path.display()))?;
+        listener.set_nonblocking(true)?;
+        let epoll = epoll().context("could not create epoll instance")?;
+        epoll_add(&epoll, listener.as_raw_fd()).context("could not watch IPC socket")?;
+        Ok(Self {
+            listener,
+            path: path.to_owned(),
+            epoll,
+            clients: Vec::new(),
+        })
+    }
+
+    /// Accept new connections and answer what the clients have sent.
+    pub fn handle(state: &mut State) {
+        let Some(ipc) = &mut state.ipc else {
+            return;
+        };
+        ipc.accept();
+        // Taken out while they are served, which needs all of the state
+        let mut clients = std::mem::take(&mut ipc.clients);
+        clients.retain_mut(|client| serve(state, client));
+        state.ipc.as_mut().unwrap().clients = clients;
+    }
+
+    /// Send an `image` event to the clients which subscribed to it and have not been told of the
+    /// current image yet.
+    pub fn notify(state: &mut State) {
+        let Some(ipc) = &state.ipc else {
+            return;
+        };
+        if !ipc.clients.iter().any(|client| client.image_events) {
+            return;
+        }
+        let image = image(state);
+        state.ipc.as_mut().unwrap().clients.retain_mut(|client| {
+            if !client.image_events || client.last_image.as_ref() == Some(&image) {
+                return true;
+            }
+            client.last_image = Some(image.clone());
+            let notification = Value::object([
+                ("jsonrpc", "2.0".into()),
+                ("method", "image".into()),
+                ("params", image.clone()),
+            ]);
+            send(client, &notification.to_string())
+        });
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    eprintln!("reimv: could not accept IPC connection: {e}");
+                    return;
+                }
+            };
+            if self.clients.len() >= MAX_CLIENTS
+                || stream.set_nonblocking(true).is_err()
+                || epoll_add(&self.epoll, stream.as_raw_fd()).is_err()
+            {
+                continue;
+            }
+            self.clients.push(Client {
+                stream,
+                pending: Vec::new(),
+                image_events: false,
+                last_image: None,
+            });
+        }
+    }
+}
+
+/// Answer the complete lines a client has sent. Returns whether to keep the connection.
+fn serve(state: &mut State, client: &mut Client) -> bool {
+    let mut buf = [0; 4096];
+    loop {
+        let len = match (&client.stream).read(&mut buf) {
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return true,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => return false,
+        };
+        if len == 0 {
+            // The last line may lack its newline, like from `printf`
+            let line = std::mem::take(&mut client.pending);
+            answer(state, client, &line);
+            return false;
+        }
+        client.pending.extend_from_slice(&buf[..len]);
+        while let Some(end) = client.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = client.pending.drain(..=end).collect();
+            if !answer(state, client, &line) {
+                return false;
+            }
+        }
+        if client.pending.len() > MAX_LINE_LEN {
+            return false;
+        }
+    }
+}
+
+/// Answer a line. Returns whether to keep the connection.
+fn answer(state: &mut State, client: &mut Client, line: &[u8]) -> bool {
+    let line = String::from_utf8_lossy(line);
+    let line = line.trim_end_matches(['\r', '\n']);
+    if line.trim().is_empty() {
+        return true;
+    }
+    if !line.trim_start().starts_with(['{', '[']) {
+        let reply = match run(state, line) {
+            Ok(()) => "ok".to_owned(),
+            Err(e) => format!("error: {e:#}"),
+        };
+        send(client, &reply);
+        return false;
+    }
+    match respond(state, client, line) {
+        Some(response) => send(client, &response.to_string()),
+        None => true,
+    }
+}
+
+/// Send a line, which fails for clients which do not read what they get.
+fn send(client: &Client, line: &str) -> bool {
+    (&client.stream)
+        .write_all(format!("{line}\n").as_bytes())
+        .is_ok()
+}
+
+/// The response to a message, unless it only has notifications.
+fn respond(state: &mut State, client: &mut Client, message: &str) -> Option<Value> {
+    let message = match Value::parse(message) {
+        Ok(message) => message,
+        Err(e) => return Some(response(Value::Null, Err(Error::new(PARSE_ERROR, e)))),
+    };
+    match message {
+        Value::Array(batch) if batch.is_empty() => Some(response(
+            Value::Null,
+            Err(Error::new(INVALID_REQUEST, "empty batch")),
+        )),
+        Value::Array(batch) => {
+            let responses: Vec<Value> = batch
+                .iter()
+                .filter_map(|request| self::request(state, client, request))
+                .collect();
+            (!responses.is_empty()).then_some(Value::Array(responses))
+        }
+        request => self::request(state, client, &request),
+    }
+}
+
+/// Carry out a request, returning its response unless it is a notification, without an `id`.
+fn request(state: &mut State, client: &mut Client, request: &Value) -> Option<Value> {
+    let id = request.get("id");
+    let valid_id = matches!(
+        id,
+        None | Some(Value::Null | Value::Number(_) | Value::String(_))
+    );
+    let version = request.get("jsonrpc").and_then(Value::as_str);
+    let method = request.get("method").and_then(Value::as_str);
+    let (true, Some("2.0"), Some(method)) = (valid_id, version, method) else {
+        let id = id.filter(|_| valid_id).cloned().unwrap_or(Value::Null);
+        let error = Error::new(INVALID_REQUEST, "expected a JSON-RPC 2.0 request");
+        return Some(response(id, Err(error)));
+    };
+    let result = call(state, client, method, Params(request.get("params")));
+    Some(response(id?.clone(), result))
+}
+
+fn response(id: Value, result: Result<Value, Error>) -> Value {
+    let (key, value) = match result {
+        Ok(result) => ("result", result),
+        Err(e) => (
+            "error",
+            Value::object([("code", e.code.into()), ("message", e.message.into())]),
+        ),
+    };
+    Value::object([("jsonrpc", "2.0".into()), ("id", id), (key, value)])
+}
+
+fn call(
+    state: &mut State,
+    client: &mut Client,
+    method: &str,
+    params: Params,
+) -> Result<Value, Error> {
+    match method {
+        "version" => {
+            params.only(&[])?;
+            Ok(Value::object([
+                ("protocol", i64::from(PROTOCOL_VERSION).into()),
+                ("version", env!("CARGO_PKG_VERSION").into()),
+            ]))
+        }
+        "image.get" => {
+            params.only(&[])?;
+            Ok(image(state))
+        }
+        "view.get" => {
+            params.only(&[])?;
+            let window = (state.window.width as f32, state.window.height as f32);
+            let (x, y) = state
+                .img_transform
+                .image_point(window.0 / 2.0, window.1 / 2.0);
+            Ok(Value::object([
+                ("zoom", state.img_transform.scale.into()),
+                ("center", vec![x.into(), y.into()].into()),
+            ]))
+        }
+        "view.set" => {
+            params.only(&["zoom", "center", "animate"])?;
+            let scale = params
+                .number("zoom", |zoom| zoom > 0.0, "a positive number")?
+                .map(|zoom| zoom as f32);
+            let center = match params.get("center") {
+                None => None,
+                Some(center) => {
+                    let point = center
+                        .as_array()
+                        .and_then(|point| match point {
+                            [x, y] => Some((x.as_f64()?, y.as_f64()?)),
+                            _ => None,
+                        })
+                        .filter(|(x, y)| x.is_finite() && y.is_finite())
+                        .ok_or_else(|| Error::invalid_params("center must be [x, y]"))?;
+                    Some((point.0 as f32, point.1 as f32))
+                }
+            };
+            let duration = params
+                .number("animate", |ms| ms >= 0.0, "a number of milliseconds")?
+                .map_or(Duration::ZERO, |ms| Duration::from_secs_f64(ms / 1000.0));
+            set_view(state, center, scale, duration);
+            Ok(Value::Null)
+        }
+        "snapshot" => {
+            params.only(&["path"])?;
+            let path = params
+                .get("path")
+                .and_then(Value::as_str)
+                .ok_or_else(|| Error::invalid_params("path must be a string"))?;
+            snapshot(state, Path::new(path))?;
+            Ok(Value::Null)
+        }
+        "subscribe" | "unsubscribe" => {
+            params.only(&["events"])?;
+            let events = params
+                .get("events")
+                .and_then(Value::as_array)
+                .ok_or_else(|| Error::invalid_params("events must be an array of names"))?;
+            for event in events {
+                match event.as_str() {
+                    Some("image") => (),
+                    _ => return Err(Error::invalid_params(format!("unknown event {event}"))),
+                }
+            }
+            if !events.is_empty() {
+                client.image_events = method == "subscribe";
+                // Subscribers are told of the current image at once
+                client.last_image = None;
+            }
+            Ok(Value::Null)
+        }
+        _ => Err(Error::new(
+            METHOD_NOT_FOUND,
+            format!("unknown method {method:?}"),
+        )),
+    }
+}
+
+/// What the `image` event and `image.get` say about the current image.
+fn image(state: &State) -> Value {
+    let (position, count) = state.files.position();
+    Value::object([
+        ("name", state.files.current().name().into()),
+        ("position", position.into()),
+        ("count", count.into()),
+        ("page", state.backend.page_label().into()),
+        ("marked", state.files.is_marked().into()),
+    ])
+}
+
+/// The parameters of a request, given by name.
+struct Params<'a>(Option<&'a Value>);
+
+impl<'a> Params<'a> {
+    /// Check that there are no parameters but `known`.
+    fn only(&self, known: &[&str]) -> Result<(), Error> {
+        let members = match self.0 {
+            None => return Ok(()),
+            Some(params) => params.as_object().ok_or_else(|| {
+                Error::invalid_params("expected the parameters by name, in an object")
+            })?,
+        };
+        match members
+            .iter()
+            .find(|(key, _)| !known.contains(&key.as_str()))
+        {
+            Some((key, _)) => Err(Error::invalid_params(format!("unknown parameter {key:?}"))),
+            None => Ok(()),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&'a Value> {
+        self.0.and_then(|params| params.get(key))
+    }
+
+    /// A number, which must be finite and `valid`, and is described as `expected` otherwise.
+    fn number(
+        &self,
+        key: &str,
+        valid: impl Fn(f64) -> bool,
+        expected: &str,
+    ) -> Result<Option<f64>, Error> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(value) => value
+                .as_f64()
+                .filter(|n| n.is_finite() && valid(*n))
+                .map(Some)
+                .ok_or_else(|| Error::invalid_params(format!("{key} must be {expected}"))),
+        }
+    }
+}
+
+impl Error {
+    fn new(code: i64, message: impl std::fmt::Display) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    fn invalid_params(message: impl std::fmt::Display) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(FAILED, format!("{e:#}"))
+    }
+}
+
+fn epoll() -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Watch `fd` for input. Closing it stops watching it.
+fn epoll_add(epoll: &OwnedFd, fd: RawFd) -> io::Result<()> {
+    let mut event = libc::epoll_event {
+        events: libc::EPOLLIN as u32,
+        u64: fd as u64,
+    };
+    if unsafe { libc::epoll_ctl(epoll.as_raw_fd(), libc::EPOLL_CTL_ADD, fd, &mut event) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn run(state: &mut State, command: &str) -> Result<()> {
+    match command.split_once(' ') {
+        Some(("snapshot", path)) => snapshot(state, Path::new(path)),
+        Some(("view", args)) => view(state, args),
+        _ => bail!("unknown command {command:?}"),
+    }
+}
+
+fn view(state: &mut State, args: &str) -> Result<()> {
+    let mut words = args.split_whitespace();
+    ensure!(
+        words.next() == Some("set"),
+        "unknown command, expected `view set`"
+    );
+    let mut scale = None;
+    let mut center = None;
+    let mut duration = Duration::ZERO;
+    while let Some(word) = words.next() {
+        let (option, value) = match word.split_once('=') {
+            Some((option, value)) => (option, value),
+            None => (
+                word,
+                words
+                    .next()
+                    .with_context(|| format!("{word} needs a value"))?,
+            ),
+        };
+        match option {
+            "--zoom" => {
+                let zoom: f32 = value.parse().context("invalid zoom")?;
+                ensure!(zoom.is_finite() && zoom > 0.0, "the zoom must be positive");
+                scale = Some(zoom);
+            }
+            "--center" => {
+                let parse = |(x, y): (&str, &str)| Some((x.parse().ok()?, y.parse().ok()?));
+                let point = value
+                    .split_once(',')
+                    .and_then(parse)
+                    .filter(|(x, y): &(f32, f32)| x.is_finite() && y.is_finite())
+                    .context("invalid center, expected X,Y")?;
+                center = Some(point);
+            }
+            "--animate" => duration = parse_duration(value)?,
+            _ => bail!("unknown option {option:?}"),
+        }
+    }
+    set_view(state, center, scale, duration);
+    Ok(())
+}
+
+/// Show the point `center` of the image at the center of the window at `scale`, moving there
+/// over `duration`. What is not given stays as it is.
+fn set_view(
+    state: &mut State,
+    center: Option<(f32, f32)>,
+    scale: Option<f32>,
+    mut duration: Duration,
+) {
+    // Animations depend on timing
+    if state.deterministic {
+        duration = Duration::ZERO;
+    }
+    let window = (state.window.width as f32, state.window.height as f32);
+    state.view_animation = Some(ViewAnimation::new(
+        state.img_transform,
+        window,
+        center,
+        scale,
+        duration,
+    ));
+}
+
+/// A duration like `300ms` or `1.5s`.
+fn parse_duration(text: &str) -> Result<Duration> {
+    let (number, unit) = match text.strip_suffix("ms") {
+        Some(number) => (number, 1e-3),
+        None => (text.strip_suffix('s').unwrap_or(text), 1.0),
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .and_then(|n| Duration::try_from_secs_f64(n * unit).ok())
+        .with_context(|| format!("invalid duration {text:?}, expected e.g. 300ms or 1.5s"))
+}
+
+/// Draw the window contents off-screen, at the scale of its buffers.
+fn snapshot(state: &mut State, path: &Path) -> Result<()> {
+    let scale120 = state.window.ui_scale120(state);
+    let (width, height) = backend::buffer_size(state.window.width, state.window.height, scale120);
+    let mut pixmap = tiny_skia::Pixmap::new(width, height).context("the window has no size")?;
+
+    state
+        .window
+        .draw_background(pixmap.data_mut(), width, scale120);
+    state
+        .backend
+        .draw(&mut pixmap.as_mut(), scale120, &state.img_transform);
+    if !state.overlay.is_empty(state) {
+        // The overlay is a surface of its own, which the compositor blends over the image
+        let mut overlay = tiny_skia::Pixmap::new(width, height).unwrap();
+        Overlay::draw(state, &mut overlay.as_mut(), scale120);
+        let space = match state.backend.shows_hdr() {
+            true => Space::Linear,
+            false => Space::Srgb,
+        };
+        blend::over(pixmap.data_mut(), overlay.data(), space);
+    }
+
+    pixmap
+        .save_png(path)
+        .with_context(|| format!("could not save {}", path.display()))
+}
+
+impl AsRawFd for Ipc {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+impl Drop for Ipc {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,233 @@
+//! Running decoders in a child process.
+//!
+//! With `--isolate-decoders`, every decode of untrusted data happens in a forked child, so a
+//! crafted image that crashes or takes over a decoder only affects the child. The child sends the
+//! decoded pixels back through a memfd and exits. It gives up its privileges first, and then
+//! applies the restrictions passed to [`enable`]. Its CPU time can be limited as well, which stops
+//! decoders that would otherwise run for minutes.
+//!
+//! SVG documents are still parsed in the viewer, since they are rendered from the parsed tree.
+//!
+//! The viewer has other threads, and only the thread which forks lives on in the child, so a lock
+//! held by any of the others stays locked there. The child still allocates freely: glibc takes the
+//! locks of its allocator around `fork` and resets them in the child. The thread pools of the JPEG
+//! and OpenEXR decoders are only ever started in children, because with isolation the viewer
+//! decodes nothing itself. The child writes nothing to stdout or stderr, and a panic in it is not
+//! reported, since their locks may be held.
+//!
+//! The result is not trusted either: its header is checked against the [`Limits`] of the image
+//! before anything is allocated for the pixels.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::os::fd::FromRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use image::{DynamicImage, Rgba32FImage, RgbaImage};
+
+use crate::decode::Rgba16Image;
+use crate::limits::Limits;
+
+static ISOLATION: OnceLock<Isolation> = OnceLock::new();
+
+#[derive(Clone, Copy)]
+struct Isolation {
+    /// Applied to the child before decoding
+    restrict: fn() -> Result<()>,
+    timeout: Option<Duration>,
+}
+
+const RESULT_RGBA8: u8 = 0;
+const RESULT_RGBA32F: u8 = 1;
+const RESULT_ERROR: u8 = 2;
+const RESULT_RGBA16: u8 = 3;
+
+/// The length of the header: the kind of result, and the width and height of the image
+const HEADER_LEN: u64 = 9;
+/// Of the message of an error, beyond which it is cut off
+const MAX_ERROR_LEN: u64 = 4096;
+
+/// Decode in child processes from now on, which call `restrict` before decoding and are killed
+/// after using `timeout` of CPU time.
+pub fn enable(restrict: fn() -> Result<()>, timeout: Option<Duration>) {
+    let _ = ISOLATION.set(Isolation { restrict, timeout });
+}
+
+/// Run `decode`, in a child process if isolation is enabled, which may only send back an image
+/// within `limits`. HDR images are returned as [`DynamicImage::ImageRgba32F`], 16-bit images as
+/// [`DynamicImage::ImageRgba16`] and everything else as [`DynamicImage::ImageRgba8`].
+pub fn run(limits: &Limits, decode: impl FnOnce() -> Result<DynamicImage>) -> Result<DynamicImage> {
+    let Some(&isolation) = ISOLATION.get() else {
+        return decode();
+    };
+
+    let mut file = create_memfd().context("could not create result file")?;
+    // SAFETY: the child only decodes and calls _exit, never returning into the caller
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(io::Error::last_os_error()).context("could not start decoder process");
+    }
+    if pid == 0 {
+        // The hook of the viewer saves crash reports and prints, see above
+        panic::set_hook(Box::new(|_| {}));
+        let ok = panic::catch_unwind(AssertUnwindSafe(|| child(decode, isolation, &mut file)))
+            .is_ok_and(|res| res.is_ok());
+        // SAFETY: _exit does not run destructors or atexit handlers of the parent's state
+        unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+    }
+
+    let mut status = 0;
+    // SAFETY: `pid` is our child and `status` is valid for writes
+    while unsafe { libc::waitpid(pid, &mut status, 0) } < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err).context("could not wait for decoder process");
+        }
+    }
+    if libc::WIFSIGNALED(status) {
+        match (libc::WTERMSIG(status), isolation.timeout) {
+            (libc::SIGXCPU | libc::SIGKILL, Some(timeout)) => {
+                bail!("decoding took longer than {timeout:?}")
+            }
+            (signal, _) => bail!("decoder crashed with signal {signal}"),
+        }
+    }
+    if libc::WEXITSTATUS(status) != 0 {
+        bail!("decoder failed unexpectedly");
+    }
+
+    read_result(&mut file, limits)
+}
+
+fn child(
+    decode: impl FnOnce() -> Result<DynamicImage>,
+    isolation: Isolation,
+    file: &mut File,
+) -> io::Result<()> {
+    // SAFETY: these prctl options take integer arguments
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+        libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0);
+    }
+    if let Some(timeout) = isolation.timeout {
+        // SIGXCPU at the soft limit, and SIGKILL at the hard limit if that is ignored
+        let secs = timeout.as_secs_f64().ceil().max(1.0) as libc::rlim_t;
+        let limit = libc::rlimit {
+            rlim_cur: secs,
+            rlim_max: secs + 1,
+        };
+        // SAFETY: `limit` is a valid rlimit
+        unsafe { libc::setrlimit(libc::RLIMIT_CPU, &limit) };
+    }
+
+    let mut out = BufWriter::new(file);
+    match (isolation.restrict)().and_then(|()| decode()) {
+        Ok(image @ (DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_))) => {
+            let image = image.into_rgba32f();
+            write_header(&mut out, RESULT_RGBA32F, image.width(), image.height())?;
+            for sample in image.as_raw() {
+                out.write_all(&sample.to_ne_bytes())?;
+            }
+        }
+        Ok(
+            image @ (DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageLumaA16(_)
+            | DynamicImage::ImageRgb16(_)
+            | DynamicImage::ImageRgba16(_)),
+        ) => {
+            let image = image.into_rgba16();
+            write_header(&mut out, RESULT_RGBA16, image.width(), image.height())?;
+            for sample in image.as_raw() {
+                out.write_all(&sample.to_ne_bytes())?;
+            }
+        }
+        Ok(image) => {
+            let image = image.into_rgba8();
+            write_header(&mut out, RESULT_RGBA8, image.width(), image.height())?;
+            out.write_all(image.as_raw())?;
+        }
+        Err(e) => {
+            write_header(&mut out, RESULT_ERROR, 0, 0)?;
+            out.write_all(format!("{e:#}").as_bytes())?;
+        }
+    }
+    out.flush()
+}
+
+fn write_header(out: &mut impl Write, kind: u8, width: u32, height: u32) -> io::Result<()> {
+    out.write_all(&[kind])?;
+    out.write_all(&width.to_ne_bytes())?;
+    out.write_all(&height.to_ne_bytes())
+}
+
+fn read_result(file: &mut File, limits: &Limits) -> Result<DynamicImage> {
+    let len = file.metadata()?.len();
+    // The child shares the file offset with us
+    file.rewind()?;
+    let mut header = [0; HEADER_LEN as usize];
+    file.read_exact(&mut header)
+        .context("decoder sent no result")?;
+    let width = u32::from_ne_bytes(header[1..5].try_into().unwrap());
+    let height = u32::from_ne_bytes(header[5..9].try_into().unwrap());
+
+    let sample_len = match header[0] {
+        RESULT_RGBA8 => 1,
+        RESULT_RGBA16 => 2,
+        RESULT_RGBA32F => 4,
+        RESULT_ERROR => {
+            let mut message = Vec::new();
+            file.take(MAX_ERROR_LEN).read_to_end(&mut message)?;
+            bail!("{}", String::from_utf8_lossy(&message));
+        }
+        _ => bail!("decoder sent an invalid result"),
+    };
+    limits
+        .check(width, height)
+        .context("decoder sent an image beyond the limits")?;
+    let pixels_len = width as u64 * height as u64 * 4 * sample_len;
+    ensure!(
+        len - HEADER_LEN == pixels_len,
+        "decoder sent a truncated image"
+    );
+    let mut rest = vec![0; pixels_len as usize];
+    file.read_exact(&mut rest)?;
+
+    match header[0] {
+        RESULT_RGBA8 => RgbaImage::from_raw(width, height, rest)
+            .map(DynamicImage::ImageRgba8)
+            .context("decoder sent a truncated image"),
+        RESULT_RGBA32F => {
+            let samples = rest
+                .chunks_exact(4)
+                .map(|s| f32::from_ne_bytes(s.try_into().unwrap()))
+                .collect();
+            Rgba32FImage::from_raw(width, height, samples)
+                .map(DynamicImage::ImageRgba32F)
+                .context("decoder sent a truncated image")
+        }
+        RESULT_RGBA16 => {
+            let samples = rest
+                .chunks_exact(2)
+                .map(|s| u16::from_ne_bytes(s.try_into().unwrap()))
+                .collect();
+            Rgba16Image::from_raw(width, height, samples)
+                .map(DynamicImage::ImageRgba16)
+                .context("decoder sent a truncated image")
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn create_memfd() -> io::Result<File> {
+    let name = c"reimv-decoder";
+    // SAFETY: the name is a valid C string
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the fd was just created and is not owned by anything else
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
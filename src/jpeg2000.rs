@@ -0,0 +1,93 @@
+//! JPEG 2000 images, as JP2 files or bare codestreams, decoded by `opj_decompress` from OpenJPEG.
+//!
+//! JPEG 2000 is common for archive and museum scans, but the `image` crate cannot decode it. Its
+//! wavelet codec is large, so the images are decoded by the command line tool of the reference
+//! implementation, which only reads and writes files with known extensions: the image is copied
+//! into a temporary directory and decoded to PNM there. The size is read here beforehand, to
+//! refuse images beyond the limits without decoding them.
+
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use image::DynamicImage;
+
+use crate::isolate;
+use crate::limits::Limits;
+use crate::pnm;
+use crate::temp::TempDir;
+
+const JP2_SIGNATURE: &[u8] = b"\0\0\0\x0cjP  \r\n\x87\n";
+const CODESTREAM_SIGNATURE: &[u8] = b"\xff\x4f\xff\x51";
+
+pub fn is_jpeg2000(data: &[u8]) -> bool {
+    data.starts_with(JP2_SIGNATURE) || data.starts_with(CODESTREAM_SIGNATURE)
+}
+
+/// The width and height, from the image header box of a JP2 file or the SIZ marker of a
+/// codestream.
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let u32_at = |data: &[u8], pos: usize| {
+        Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+    };
+    if data.starts_with(CODESTREAM_SIGNATURE) {
+        // The marker length and the capabilities come before the size and the offset
+        let (width, height) = (u32_at(data, 8)?, u32_at(data, 12)?);
+        let (x, y) = (u32_at(data, 16)?, u32_at(data, 20)?);
+        return Some((width.checked_sub(x)?, height.checked_sub(y)?));
+    }
+    let header = boxes(data).find_map(|(kind, contents)| (kind == b"jp2h").then_some(contents))?;
+    let ihdr = boxes(header).find_map(|(kind, contents)| (kind == b"ihdr").then_some(contents))?;
+    Some((u32_at(ihdr, 4)?, u32_at(ihdr, 0)?))
+}
+
+pub fn decode(data: &[u8], limits: &Limits) -> Result<DynamicImage> {
+    let (width, height) = dimensions(data).context("the image header is missing")?;
+    limits.check(width, height)?;
+
+    let dir = TempDir::new().context("could not create a temporary directory")?;
+    let extension = match data.starts_with(JP2_SIGNATURE) {
+        true => "jp2",
+        false => "j2k",
+    };
+    let input = dir.path().join(format!("image.{extension}"));
+    let output = dir.path().join("image.pnm");
+    std::fs::write(&input, data).context("could not write a temporary file")?;
+
+    let result = Command::new("opj_decompress")
+        .arg("-i")
+        .arg(&input)
+        .arg("-o")
+        .arg(&output)
+        // Chroma can have fewer samples, which would otherwise go into files of their own
+        .arg("-upsample")
+        .output()
+        .context("could not run opj_decompress, which is needed to show JPEG 2000 images")?;
+    if !result.status.success() {
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        let mut lines = stderr.lines().map(str::trim).filter(|l| !l.is_empty());
+        match lines.find(|line| line.starts_with("[ERROR]")) {
+            Some(line) => bail!("opj_decompress failed: {}", line["[ERROR]".len()..].trim()),
+            None => bail!("opj_decompress failed: {}", result.status),
+        }
+    }
+    let pnm = std::fs::read(&output).context("opj_decompress wrote no image")?;
+    isolate::run(limits, || pnm::decode(&pnm, limits).map(Into::into))
+}
+
+/// The boxes of a JP2 file or of a super box: their types and contents.
+fn boxes(mut data: &[u8]) -> impl Iterator<Item = (&[u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let len = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as u64;
+        let kind: &[u8; 4] = data.get(4..8)?.try_into().ok()?;
+        let (start, len) = match len {
+            // The box goes up to the end
+            0 => (8, data.len() as u64),
+            1 => (16, u64::from_be_bytes(data.get(8..16)?.try_into().ok()?)),
+            len => (8, len),
+        };
+        let end = usize::try_from(len).ok()?.min(data.len());
+        let contents = data.get(start..end)?;
+        data = &data[end..];
+        Some((kind, contents))
+    })
+}
@@ -0,0 +1,401 @@
+//! JSON, the format of the requests and replies on the IPC socket of reimv and of `reimv-ctl`.
+//!
+//! Numbers are `f64`, like in JavaScript, and objects keep their members in the order they were
+//! given or built in. Requests come from other programs, so nesting is limited to keep the parser
+//! from running out of stack.
+
+use std::fmt::{self, Display, Write};
+
+use anyhow::{bail, ensure, Context, Result};
+
+/// How deeply arrays and objects may be nested.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Parse a JSON text, which is a single value with nothing but whitespace around it.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        ensure!(
+            parser.pos == text.len(),
+            "unexpected text after the value at byte {}",
+            parser.pos
+        );
+        Ok(value)
+    }
+
+    /// An object with these members.
+    pub fn object<'a>(members: impl IntoIterator<Item = (&'a str, Value)>) -> Self {
+        Self::Object(
+            members
+                .into_iter()
+                .map(|(key, value)| (key.to_owned(), value))
+                .collect(),
+        )
+    }
+
+    /// The member `key` of an object. If it is given twice, the last one counts.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Self::Object(members) => members.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Self::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, Value)]> {
+        match self {
+            Self::Object(members) => Some(members),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Self::Number(value)
+    }
+}
+
+impl From<f32> for Value {
+    fn from(value: f32) -> Self {
+        Self::Number(value.into())
+    }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Self {
+        Self::Number(value as f64)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Self::Number(value as f64)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(values: Vec<Value>) -> Self {
+        Self::Array(values)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Self::Null, Into::into)
+    }
+}
+
+/// Compact JSON on a single line. Numbers which are not finite are written as `null`.
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(value) => write!(f, "{value}"),
+            // Whole numbers without a fraction, so that indices look like integers
+            Self::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Self::Number(n) if n.is_finite() => write!(f, "{n}"),
+            Self::Number(_) => f.write_str("null"),
+            Self::String(value) => write_string(f, value),
+            Self::Array(values) => {
+                f.write_char('[')?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                f.write_char(']')
+            }
+            Self::Object(members) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, text: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in text.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c < ' ' => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn value(&mut self, depth: usize) -> Result<Value> {
+        ensure!(depth < MAX_DEPTH, "too deeply nested");
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'-' | b'0'..=b'9') => self.number().map(Value::Number),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                if !self.eat(b']') {
+                    loop {
+                        values.push(self.value(depth + 1)?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Value::Array(values))
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                if !self.eat(b'}') {
+                    loop {
+                        self.skip_whitespace();
+                        ensure!(self.peek() == Some(b'"'), self.error("expected a key"));
+                        let key = self.string()?;
+                        self.expect(b':')?;
+                        members.push((key, self.value(depth + 1)?));
+                        if self.eat(b'}') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Value::Object(members))
+            }
+            Some(_) => bail!(self.error("expected a value")),
+            None => bail!("unexpected end of the text"),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value> {
+        ensure!(
+            self.text[self.pos..].starts_with(word.as_bytes()),
+            self.error("expected a value")
+        );
+        self.pos += word.len();
+        Ok(value)
+    }
+
+    /// A string, starting at its opening quote.
+    fn string(&mut self) -> Result<String> {
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let byte = self.peek().context("unclosed string")?;
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = self.peek().context("unclosed string")?;
+                    self.pos += 1;
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => bail!(self.error("invalid escape sequence")),
+                    };
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                ..b' ' => bail!(self.error("control character in a string")),
+                byte => bytes.push(byte),
+            }
+        }
+        // Only split around ASCII characters of the text, which is UTF-8
+        Ok(String::from_utf8(bytes).unwrap())
+    }
+
+    /// The character of a `\uXXXX` escape after the `u`, which may be followed by another one for
+    /// characters outside of the Basic Multilingual Plane.
+    fn unicode_escape(&mut self) -> Result<char> {
+        let high = self.hex4()?;
+        let code = match high {
+            0xd800..=0xdbff => {
+                ensure!(
+                    self.text[self.pos..].starts_with(b"\\u"),
+                    self.error("unpaired surrogate")
+                );
+                self.pos += 2;
+                let low = self.hex4()?;
+                ensure!(
+                    (0xdc00..=0xdfff).contains(&low),
+                    self.error("unpaired surrogate")
+                );
+                0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+            }
+            code => code,
+        };
+        char::from_u32(code).with_context(|| self.error("unpaired surrogate"))
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let digits = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .filter(|digits| digits.bytes().all(|b| b.is_ascii_hexdigit()))
+            .with_context(|| self.error("expected four hex digits"))?;
+        self.pos += 4;
+        Ok(u32::from_str_radix(digits, 16).unwrap())
+    }
+
+    fn number(&mut self) -> Result<f64> {
+        let start = self.pos;
+        self.eat_byte(b'-');
+        match self.peek() {
+            Some(b'0') => self.pos += 1,
+            Some(b'1'..=b'9') => self.digits(),
+            _ => bail!(self.error("expected a digit")),
+        }
+        if self.eat_byte(b'.') {
+            ensure!(
+                self.peek().is_some_and(|b| b.is_ascii_digit()),
+                self.error("expected a digit")
+            );
+            self.digits();
+        }
+        if self.eat_byte(b'e') || self.eat_byte(b'E') {
+            if !self.eat_byte(b'+') {
+                self.eat_byte(b'-');
+            }
+            ensure!(
+                self.peek().is_some_and(|b| b.is_ascii_digit()),
+                self.error("expected a digit")
+            );
+            self.digits();
+        }
+        // Only ASCII digits and signs
+        let number = std::str::from_utf8(&self.text[start..self.pos]).unwrap();
+        Ok(number.parse().unwrap())
+    }
+
+    fn digits(&mut self) {
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).copied()
+    }
+
+    fn eat_byte(&mut self, byte: u8) -> bool {
+        let found = self.peek() == Some(byte);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    /// Skip whitespace and `byte` if it follows.
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        self.eat_byte(byte)
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<()> {
+        ensure!(
+            self.eat(byte),
+            self.error(&format!("expected `{}`", byte as char))
+        );
+        Ok(())
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("{message} at byte {}", self.pos)
+    }
+}
@@ -0,0 +1,358 @@
+//! Keyboards, with their xkb keymaps and state.
+//!
+//! This is a copy of the keyboard wrapper from `wayrs-utils`, which ignores the keys that are
+//! already held down when the window gets the keyboard focus. Those are pressed here once the
+//! modifiers which follow the focus are known, so that holding an arrow while switching to the
+//! window moves the image at once and keeps repeating, as it does in other applications. Key
+//! repeat stops when the focus is lost, since the release of the key goes to another window.
+//!
+//! Bindings are found by the character a key types, see [`Bindings`]. They follow the active
+//! layout, and a key which repeats while the layout is switched is looked up again.
+//!
+//! Unless bindings are found by position, keys go through the compose table of the locale first,
+//! like in other applications, so that dead keys and compose sequences type what they do
+//! elsewhere: `´` and then `e` types `é`, as does Compose, `'`, `e`, and the keys which start a
+//! sequence type nothing by themselves.
+
+use std::ffi::OsString;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use wayrs_client::object::ObjectId;
+use wayrs_client::protocol::*;
+use wayrs_client::proxy::Proxy;
+use wayrs_client::Connection;
+use wayrs_utils::keyboard::RepeatInfo;
+
+use crate::{EventCtx, State};
+
+pub use wayrs_utils::keyboard::xkb;
+use xkb::compose;
+
+/// How the keys of bindings such as `h` and `n` are found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Bindings {
+    /// By the character the key types in the active layout, or in the first layout of the keymap
+    /// with a Latin character on that key, e.g. for Cyrillic layouts
+    #[default]
+    Layout,
+    /// By the position of the key, as the character it types on a US QWERTY keyboard, whatever
+    /// the layout is
+    Physical,
+}
+
+/// The characters of the keys of a US QWERTY keyboard, by evdev key code, without and with Shift.
+const QWERTY: &[(u32, char, char)] = &[
+    (2, '1', '!'),
+    (3, '2', '@'),
+    (4, '3', '#'),
+    (5, '4', '$'),
+    (6, '5', '%'),
+    (7, '6', '^'),
+    (8, '7', '&'),
+    (9, '8', '*'),
+    (10, '9', '('),
+    (11, '0', ')'),
+    (12, '-', '_'),
+    (13, '=', '+'),
+    (16, 'q', 'Q'),
+    (17, 'w', 'W'),
+    (18, 'e', 'E'),
+    (19, 'r', 'R'),
+    (20, 't', 'T'),
+    (21, 'y', 'Y'),
+    (22, 'u', 'U'),
+    (23, 'i', 'I'),
+    (24, 'o', 'O'),
+    (25, 'p', 'P'),
+    (26, '[', '{'),
+    (27, ']', '}'),
+    (30, 'a', 'A'),
+    (31, 's', 'S'),
+    (32, 'd', 'D'),
+    (33, 'f', 'F'),
+    (34, 'g', 'G'),
+    (35, 'h', 'H'),
+    (36, 'j', 'J'),
+    (37, 'k', 'K'),
+    (38, 'l', 'L'),
+    (39, ';', ':'),
+    (40, '\'', '"'),
+    (41, '`', '~'),
+    (43, '\\', '|'),
+    (44, 'z', 'Z'),
+    (45, 'x', 'X'),
+    (46, 'c', 'C'),
+    (47, 'v', 'V'),
+    (48, 'b', 'B'),
+    (49, 'n', 'N'),
+    (50, 'm', 'M'),
+    (51, ',', '<'),
+    (52, '.', '>'),
+    (53, '/', '?'),
+    (55, '*', '*'),
+    (57, ' ', ' '),
+    (74, '-', '-'),
+    (78, '+', '+'),
+    (98, '/', '/'),
+];
+
+pub struct Keyboard {
+    seat: WlSeat,
+    wl: WlKeyboard,
+    focused_surface: Option<ObjectId>,
+    xkb_context: xkb::Context,
+    xkb_state: Option<xkb::State>,
+    repeat_info: Option<RepeatInfo>,
+    /// The keys held down when the focus was gained, until the modifiers are known
+    entered_keys: Vec<xkb::Keycode>,
+    /// Missing if there is no compose table for the locale
+    compose: Option<compose::State>,
+}
+
+pub struct KeyboardEvent {
+    pub keycode: xkb::Keycode,
+    /// How the key is repeated, if it is repeated at all
+    pub repeat_info: Option<RepeatInfo>,
+    pub xkb_state: xkb::State,
+    /// What the compose sequence which the key finished types, like `é` for `´` and then `e`
+    pub composed: Option<String>,
+}
+
+impl KeyboardEvent {
+    /// The character of the key for bindings, or an empty string if it has none.
+    pub fn text(&self, bindings: Bindings) -> String {
+        let state = &self.xkb_state;
+        match bindings {
+            Bindings::Layout => {
+                if let Some(text) = &self.composed {
+                    return text.clone();
+                }
+                let text = state.key_get_utf8(self.keycode);
+                // With Control, the character is a control character, which nothing is bound to
+                if text.is_ascii() || state.mod_name_is_active(xkb::MOD_NAME_CTRL, MODS) {
+                    return text;
+                }
+                self.latin_text().unwrap_or(text)
+            }
+            Bindings::Physical => {
+                if state.mod_name_is_active(xkb::MOD_NAME_CTRL, MODS) {
+                    return String::new();
+                }
+                let Some(&(_, c, shifted)) = QWERTY
+                    .iter()
+                    .find(|(key, _, _)| keycode(*key) == self.keycode)
+                else {
+                    return String::new();
+                };
+                let shift = state.mod_name_is_active(xkb::MOD_NAME_SHIFT, MODS);
+                // Caps Lock only changes letters
+                let caps =
+                    c.is_ascii_alphabetic() && state.mod_name_is_active(xkb::MOD_NAME_CAPS, MODS);
+                match shift != caps {
+                    true => shifted.into(),
+                    false => c.into(),
+                }
+            }
+        }
+    }
+
+    /// The character of the key in the first layout with an ASCII character on it, at the shift
+    /// level of the current modifiers.
+    fn latin_text(&self) -> Option<String> {
+        let keymap = self.xkb_state.get_keymap();
+        (0..keymap.num_layouts_for_key(self.keycode)).find_map(|layout| {
+            let level = self.xkb_state.key_get_level(self.keycode, layout);
+            let &sym = keymap
+                .key_get_syms_by_level(self.keycode, layout, level)
+                .first()?;
+            let c = char::from_u32(xkb::keysym_to_utf32(sym))?;
+            (c.is_ascii() && !c.is_ascii_control()).then(|| c.into())
+        })
+    }
+}
+
+const MODS: xkb::StateComponent = xkb::STATE_MODS_EFFECTIVE;
+
+impl Keyboard {
+    /// Call this only when `wl_seat` advertises a keyboard capability.
+    pub fn new(conn: &mut Connection<State>, seat: WlSeat) -> Self {
+        let xkb_context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let compose =
+            compose::Table::new_from_locale(&xkb_context, &locale(), compose::COMPILE_NO_FLAGS)
+                .ok()
+                .map(|table| compose::State::new(&table, compose::STATE_NO_FLAGS));
+        Self {
+            seat,
+            wl: seat.get_keyboard_with_cb(conn, wl_keyboard_cb),
+            focused_surface: None,
+            xkb_context,
+            xkb_state: None,
+            repeat_info: None,
+            entered_keys: Vec::new(),
+            compose,
+        }
+    }
+
+    pub fn seat(&self) -> WlSeat {
+        self.seat
+    }
+
+    pub fn destroy(self, conn: &mut Connection<State>) {
+        if self.wl.version() >= 3 {
+            self.wl.release(conn);
+        }
+    }
+
+    fn event(&self, keycode: xkb::Keycode) -> Option<KeyboardEvent> {
+        self.focused_surface?;
+        let xkb_state = self.xkb_state.clone()?;
+        let repeat_info = match xkb_state.get_keymap().key_repeats(keycode) {
+            true => self.repeat_info,
+            false => None,
+        };
+        Some(KeyboardEvent {
+            keycode,
+            repeat_info,
+            xkb_state,
+            composed: None,
+        })
+    }
+
+    /// Feed a pressed key to the compose sequence. Returns `false` if the key only took part in
+    /// a sequence which is not finished yet, or which was cancelled.
+    fn compose(&mut self, event: &mut KeyboardEvent) -> bool {
+        let Some(compose) = &mut self.compose else {
+            return true;
+        };
+        let keysym = event.xkb_state.key_get_one_sym(event.keycode);
+        // Shift is held for capitals
+        if keysym.is_modifier_key() || compose.feed(keysym) != compose::FeedResult::Accepted {
+            return true;
+        }
+        match compose.status() {
+            compose::Status::Composing => false,
+            compose::Status::Cancelled => {
+                compose.reset();
+                false
+            }
+            compose::Status::Composed => {
+                event.composed = compose.utf8();
+                compose.reset();
+                true
+            }
+            compose::Status::Nothing => true,
+        }
+    }
+}
+
+/// The locale which the compose table is for, looked up like libc does.
+fn locale() -> OsString {
+    ["LC_ALL", "LC_CTYPE", "LANG"]
+        .into_iter()
+        .filter_map(std::env::var_os)
+        .find(|locale| !locale.is_empty())
+        .unwrap_or_else(|| "C".into())
+}
+
+/// Evdev key codes are 8 less than xkb key codes.
+fn keycode(key: u32) -> xkb::Keycode {
+    xkb::Keycode::new(key + 8)
+}
+
+fn wl_keyboard_cb(ctx: EventCtx<WlKeyboard>) {
+    let composes = ctx.state.composes();
+    let kbd = ctx
+        .state
+        .keyboards
+        .iter_mut()
+        .find(|k| k.wl == ctx.proxy)
+        .unwrap();
+
+    match ctx.event {
+        wl_keyboard::Event::Keymap(args) if args.format == wl_keyboard::KeymapFormat::XkbV1 => {
+            // SAFETY: the compositor sends a keymap of `size` bytes
+            let keymap = unsafe {
+                xkb::Keymap::new_from_fd(
+                    &kbd.xkb_context,
+                    args.fd,
+                    args.size as usize,
+                    xkb::FORMAT_TEXT_V1,
+                    xkb::KEYMAP_COMPILE_NO_FLAGS,
+                )
+            };
+            if let Ok(Some(keymap)) = keymap {
+                kbd.xkb_state = Some(xkb::State::new(&keymap));
+            }
+        }
+        wl_keyboard::Event::Enter(args) => {
+            kbd.focused_surface = Some(args.surface);
+            kbd.entered_keys = args
+                .keys
+                .chunks_exact(4)
+                .map(|key| keycode(u32::from_ne_bytes(key.try_into().unwrap())))
+                .collect();
+        }
+        wl_keyboard::Event::Leave(_) => {
+            kbd.focused_surface = None;
+            kbd.entered_keys.clear();
+            if let Some(compose) = &mut kbd.compose {
+                compose.reset();
+            }
+            ctx.state.kbd_repeat = None;
+        }
+        wl_keyboard::Event::Key(args) => {
+            let Some(mut event) = kbd.event(keycode(args.key)) else {
+                return;
+            };
+            match args.state {
+                wl_keyboard::KeyState::Released => ctx.state.key_released(event),
+                wl_keyboard::KeyState::Pressed if !composes || kbd.compose(&mut event) => {
+                    ctx.state.key_pressed(ctx.conn, event)
+                }
+                _ => (),
+            }
+        }
+        wl_keyboard::Event::Modifiers(args) => {
+            let mut layout_changed = false;
+            if let Some(xkb_state) = &mut kbd.xkb_state {
+                let layout = xkb_state.serialize_layout(xkb::STATE_LAYOUT_EFFECTIVE);
+                xkb_state.update_mask(
+                    args.mods_depressed,
+                    args.mods_latched,
+                    args.mods_locked,
+                    0,
+                    0,
+                    args.group,
+                );
+                layout_changed = xkb_state.serialize_layout(xkb::STATE_LAYOUT_EFFECTIVE) != layout;
+            }
+            // The key which repeats may mean something else in the new layout
+            let rebound = match (layout_changed, &ctx.state.kbd_repeat) {
+                (true, Some(repeat)) => kbd.event(repeat.key),
+                _ => None,
+            };
+            let events: Vec<_> = std::mem::take(&mut kbd.entered_keys)
+                .into_iter()
+                .filter_map(|key| kbd.event(key))
+                .collect();
+            if let Some(event) = rebound {
+                ctx.state.key_rebound(event);
+            }
+            for event in events {
+                ctx.state.key_pressed(ctx.conn, event);
+            }
+        }
+        wl_keyboard::Event::RepeatInfo(args) => {
+            if args.rate == 0 {
+                kbd.repeat_info = None;
+            } else if args.rate > 0 && args.delay > 0 {
+                kbd.repeat_info = Some(RepeatInfo {
+                    delay: Duration::from_millis(args.delay as u64),
+                    interval: Duration::from_micros(1_000_000 / args.rate as u64),
+                });
+            }
+        }
+        _ => (),
+    }
+}
@@ -0,0 +1,29 @@
+//! The decoders of reimv, and the JSON of its IPC socket, which `reimv-ctl` shares. They are a
+//! library of their own so that they can be fuzzed.
+
+#![allow(clippy::field_reassign_with_default)]
+
+pub mod archive;
+pub mod cmyk;
+pub mod color;
+pub mod decode;
+pub mod djvu;
+pub mod format;
+pub mod frames;
+pub mod hdr;
+pub mod isolate;
+pub mod jpeg2000;
+pub mod json;
+pub mod limits;
+pub mod metadata;
+pub mod pages;
+pub mod pdf;
+pub mod pnm;
+pub mod psd;
+pub mod pyramid;
+pub mod raw;
+pub mod smil;
+pub mod temp;
+pub mod video;
+pub mod xbm;
+pub mod xpm;
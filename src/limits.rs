@@ -0,0 +1,72 @@
+//! Limits on what a file may decode to, so that a small crafted file cannot make us allocate
+//! gigabytes or render forever.
+
+use std::io::Cursor;
+
+use anyhow::{ensure, Result};
+use image::{DynamicImage, ImageFormat};
+
+const MIB: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Maximum width and height of raster images
+    pub max_dimension: u32,
+    /// Maximum memory for the pixels of raster images and for decompressed SVG documents
+    pub max_bytes: u64,
+    /// Maximum number of SVG elements, after `<use>` elements are expanded
+    pub max_svg_nodes: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_dimension: 65535,
+            max_bytes: 1024 * MIB,
+            max_svg_nodes: 1_000_000,
+        }
+    }
+}
+
+impl Limits {
+    /// Check the size of a raster image before allocating its pixels.
+    pub fn check(&self, width: u32, height: u32) -> Result<()> {
+        let max = self.max_dimension;
+        ensure!(
+            width <= max && height <= max,
+            "the image is {width}x{height}, larger than the limit of {max}x{max}"
+        );
+        let bytes = width as u64 * height as u64 * 4;
+        ensure!(
+            bytes <= self.max_bytes,
+            "the image needs {} MiB, more than the limit of {} MiB",
+            bytes.div_ceil(MIB),
+            self.max_bytes / MIB
+        );
+        Ok(())
+    }
+
+    pub fn check_svg_len(&self, len: usize) -> Result<()> {
+        ensure!(
+            len as u64 <= self.max_bytes,
+            "the SVG document is larger than the limit of {} MiB",
+            self.max_bytes / MIB
+        );
+        Ok(())
+    }
+
+    /// Decode with the `image` crate, which also limits the memory used while decoding.
+    pub fn decode(&self, data: &[u8], format: ImageFormat) -> Result<DynamicImage> {
+        let reader = || image::io::Reader::with_format(Cursor::new(data), format);
+        let (width, height) = reader().into_dimensions()?;
+        self.check(width, height)?;
+
+        let mut limits = image::io::Limits::default();
+        limits.max_image_width = Some(self.max_dimension);
+        limits.max_image_height = Some(self.max_dimension);
+        limits.max_alloc = Some(self.max_bytes);
+        let mut reader = reader();
+        reader.limits(limits);
+        Ok(reader.decode()?)
+    }
+}
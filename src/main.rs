@@ -1,16 +1,85 @@
 #![allow(clippy::field_reassign_with_default)]
 
+mod animation;
+mod backend;
+mod blend;
+mod cache;
+mod config;
+mod convert;
+mod crash;
+mod current;
+mod download;
+mod error;
+mod files;
+mod frame;
 mod globals;
+mod guides;
+mod hdr_output;
 mod image;
+mod inspect;
+mod ipc;
+mod keyboard;
+mod measure;
+mod overlay;
+mod pan;
+mod persist;
+mod portal;
+mod power;
+mod preload;
+mod present;
+mod pressure;
+mod prompt;
+mod protocols;
+mod recent;
+#[cfg(feature = "sandbox")]
+mod sandbox;
+mod settle;
+mod shm;
+mod sync;
+mod template;
+mod tiles;
+mod trace;
+mod trash;
+mod watch;
 mod window;
 
+use reimv::{decode, format, frames, hdr, isolate, limits, metadata, pages, pnm, pyramid};
+
+use std::ffi::OsString;
 use std::io::{self, ErrorKind};
 use std::os::fd::{AsRawFd, RawFd};
-use std::time::Duration;
-
-use crate::image::{Image, ImageTransform};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::image::{integer_scale, next_integer_scale, DecodeOptions, Image, ImageTransform};
+use animation::{KeyRepeat, Sequence, Slideshow, ViewAnimation};
+use config::{Background, Config, Fit, Settings, Upscale};
+use current::CurrentLink;
+use download::Download;
+use error::{DecodeError, WaylandError};
+use files::{AtEnd, Entry, FileList, Filter, Random, Sort, Step};
 use globals::Globals;
-use wayrs_utils::timer::Timer;
+use guides::Guide;
+use hdr::ToneMapping;
+use hdr_output::HdrOutput;
+use inspect::Inspect;
+use ipc::Ipc;
+use keyboard::{xkb, Bindings, Keyboard, KeyboardEvent};
+use limits::Limits;
+use measure::Measure;
+use overlay::Overlay;
+use persist::{FileState, Session, View};
+use portal::FileChooser;
+use preload::Preload;
+use present::Present;
+use pressure::Pressure;
+use prompt::{Input, Prompt};
+use settle::Settle;
+use shm::ShmAlloc;
+use sync::SyncGroup;
+use template::{Info, Template};
+use trace::Trace;
+use watch::{Change, Directories, Watch};
 use window::Window;
 
 use wayrs_client::global::{Global, GlobalExt};
@@ -18,47 +87,534 @@ use wayrs_client::protocol::*;
 use wayrs_client::proxy::Proxy;
 use wayrs_client::{Connection, IoMode};
 use wayrs_protocols::pointer_gestures_unstable_v1::*;
+use wayrs_protocols::wlr_output_power_management_unstable_v1::*;
 use wayrs_utils::cursor::{CursorImage, CursorShape, CursorTheme, ThemedPointer};
-use wayrs_utils::keyboard::{xkb, Keyboard, KeyboardEvent, KeyboardHandler};
 use wayrs_utils::seats::{SeatHandler, Seats};
-use wayrs_utils::shm_alloc::ShmAlloc;
 
-use anyhow::{bail, Result};
-use clap::Parser;
+use anyhow::{ensure, Context, Result};
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
 
 type EventCtx<'a, P> = wayrs_client::EventCtx<'a, State, P>;
 
 /// Simple native Wayland image viewer that works
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+// Later values replace earlier ones, such as those from the config file
+#[command(author, version, about, long_about = None, args_override_self = true)]
 struct CliArgs {
-    /// The path of the image
-    file: String,
+    /// The paths of the images, - to read one from stdin, or http(s) URLs to download
+    #[arg(required_unless_present_any = ["dump_config", "sequence", "stdin_files", "resume"])]
+    files: Vec<String>,
+    /// Also show the files listed on stdin, one per line, like the output of `find` or `fd`
+    #[arg(long, conflicts_with = "sequence")]
+    stdin_files: bool,
+    /// Separate the files listed on stdin, and those printed with `--print-marked`, by NUL
+    /// characters, like `find -print0` does
+    #[arg(short = '0', long)]
+    null: bool,
+    /// Print the paths of the images marked with `m` to stdout when the window is closed, one
+    /// per line, so that reimv can pick files in a pipeline
+    #[arg(short = 'o', long, env = "REIMV_PRINT_MARKED")]
+    print_marked: bool,
+    /// Write the paths of the marked images to this file when the window is closed, replacing it
+    #[arg(long, env = "REIMV_MARKED_FILE", value_name = "FILE")]
+    marked_file: Option<PathBuf>,
+    /// Play the files matching this pattern as an animation, in the order of their numbers.
+    /// The file name may contain a number like `%04d`, or `*` and `?`, e.g. 'render/frame_%04d.png'
+    #[arg(long, value_name = "PATTERN", conflicts_with = "files")]
+    sequence: Option<String>,
+    /// How many frames of `--sequence` are shown per second
+    #[arg(long, env = "REIMV_FPS", default_value_t = 24.0, value_parser = animation::parse_fps)]
+    fps: f32,
+    /// Also show the images in the subdirectories of the given directories
+    #[arg(short, long, env = "REIMV_RECURSIVE")]
+    recursive: bool,
+    /// Show the files of directories which match these patterns, like '*.jpg', instead of the
+    /// images among them. Patterns with a slash match the whole path, and ** also matches slashes
+    #[arg(
+        long,
+        env = "REIMV_INCLUDE",
+        value_name = "PATTERN",
+        value_delimiter = ','
+    )]
+    include: Vec<String>,
+    /// Leave out the files and directories which match these patterns, like '**/thumbnails'
+    #[arg(
+        long,
+        env = "REIMV_EXCLUDE",
+        value_name = "PATTERN",
+        value_delimiter = ','
+    )]
+    exclude: Vec<String>,
+    /// The order of the images, and of those in directories
+    #[arg(long, env = "REIMV_SORT", value_enum, default_value_t)]
+    sort: Sort,
+    /// Show the images in the reverse order of `--sort`
+    #[arg(long, env = "REIMV_REVERSE")]
+    reverse: bool,
+    /// Show the images in a random order instead of the one of `--sort`
+    #[arg(long, env = "REIMV_SHUFFLE")]
+    shuffle: bool,
+    /// Move on to the next image every this many seconds. `s` starts and stops it, every 5
+    /// seconds if this is not given
+    #[arg(
+        long,
+        env = "REIMV_SLIDESHOW",
+        value_name = "SECONDS",
+        value_parser = animation::parse_interval,
+        conflicts_with = "sequence"
+    )]
+    slideshow: Option<Duration>,
+    /// Show the image again when its file changes, e.g. as the preview of a script which
+    /// renders it, and add and remove the files of the directories given as they come and go
+    #[arg(long, env = "REIMV_WATCH", conflicts_with = "sequence")]
+    watch: bool,
+    /// Show the files which are added to the directories with `--watch` right away
+    #[arg(long, env = "REIMV_JUMP_TO_NEW")]
+    jump_to_new: bool,
+    /// What moving past the last image does
+    #[arg(long, env = "REIMV_AT_END", value_enum, default_value_t)]
+    at_end: AtEnd,
+    /// The shell command to run with `--at-end hook`, which gets the path of the last image in
+    /// $REIMV_FILE
+    #[arg(
+        long,
+        env = "REIMV_END_HOOK",
+        value_name = "COMMAND",
+        required_if_eq("at_end", "hook")
+    )]
+    end_hook: Option<String>,
+    /// How long a file which is still being written, or has changed with `--watch`, has to stay
+    /// the same before it is shown, so that it is not shown halfway
+    #[arg(
+        long,
+        env = "REIMV_SETTLE_DELAY",
+        value_name = "MILLISECONDS",
+        default_value_t = 200
+    )]
+    settle_delay: u64,
+    /// The window title, with variables such as {name} in braces, see the README. By default
+    /// the name, page and position of the image
+    #[arg(long, env = "REIMV_TITLE", value_name = "TEMPLATE")]
+    title: Option<Template>,
+    /// Show this text in the top left corner, with the same variables as `--title`
+    #[arg(long, env = "REIMV_OSD", value_name = "TEMPLATE")]
+    osd: Option<Template>,
+    /// How keys are matched to bindings: by the character they type in the keyboard layout, or
+    /// by their position on a US QWERTY keyboard
+    #[arg(long, env = "REIMV_BINDINGS", value_enum, default_value_t)]
+    bindings: Bindings,
+    /// What pinching on a touchpad does besides zooming
+    #[arg(long, env = "REIMV_PINCH", value_enum, default_value_t)]
+    pinch: Pinch,
+    /// How fast pinching zooms, e.g. 0.5 for half as fast
+    #[arg(
+        long,
+        env = "REIMV_PINCH_SENSITIVITY",
+        value_name = "FACTOR",
+        default_value_t = 1.0,
+        value_parser = parse_sensitivity
+    )]
+    pinch_sensitivity: f32,
+    /// Keep the zoom and position when moving to another image of the same size, instead of
+    /// showing it anew as `--fit` says. `v` turns it on and off
+    #[arg(long, env = "REIMV_KEEP_VIEW")]
+    keep_view: bool,
+    /// Start at the image which the last session in the same directory ended at, with its zoom
+    /// and position. Without files, reopen the directory of the last session
+    #[arg(long, env = "REIMV_RESUME", conflicts_with = "sequence")]
+    resume: bool,
+    /// The directories which 1 to 9 move the current file into, e.g. to sort photos into the
+    /// ones to keep and those to throw away
+    #[arg(long, env = "REIMV_MOVE_TO", value_name = "DIR", value_delimiter = ',')]
+    move_to: Vec<String>,
+    /// The directories which Alt+1 to Alt+9 copy the current file into
+    #[arg(long, env = "REIMV_COPY_TO", value_name = "DIR", value_delimiter = ',')]
+    copy_to: Vec<String>,
+    /// Only zoom to whole numbers of display pixels per pixel of the image, or of pixels of the
+    /// image per display pixel when zoomed out. `I` turns it on and off
+    #[arg(long, env = "REIMV_INTEGER_ZOOM")]
+    integer_zoom: bool,
+    /// Keep the image within the window when it is panned: inside it if it is smaller, and
+    /// covering it otherwise. It can be dragged a bit past the edges and springs back
+    #[arg(long, env = "REIMV_CLAMP_PAN")]
+    clamp_pan: bool,
+    /// Leave out motion which is only there to look nice, so that images stop at the edges of
+    /// `--clamp-pan` instead of springing back
+    #[arg(long, env = "REIMV_REDUCED_MOTION")]
+    reduced_motion: bool,
+    /// Start in presentation mode, see `P`
+    #[arg(long, env = "REIMV_PRESENT")]
+    present: bool,
+    /// How large images are when they are shown
+    #[arg(long, env = "REIMV_FIT", value_enum, default_value_t)]
+    fit: Fit,
+    /// How images smaller than the window are shown, instead of as `--fit` says
+    #[arg(long, env = "REIMV_UPSCALE_SMALL_IMAGES", value_enum)]
+    upscale_small_images: Option<Upscale>,
+    /// The color around the image, as #rrggbb or #rrggbbaa, or checkerboard to also show it
+    /// through transparent parts
+    #[arg(long, env = "REIMV_BACKGROUND", value_name = "COLOR", value_parser = config::parse_background)]
+    background: Option<Background>,
+    /// Read the settings for matching images from this file instead of
+    /// $XDG_CONFIG_HOME/reimv/config.toml
+    #[arg(long, env = "REIMV_CONFIG", value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Mirror zoom and pan, and moving through the file list, with other instances started with
+    /// the same group name
+    #[arg(long, env = "REIMV_SYNC_GROUP", value_name = "NAME")]
+    sync_group: Option<String>,
+    /// How HDR images (OpenEXR, Radiance HDR) are mapped to the display range, unless the
+    /// compositor can show them as they are
+    #[arg(long, env = "REIMV_TONE_MAPPING", value_enum, default_value_t)]
+    tone_mapping: ToneMapping,
+    /// Advise the kernel to back large image buffers with transparent hugepages
+    #[arg(long, env = "REIMV_HUGEPAGES")]
+    hugepages: bool,
+    /// Decode images in a separate process, so that malicious files cannot affect the viewer
+    #[arg(long, env = "REIMV_ISOLATE_DECODERS")]
+    isolate_decoders: bool,
+    /// Stop decoding after this much CPU time
+    #[arg(
+        long,
+        env = "REIMV_DECODE_TIMEOUT",
+        value_name = "SECONDS",
+        requires = "isolate_decoders"
+    )]
+    decode_timeout: Option<f64>,
+    /// Refuse images wider or taller than this
+    #[arg(long, env = "REIMV_MAX_DIMENSION", value_name = "PIXELS", default_value_t = Limits::default().max_dimension)]
+    max_dimension: u32,
+    /// Refuse images which need more memory than this
+    #[arg(long, env = "REIMV_MAX_MEMORY", value_name = "MIB", default_value_t = Limits::default().max_bytes >> 20)]
+    max_memory: u64,
+    /// Decode raster images with more pixels than this at a reduced resolution, which needs much
+    /// less memory, and at full resolution once they are zoomed in
+    #[arg(long, env = "REIMV_DOWNSCALE_ABOVE", value_name = "MEGAPIXELS")]
+    downscale_above: Option<u32>,
+    /// Keep large images scaled down in a cache on disk of at most this size, so that they are
+    /// shown at once when they are opened again
+    #[arg(long, env = "REIMV_DISK_CACHE", value_name = "MIB")]
+    disk_cache: Option<u64>,
+    /// Keep the images which have been shown decoded in memory, up to this size, so that going
+    /// back to them shows them at once. 0 keeps none
+    #[arg(
+        long,
+        env = "REIMV_MEMORY_CACHE",
+        value_name = "MIB",
+        default_value_t = 256
+    )]
+    memory_cache: u64,
+    /// Don't decode the next and the previous image in the background while one is shown, which
+    /// takes memory for two more images
+    #[arg(long, env = "REIMV_NO_PRELOAD")]
+    no_preload: bool,
+    /// Refuse SVG images with more elements than this
+    #[arg(long, env = "REIMV_MAX_SVG_NODES", value_name = "COUNT", default_value_t = Limits::default().max_svg_nodes)]
+    max_svg_nodes: u32,
+    /// Show images as they are, instead of converting them from their embedded color profile to
+    /// sRGB
+    #[arg(long, env = "REIMV_NO_COLOR_MANAGEMENT")]
+    no_color_management: bool,
+    /// Connect to this Wayland display instead of $WAYLAND_DISPLAY, e.g. a headless compositor
+    #[arg(long, env = "REIMV_WAYLAND_DISPLAY", value_name = "NAME")]
+    wayland_display: Option<String>,
+    /// Print the messages between reimv and the compositor to stderr as JSON lines, only those on
+    /// objects of these interfaces if any are given
+    #[arg(
+        long,
+        value_name = "INTERFACES",
+        num_args = 0..=1,
+        require_equals = true,
+        value_delimiter = ','
+    )]
+    protocol_trace: Option<Vec<String>>,
+    /// Exit once the full image has been shown, e.g. to take screenshots in automated tests.
+    /// Lost connections are not retried
+    #[arg(long, env = "REIMV_EXIT_AFTER_FIRST_FRAME")]
+    exit_after_first_frame: bool,
+    /// Render the same on every system, for comparing snapshots in tests: an opaque background,
+    /// a fixed font for labels, buffer scale 1, only 8-bit buffers and animations frozen at
+    /// their start
+    #[arg(long, env = "REIMV_DETERMINISTIC")]
+    deterministic: bool,
+    /// Accept JSON-RPC commands, like from reimv-ctl, on a Unix socket at this path
+    #[arg(long, env = "REIMV_IPC_SOCKET", value_name = "PATH")]
+    ipc_socket: Option<PathBuf>,
+    /// Keep $XDG_RUNTIME_DIR/reimv/current a symlink to the file of the current image, for
+    /// scripts
+    #[arg(long, env = "REIMV_CURRENT_LINK")]
+    current_link: bool,
+    /// Print the effective configuration, with where each value comes from, and exit
+    #[arg(long)]
+    dump_config: bool,
+}
+
+impl CliArgs {
+    fn filter(&self) -> Filter {
+        Filter {
+            recursive: self.recursive,
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            max_dimension: self.max_dimension,
+            max_bytes: self.max_memory << 20,
+            max_svg_nodes: self.max_svg_nodes,
+        }
+    }
+
+    /// The settings of images which are not changed by the config file.
+    fn settings(&self) -> Settings {
+        Settings {
+            decode: DecodeOptions {
+                tone_mapping: self.tone_mapping,
+                limits: self.limits(),
+                color_management: !self.no_color_management,
+                max_pixels: self.downscale_above.map(|mp| mp as u64 * 1_000_000),
+                disk_cache: self.disk_cache.map(|mib| mib << 20),
+            },
+            fit: self.fit,
+            upscale: self.upscale_small_images,
+            background: self.background,
+        }
+    }
 }
 
+/// How many times to try reconnecting after the connection to the compositor was lost.
+const RECONNECT_ATTEMPTS: u32 = 10;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Losing a connection older than this is not counted as a failed attempt.
+const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
 fn main() -> Result<()> {
-    let cli_args = CliArgs::parse();
+    let Some((cli_args, config)) = parse_args()? else {
+        return Ok(());
+    };
+    crash::install_hook();
+    // Kept across reconnects, so that the same image is shown again
+    let paths = match &cli_args.sequence {
+        Some(pattern) => files::sequence(pattern)?,
+        None if cli_args.stdin_files => {
+            ensure!(
+                !cli_args.files.iter().any(|path| path == "-"),
+                "- cannot be shown with --stdin-files, which reads stdin already"
+            );
+            let mut paths = cli_args.files.clone();
+            paths.extend(files::read_list(io::stdin().lock(), cli_args.null)?);
+            paths
+        }
+        None if cli_args.resume && cli_args.files.is_empty() => {
+            vec![Session::last().context("there is no session to resume")?]
+        }
+        None => cli_args.files.clone(),
+    };
+    // The frames of a sequence keep the order of their numbers
+    let mut files = match cli_args.sequence {
+        Some(_) => FileList::new(&paths, &Filter::default(), Sort::None, false)?,
+        None => FileList::new(&paths, &cli_args.filter(), cli_args.sort, cli_args.reverse)?,
+    };
+    if cli_args.shuffle && cli_args.sequence.is_none() {
+        files.shuffle(&mut Random::new(cli_args.deterministic));
+    }
+    let session = match cli_args.sequence {
+        Some(_) => None,
+        None => persist::session_dir(&paths),
+    };
+    let mut resume_view = None;
+    if let Some((dir, last)) = session
+        .as_deref()
+        .filter(|_| cli_args.resume)
+        .and_then(|dir| Some((dir, Session::load(dir)?)))
+    {
+        // Unless the image is gone, or no longer matches the filters
+        if let Some(index) = files.find(&Path::new(dir).join(&last.file)) {
+            files.start_at(index);
+            resume_view = last
+                .view
+                .map(|view| (files.current().path().to_owned(), view));
+        }
+    }
+    crash::set_path(&files.current().name());
+    if cli_args.isolate_decoders {
+        let timeout = cli_args
+            .decode_timeout
+            .map(|secs| Duration::try_from_secs_f64(secs).context("invalid decode timeout"))
+            .transpose()?;
+        #[cfg(feature = "sandbox")]
+        isolate::enable(sandbox::restrict_decoder, timeout);
+        #[cfg(not(feature = "sandbox"))]
+        isolate::enable(|| Ok(()), timeout);
+    }
+    if let Some(display) = &cli_args.wayland_display {
+        // A name in $XDG_RUNTIME_DIR or an absolute path, like the variable itself
+        std::env::set_var("WAYLAND_DISPLAY", display);
+    }
+    // Points $WAYLAND_DISPLAY to its own socket, until reimv exits
+    let _trace = cli_args
+        .protocol_trace
+        .clone()
+        .map(Trace::start)
+        .transpose()
+        .context("could not start the protocol trace")?;
+
+    // Opened before the sandbox is entered, and kept across reconnects like the files
+    let mut pressure = match cli_args.deterministic {
+        true => None,
+        false => Pressure::new(),
+    };
+    // Whether we have tried to reconnect since the last successful connection
+    let mut attempts = None;
+    loop {
+        let started = Instant::now();
+        // A new connection shows the image anew
+        let resume_view = resume_view.take();
+        let Err(err) = run(
+            &cli_args,
+            &config,
+            &mut files,
+            &mut pressure,
+            session.as_deref(),
+            resume_view,
+        ) else {
+            return write_marked(&cli_args, &files);
+        };
+        let err = err.downcast::<WaylandError>()?;
+        let attempt = match (&err, attempts) {
+            // Tests should fail rather than wait for the compositor
+            _ if cli_args.exit_after_first_frame => None,
+            (WaylandError::Lost(_), _) if started.elapsed() >= STABLE_CONNECTION => Some(1),
+            (WaylandError::Lost(_), None) => Some(1),
+            (_, Some(n)) if n < RECONNECT_ATTEMPTS => Some(n + 1),
+            _ => None,
+        };
+        let Some(attempt) = attempt else {
+            return Err(match err.hint() {
+                Some(hint) => anyhow::Error::from(err).context(hint),
+                None => err.into(),
+            });
+        };
+        attempts = Some(attempt);
+        eprintln!(
+            "reimv: {:#}, reconnecting ({attempt}/{RECONNECT_ATTEMPTS})",
+            anyhow::Error::from(err)
+        );
+        std::thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+/// Print the marked images for `--print-marked` and write them to `--marked-file`.
+fn write_marked(cli_args: &CliArgs, files: &FileList) -> Result<()> {
+    let marked = files.marked();
+    if cli_args.print_marked {
+        files::write_list(io::stdout().lock(), &marked, cli_args.null)
+            .context("could not print the marked images")?;
+    }
+    if let Some(path) = &cli_args.marked_file {
+        let result = std::fs::File::create(path)
+            .and_then(|file| files::write_list(io::BufWriter::new(file), &marked, cli_args.null));
+        result
+            .with_context(|| format!("could not write the marked images to {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Parse the command line, with the options of the config file as defaults. Returns `None` after
+/// printing the configuration for `--dump-config`.
+fn parse_args() -> Result<Option<(CliArgs, Config)>> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let command = CliArgs::command();
+    // Only for finding the config file, which may complete the arguments
+    let given = command.clone().ignore_errors(true).get_matches_from(&args);
+    let config = Config::load(given.get_one::<PathBuf>("config").map(PathBuf::as_path))?;
+    let matches = command
+        .clone()
+        .get_matches_from(config.layer_args(&command, args)?);
+    let cli_args = CliArgs::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if cli_args.dump_config {
+        print!("{}", config.dump(&command, &matches, &given));
+        return Ok(None);
+    }
+    Ok(Some((cli_args, config)))
+}
+
+/// Show the window until it is closed.
+fn run(
+    cli_args: &CliArgs,
+    config: &Config,
+    files: &mut FileList,
+    pressure: &mut Option<Pressure>,
+    session: Option<&str>,
+    resume_view: Option<(String, View)>,
+) -> Result<()> {
+    let sync = cli_args
+        .sync_group
+        .as_deref()
+        .map(SyncGroup::join)
+        .transpose()?;
+    let ipc = cli_args.ipc_socket.as_deref().map(Ipc::bind).transpose()?;
+    let current_link = cli_args.current_link.then(CurrentLink::new).transpose()?;
+    let watch = cli_args
+        .watch
+        .then(|| Watch::new(Duration::from_millis(cli_args.settle_delay)))
+        .transpose()
+        .context("could not watch files for changes")?;
+    let directories = match cli_args.watch && !files.directories().is_empty() {
+        true => Some(
+            Directories::new(files.directories())
+                .context("could not watch the directories for new files")?,
+        ),
+        false => None,
+    };
 
-    let (mut conn, wl_globals) = Connection::connect_and_collect_globals()?;
+    let (mut conn, wl_globals) =
+        Connection::connect_and_collect_globals().map_err(WaylandError::Connect)?;
     conn.add_registry_cb(wl_registry_cb);
 
+    crash::set_compositor(conn.as_raw_fd());
+
     let globals = Globals::bind(&mut conn, &wl_globals)?;
-    let mut shm_alloc = ShmAlloc::bind(&mut conn, &wl_globals)?;
-    let window = Window::new(&mut conn, &globals, format!("{} - reimv", cli_args.file));
+    crash::set_globals(&globals);
+    let shm_alloc = ShmAlloc::new(globals.wl_shm, cli_args.hugepages);
+    let cursor_shm = wayrs_utils::shm_alloc::ShmAlloc::new(globals.wl_shm);
+    let window = Window::new(&mut conn, &globals, cli_args.deterministic);
+
+    #[cfg(feature = "sandbox")]
+    if let Err(e) = sandbox::enter(
+        &files.paths(),
+        sync.is_some(),
+        cli_args.ipc_socket.as_deref(),
+        cli_args.disk_cache.is_some(),
+        current_link.is_some(),
+    ) {
+        eprintln!("reimv: could not enter the sandbox: {e:#}");
+    }
 
-    let backend = Image::from_file(
-        &cli_args.file,
+    // Animations depend on timing, so deterministic images only show their start
+    let backend = Image::new(
         window.surface,
         &globals,
-        &mut shm_alloc,
         &mut conn,
-    )?;
+        cli_args.deterministic,
+        cli_args.sequence.is_some(),
+        cli_args.memory_cache << 20,
+    );
+    // Created after the image, so that it is stacked above it
+    let overlay = Overlay::new(&mut conn, &globals, window.surface);
     let cursor_theme = CursorTheme::new(&mut conn, &wl_globals, globals.wl_compositor);
 
+    let file_state = FileState::load(files.current().path());
+    let view_size = backend.size();
+
     let mut state = State {
+        files: files.clone(),
         globals,
         shm_alloc,
+        cursor_shm,
         backend,
+        overlay,
 
         default_cursor: cursor_theme.get_image(CursorShape::Default)?,
         move_cursor: cursor_theme.get_image(CursorShape::Move)?,
@@ -79,66 +635,339 @@ fn main() -> Result<()> {
         },
 
         move_transaction: None,
+        view_animation: None,
+        sequence: cli_args
+            .sequence
+            .as_ref()
+            .map(|_| Sequence::new(cli_args.fps, cli_args.deterministic)),
+        slideshow: Slideshow::new(
+            cli_args.slideshow.unwrap_or(animation::SLIDESHOW_INTERVAL),
+            cli_args.slideshow.is_none() || cli_args.deterministic,
+        ),
         kbd_repeat: None,
+        measure: None,
+        inspect: None,
+        present: cli_args.present.then(Present::default),
+        guides: file_state.guides,
+        delete_prompt: None,
+        rename_prompt: None,
+        count: None,
+        session: session.map(str::to_owned),
+        resume_view,
+
+        sync,
+        navigated: false,
+        ipc,
+        current_link,
+        preload: (!cli_args.no_preload).then(Preload::new),
+        pressure: pressure.take(),
+        watch,
+        directories,
+        jump_to_new: cli_args.jump_to_new,
+        download: None,
+        settle: None,
+        settle_delay: Duration::from_millis(cli_args.settle_delay),
+        file_chooser: None,
+        hdr_output: HdrOutput::default(),
+        config: config.clone(),
+        defaults: cli_args.settings(),
+        settings: cli_args.settings(),
+        at_end: cli_args.at_end,
+        bindings: cli_args.bindings,
+        pinch: cli_args.pinch,
+        pinch_sensitivity: cli_args.pinch_sensitivity,
+        keep_view: cli_args.keep_view,
+        view_size,
+        pending_view: None,
+        integer_zoom: cli_args.integer_zoom,
+        unsnapped_scale: None,
+        clamp_pan: cli_args.clamp_pan,
+        reduced_motion: cli_args.reduced_motion,
+        move_to: cli_args
+            .move_to
+            .iter()
+            .map(|dir| config::expand_home(dir))
+            .collect(),
+        copy_to: cli_args
+            .copy_to
+            .iter()
+            .map(|dir| config::expand_home(dir))
+            .collect(),
+        random: Random::new(cli_args.deterministic),
+        end_hook: cli_args.end_hook.clone(),
+        title: cli_args.title.clone(),
+        osd: cli_args.osd.clone(),
+        exit_after_first_frame: cli_args.exit_after_first_frame,
+        deterministic: cli_args.deterministic,
     };
 
     wl_globals
         .iter()
         .filter(|g| g.is::<WlOutput>())
         .for_each(|g| state.bind_output(&mut conn, g));
+    match state.load_current(&mut conn) {
+        Ok(()) => state.update_title(&mut conn),
+        // Rather than an empty window, show the next image which can be shown
+        Err(e) if state.files.position().1 > 1 => state.navigate(&mut conn, 1, vec![e]),
+        Err(e) => {
+            state.overlay.message = Some(e.to_string());
+            state.update_title(&mut conn);
+        }
+    }
+    // Every member of a sync group finds the first image it can show on its own
+    state.navigated = false;
 
-    conn.flush(IoMode::Blocking)?;
+    Window::render(&mut state, &mut conn);
+    conn.flush(IoMode::Blocking).map_err(WaylandError::Lost)?;
+
+    let result = event_loop(&mut state, &mut conn);
+    if result.is_ok() {
+        state.save_session();
+    }
+    *pressure = state.pressure.take();
+    *files = state.files;
+    result
+}
 
+/// Handle events until the window is closed.
+fn event_loop(state: &mut State, conn: &mut Connection<State>) -> Result<()> {
     while !state.window.closed {
-        let timeout = state.kbd_repeat.as_ref().map(|k| k.timer.sleep());
-        poll(conn.as_raw_fd(), timeout)?;
+        // Nobody sees the animation
+        let displays_off = state.displays_off();
+        state.backend.pause_animation(displays_off);
+        if let Some(sequence) = &mut state.sequence {
+            sequence.hide(displays_off);
+        }
+        let interacting = state.interacting();
+        if interacting {
+            state.slideshow.restart();
+        }
+        state.hold_preload(interacting);
+        state.slideshow.hide(displays_off);
+        let timeout = [
+            state.kbd_repeat.as_ref().and_then(|k| k.timer.sleep()),
+            state.backend.animation_timeout(),
+            state.view_animation.as_ref().and_then(ViewAnimation::sleep),
+            state.sequence.as_ref().and_then(Sequence::sleep),
+            state.settle.as_ref().and_then(Settle::sleep),
+            state.slideshow.sleep(),
+            state.watch.as_ref().and_then(Watch::sleep),
+            state.pressure.as_ref().and_then(Pressure::sleep),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let sync_fd = state.sync.as_ref().map(|s| s.as_raw_fd());
+        let ipc_fd = state.ipc.as_ref().map(|i| i.as_raw_fd());
+        let download_fd = state.download.as_ref().map(Download::fd);
+        let file_chooser_fd = state.file_chooser.as_ref().map(FileChooser::fd);
+        let watch_fd = state.watch.as_mut().map(|watch| {
+            // Only files on disk can change
+            let path = match state.files.current() {
+                Entry::File(path) if path != "-" => Some(Path::new(path)),
+                _ => None,
+            };
+            watch.follow(path);
+            watch.fd()
+        });
+        let directories_fd = state.directories.as_ref().map(Directories::fd);
+        let pressure_fd = state.pressure.as_ref().map(Pressure::fd);
+        // Uploading the decoded image takes a while, so don't do it in the middle of a gesture
+        let decode_fd = state.backend.pending_fd().filter(|_| !state.interacting());
+        // Frames are drawn by advance_animation below
+        let animation_fd = state.backend.animation_fd();
+        let mut fds = [
+            Some(conn.as_raw_fd()),
+            sync_fd,
+            decode_fd,
+            ipc_fd,
+            download_fd,
+            watch_fd,
+            directories_fd,
+            file_chooser_fd,
+            None,
+            animation_fd,
+        ]
+        .map(|fd| fd.map(|fd| (fd, libc::POLLIN)));
+        // Pressure files are always readable, and only the trigger firing is priority data
+        fds[8] = pressure_fd.map(|fd| (fd, libc::POLLPRI));
+        let [_, sync_ready, decode_ready, ipc_ready, download_ready, watch_ready, directories_ready, file_chooser_ready, pressure_ready, _] =
+            poll(fds, timeout)?;
+
+        if pressure_ready {
+            state.memory_low();
+        }
+        if state.pressure.as_mut().is_some_and(Pressure::calmed) {
+            state.memory_recovered();
+        }
+
+        if decode_ready {
+            // Keep the apparent size of the image when the preview is replaced
+            let (old_width, _) = state.backend.size();
+            let changed = state.backend.finish_pending(conn, &mut state.shm_alloc);
+            let new_size = state.backend.size();
+            // The view of the previous image if the full image has its size after all
+            let kept = state.pending_view.take();
+            if changed {
+                match kept.filter(|_| new_size == state.view_size) {
+                    Some(transform) => state.img_transform = transform,
+                    None => state.img_transform.scale *= old_width / new_size.0,
+                }
+            }
+            state.view_size = new_size;
+            // Even an unchanged image needs a frame to exit after
+            if changed || state.exit_after_first_frame {
+                Window::frame(state);
+            }
+        }
+
+        if sync_ready {
+            let received = state.sync.as_mut().unwrap().recv()?;
+            // The view of the new image follows
+            if let Some(index) = received.index {
+                state.show_synced(conn, index);
+            }
+            if let Some(transform) = received.transform {
+                state.img_transform = transform;
+                Window::frame(state);
+            }
+        }
+
+        if ipc_ready {
+            Ipc::handle(state);
+        }
+
+        if download_ready {
+            state.finish_download(conn);
+        }
+
+        if state.settle.as_mut().is_some_and(Settle::due) {
+            state.finish_settle(conn);
+        }
+
+        if file_chooser_ready {
+            state.finish_file_chooser(conn);
+        }
+
+        if watch_ready {
+            state.watch.as_mut().unwrap().read_events();
+        }
+        if state.watch.as_mut().is_some_and(Watch::due) {
+            state.reload(conn);
+        }
+        if directories_ready {
+            state.follow_directories(conn);
+        }
+
+        if state.backend.advance_animation() {
+            Window::frame(state);
+        }
+
+        if state.sequence.as_mut().is_some_and(Sequence::tick) {
+            state.next_frame(conn);
+        }
+
+        if state.slideshow.tick() {
+            state.next_slide(conn);
+        }
+
+        if let Some(view) = &mut state.view_animation {
+            let window = (state.window.width as f32, state.window.height as f32);
+            let changed = view.advance(&mut state.img_transform, window);
+            if view.finished() {
+                state.view_animation = None;
+            }
+            if changed {
+                Window::frame(state);
+            }
+        }
 
         if let Some(repeat) = &mut state.kbd_repeat {
             if repeat.timer.tick() {
                 let action = repeat.action;
-                state.handle_action(&mut conn, action);
+                state.handle_action(conn, action);
             }
         }
 
         match conn.recv_events(IoMode::NonBlocking) {
             Ok(()) => (),
             Err(e) if e.kind() == ErrorKind::WouldBlock => (),
-            Err(e) => bail!(e),
+            Err(e) => return Err(WaylandError::Lost(e).into()),
+        }
+
+        conn.dispatch_events(state);
+        Ipc::notify(state);
+
+        if let Some(sync) = &mut state.sync {
+            if std::mem::take(&mut state.navigated) {
+                sync.broadcast_index(state.files.position().0 - 1);
+            }
+            sync.broadcast(&state.img_transform);
         }
+        crash::set_transform(state.img_transform);
 
-        conn.dispatch_events(&mut state);
-        conn.flush(IoMode::Blocking)?;
+        // Once for everything which has changed since the last round
+        Window::render(state, conn);
+        conn.flush(IoMode::Blocking).map_err(WaylandError::Lost)?;
     }
 
     Ok(())
 }
 
-fn poll(fd: RawFd, timeout: Option<Duration>) -> io::Result<()> {
-    let mut fds = [libc::pollfd {
-        fd,
-        events: libc::POLLIN,
-        revents: 0,
-    }];
-
-    let result = unsafe {
-        libc::poll(
-            fds.as_mut_ptr(),
-            1,
-            timeout.map_or(-1, |t| t.as_secs() as _),
-        )
-    };
+/// Says which images were skipped while moving through the file list, if any.
+fn skipped_notice(skipped: &[DecodeError]) -> Option<String> {
+    match skipped {
+        [] => None,
+        [e] => Some(format!("Skipped {e}")),
+        [.., e] => Some(format!(
+            "Skipped {} images, the last one {e}",
+            skipped.len()
+        )),
+    }
+}
+
+fn join_notices(skipped: &[DecodeError], notice: &str) -> String {
+    match skipped_notice(skipped) {
+        Some(skipped) => format!("{notice}. {skipped}"),
+        None => notice.to_owned(),
+    }
+}
+
+/// Wait until one of the file descriptors becomes readable or the timeout expires. Returns which
+/// of the file descriptors are readable. `None` entries are ignored.
+fn poll<const N: usize>(
+    fds: [Option<(RawFd, libc::c_short)>; N],
+    timeout: Option<Duration>,
+) -> io::Result<[bool; N]> {
+    let mut pollfds = fds.map(|fd| {
+        let (fd, events) = fd.unwrap_or((-1, 0));
+        libc::pollfd {
+            fd,
+            events,
+            revents: 0,
+        }
+    });
+
+    // Round up, so that we don't wake up too early and spin
+    let timeout = timeout.map_or(-1, |t| t.as_micros().div_ceil(1000) as _);
+
+    let result = unsafe { libc::poll(pollfds.as_mut_ptr(), N as _, timeout) };
 
     if result == -1 {
         Err(io::Error::last_os_error())
     } else {
-        Ok(())
+        Ok(pollfds.map(|p| p.revents & p.events != 0))
     }
 }
 
 pub struct State {
+    pub files: FileList,
     pub globals: Globals,
     pub shm_alloc: ShmAlloc,
+    /// Cursor themes need the allocator from `wayrs-utils`
+    pub cursor_shm: wayrs_utils::shm_alloc::ShmAlloc,
     pub backend: Image,
+    pub overlay: Overlay,
 
     pub cursor_theme: CursorTheme,
     pub default_cursor: CursorImage,
@@ -155,22 +984,114 @@ pub struct State {
     img_transform: ImageTransform,
 
     move_transaction: Option<MoveTransaction>,
+    /// A change of the view asked for over IPC, or the image springing back after
+    /// `--clamp-pan` let it be dragged past the edges
+    view_animation: Option<ViewAnimation>,
+    /// The playback of `--sequence`
+    sequence: Option<Sequence>,
+    slideshow: Slideshow,
     kbd_repeat: Option<RepeatState>,
+    /// Present in measure mode
+    measure: Option<Measure>,
+    /// Present in inspect mode
+    inspect: Option<Inspect>,
+    /// Present in presentation mode
+    present: Option<Present>,
+    /// Shown together with the rulers
+    guides: Vec<Guide>,
+    /// The file which is deleted if the next key press confirms it, and whether permanently
+    /// rather than to the trash
+    delete_prompt: Option<(String, bool)>,
+    /// The file which is renamed once its new name has been typed
+    rename_prompt: Option<(String, Prompt)>,
+    /// The number typed before a key, like `42` of `42g`
+    count: Option<usize>,
+    /// The directory which the session is remembered for, see `--resume`
+    session: Option<String>,
+    /// The file which `--resume` starts at, and how it was shown, until the window has its size
+    resume_view: Option<(String, View)>,
+
+    sync: Option<SyncGroup>,
+    /// Whether another image has been moved to since the sync group was last told
+    navigated: bool,
+    ipc: Option<Ipc>,
+    current_link: Option<CurrentLink>,
+    /// Unless `--no-preload` is given
+    preload: Option<Preload>,
+    /// Missing without PSI, see [`pressure`]
+    pressure: Option<Pressure>,
+    /// Of the current file, with `--watch`
+    watch: Option<Watch>,
+    /// Of the files, with `--watch`
+    directories: Option<Directories>,
+    jump_to_new: bool,
+    /// Of the current image, or of one shown before which is still needed
+    download: Option<Download>,
+    /// Of the current file, while it is still being written
+    settle: Option<Settle>,
+    /// See `--settle-delay`
+    settle_delay: Duration,
+    /// Shown with `o`, until the user has chosen
+    file_chooser: Option<FileChooser>,
+    hdr_output: HdrOutput,
+    config: Config,
+    /// The settings from the command line
+    defaults: Settings,
+    /// The settings of the current image, with the overrides of the config file
+    settings: Settings,
+    at_end: AtEnd,
+    bindings: Bindings,
+    pinch: Pinch,
+    pinch_sensitivity: f32,
+    /// See `--keep-view`
+    keep_view: bool,
+    /// The natural size of the image whose view is shown, which the next one is compared with
+    view_size: (f32, f32),
+    /// The view of the previous image, while a preview of the next one of another size is shown
+    pending_view: Option<ImageTransform>,
+    /// See `--integer-zoom`
+    integer_zoom: bool,
+    /// The scale which zooming with `integer_zoom` has reached without snapping, and the level
+    /// it has been snapped to
+    unsnapped_scale: Option<(f32, f32)>,
+    /// See `--clamp-pan` and `--reduced-motion`
+    clamp_pan: bool,
+    reduced_motion: bool,
+    /// See `--move-to` and `--copy-to`
+    move_to: Vec<String>,
+    copy_to: Vec<String>,
+    /// For jumping to a random image
+    random: Random,
+    end_hook: Option<String>,
+    title: Option<Template>,
+    osd: Option<Template>,
+    /// Close the window after the full image has been presented
+    exit_after_first_frame: bool,
+    /// See `--deterministic`. Nothing may depend on timing or on the environment when it is set
+    deterministic: bool,
 }
 
 pub struct RepeatState {
     key: xkb::Keycode,
     action: Action,
-    timer: Timer,
+    timer: KeyRepeat,
 }
 
 impl State {
     pub fn handle_action(&mut self, conn: &mut Connection<Self>, action: Action) {
+        // The user is looking at the image
+        self.slideshow.restart();
+        // A number is for the next key only
+        let count = self.count.take();
+        if count.is_some() && !matches!(action, Action::Digit(_) | Action::GoTo { .. }) {
+            self.overlay.message = None;
+        }
         match action {
-            Action::MoveLeft => self.img_transform.x += self.window.width as f32 * 0.05,
-            Action::MoveRight => self.img_transform.x -= self.window.width as f32 * 0.05,
-            Action::MoveUp => self.img_transform.y += self.window.height as f32 * 0.05,
-            Action::MoveDown => self.img_transform.y -= self.window.height as f32 * 0.05,
+            Action::MoveLeft => self.move_by(self.window.width as f32 * 0.05, 0.0),
+            Action::MoveRight => self.move_by(self.window.width as f32 * -0.05, 0.0),
+            Action::MoveUp => self.move_by(0.0, self.window.height as f32 * 0.05),
+            Action::MoveDown => self.move_by(0.0, self.window.height as f32 * -0.05),
+            Action::Zoom { x, y, val } if self.integer_zoom => self.zoom_integer(x, y, val),
             Action::Zoom { x, y, val } => {
                 // When zooming we want to move the image in such a way that the pointer's
                 // coordinates in image lacal coordinates do not change. This can be expressed as
@@ -182,30 +1103,1139 @@ impl State {
                 self.img_transform.y += (self.img_transform.y - y) * delta_scale / prev_scale;
                 self.img_transform.scale += delta_scale;
             }
+            Action::PhysicalSize => {
+                if let Some(scale) = self.physical_scale() {
+                    let x = self.window.width as f32 / 2.0;
+                    let y = self.window.height as f32 / 2.0;
+                    self.img_transform.zoom_to(x, y, scale);
+                }
+            }
+            Action::Exposure(stops) => {
+                if !self
+                    .backend
+                    .adjust_exposure(conn, &mut self.shm_alloc, stops)
+                {
+                    return;
+                }
+            }
+            Action::ToggleRulers => self.overlay.rulers = !self.overlay.rulers,
+            Action::ToggleMeasure => {
+                self.inspect = None;
+                if self.measure.take().is_some() {
+                    self.overlay.message = None;
+                } else {
+                    self.measure = Some(Measure::default());
+                    self.overlay.message = Some("Measure: click two points".into());
+                }
+            }
+            Action::ToggleInspect => {
+                self.measure = None;
+                if self.inspect.take().is_some() {
+                    self.overlay.message = None;
+                } else if self.backend.pixels().is_none() {
+                    self.overlay.message = Some("Inspect: only raster images are supported".into());
+                } else {
+                    self.inspect = Some(Inspect::default());
+                    self.overlay.message = Some("Inspect: hover a pixel or drag a region".into());
+                }
+            }
+            Action::TogglePresent => {
+                self.measure = None;
+                self.inspect = None;
+                if let Some(mut present) = self.present.take() {
+                    present.release(&mut self.img_transform);
+                    self.overlay.message = None;
+                } else {
+                    self.present = Some(Present::default());
+                    self.overlay.message = Some(
+                        "Presentation: L for the laser pointer, hold the button to magnify".into(),
+                    );
+                }
+            }
+            Action::ToggleLaser => {
+                let Some(present) = &mut self.present else {
+                    return;
+                };
+                let pointer = self.pointers.first().map_or((0.0, 0.0), |p| (p.x, p.y));
+                present.toggle_laser(pointer);
+            }
+            Action::TurnPage(delta) => {
+                match self.backend.turn_page(conn, &mut self.shm_alloc, delta) {
+                    Ok(true) => {
+                        self.overlay.message = self.backend.page_label();
+                        self.update_title(conn);
+                    }
+                    Ok(false) => return,
+                    Err(e) => self.overlay.message = Some(format!("{e:#}")),
+                }
+            }
             Action::ToggleFullscreen => self.window.toggle_fullscreen(conn),
+            Action::Navigate(delta) => self.navigate(conn, delta, Vec::new()),
+            Action::History(delta) => self.step_history(conn, delta),
+            Action::ToggleKeepView => {
+                self.keep_view = !self.keep_view;
+                self.overlay.message = Some(match self.keep_view {
+                    true => "Keep the view of images of the same size: on".into(),
+                    false => "Keep the view of images of the same size: off".into(),
+                });
+            }
+            Action::Random => match self.files.random_step(&mut self.random) {
+                0 => self.overlay.message = Some("There is no other image".into()),
+                delta => self.navigate(conn, delta, Vec::new()),
+            },
+            Action::Digit(digit) => {
+                let count = count.unwrap_or(0).saturating_mul(10).saturating_add(digit);
+                self.count = Some(count);
+                self.overlay.message = Some(format!("Go to image {count} with g"));
+            }
+            Action::GoTo { last } => self.go_to(conn, count, last),
+            Action::Delete { permanently } => match self.files.current() {
+                Entry::File(path) if path != "-" => {
+                    self.overlay.message = Some(match permanently {
+                        true => format!("Delete {path} permanently? Press y to confirm"),
+                        false => format!("Move {path} to the trash? Press y to confirm"),
+                    });
+                    self.delete_prompt = Some((path.clone(), permanently));
+                }
+                _ => self.overlay.message = Some("Only files can be deleted".into()),
+            },
+            Action::Rename => match self.files.current() {
+                Entry::File(path) if path != "-" => {
+                    let name = Path::new(path).file_name().unwrap_or_default();
+                    // Before the extension, which usually stays
+                    let cursor = Path::new(name).file_stem().unwrap_or_default().len();
+                    let name = name.to_string_lossy().into_owned();
+                    let prompt = Prompt::new("Rename to", name, cursor);
+                    self.overlay.message = Some(prompt.message());
+                    self.rename_prompt = Some((path.clone(), prompt));
+                }
+                _ => self.overlay.message = Some("Only files can be renamed".into()),
+            },
+            Action::ToggleMark => {
+                let marked = self.files.toggle_mark();
+                let count = self.files.marked().len();
+                self.overlay.message = Some(match marked {
+                    true => format!("Marked ({count} in total)"),
+                    false => format!("Unmarked ({count} in total)"),
+                });
+                self.update_title(conn);
+            }
+            Action::Open if self.sequence.is_some() => {
+                self.overlay.message = Some("No files can be opened during a sequence".into());
+            }
+            Action::Open => self.open_file_chooser(),
+            Action::MoveTo(index) => self.copy_or_move(conn, index, false),
+            Action::CopyTo(index) => self.copy_or_move(conn, index, true),
+            Action::ToggleIntegerZoom => {
+                self.integer_zoom = !self.integer_zoom;
+                self.overlay.message = Some(match self.integer_zoom {
+                    true => "Integer zoom: on".into(),
+                    false => "Integer zoom: off".into(),
+                });
+                if self.integer_zoom {
+                    let x = self.window.width as f32 / 2.0;
+                    let y = self.window.height as f32 / 2.0;
+                    self.zoom_integer(x, y, 0.0);
+                }
+            }
+            Action::ToggleSlideshow => {
+                let stopped = self.slideshow.toggle();
+                self.overlay.message = Some(match stopped {
+                    true => "Slideshow: stopped".into(),
+                    false => "Slideshow: started".into(),
+                });
+            }
+            Action::ToggleAnimation if self.sequence.is_some() => {
+                let paused = self.sequence.as_mut().unwrap().toggle();
+                self.overlay.message = paused.then(|| "Sequence: paused".into());
+            }
+            Action::ToggleAnimation => match self.backend.toggle_video() {
+                Some(paused) => self.overlay.message = paused.then(|| "Video: paused".into()),
+                None => match self.backend.toggle_animation() {
+                    Some(true) => {
+                        self.overlay.message = Some("Animation: frozen at the start".into())
+                    }
+                    Some(false) => self.overlay.message = None,
+                    None => return,
+                },
+            },
+        }
+        Window::frame(self);
+    }
+
+    /// Zoom by `val` percent like [`Action::Zoom`], to the closest level of `--integer-zoom`.
+    /// Steps of 10% or more, from keys and mouse wheels, go to the next level, while smaller
+    /// ones, from pinching and touchpads, add up and show the level closest to their sum.
+    fn zoom_integer(&mut self, x: f32, y: f32, val: f32) {
+        let buffer_scale = self.buffer_scale();
+        let shown = self.img_transform.scale;
+        let unsnapped = match self.unsnapped_scale {
+            Some((unsnapped, snapped)) if snapped == shown => unsnapped,
+            _ => shown,
+        };
+        let (unsnapped, snapped) = match val.abs() >= 10.0 {
+            true => {
+                let next = next_integer_scale(shown * buffer_scale, val < 0.0);
+                (next / buffer_scale, next / buffer_scale)
+            }
+            false => {
+                let unsnapped = unsnapped * (1.0 - val * 0.01);
+                let snapped = integer_scale(unsnapped * buffer_scale) / buffer_scale;
+                (unsnapped, snapped)
+            }
+        };
+        self.img_transform.zoom_to(x, y, snapped);
+        self.unsnapped_scale = Some((unsnapped, snapped));
+    }
+
+    /// How many device pixels there are per surface local pixel.
+    fn buffer_scale(&self) -> f32 {
+        match self.window.scale120 {
+            Some(scale120) => scale120 as f32 / 120.0,
+            None => self.window.get_int_scale(self) as f32,
+        }
+    }
+
+    /// Whether the user is currently dragging or pinching. Expensive work which is not needed to
+    /// follow the gesture should be deferred while this is true.
+    fn interacting(&self) -> bool {
+        self.move_transaction.is_some()
+            || self
+                .pointers
+                .iter()
+                .any(|p| p.pinch_gesture.as_ref().is_some_and(|g| g.state.is_some()))
+    }
+
+    /// The edges which `--clamp-pan` keeps the image within.
+    fn edges(&self) -> pan::Edges {
+        let window = (self.window.width as f32, self.window.height as f32);
+        pan::Edges::new(self.backend.size(), self.img_transform.scale, window)
+    }
+
+    /// Pan by `(dx, dy)` with the keys, up to the edges with `--clamp-pan`.
+    fn move_by(&mut self, dx: f32, dy: f32) {
+        self.img_transform.x += dx;
+        self.img_transform.y += dy;
+        if self.clamp_pan {
+            self.edges().clamp(&mut self.img_transform);
+        }
+    }
+
+    /// Pan by `(dx, dy)` as the image is dragged or pinched.
+    fn drag_by(&mut self, dx: f32, dy: f32) {
+        if !self.clamp_pan {
+            self.img_transform.x += dx;
+            self.img_transform.y += dy;
+            return;
+        }
+        let (edges, springy) = (self.edges(), !self.reduced_motion);
+        pan::drag(&mut self.img_transform, (dx, dy), &edges, springy);
+    }
+
+    /// Let the image spring back within the edges once it is no longer dragged.
+    fn release_drag(&mut self) {
+        if !self.clamp_pan {
+            return;
+        }
+        let window = (self.window.width as f32, self.window.height as f32);
+        let animate = !self.reduced_motion && !self.deterministic;
+        if let Some(bounce) = pan::bounce(self.img_transform, &self.edges(), window, animate) {
+            self.view_animation = Some(bounce);
+        }
+    }
+
+    /// Update the inspect mode readout after the pointer has moved to `(x, y)`.
+    fn inspect_pointer(&mut self, x: f32, y: f32, finish: bool) {
+        let (Some(inspect), Some(pixels)) = (&mut self.inspect, self.backend.pixels()) else {
+            return;
+        };
+        let t = self.img_transform;
+        let point = ((x - t.x) / t.scale, (y - t.y) / t.scale);
+
+        if inspect.dragging {
+            inspect.cursor = Some(point);
+            inspect.dragging = !finish;
+        }
+
+        let text = match inspect.selection(pixels.width(), pixels.height()) {
+            Some(rect) => {
+                let text = inspect::describe_region(pixels, rect);
+                if finish {
+                    println!("{text}");
+                }
+                Some(text)
+            }
+            None => inspect::describe_pixel(pixels, point.0, point.1),
+        };
+        self.overlay.message = text;
+    }
+
+    /// Move `delta` images through the file list, doing what `--at-end` says past the last one.
+    /// Images which cannot be shown are skipped, continuing in the same direction. `skipped` are
+    /// the ones which have already been skipped.
+    fn navigate(
+        &mut self,
+        conn: &mut Connection<Self>,
+        delta: isize,
+        mut skipped: Vec<DecodeError>,
+    ) {
+        let origin = self.files.cursor();
+        let mut step = delta;
+        loop {
+            match self.files.step(step, self.at_end == AtEnd::Wrap) {
+                Step::Moved if self.files.cursor() == origin => {
+                    // Went all the way around
+                    self.overlay.message = skipped_notice(&skipped);
+                    return;
+                }
+                Step::Moved => (),
+                Step::PastStart => {
+                    self.files.restore(origin);
+                    self.overlay.message = Some(join_notices(&skipped, "This is the first image"));
+                    return;
+                }
+                Step::PastEnd => {
+                    self.files.restore(origin);
+                    self.overlay.message = skipped_notice(&skipped);
+                    match self.at_end {
+                        AtEnd::Stop | AtEnd::Wrap => {
+                            let notice = join_notices(&skipped, "This is the last image");
+                            self.overlay.message = Some(notice);
+                        }
+                        AtEnd::Quit => self.window.closed = true,
+                        AtEnd::Hook => {
+                            let hook = self.end_hook.as_deref().unwrap();
+                            let path = self.files.current().path();
+                            if let Err(e) = files::run_hook(hook, path) {
+                                self.overlay.message = Some(format!("{e:#}"));
+                            }
+                        }
+                    }
+                    return;
+                }
+            }
+
+            match self.load_current(conn) {
+                Ok(()) => break,
+                Err(e) => {
+                    skipped.push(e);
+                    step = delta.signum();
+                }
+            }
+        }
+
+        self.files.record();
+        self.navigated = true;
+        self.current_shown(conn, &skipped);
+    }
+
+    /// Show the current image again after its file has changed, keeping the view. If it cannot
+    /// be decoded, the previous version stays until it is tried again, since the file may only
+    /// be partly written.
+    fn reload(&mut self, conn: &mut Connection<Self>) {
+        let settings = self.config.settings(self.defaults, self.files.current());
+        let result = self.backend.load(
+            self.files.current(),
+            &mut self.shm_alloc,
+            conn,
+            settings.decode,
+            // The file has changed
+            None,
+        );
+        let watch = self.watch.as_mut().unwrap();
+        match result {
+            Ok(()) => {
+                watch.reloaded();
+                self.settings = settings;
+                self.window
+                    .set_background(conn, &self.globals, self.settings.background);
+                self.update_title(conn);
+            }
+            Err(_) if watch.retry() => return,
+            Err(e) => self.overlay.message = Some(e.to_string()),
+        }
+        Window::frame(self);
+    }
+
+    /// Add the files which have appeared in the directories to the file list and remove the
+    /// deleted ones. With `--jump-to-new`, the last new file which can be shown is shown.
+    fn follow_directories(&mut self, conn: &mut Connection<Self>) {
+        let changes = self.directories.as_mut().unwrap().read_events();
+        if changes.is_empty() {
+            return;
+        }
+        let mut added = None;
+        for change in changes {
+            match change {
+                Change::Added(path) => {
+                    if self.files.add(path.clone()) {
+                        added = Some(path);
+                    }
+                }
+                Change::Removed(path) => self.files.remove(&path),
+            }
+        }
+        let added = added.filter(|_| self.jump_to_new);
+        match added.and_then(|path| self.files.index_of(&path)) {
+            Some(index) => {
+                self.jump(conn, index);
+            }
+            // The position has changed
+            None => self.update_title(conn),
+        }
+        Window::frame(self);
+    }
+
+    /// Show the `n`th image of the list, counting from 1, or without `n` the first one or the
+    /// `last` one. The overlay says where it is in the list.
+    fn go_to(&mut self, conn: &mut Connection<Self>, n: Option<usize>, last: bool) {
+        let (_, count) = self.files.position();
+        let index = match n {
+            Some(n @ 1..) if n <= count => n - 1,
+            Some(n) => {
+                self.overlay.message = Some(format!("There is no image {n}, only {count}"));
+                return;
+            }
+            None if last => count - 1,
+            None => 0,
+        };
+        if self.jump(conn, index) {
+            self.overlay.message = Some(format!("Image {} of {count}", index + 1));
+        }
+    }
+
+    /// Show the image at `index` of the list, staying on the current one if it cannot be shown.
+    /// Returns whether it is shown.
+    fn jump(&mut self, conn: &mut Connection<Self>, index: usize) -> bool {
+        let origin = self.files.cursor();
+        self.files.go_to(index);
+        match self.load_current(conn) {
+            Ok(()) => {
+                self.files.record();
+                self.navigated = true;
+                self.current_shown(conn, &[]);
+                true
+            }
+            Err(e) => {
+                self.files.restore(origin);
+                self.overlay.message = Some(e.to_string());
+                false
+            }
+        }
+    }
+
+    /// Delete the file at `path`, or move it to the trash, and show the next image if it was the
+    /// current one. The window is closed once there are no images left.
+    fn delete(&mut self, conn: &mut Connection<Self>, path: String, permanently: bool) {
+        let result = match permanently {
+            true => std::fs::remove_file(&path).context("could not delete the file"),
+            false => trash::trash(Path::new(&path)),
+        };
+        if let Err(e) = result {
+            self.overlay.message = Some(format!("{path}: {e:#}"));
+            return;
+        }
+        let notice = match permanently {
+            true => format!("Deleted {path}"),
+            false => format!("Moved {path} to the trash"),
+        };
+        self.forget(conn, &path, notice);
+    }
+
+    /// Rename the file at `path` to `name`, in its directory. It keeps its place in the list.
+    fn rename(&mut self, conn: &mut Connection<Self>, path: &str, name: &str) {
+        if Path::new(path).file_name() == Some(name.as_ref()) {
+            self.overlay.message = None;
+            return;
+        }
+        match files::rename(path, name) {
+            Ok(renamed) => {
+                self.files.rename(path, &renamed);
+                self.update_title(conn);
+                self.overlay.message = Some(format!("Renamed to {renamed}"));
+            }
+            Err(e) => self.overlay.message = Some(format!("{path}: {e:#}")),
         }
-        Window::frame(self, conn);
+    }
+
+    /// Copy the current file into the `index`th directory of `--copy-to`, or move it into the one
+    /// of `--move-to` unless `copy`, and show the next image after moving it.
+    fn copy_or_move(&mut self, conn: &mut Connection<Self>, index: usize, copy: bool) {
+        let (dirs, option) = match copy {
+            true => (&self.copy_to, "--copy-to"),
+            false => (&self.move_to, "--move-to"),
+        };
+        let Some(dir) = dirs.get(index) else {
+            let n = index + 1;
+            self.overlay.message = Some(format!("There is no {option} directory {n}"));
+            return;
+        };
+        let path = match self.files.current() {
+            Entry::File(path) if path != "-" => path.clone(),
+            _ => {
+                self.overlay.message = Some("Only files can be copied or moved".into());
+                return;
+            }
+        };
+        match files::copy_or_move(&path, dir, copy) {
+            Ok(target) if copy => {
+                self.overlay.message = Some(format!("Copied to {}", target.display()));
+            }
+            Ok(target) => self.forget(conn, &path, format!("Moved to {}", target.display())),
+            Err(e) => self.overlay.message = Some(format!("{path}: {e:#}")),
+        }
+    }
+
+    /// Remove the file at `path` from the file list after it has been deleted or moved away, and
+    /// show the next image if it was the current one, with `notice`. The window is closed once
+    /// there are no images left.
+    fn forget(&mut self, conn: &mut Connection<Self>, path: &str, notice: String) {
+        if self.files.current().path() != path {
+            // The list has moved on since the prompt
+            self.files.remove(path);
+            self.update_title(conn);
+        } else if !self.files.remove_current() {
+            self.window.closed = true;
+            return;
+        } else {
+            match self.load_current(conn) {
+                Ok(()) => {
+                    self.files.record();
+                    self.current_shown(conn, &[]);
+                }
+                Err(e) => self.navigate(conn, 1, vec![e]),
+            }
+        }
+        // After the notices of the images skipped on the way
+        self.overlay.message = Some(match self.overlay.message.take() {
+            Some(message) => format!("{notice}. {message}"),
+            None => notice,
+        });
+    }
+
+    /// Move on to the next image of the slideshow, and stop it if there is none.
+    fn next_slide(&mut self, conn: &mut Connection<Self>) {
+        let origin = self.files.cursor();
+        self.navigate(conn, 1, Vec::new());
+        if self.files.cursor() == origin {
+            self.slideshow.stop();
+        }
+        Window::frame(self);
+    }
+
+    /// Show the next frame of the `--sequence`, after the last one the first again. The view
+    /// stays as it is, and frames which cannot be shown are skipped.
+    fn next_frame(&mut self, conn: &mut Connection<Self>) {
+        self.files.step(1, true);
+        if self.load_current(conn).is_ok() {
+            crash::set_path(&self.files.current().name());
+        }
+        self.update_title(conn);
+        Window::frame(self);
+    }
+
+    /// Move `delta` images through the history of shown images, skipping the ones which cannot
+    /// be shown anymore.
+    fn step_history(&mut self, conn: &mut Connection<Self>, delta: isize) {
+        let origin = self.files.cursor();
+        let mut skipped = Vec::new();
+        loop {
+            if !self.files.step_history(delta) {
+                self.files.restore(origin);
+                let notice = match delta {
+                    ..0 => "There is nothing to go back to",
+                    _ => "There is nothing to go forward to",
+                };
+                self.overlay.message = Some(join_notices(&skipped, notice));
+                return;
+            }
+            match self.load_current(conn) {
+                Ok(()) => break,
+                Err(e) => skipped.push(e),
+            }
+        }
+        self.navigated = true;
+        self.current_shown(conn, &skipped);
+    }
+
+    /// Show the image which another member of the sync group has moved to, without telling the
+    /// group about it again. The lists are expected to match, so other indices are ignored.
+    fn show_synced(&mut self, conn: &mut Connection<Self>, index: usize) {
+        let (position, count) = self.files.position();
+        if index + 1 != position && index < count {
+            self.jump(conn, index);
+            self.navigated = false;
+            Window::frame(self);
+        }
+    }
+
+    /// Load the current image of the file list. On failure, the previous image stays.
+    ///
+    /// Images on the web are downloaded first, in the background. The previous image stays until
+    /// they have arrived, and likewise until files which are still being written have been.
+    ///
+    fn load_current(&mut self, conn: &mut Connection<Self>) -> Result<(), DecodeError> {
+        let settings = self.config.settings(self.defaults, self.files.current());
+        if let Entry::Url { url, data } = self.files.current() {
+            if data.get().is_none() {
+                if !self.download.as_ref().is_some_and(|d| d.is_for(data)) {
+                    let max_bytes = settings.decode.limits.max_bytes;
+                    self.download = Some(Download::start(url, data.clone(), max_bytes).map_err(
+                        |source| DecodeError {
+                            path: url.clone(),
+                            source,
+                        },
+                    )?);
+                }
+                self.settings = settings;
+                return Ok(());
+            }
+        }
+        let mut options = settings.decode;
+        // At the size of the window, while the system is low on memory
+        if self.pressure.as_ref().is_some_and(Pressure::low) {
+            let scale = self.buffer_scale();
+            let width = (self.window.width as f32 * scale) as u64;
+            let height = (self.window.height as f32 * scale) as u64;
+            if width * height > 0 {
+                let max_pixels = options.max_pixels.unwrap_or(u64::MAX);
+                options.max_pixels = Some(max_pixels.min(width * height));
+            }
+        }
+        let preloaded = match (&mut self.preload, self.files.current()) {
+            (Some(preload), Entry::File(path)) => preload.take(path),
+            _ => None,
+        };
+        let result = self.backend.load(
+            self.files.current(),
+            &mut self.shm_alloc,
+            conn,
+            options,
+            preloaded,
+        );
+        match &result {
+            Err(_) if self.wait_until_written() => return Ok(()),
+            Ok(()) => self.settings = settings,
+            // Deleted while browsing, or not an image at all
+            Err(e) => eprintln!("reimv: {e}"),
+        }
+        self.settle = None;
+        result
+    }
+
+    /// Try the current file again later if it may still be being written, after it could not
+    /// be decoded. Returns `false` if it is not waited for, or has been waited for too often.
+    fn wait_until_written(&mut self) -> bool {
+        let path = match self.files.current() {
+            Entry::File(path) if path != "-" => Path::new(path),
+            _ => return false,
+        };
+        match &mut self.settle {
+            Some(settle) if settle.is_for(path) => settle.retry(),
+            _ if Settle::is_written(path) => {
+                self.settle = Some(Settle::new(path, self.settle_delay));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Show the file which was being written if it is still the current one, or the error if it
+    /// still cannot be decoded.
+    fn finish_settle(&mut self, conn: &mut Connection<Self>) {
+        let settle = self.settle.as_ref().unwrap();
+        if !matches!(self.files.current(), Entry::File(path) if settle.is_for(Path::new(path))) {
+            self.settle = None;
+            return;
+        }
+        match self.load_current(conn) {
+            // Tried again later
+            Ok(()) if self.settle.is_some() => return,
+            Ok(()) => self.current_shown(conn, &[]),
+            Err(e) => {
+                self.overlay.message = Some(e.to_string());
+                self.update_title(conn);
+            }
+        }
+        Window::frame(self);
+    }
+
+    /// Show the file chooser of the desktop, in the directory of the current image.
+    fn open_file_chooser(&mut self) {
+        if self.file_chooser.is_some() {
+            self.overlay.message = Some("The file chooser is open already".into());
+            return;
+        }
+        let folder = match self.files.current() {
+            Entry::File(path) if path != "-" => std::path::absolute(path)
+                .ok()
+                .and_then(|path| path.parent().map(Path::to_owned)),
+            _ => None,
+        };
+        match FileChooser::open(folder.as_deref()) {
+            Ok(file_chooser) => self.file_chooser = Some(file_chooser),
+            Err(e) => {
+                eprintln!("reimv: could not open the file chooser: {e:#}");
+                self.overlay.message = Some(format!("Could not open the file chooser: {e:#}"));
+            }
+        }
+    }
+
+    /// Add the chosen files to the list and show the first one, once the user has chosen.
+    fn finish_file_chooser(&mut self, conn: &mut Connection<Self>) {
+        let Some(result) = self.file_chooser.as_mut().unwrap().read() else {
+            return;
+        };
+        self.file_chooser = None;
+        let opened = match result {
+            // Cancelled
+            Ok(paths) if paths.is_empty() => return,
+            Ok(paths) => self.files.open(&paths),
+            Err(e) => Err(e),
+        };
+        match opened {
+            Ok(index) => {
+                self.jump(conn, index);
+            }
+            Err(e) => {
+                eprintln!("reimv: {e:#}");
+                self.overlay.message = Some(format!("Could not open the files: {e:#}"));
+            }
+        }
+        Window::frame(self);
+    }
+
+    /// Show the downloaded image if it is still the current one, or just the progress.
+    fn finish_download(&mut self, conn: &mut Connection<Self>) {
+        let Some(result) = self.download.as_mut().unwrap().finish() else {
+            self.update_title(conn);
+            return;
+        };
+        let download = self.download.take().unwrap();
+        let Entry::Url { url, data } = self.files.current() else {
+            return;
+        };
+        if !download.is_for(data) {
+            return;
+        }
+        let result = match result {
+            Ok(()) => self.load_current(conn),
+            Err(source) => {
+                let e = DecodeError {
+                    path: url.clone(),
+                    source: source.context("could not download the image"),
+                };
+                eprintln!("reimv: {e}");
+                Err(e)
+            }
+        };
+        match result {
+            Ok(()) => self.current_shown(conn, &[]),
+            Err(e) => {
+                self.overlay.message = Some(e.to_string());
+                self.update_title(conn);
+            }
+        }
+        Window::frame(self);
+    }
+
+    /// Start over with a new image from the file list.
+    fn current_shown(&mut self, conn: &mut Connection<Self>, skipped: &[DecodeError]) {
+        let entry = self.files.current();
+        crash::set_path(&entry.name());
+        self.measure = None;
+        self.inspect = None;
+        self.overlay.message = skipped_notice(skipped);
+        // Images in an archive share its guides
+        self.guides = FileState::load(entry.path()).guides;
+        // A guide being dragged was one of the previous image, while panning can go on
+        if let Some(mt) = &mut self.move_transaction {
+            mt.guide = None;
+        }
+        // Previews are smaller than their image, which is compared once it has been decoded
+        let size = self.backend.size();
+        let pending = self.backend.pending_fd().is_some();
+        self.pending_view = None;
+        if self.keep_view && size == self.view_size {
+            self.window
+                .set_background(conn, &self.globals, self.settings.background);
+        } else {
+            if self.keep_view && pending {
+                self.pending_view = Some(self.img_transform);
+            }
+            self.reset_view(conn);
+        }
+        if !pending {
+            self.view_size = size;
+        }
+        self.update_title(conn);
+    }
+
+    /// Show the current image the way its settings say, as large as `fit` makes it.
+    pub fn reset_view(&mut self, conn: &mut Connection<Self>) {
+        self.window
+            .set_background(conn, &self.globals, self.settings.background);
+        let (width, height) = self.backend.size();
+        let (win_width, win_height) = (self.window.width as f32, self.window.height as f32);
+        let contain = (win_width / width).min(win_height / height);
+        let buffer_scale = self.buffer_scale();
+        let small = width <= win_width && height <= win_height;
+        let scale = match (self.settings.upscale, self.settings.fit) {
+            (Some(Upscale::Never), _) if small => Some(1.0),
+            (Some(Upscale::Fit), _) if small => Some(contain),
+            (Some(Upscale::Integer), _) if small => {
+                Some((contain * buffer_scale).floor().max(1.0) / buffer_scale)
+            }
+            (_, Fit::None) => None,
+            (_, Fit::Shrink) => Some(contain.min(1.0)),
+            (_, Fit::Contain) => Some(contain),
+        };
+        // On whole display pixels, so that the edges of the pixels are sharp too
+        let center = |win_len: f32, len: f32, scale: f32| {
+            ((win_len - len * scale) / 2.0 * buffer_scale).round() / buffer_scale
+        };
+        // There is nothing to fit before an image has been shown
+        self.img_transform = match scale.filter(|s| s.is_finite() && *s > 0.0) {
+            Some(scale) => ImageTransform {
+                x: center(win_width, width, scale),
+                y: center(win_height, height, scale),
+                scale,
+            },
+            None => ImageTransform {
+                x: 0.0,
+                y: 0.0,
+                scale: 1.0,
+            },
+        };
+
+        // Where the last session left off, once the window has its size
+        if !self.window.mapped {
+            return;
+        }
+        let Some((path, view)) = self.resume_view.take() else {
+            return;
+        };
+        let scale = view.width / width;
+        if path == self.files.current().path() && scale.is_finite() && scale > 0.0 {
+            self.img_transform = ImageTransform::showing(
+                (view.center.0 * width, view.center.1 * height),
+                (win_width / 2.0, win_height / 2.0),
+                scale,
+            );
+        }
+    }
+
+    /// Remember the current image and how it is shown for `--resume`, when the window is closed.
+    fn save_session(&self) {
+        let Some(dir) = &self.session else {
+            return;
+        };
+        let path = match self.files.current() {
+            Entry::File(path) if path != "-" => path,
+            _ => return,
+        };
+        let (Ok(path), Ok(dir_path)) = (std::path::absolute(path), std::path::absolute(dir)) else {
+            return;
+        };
+        let (width, height) = self.backend.size();
+        let (x, y) = self.img_transform.image_point(
+            self.window.width as f32 / 2.0,
+            self.window.height as f32 / 2.0,
+        );
+        let view = View {
+            center: (x / width, y / height),
+            width: self.img_transform.scale * width,
+        };
+        let session = Session {
+            file: path
+                .strip_prefix(&dir_path)
+                .map_or_else(|_| path.clone(), Path::to_path_buf),
+            // Nothing has been shown of an image which could not be decoded
+            view: [view.center.0, view.center.1, view.width]
+                .iter()
+                .all(|n| n.is_finite())
+                .then_some(view),
+        };
+        if let Err(e) = session.save(dir) {
+            eprintln!("reimv: could not save the session: {e}");
+        }
+    }
+
+    /// Use less memory after the system has said that it is low on it, see [`pressure`].
+    fn memory_low(&mut self) {
+        if !self.pressure.as_mut().unwrap().report() {
+            return;
+        }
+        if let Some(preload) = &mut self.preload {
+            preload.set_paused(true);
+        }
+        self.backend.set_low_memory(true);
+        pressure::trim();
+        self.overlay.message =
+            Some("Memory is low, so new images are shown at a reduced resolution".to_owned());
+        Window::frame(self);
+    }
+
+    /// Go on as before once memory has not been low for a while.
+    fn memory_recovered(&mut self) {
+        if let Some(preload) = &mut self.preload {
+            preload.set_paused(false);
+        }
+        self.backend.set_low_memory(false);
+        self.preload_neighbours();
+    }
+
+    /// Keep the preload from taking the CPU from a drag or pinch, see [`Self::interacting`], and
+    /// go on once it is over.
+    fn hold_preload(&mut self, interacting: bool) {
+        let ended = self
+            .preload
+            .as_mut()
+            .is_some_and(|preload| preload.set_interacting(interacting));
+        if ended {
+            self.preload_neighbours();
+        }
+    }
+
+    /// Decode the images next to the current one in the background, unless `--no-preload` is
+    /// given. Only files on disk are preloaded, unless they are kept decoded already.
+    fn preload_neighbours(&mut self) {
+        let Some(preload) = &mut self.preload else {
+            return;
+        };
+        let wrap = self.sequence.is_some() || self.at_end == AtEnd::Wrap;
+        let files = self
+            .files
+            .neighbours(wrap)
+            .into_iter()
+            .filter_map(|entry| match entry {
+                Entry::File(path) if path != "-" && !self.backend.is_kept(entry) => {
+                    let settings = self.config.settings(self.defaults, entry);
+                    Some((path.clone(), settings.decode))
+                }
+                _ => None,
+            })
+            .collect();
+        preload.want(files);
+    }
+
+    /// Update the title and the on-screen display after the shown image or page has changed.
+    pub fn update_title(&mut self, conn: &mut Connection<Self>) {
+        self.preload_neighbours();
+        let info = Info {
+            name: self.files.current().name(),
+            page: self.backend.page_label(),
+            position: self.files.position(),
+            size: self.backend.file_size(),
+            exif: self.backend.exif(),
+            marked: self.files.is_marked(),
+        };
+        self.overlay.osd = self.osd.as_ref().map(|osd| osd.expand(&info));
+        if let Some(link) = &mut self.current_link {
+            link.update(match self.files.current() {
+                Entry::File(path) if path != "-" => Some(path),
+                _ => None,
+            });
+        }
+        let downloading = match self.files.current() {
+            Entry::Url { data, .. } if data.get().is_none() => self
+                .download
+                .as_ref()
+                .filter(|d| d.is_for(data))
+                .map(Download::progress),
+            _ => None,
+        };
+        let title = match &self.title {
+            _ if self.settle.is_some() => format!("{} (being written) - reimv", info.name),
+            _ if downloading.is_some() => {
+                let progress = downloading.unwrap();
+                let done = match progress.total {
+                    Some(total) if total > 0 => {
+                        format!("{}%", progress.received * 100 / total)
+                    }
+                    _ => template::format_size(progress.received),
+                };
+                format!("{} (downloading {done}) - reimv", info.name)
+            }
+            Some(title) => title.expand(&info),
+            None => {
+                let mut title = info.name;
+                if let Some(label) = info.page {
+                    title.push_str(&format!(" [{label}]"));
+                }
+                if let (current, total @ 2..) = info.position {
+                    title.push_str(&format!(" ({current}/{total})"));
+                }
+                if info.marked {
+                    title.push_str(" (marked)");
+                }
+                title.push_str(" - reimv");
+                title
+            }
+        };
+        self.window.set_title(conn, title);
     }
 
     pub fn bind_output(&mut self, conn: &mut Connection<Self>, global: &Global) {
+        let wl = global.bind_with_cb(conn, 1..=4, wl_output_cb).unwrap();
         self.outputs.push(Output {
             reg_name: global.name,
-            wl: global.bind_with_cb(conn, 1..=4, wl_output_cb).unwrap(),
+            wl,
             scale: 1,
+            physical_width: 0,
+            mode_width: 0,
+            power: power::watch(conn, &self.globals, wl),
+            powered: true,
         });
     }
+
+    /// Whether all the outputs the window is on are turned off, e.g. by an idle daemon.
+    pub fn displays_off(&self) -> bool {
+        let mut outputs = self
+            .outputs
+            .iter()
+            .filter(|o| self.window.outputs.contains(&o.wl.id()))
+            .peekable();
+        outputs.peek().is_some() && outputs.all(|o| !o.powered)
+    }
+
+    /// The image scale at which the image is shown at its physical size, according to its
+    /// resolution metadata. Images without such metadata are assumed to be 72 DPI.
+    ///
+    /// Returns `None` if the physical size of the output the window is on is unknown.
+    pub fn physical_scale(&self) -> Option<f32> {
+        let output_dpi = self
+            .outputs
+            .iter()
+            .filter(|o| self.window.outputs.contains(&o.wl.id()))
+            .filter(|o| o.physical_width > 0 && o.mode_width > 0)
+            .map(|o| o.mode_width as f32 / (o.physical_width as f32 / 25.4))
+            .next()?;
+        let image_dpi = self.backend.dpi().unwrap_or(72.0);
+        let buffer_scale = match self.window.scale120 {
+            Some(scale120) => scale120 as f32 / 120.0,
+            None => self.window.get_int_scale(self) as f32,
+        };
+        Some(output_dpi / image_dpi / buffer_scale)
+    }
 }
 
-impl KeyboardHandler for State {
-    fn get_keyboard(&mut self, wl_keyboard: WlKeyboard) -> &mut Keyboard {
-        self.keyboards
-            .iter_mut()
-            .find(|k| k.wl_keyboard() == wl_keyboard)
-            .unwrap()
+/// The keys which show the next image in presentation mode, in addition to `n`.
+const NEXT_SLIDE_KEYS: &[xkb::Keysym] = &[
+    xkb::Keysym::Right,
+    xkb::Keysym::Page_Down,
+    xkb::Keysym::space,
+];
+const PREVIOUS_SLIDE_KEYS: &[xkb::Keysym] = &[
+    xkb::Keysym::Left,
+    xkb::Keysym::Page_Up,
+    xkb::Keysym::BackSpace,
+];
+
+impl State {
+    fn key_pressed(&mut self, conn: &mut Connection<Self>, event: KeyboardEvent) {
+        if let Some((_, prompt)) = &mut self.rename_prompt {
+            match prompt.key(&event) {
+                Input::Ignored => return,
+                Input::Edited => self.overlay.message = Some(prompt.message()),
+                Input::Done(name) => {
+                    let (path, _) = self.rename_prompt.take().unwrap();
+                    self.rename(conn, &path, &name);
+                }
+                Input::Cancelled => {
+                    self.rename_prompt = None;
+                    self.overlay.message = None;
+                }
+            }
+            Window::frame(self);
+            return;
+        }
+        if self.delete_prompt.is_some() {
+            let keysym = event.xkb_state.key_get_one_sym(event.keycode);
+            // Shift is held for Y
+            if keysym.is_modifier_key() {
+                return;
+            }
+            let (path, permanently) = self.delete_prompt.take().unwrap();
+            match event.text(self.bindings).as_str() {
+                "y" | "Y" => self.delete(conn, path, permanently),
+                _ => self.overlay.message = None,
+            }
+            Window::frame(self);
+            return;
+        }
+
+        let Some(action) = self.binding(&event) else {
+            return;
+        };
+
+        // Holding the key must not move the next images too
+        let once = matches!(
+            action,
+            Action::MoveTo(_)
+                | Action::CopyTo(_)
+                | Action::Rename
+                | Action::Open
+                | Action::Digit(_)
+                | Action::GoTo { .. }
+        );
+        if let Some(info) = event.repeat_info.filter(|_| !once) {
+            if event.xkb_state.get_keymap().key_repeats(event.keycode) {
+                self.kbd_repeat = Some(RepeatState {
+                    key: event.keycode,
+                    action,
+                    timer: KeyRepeat::new(info.delay, info.interval),
+                });
+            }
+        }
+
+        self.handle_action(conn, action);
+    }
+
+    /// Look up the action of the key which repeats again, after the layout has changed.
+    fn key_rebound(&mut self, event: KeyboardEvent) {
+        match self.binding(&event) {
+            Some(action) => {
+                if let Some(repeat) = &mut self.kbd_repeat {
+                    repeat.action = action;
+                }
+            }
+            None => self.kbd_repeat = None,
+        }
     }
 
-    fn key_presed(&mut self, conn: &mut Connection<Self>, event: KeyboardEvent) {
-        let action = match event.xkb_state.key_get_utf8(event.keycode).as_str() {
+    fn binding(&self, event: &KeyboardEvent) -> Option<Action> {
+        let keysym = event.xkb_state.key_get_one_sym(event.keycode);
+        let alt = event
+            .xkb_state
+            .mod_name_is_active(xkb::MOD_NAME_ALT, xkb::STATE_MODS_EFFECTIVE);
+        let presenting = self.present.is_some();
+        let action = match event.text(self.bindings).as_str() {
+            _ if alt && keysym == xkb::Keysym::Left => Action::History(-1),
+            _ if alt && keysym == xkb::Keysym::Right => Action::History(1),
+            // What presentation remotes send
+            _ if presenting && NEXT_SLIDE_KEYS.contains(&keysym) => Action::Navigate(1),
+            _ if presenting && PREVIOUS_SLIDE_KEYS.contains(&keysym) => Action::Navigate(-1),
+            _ if keysym == xkb::Keysym::BackSpace => Action::Navigate(-1),
+            digit @ ("1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9")
+                if alt || !self.move_to.is_empty() =>
+            {
+                let index = digit.parse::<usize>().unwrap() - 1;
+                match alt {
+                    true => Action::CopyTo(index),
+                    false => Action::MoveTo(index),
+                }
+            }
+            digit @ ("0" | "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9") => {
+                Action::Digit(digit.parse().unwrap())
+            }
+            _ if keysym == xkb::Keysym::Delete => Action::Delete {
+                permanently: event
+                    .xkb_state
+                    .mod_name_is_active(xkb::MOD_NAME_SHIFT, xkb::STATE_MODS_EFFECTIVE),
+            },
+            _ if keysym == xkb::Keysym::F2 => Action::Rename,
+            " " => Action::Navigate(1),
             "h" => Action::MoveLeft,
             "l" => Action::MoveRight,
             "k" => Action::MoveUp,
@@ -220,24 +2250,42 @@ impl KeyboardHandler for State {
                 y: self.window.height as f32 / 2.0,
                 val: -10.0,
             },
+            "p" => Action::PhysicalSize,
+            "e" => Action::Exposure(0.5),
+            "E" => Action::Exposure(-0.5),
+            "r" => Action::ToggleRulers,
+            "M" => Action::ToggleMeasure,
+            "i" => Action::ToggleInspect,
+            "P" => Action::TogglePresent,
+            "L" => Action::ToggleLaser,
+            "[" => Action::TurnPage(-1),
+            "]" => Action::TurnPage(1),
+            "{" => Action::TurnPage(isize::MIN),
+            "}" => Action::TurnPage(isize::MAX),
             "f" => Action::ToggleFullscreen,
-            _ => return,
+            "a" => Action::ToggleAnimation,
+            "n" => Action::Navigate(1),
+            "N" => Action::Navigate(-1),
+            "v" => Action::ToggleKeepView,
+            "z" => Action::Random,
+            "g" => Action::GoTo { last: false },
+            "G" => Action::GoTo { last: true },
+            "s" => Action::ToggleSlideshow,
+            "I" => Action::ToggleIntegerZoom,
+            "m" => Action::ToggleMark,
+            "o" => Action::Open,
+            _ => return None,
         };
+        Some(action)
+    }
 
-        if let Some(info) = event.repeat_info {
-            if event.xkb_state.get_keymap().key_repeats(event.keycode) {
-                self.kbd_repeat = Some(RepeatState {
-                    key: event.keycode,
-                    action,
-                    timer: info.timer(),
-                });
-            }
-        }
-
-        self.handle_action(conn, action);
+    /// Whether keys go through compose sequences: while text is typed, and for bindings by
+    /// character. Keys found by their position are not, since a dead key would then do nothing.
+    fn composes(&self) -> bool {
+        self.rename_prompt.is_some() || self.bindings == Bindings::Layout
     }
 
-    fn key_released(&mut self, _: &mut Connection<Self>, event: KeyboardEvent) {
+    fn key_released(&mut self, event: KeyboardEvent) {
         if self.kbd_repeat.as_ref().map(|r| r.key) == Some(event.keycode) {
             self.kbd_repeat = None;
         }
@@ -250,19 +2298,76 @@ pub enum Action {
     MoveRight,
     MoveUp,
     MoveDown,
-    Zoom { x: f32, y: f32, val: f32 },
+    Zoom {
+        x: f32,
+        y: f32,
+        val: f32,
+    },
+    PhysicalSize,
+    /// Change the exposure of an HDR image, in stops
+    Exposure(f32),
+    ToggleRulers,
+    ToggleMeasure,
+    ToggleInspect,
+    /// Enter or leave presentation mode
+    TogglePresent,
+    ToggleLaser,
+    TurnPage(isize),
     ToggleFullscreen,
+    /// Freeze animations at their start or play them again
+    ToggleAnimation,
+    /// Move through the file list
+    Navigate(isize),
+    /// Move through the images shown so far, independent of the order of the list
+    History(isize),
+    /// See `--keep-view`
+    ToggleKeepView,
+    /// Jump to a random image of the file list
+    Random,
+    /// Add a digit to the number for the next key, like `42` of `42g`
+    Digit(usize),
+    /// Show the image at the position typed before, or else the first or the `last` one
+    GoTo {
+        last: bool,
+    },
+    /// Start or stop moving on to the next image after a while
+    ToggleSlideshow,
+    /// See `--integer-zoom`
+    ToggleIntegerZoom,
+    /// Mark or unmark the current image, for `--print-marked`
+    ToggleMark,
+    /// Move the current file into a directory of `--move-to`, by its index
+    MoveTo(usize),
+    /// Copy the current file into a directory of `--copy-to`, by its index
+    CopyTo(usize),
+    /// Ask to move the current file to the trash, or to delete it
+    Delete {
+        permanently: bool,
+    },
+    /// Ask for a new name of the current file
+    Rename,
+    /// Choose more files to show with the file chooser of the desktop
+    Open,
 }
 
 #[derive(Clone, Copy)]
 struct MoveTransaction {
     wl_seat: WlSeat,
+    /// Index of the guide being dragged. The image is panned if `None`.
+    guide: Option<usize>,
 }
 
 pub struct Output {
     reg_name: u32,
     wl: WlOutput,
     scale: u32,
+    /// In millimeters, zero if unknown
+    physical_width: u32,
+    /// In pixels, of the current mode
+    mode_width: u32,
+    power: Option<ZwlrOutputPowerV1>,
+    /// Whether the output is on, which it is as far as we know without the power protocol
+    powered: bool,
 }
 
 pub struct Pointer {
@@ -275,6 +2380,25 @@ pub struct Pointer {
     y: f32,
 }
 
+/// What pinching does besides zooming around the fingers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Pinch {
+    /// Nothing, the image only moves to keep the point between the fingers in place
+    Zoom,
+    /// Move the image along with the fingers
+    #[default]
+    Pan,
+}
+
+fn parse_sensitivity(text: &str) -> Result<f32> {
+    let factor: f32 = text.parse()?;
+    ensure!(
+        factor.is_finite() && factor > 0.0,
+        "expected a positive factor"
+    );
+    Ok(factor)
+}
+
 struct PinchGesture {
     wl: ZwpPointerGesturePinchV1,
     state: Option<PinchGestureState>,
@@ -353,7 +2477,12 @@ fn wl_registry_cb(conn: &mut Connection<State>, state: &mut State, event: &wl_re
         }
         wl_registry::Event::GlobalRemove(name) => {
             if let Some(output_i) = state.outputs.iter().position(|o| o.reg_name == *name) {
-                let output = state.outputs.swap_remove(output_i).wl;
+                let Output {
+                    wl: output, power, ..
+                } = state.outputs.swap_remove(output_i);
+                if let Some(power) = power {
+                    power.destroy(conn);
+                }
                 state.window.outputs.remove(&output.id());
                 if output.version() >= 3 {
                     output.release(conn);
@@ -365,17 +2494,26 @@ fn wl_registry_cb(conn: &mut Connection<State>, state: &mut State, event: &wl_re
 }
 
 fn wl_output_cb(ctx: EventCtx<WlOutput>) {
-    if let wl_output::Event::Scale(scale) = ctx.event {
-        let output = ctx
-            .state
-            .outputs
-            .iter_mut()
-            .find(|o| o.wl == ctx.proxy)
-            .unwrap();
-        output.scale = scale.try_into().unwrap();
-        if ctx.state.window.outputs.contains(&ctx.proxy.id()) {
-            Window::frame(ctx.state, ctx.conn);
+    let output = ctx
+        .state
+        .outputs
+        .iter_mut()
+        .find(|o| o.wl == ctx.proxy)
+        .unwrap();
+    match ctx.event {
+        wl_output::Event::Scale(scale) => {
+            output.scale = scale.try_into().unwrap();
+            if ctx.state.window.outputs.contains(&ctx.proxy.id()) {
+                Window::frame(ctx.state);
+            }
+        }
+        wl_output::Event::Geometry(args) => {
+            output.physical_width = args.physical_width.try_into().unwrap_or(0);
+        }
+        wl_output::Event::Mode(args) if args.flags.contains(wl_output::Mode::Current) => {
+            output.mode_width = args.width.try_into().unwrap_or(0);
         }
+        _ => (),
     }
 }
 
@@ -399,7 +2537,7 @@ fn wl_pointer_cb(ctx: EventCtx<WlPointer>) {
             ptr.y = args.surface_y.as_f32();
             ptr.themed.set_cursor(
                 ctx.conn,
-                &mut ctx.state.shm_alloc,
+                &mut ctx.state.cursor_shm,
                 &ctx.state.default_cursor,
                 gui_scale,
                 ptr.enter_serial,
@@ -409,7 +2547,11 @@ fn wl_pointer_cb(ctx: EventCtx<WlPointer>) {
             assert_eq!(args.surface, ctx.state.window.surface.id());
             if let Some(mt) = &mut ctx.state.move_transaction {
                 if mt.wl_seat == ptr.seat {
+                    let panning = mt.guide.is_none();
                     ctx.state.move_transaction = None;
+                    if panning {
+                        ctx.state.release_drag();
+                    }
                 }
             }
         }
@@ -420,58 +2562,161 @@ fn wl_pointer_cb(ctx: EventCtx<WlPointer>) {
             let dy = y - ptr.y;
             ptr.x = x;
             ptr.y = y;
+            if ctx.state.inspect.is_some() {
+                ctx.state.inspect_pointer(x, y, false);
+                Window::frame(ctx.state);
+                return;
+            }
+            if let Some(present) = &mut ctx.state.present {
+                let laser = present.laser.is_some();
+                if laser {
+                    present.laser = Some((x, y));
+                }
+                let magnifying = present.magnifying();
+                if magnifying {
+                    present.magnify(&mut ctx.state.img_transform, (x, y));
+                }
+                if laser || magnifying {
+                    Window::frame(ctx.state);
+                }
+                return;
+            }
             if let Some(mt) = &mut ctx.state.move_transaction {
                 if mt.wl_seat == ptr.seat {
-                    ctx.state.img_transform.x += dx;
-                    ctx.state.img_transform.y += dy;
-                    Window::frame(ctx.state, ctx.conn);
+                    match mt.guide {
+                        Some(i) => {
+                            if let Some(guide) = ctx.state.guides.get_mut(i) {
+                                guide.move_to(&ctx.state.img_transform, x, y);
+                            }
+                        }
+                        None => ctx.state.drag_by(dx, dy),
+                    }
+                    Window::frame(ctx.state);
                 }
             }
         }
         wl_pointer::Event::Button(args) => {
             match (args.button, args.state, &mut ctx.state.move_transaction) {
+                (LEFT_PTR_BUTTON, wl_pointer::ButtonState::Pressed, None)
+                    if ctx.state.measure.is_some() =>
+                {
+                    let t = ctx.state.img_transform;
+                    let point = ((ptr.x - t.x) / t.scale, (ptr.y - t.y) / t.scale);
+                    let measure = ctx.state.measure.as_mut().unwrap();
+                    ctx.state.overlay.message = match measure.click(point) {
+                        Some(distance) => {
+                            let text = measure::describe(distance, ctx.state.backend.dpi());
+                            println!("{text}");
+                            Some(text)
+                        }
+                        None => Some("Measure: click the second point".into()),
+                    };
+                    Window::frame(ctx.state);
+                }
+                (LEFT_PTR_BUTTON, wl_pointer::ButtonState::Pressed, _)
+                    if ctx.state.inspect.is_some() =>
+                {
+                    let t = ctx.state.img_transform;
+                    let point = ((ptr.x - t.x) / t.scale, (ptr.y - t.y) / t.scale);
+                    let inspect = ctx.state.inspect.as_mut().unwrap();
+                    inspect.anchor = Some(point);
+                    inspect.cursor = Some(point);
+                    inspect.dragging = true;
+                    Window::frame(ctx.state);
+                }
+                (LEFT_PTR_BUTTON, wl_pointer::ButtonState::Released, _)
+                    if ctx.state.inspect.is_some() =>
+                {
+                    let (x, y) = (ptr.x, ptr.y);
+                    ctx.state.inspect_pointer(x, y, true);
+                    Window::frame(ctx.state);
+                }
+                (LEFT_PTR_BUTTON, wl_pointer::ButtonState::Pressed, None)
+                    if ctx.state.present.is_some() =>
+                {
+                    let present = ctx.state.present.as_mut().unwrap();
+                    present.magnify(&mut ctx.state.img_transform, (ptr.x, ptr.y));
+                    Window::frame(ctx.state);
+                }
+                (LEFT_PTR_BUTTON, wl_pointer::ButtonState::Released, _)
+                    if ctx.state.present.as_ref().is_some_and(Present::magnifying) =>
+                {
+                    let present = ctx.state.present.as_mut().unwrap();
+                    present.release(&mut ctx.state.img_transform);
+                    Window::frame(ctx.state);
+                }
                 (LEFT_PTR_BUTTON, wl_pointer::ButtonState::Pressed, None) => {
-                    ctx.state.move_transaction = Some(MoveTransaction { wl_seat: ptr.seat });
+                    let guide = if ctx.state.overlay.rulers {
+                        guides::grab(
+                            &mut ctx.state.guides,
+                            &ctx.state.img_transform,
+                            ptr.x,
+                            ptr.y,
+                        )
+                    } else {
+                        None
+                    };
+                    ctx.state.move_transaction = Some(MoveTransaction {
+                        wl_seat: ptr.seat,
+                        guide,
+                    });
                     ptr.themed.set_cursor(
                         ctx.conn,
-                        &mut ctx.state.shm_alloc,
+                        &mut ctx.state.cursor_shm,
                         &ctx.state.move_cursor,
                         gui_scale,
                         ptr.enter_serial,
                     );
+                    if guide.is_some() {
+                        Window::frame(ctx.state);
+                    }
                 }
                 (LEFT_PTR_BUTTON, wl_pointer::ButtonState::Released, Some(mt))
                     if mt.wl_seat == ptr.seat =>
                 {
                     ptr.themed.set_cursor(
                         ctx.conn,
-                        &mut ctx.state.shm_alloc,
+                        &mut ctx.state.cursor_shm,
                         &ctx.state.default_cursor,
                         gui_scale,
                         ptr.enter_serial,
                     );
+                    if let Some(i) = mt.guide.filter(|&i| i < ctx.state.guides.len()) {
+                        if ctx.state.guides[i].over_ruler(ptr.x, ptr.y) {
+                            ctx.state.guides.remove(i);
+                            Window::frame(ctx.state);
+                        }
+                        let file_state = FileState {
+                            guides: ctx.state.guides.clone(),
+                        };
+                        if let Err(e) = file_state.save(ctx.state.files.current().path()) {
+                            ctx.state.overlay.message = Some(format!("Could not save guides: {e}"));
+                            Window::frame(ctx.state);
+                        }
+                    } else {
+                        ctx.state.release_drag();
+                    }
                     ctx.state.move_transaction = None;
                 }
                 _ => (),
             }
         }
-        wl_pointer::Event::Axis(args) => {
+        wl_pointer::Event::Axis(args)
             if args.axis == wl_pointer::Axis::VerticalScroll
                 && ctx
                     .state
                     .move_transaction
-                    .map_or(true, |mt| mt.wl_seat == ptr.seat)
-            {
-                let (x, y) = (ptr.x, ptr.y);
-                ctx.state.handle_action(
-                    ctx.conn,
-                    Action::Zoom {
-                        x,
-                        y,
-                        val: args.value.as_f32(),
-                    },
-                );
-            }
+                    .is_none_or(|mt| mt.wl_seat == ptr.seat) =>
+        {
+            let (x, y) = (ptr.x, ptr.y);
+            ctx.state.handle_action(
+                ctx.conn,
+                Action::Zoom {
+                    x,
+                    y,
+                    val: args.value.as_f32(),
+                },
+            );
         }
         _ => (),
     }
@@ -502,25 +2747,26 @@ fn pointer_pinch_cb(ctx: EventCtx<ZwpPointerGesturePinchV1>) {
             });
             ptr.themed.set_cursor(
                 ctx.conn,
-                &mut ctx.state.shm_alloc,
+                &mut ctx.state.cursor_shm,
                 &ctx.state.move_cursor,
                 gui_scale,
                 ptr.enter_serial,
             );
         }
         (Event::Update(args), Some(s)) => {
-            let val = (args.scale.as_f32() - s.prev_scale) * -100.0;
+            let val = (args.scale.as_f32() - s.prev_scale) * -100.0 * ctx.state.pinch_sensitivity;
             let (x, y) = (ptr.x, ptr.y);
             s.prev_scale = args.scale.as_f32();
-            ctx.state.img_transform.x += args.dx.as_f32();
-            ctx.state.img_transform.y += args.dy.as_f32();
+            if ctx.state.pinch == Pinch::Pan {
+                ctx.state.drag_by(args.dx.as_f32(), args.dy.as_f32());
+            }
             ctx.state
                 .handle_action(ctx.conn, Action::Zoom { x, y, val });
         }
         (Event::End(args), Some(s)) => {
             ptr.themed.set_cursor(
                 ctx.conn,
-                &mut ctx.state.shm_alloc,
+                &mut ctx.state.cursor_shm,
                 &ctx.state.default_cursor,
                 gui_scale,
                 ptr.enter_serial,
@@ -529,7 +2775,8 @@ fn pointer_pinch_cb(ctx: EventCtx<ZwpPointerGesturePinchV1>) {
                 ctx.state.img_transform = s.fallback_transform;
             }
             pg.state = None;
-            Window::frame(ctx.state, ctx.conn);
+            ctx.state.release_drag();
+            Window::frame(ctx.state);
         }
         _ => (),
     }
@@ -0,0 +1,41 @@
+//! Measuring distances between two points of the image.
+
+/// Points are in image coordinates, so that they follow panning and zooming.
+#[derive(Debug, Default)]
+pub struct Measure {
+    pub start: Option<(f32, f32)>,
+    pub end: Option<(f32, f32)>,
+}
+
+impl Measure {
+    /// Register a click. Every second click completes a measurement, in which case the distance
+    /// between the two points is returned.
+    pub fn click(&mut self, point: (f32, f32)) -> Option<f32> {
+        match (self.start, self.end) {
+            (Some(start), None) => {
+                self.end = Some(point);
+                Some((point.0 - start.0).hypot(point.1 - start.1))
+            }
+            _ => {
+                self.start = Some(point);
+                self.end = None;
+                None
+            }
+        }
+    }
+
+    pub fn points(&self) -> impl Iterator<Item = (f32, f32)> {
+        self.start.into_iter().chain(self.end)
+    }
+}
+
+/// Format a distance in image pixels, adding physical units if the resolution is known.
+pub fn describe(distance: f32, dpi: Option<f32>) -> String {
+    match dpi {
+        Some(dpi) => {
+            let inches = distance / dpi;
+            format!("{distance:.1} px ({:.2} cm, {inches:.2} in)", inches * 2.54)
+        }
+        None => format!("{distance:.1} px"),
+    }
+}
@@ -0,0 +1,498 @@
+//! Image metadata: physical resolution, color profiles and the TIFF/EXIF structures that carry
+//! them.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+
+use flate2::read::ZlibDecoder;
+
+use crate::psd;
+
+pub const TAG_X_RESOLUTION: u16 = 0x11A;
+pub const TAG_RESOLUTION_UNIT: u16 = 0x128;
+pub const TAG_SUB_IFDS: u16 = 0x14A;
+pub const TAG_EXIF_IFD: u16 = 0x8769;
+pub const TAG_ICC_PROFILE: u16 = 0x8773;
+pub const TAG_MAKE: u16 = 0x10F;
+pub const TAG_MODEL: u16 = 0x110;
+pub const TAG_DATE_TIME: u16 = 0x132;
+pub const TAG_GPS_IFD: u16 = 0x8825;
+pub const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+/// The first tag of a GPS IFD which records a location.
+const TAG_GPS_LATITUDE: u16 = 0x2;
+
+/// Larger ICC profiles are ignored. Real ones rarely exceed a few hundred kilobytes.
+const MAX_ICC_PROFILE_LEN: u64 = 4 << 20;
+
+/// Read the horizontal resolution of the image, in dots per inch.
+pub fn dpi(data: &[u8]) -> Option<f32> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        png_dpi(data)
+    } else if data.starts_with(&[0xFF, 0xD8]) {
+        jpeg_dpi(data)
+    } else if psd::is_psd(data) {
+        psd_dpi(data)
+    } else {
+        tiff_dpi(&Tiff::new(data)?)
+    }
+    .filter(|dpi| dpi.is_finite() && *dpi > 0.0)
+}
+
+fn png_dpi(data: &[u8]) -> Option<f32> {
+    let (_, chunk) = png_chunks(data).find(|(kind, _)| *kind == b"pHYs")?;
+    if chunk.len() != 9 || chunk[8] != 1 {
+        return None;
+    }
+    let ppm = u32::from_be_bytes(chunk[..4].try_into().unwrap());
+    Some(ppm as f32 * 0.0254)
+}
+
+/// The type and data of each PNG chunk before the image data, where metadata ends.
+fn png_chunks(data: &[u8]) -> impl Iterator<Item = (&[u8; 4], &[u8])> {
+    let mut i = 8;
+    std::iter::from_fn(move || {
+        let len = u32::from_be_bytes(data.get(i..i + 4)?.try_into().unwrap()) as usize;
+        let kind: &[u8; 4] = data.get(i + 4..i + 8)?.try_into().unwrap();
+        let chunk = data.get(i + 8..(i + 8).checked_add(len)?)?;
+        i += len + 12;
+        (kind != b"IDAT" && kind != b"IEND").then_some((kind, chunk))
+    })
+}
+
+/// The color primaries and transfer function of a PNG file, as coded in ITU-T H.273.
+pub fn png_cicp(data: &[u8]) -> Option<(u8, u8)> {
+    if !data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return None;
+    }
+    let (_, chunk) = png_chunks(data).find(|(kind, _)| *kind == b"cICP")?;
+    // Only RGB data is allowed in PNG files, so the matrix coefficients must be zero
+    match *chunk {
+        [primaries, transfer, 0, _] => Some((primaries, transfer)),
+        _ => None,
+    }
+}
+
+/// The embedded ICC profile of a PNG, JPEG, WebP, TIFF or PSD file.
+pub fn icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let profile = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        png_icc_profile(data)
+    } else if data.starts_with(&[0xFF, 0xD8]) {
+        jpeg_icc_profile(data)
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        webp_icc_profile(data)
+    } else if psd::is_psd(data) {
+        psd_resource(data, 0x40F).map(<[u8]>::to_vec)
+    } else {
+        let tiff = Tiff::new(data)?;
+        tiff.bytes(tiff.ifd0()?.get(&TAG_ICC_PROFILE)?)
+            .map(<[u8]>::to_vec)
+    }?;
+    (profile.len() as u64 <= MAX_ICC_PROFILE_LEN).then_some(profile)
+}
+
+fn png_icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let (_, chunk) = png_chunks(data).find(|(kind, _)| *kind == b"iCCP")?;
+    // A profile name, and the compression method, which is always zlib
+    let name_len = chunk.iter().position(|&b| b == 0)?;
+    let compressed = chunk.get(name_len + 2..)?;
+    let mut profile = Vec::new();
+    ZlibDecoder::new(compressed)
+        .take(MAX_ICC_PROFILE_LEN + 1)
+        .read_to_end(&mut profile)
+        .ok()?;
+    Some(profile)
+}
+
+/// Profiles larger than a segment are split across several, which are numbered from one.
+fn jpeg_icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks: Vec<(u8, &[u8])> = jpeg_segments(data)
+        .filter(|(marker, _)| *marker == 0xE2)
+        .filter_map(|(_, segment)| segment.strip_prefix(b"ICC_PROFILE\0"))
+        .filter_map(|chunk| Some((*chunk.first()?, chunk.get(2..)?)))
+        .collect();
+    if chunks.is_empty() {
+        return None;
+    }
+    chunks.sort_by_key(|&(seq, _)| seq);
+    Some(
+        chunks
+            .into_iter()
+            .flat_map(|(_, chunk)| chunk)
+            .copied()
+            .collect(),
+    )
+}
+
+fn webp_icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let mut i = 12;
+    while let Some(kind) = data.get(i..i + 4) {
+        let len = u32::from_le_bytes(data.get(i + 4..i + 8)?.try_into().unwrap()) as usize;
+        let chunk = data.get(i + 8..(i + 8).checked_add(len)?)?;
+        match kind {
+            b"ICCP" => return Some(chunk.to_vec()),
+            // The profile must come before the image data
+            b"VP8 " | b"VP8L" | b"ANIM" => return None,
+            _ => i += 8 + ((len + 1) & !1),
+        }
+    }
+    None
+}
+
+fn jpeg_dpi(data: &[u8]) -> Option<f32> {
+    let mut exif_dpi = None;
+    for (marker, segment) in jpeg_segments(data) {
+        match marker {
+            0xE0 if segment.starts_with(b"JFIF\0") && segment.len() >= 12 => {
+                let density = u16::from_be_bytes([segment[8], segment[9]]) as f32;
+                match segment[7] {
+                    1 => return Some(density),
+                    2 => return Some(density * 2.54),
+                    _ => (),
+                }
+            }
+            0xE1 if segment.starts_with(b"Exif\0\0") => {
+                exif_dpi = Tiff::new(&segment[6..]).and_then(|tiff| tiff_dpi(&tiff));
+            }
+            _ => (),
+        }
+    }
+    exif_dpi
+}
+
+/// The EXIF data of a JPEG file, which is a TIFF structure.
+pub fn jpeg_exif(data: &[u8]) -> Option<&[u8]> {
+    jpeg_segments(data)
+        .find(|(marker, segment)| *marker == 0xE1 && segment.starts_with(b"Exif\0\0"))
+        .map(|(_, segment)| &segment[6..])
+}
+
+/// What the EXIF data says about how a photo was taken.
+#[derive(Debug, Clone, Default)]
+pub struct Exif {
+    /// When the photo was taken, as `YYYY-MM-DD HH:MM:SS`
+    pub date: Option<String>,
+    /// The make and model of the camera
+    pub camera: Option<String>,
+    /// Whether the location where it was taken is recorded
+    pub gps: bool,
+}
+
+/// Read the EXIF data of a JPEG, PNG, WebP or TIFF-based file. Missing fields are left empty.
+pub fn exif(data: &[u8]) -> Exif {
+    let tiff = if data.starts_with(&[0xFF, 0xD8]) {
+        jpeg_exif(data)
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        png_chunks(data)
+            .find(|(kind, _)| *kind == b"eXIf")
+            .map(|(_, chunk)| chunk)
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        webp_exif(data)
+    } else {
+        Some(data)
+    };
+    let Some(tiff) = tiff.and_then(Tiff::new) else {
+        return Exif::default();
+    };
+    let Some(ifd0) = tiff.ifd0() else {
+        return Exif::default();
+    };
+    let sub_ifd = |tag| {
+        let offset = tiff.value_of(&ifd0, tag)?;
+        tiff.parse_ifd(offset as usize).map(|(ifd, _)| ifd)
+    };
+
+    let date = sub_ifd(TAG_EXIF_IFD)
+        .and_then(|exif| tiff.text(exif.get(&TAG_DATE_TIME_ORIGINAL)?))
+        .or_else(|| tiff.text(ifd0.get(&TAG_DATE_TIME)?))
+        .map(|date| match date.split_once(' ') {
+            // The date is written with colons, like the time
+            Some((day, time)) => format!("{} {time}", day.replace(':', "-")),
+            None => date.replace(':', "-"),
+        });
+    let make = ifd0.get(&TAG_MAKE).and_then(|e| tiff.text(e));
+    let model = ifd0.get(&TAG_MODEL).and_then(|e| tiff.text(e));
+    // Only the brand of makes like "NIKON CORPORATION"
+    let brand = make.map(|make| make.split_whitespace().next().unwrap_or(make));
+    let camera = match (brand, model) {
+        // Most models already start with the brand, as in "Canon EOS 5D"
+        (Some(brand), Some(model)) if !starts_with_ignore_case(model, brand) => {
+            Some(format!("{brand} {model}"))
+        }
+        (_, Some(model)) => Some(model.to_owned()),
+        (_, None) => make.map(str::to_owned),
+    };
+    let gps = sub_ifd(TAG_GPS_IFD).is_some_and(|gps| gps.contains_key(&TAG_GPS_LATITUDE));
+
+    Exif { date, camera, gps }
+}
+
+fn starts_with_ignore_case(text: &str, prefix: &str) -> bool {
+    text.get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+}
+
+/// WebP files keep their EXIF data in a chunk, usually after the image data.
+fn webp_exif(data: &[u8]) -> Option<&[u8]> {
+    let mut i = 12;
+    while let Some(kind) = data.get(i..i + 4) {
+        let len = u32::from_le_bytes(data.get(i + 4..i + 8)?.try_into().unwrap()) as usize;
+        let chunk = data.get(i + 8..(i + 8).checked_add(len)?)?;
+        if kind == b"EXIF" {
+            // Some writers copy the JPEG signature along with the data
+            return Some(chunk.strip_prefix(b"Exif\0\0").unwrap_or(chunk));
+        }
+        i += 8 + ((len + 1) & !1);
+    }
+    None
+}
+
+/// The marker and payload of each segment before the start of scan, where metadata ends.
+pub fn jpeg_segments(data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut i = 2;
+    std::iter::from_fn(move || {
+        let &[0xFF, marker, hi, lo] = data.get(i..i + 4)? else {
+            return None;
+        };
+        let len = u16::from_be_bytes([hi, lo]) as usize;
+        let segment = data.get(i + 4..(i + 2 + len).max(i + 4))?;
+        i += 2 + len;
+        (marker != 0xDA).then_some((marker, segment))
+    })
+}
+
+fn psd_dpi(data: &[u8]) -> Option<f32> {
+    const RESOLUTION_INFO: u16 = 0x3ED;
+    let block = psd_resource(data, RESOLUTION_INFO)?;
+    // Fixed point, always in pixels per inch regardless of the display unit
+    let res = u32::from_be_bytes(block.get(..4)?.try_into().ok()?);
+    Some(res as f32 / 65536.0)
+}
+
+/// The image resource block with the given ID.
+fn psd_resource(data: &[u8], wanted: u16) -> Option<&[u8]> {
+    let resources = psd::Sections::parse(data)?.image_resources;
+    let mut i = 0;
+    while resources.get(i..i + 4)? == b"8BIM" {
+        let id = u16::from_be_bytes(resources.get(i + 4..i + 6)?.try_into().ok()?);
+        // A Pascal string padded to an even length
+        let name_len = (*resources.get(i + 6)? as usize + 2) & !1;
+        let len_at = i + 6 + name_len;
+        let len = u32::from_be_bytes(resources.get(len_at..len_at + 4)?.try_into().ok()?) as usize;
+        let block = resources.get(len_at + 4..(len_at + 4).checked_add(len)?)?;
+        if id == wanted {
+            return Some(block);
+        }
+        i = len_at + 4 + ((len + 1) & !1);
+    }
+    None
+}
+
+fn tiff_dpi(tiff: &Tiff) -> Option<f32> {
+    let ifd0 = tiff.ifd0()?;
+    let res = tiff.real(ifd0.get(&TAG_X_RESOLUTION)?, 0)? as f32;
+    match tiff.value_of(&ifd0, TAG_RESOLUTION_UNIT).unwrap_or(2) {
+        2 => Some(res),
+        3 => Some(res * 2.54),
+        _ => None,
+    }
+}
+
+pub struct Tiff<'a> {
+    data: &'a [u8],
+    pub le: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct Entry {
+    kind: u16,
+    pub count: u32,
+    /// Offset of the first value in the file
+    offset: usize,
+}
+
+pub type Ifd = HashMap<u16, Entry>;
+
+impl<'a> Tiff<'a> {
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        let le = match data.get(..2)? {
+            b"II" => true,
+            b"MM" => false,
+            _ => return None,
+        };
+        let tiff = Self { data, le };
+        // Panasonic and Olympus use their own magic numbers
+        matches!(tiff.u16(2)?, 42 | 0x55 | 0x4F52 | 0x5352).then_some(tiff)
+    }
+
+    pub fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.le {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    pub fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.le {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    /// The first IFD of the file.
+    pub fn ifd0(&self) -> Option<Ifd> {
+        self.parse_ifd(self.u32(4)? as usize).map(|(ifd, _)| ifd)
+    }
+
+    /// Offsets of the IFDs in the main chain, i.e. the pages of a multi-page file. SubIFDs are
+    /// not included.
+    pub fn chain(&self) -> Vec<u32> {
+        let mut chain = Vec::new();
+        let mut next = self.u32(4).unwrap_or(0);
+        while next != 0 && !chain.contains(&next) && chain.len() < 4096 {
+            chain.push(next);
+            next = match self.parse_ifd(next as usize) {
+                Some((_, next)) => next,
+                None => 0,
+            };
+        }
+        chain
+    }
+
+    /// All IFDs of the file, including SubIFDs and the EXIF IFD. The first element, if any, is
+    /// IFD0.
+    pub fn ifds(&self) -> Vec<Ifd> {
+        let mut ifds = Vec::new();
+        let mut visited = HashSet::new();
+        let mut queue: Vec<usize> = self.u32(4).map(|o| o as usize).into_iter().collect();
+        let mut queue_i = 0;
+
+        while let Some(&offset) = queue.get(queue_i) {
+            queue_i += 1;
+            if !visited.insert(offset) || ifds.len() > 64 {
+                continue;
+            }
+            let Some((ifd, next)) = self.parse_ifd(offset) else {
+                continue;
+            };
+
+            for tag in [TAG_SUB_IFDS, TAG_EXIF_IFD] {
+                if let Some(entry) = ifd.get(&tag) {
+                    queue.extend(
+                        (0..entry.count as usize)
+                            .filter_map(|i| self.value(entry, i))
+                            .map(|o| o as usize),
+                    );
+                }
+            }
+            if next != 0 {
+                queue.push(next as usize);
+            }
+
+            ifds.push(ifd);
+        }
+
+        ifds.into_iter().filter(|ifd| !ifd.is_empty()).collect()
+    }
+
+    /// Parse the IFD at `offset`, returning it and the offset of the next one.
+    pub fn parse_ifd(&self, offset: usize) -> Option<(Ifd, u32)> {
+        let count = self.u16(offset)?;
+
+        let mut ifd = Ifd::new();
+        for i in 0..count as usize {
+            let pos = offset + 2 + i * 12;
+            let (Some(tag), Some(kind), Some(count)) =
+                (self.u16(pos), self.u16(pos + 2), self.u32(pos + 4))
+            else {
+                break;
+            };
+            let size = match kind {
+                1 | 2 | 6 | 7 => 1,
+                3 | 8 => 2,
+                4 | 9 | 11 | 13 => 4,
+                5 | 10 | 12 => 8,
+                _ => continue,
+            };
+            let value_offset = if size * count as usize <= 4 {
+                pos + 8
+            } else {
+                match self.u32(pos + 8) {
+                    Some(o) => o as usize,
+                    None => continue,
+                }
+            };
+            ifd.insert(
+                tag,
+                Entry {
+                    kind,
+                    count,
+                    offset: value_offset,
+                },
+            );
+        }
+
+        let next = self.u32(offset + 2 + count as usize * 12).unwrap_or(0);
+        Some((ifd, next))
+    }
+
+    /// Get an integer value.
+    pub fn value(&self, entry: &Entry, i: usize) -> Option<u32> {
+        if i >= entry.count as usize {
+            return None;
+        }
+        match entry.kind {
+            1 | 7 => self.data.get(entry.offset + i).map(|&x| x as u32),
+            3 => self.u16(entry.offset + i * 2).map(Into::into),
+            4 | 13 => self.u32(entry.offset + i * 4),
+            _ => None,
+        }
+    }
+
+    /// Get the contents of a byte array.
+    pub fn bytes(&self, entry: &Entry) -> Option<&'a [u8]> {
+        match entry.kind {
+            1 | 7 => self
+                .data
+                .get(entry.offset..entry.offset.checked_add(entry.count as usize)?),
+            _ => None,
+        }
+    }
+
+    /// Get an ASCII string, without the padding some writers add.
+    pub fn text(&self, entry: &Entry) -> Option<&'a str> {
+        if entry.kind != 2 {
+            return None;
+        }
+        let bytes = self
+            .data
+            .get(entry.offset..entry.offset.checked_add(entry.count as usize)?)?;
+        let bytes = bytes.split(|&b| b == 0).next().unwrap_or(bytes);
+        let text = std::str::from_utf8(bytes).ok()?.trim();
+        (!text.is_empty()).then_some(text)
+    }
+
+    pub fn value_of(&self, ifd: &Ifd, tag: u16) -> Option<u32> {
+        self.value(ifd.get(&tag)?, 0)
+    }
+
+    /// Get an integer or rational value, signed or not.
+    pub fn real(&self, entry: &Entry, i: usize) -> Option<f64> {
+        match entry.kind {
+            5 if i < entry.count as usize => {
+                let num = self.u32(entry.offset + i * 8)?;
+                let den = self.u32(entry.offset + i * 8 + 4)?;
+                (den != 0).then(|| num as f64 / den as f64)
+            }
+            10 if i < entry.count as usize => {
+                let num = self.u32(entry.offset + i * 8)? as i32;
+                let den = self.u32(entry.offset + i * 8 + 4)? as i32;
+                (den != 0).then(|| num as f64 / den as f64)
+            }
+            _ => self.value(entry, i).map(Into::into),
+        }
+    }
+}
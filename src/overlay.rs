@@ -0,0 +1,505 @@
+//! A transparent surface drawn on top of the image: rulers and other annotations.
+
+use std::fmt::Write;
+
+use wayrs_client::protocol::*;
+use wayrs_client::Connection;
+use wayrs_protocols::viewporter::*;
+use wayrs_utils::shm_alloc::BufferSpec;
+
+use resvg::{tiny_skia, usvg};
+use usvg::fontdb;
+
+use crate::backend;
+use crate::globals::Globals;
+use crate::guides::{Guide, Orientation};
+use crate::image::ImageTransform;
+use crate::inspect::PixelRect;
+use crate::measure::Measure;
+use crate::State;
+
+/// Thickness of a ruler, in surface local coordinates
+pub const RULER_SIZE: f32 = 18.0;
+/// Minimal distance between two labeled ruler ticks, in surface local coordinates
+const RULER_LABEL_SPACING: f32 = 60.0;
+const FONT_SIZE: f32 = 9.0;
+const MESSAGE_FONT_SIZE: f32 = 13.0;
+
+pub struct Overlay {
+    pub surface: WlSurface,
+    subsurface: WlSubsurface,
+    viewport: WpViewport,
+    /// Loaded when text is drawn for the first time
+    fontdb: Option<fontdb::Database>,
+    /// Whether a buffer is attached to the surface
+    visible: bool,
+
+    pub rulers: bool,
+    /// A status message shown in the bottom left corner
+    pub message: Option<String>,
+    /// Information about the image shown in the top left corner, see `--osd`
+    pub osd: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Edge {
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
+/// Text to be drawn in one go.
+#[derive(Default)]
+struct Labels {
+    svg: String,
+}
+
+impl Overlay {
+    pub fn new(conn: &mut Connection<State>, globals: &Globals, main_surface: WlSurface) -> Self {
+        let surface = globals.wl_compositor.create_surface(conn);
+        let subsurface = globals
+            .wl_subcompositor
+            .get_subsurface(conn, surface, main_surface);
+        let viewport = globals.wp_viewporter.get_viewport(conn, surface);
+
+        let empty_reg = globals.wl_compositor.create_region(conn);
+        surface.set_input_region(conn, Some(empty_reg));
+        empty_reg.destroy(conn);
+
+        Self {
+            surface,
+            subsurface,
+            viewport,
+            fontdb: None,
+            visible: false,
+            rulers: false,
+            message: None,
+            osd: None,
+        }
+    }
+
+    pub fn is_empty(&self, state: &State) -> bool {
+        !self.rulers
+            && self.message.is_none()
+            && self.osd.is_none()
+            && state.measure.is_none()
+            && state.inspect.is_none()
+            && state.present.as_ref().is_none_or(|p| p.laser.is_none())
+    }
+
+    pub fn render(state: &mut State, conn: &mut Connection<State>, ui_scale120: u32) {
+        if state.overlay.is_empty(state) {
+            let this = &mut state.overlay;
+            if this.visible {
+                this.surface.attach(conn, None, 0, 0);
+                this.visible = false;
+            }
+            return;
+        }
+
+        let win_width = state.window.width;
+        let win_height = state.window.height;
+
+        let (pix_width, pix_height) = backend::buffer_size(win_width, win_height, ui_scale120);
+
+        // Drawing needs the state, which the shared memory belongs to
+        let mut pixmap = tiny_skia::Pixmap::new(pix_width, pix_height).unwrap();
+        Self::draw(state, &mut pixmap.as_mut(), ui_scale120);
+
+        let (buffer, canvas) = state
+            .shm_alloc
+            .alloc_buffer(
+                conn,
+                BufferSpec {
+                    width: pix_width,
+                    height: pix_height,
+                    stride: pix_width * 4,
+                    format: wl_shm::Format::Abgr8888,
+                },
+            )
+            .unwrap();
+        canvas.copy_from_slice(pixmap.data());
+
+        let this = &mut state.overlay;
+        this.surface
+            .attach(conn, Some(buffer.into_wl_buffer()), 0, 0);
+        this.viewport
+            .set_destination(conn, win_width as i32, win_height as i32);
+        this.surface.damage(conn, 0, 0, i32::MAX, i32::MAX);
+        this.subsurface.set_position(conn, 0, 0);
+        this.visible = true;
+    }
+
+    /// Draw the overlay over `canvas`, which covers the window.
+    pub fn draw(state: &mut State, canvas: &mut tiny_skia::PixmapMut, ui_scale120: u32) {
+        let img_transform = state.img_transform;
+        let img_dpi = state.backend.dpi();
+
+        let ui_scale = ui_scale120 as f32 / 120.0;
+        let ui_transform = tiny_skia::Transform::from_scale(ui_scale, ui_scale);
+        let (w, h) = (state.window.width as f32, state.window.height as f32);
+        let mut labels = Labels::default();
+
+        if let Some(measure) = &state.measure {
+            draw_measure(canvas, ui_transform, &img_transform, measure);
+        }
+        if let (Some(inspect), Some(pixels)) = (&state.inspect, state.backend.pixels()) {
+            if let Some(rect) = inspect.selection(pixels.width(), pixels.height()) {
+                draw_selection(canvas, ui_transform, &img_transform, rect);
+            }
+        }
+
+        let this = &mut state.overlay;
+        let mut bottom_margin = 0.0;
+        if this.rulers {
+            draw_guides(canvas, ui_transform, &img_transform, &state.guides, (w, h));
+
+            let mut ruler = |edge, offset, unit, suffix| {
+                draw_ruler(
+                    canvas,
+                    &mut labels,
+                    ui_transform,
+                    ui_scale,
+                    (w, h),
+                    edge,
+                    offset,
+                    img_transform.scale * unit,
+                    suffix,
+                )
+            };
+            ruler(Edge::Top, img_transform.x, 1.0, "");
+            ruler(Edge::Left, img_transform.y, 1.0, "");
+            if let Some(dpi) = img_dpi {
+                let px_per_cm = dpi / 2.54;
+                ruler(Edge::Bottom, img_transform.x, px_per_cm, " cm");
+                ruler(Edge::Right, img_transform.y, px_per_cm, " cm");
+                bottom_margin = RULER_SIZE;
+            }
+        }
+
+        if let Some(message) = &this.message {
+            let left_margin = if this.rulers { RULER_SIZE } else { 0.0 };
+            labels.add_message(left_margin + 8.0, h - bottom_margin - 8.0, message);
+        }
+
+        if let Some(osd) = &this.osd {
+            let margin = if this.rulers { RULER_SIZE } else { 0.0 };
+            labels.add_message(margin + 8.0, margin + 8.0 + MESSAGE_FONT_SIZE, osd);
+        }
+
+        let fontdb = this
+            .fontdb
+            .get_or_insert_with(|| load_fonts(state.deterministic));
+        labels.render(canvas, fontdb, ui_transform, w, h);
+
+        // Over everything else, so that it is never hidden
+        if let Some(point) = state.present.as_ref().and_then(|p| p.laser) {
+            draw_laser(canvas, ui_transform, point);
+        }
+    }
+}
+
+fn draw_guides(
+    canvas: &mut tiny_skia::PixmapMut,
+    ui_transform: tiny_skia::Transform,
+    img_transform: &ImageTransform,
+    guides: &[Guide],
+    (w, h): (f32, f32),
+) {
+    let mut paint = tiny_skia::Paint::default();
+    paint.set_color_rgba8(0, 200, 255, 220);
+    let thickness = 1.0 / ui_transform.sx;
+    for guide in guides {
+        let pos = guide.surface_pos(img_transform);
+        let rect = match guide.orientation {
+            Orientation::Horizontal => tiny_skia::Rect::from_xywh(0.0, pos, w, thickness),
+            Orientation::Vertical => tiny_skia::Rect::from_xywh(pos, 0.0, thickness, h),
+        };
+        if let Some(rect) = rect {
+            canvas.fill_rect(rect, &paint, ui_transform, None);
+        }
+    }
+}
+
+fn draw_selection(
+    canvas: &mut tiny_skia::PixmapMut,
+    ui_transform: tiny_skia::Transform,
+    img_transform: &ImageTransform,
+    rect: PixelRect,
+) {
+    let rect = tiny_skia::Rect::from_ltrb(
+        img_transform.x + rect.x0 as f32 * img_transform.scale,
+        img_transform.y + rect.y0 as f32 * img_transform.scale,
+        img_transform.x + rect.x1 as f32 * img_transform.scale,
+        img_transform.y + rect.y1 as f32 * img_transform.scale,
+    );
+    let Some(rect) = rect else { return };
+    let path = tiny_skia::PathBuilder::from_rect(rect);
+
+    let mut paint = tiny_skia::Paint::default();
+    paint.set_color_rgba8(255, 255, 255, 40);
+    canvas.fill_path(
+        &path,
+        &paint,
+        tiny_skia::FillRule::Winding,
+        ui_transform,
+        None,
+    );
+
+    let mut stroke = tiny_skia::Stroke::default();
+    stroke.width = 3.0;
+    paint.set_color_rgba8(0, 0, 0, 160);
+    canvas.stroke_path(&path, &paint, &stroke, ui_transform, None);
+    stroke.width = 1.0;
+    paint.set_color_rgba8(255, 210, 0, 255);
+    canvas.stroke_path(&path, &paint, &stroke, ui_transform, None);
+}
+
+/// Mark the measured points and connect them with a line.
+fn draw_measure(
+    canvas: &mut tiny_skia::PixmapMut,
+    ui_transform: tiny_skia::Transform,
+    img_transform: &ImageTransform,
+    measure: &Measure,
+) {
+    let to_surface = |(x, y): (f32, f32)| {
+        (
+            img_transform.x + x * img_transform.scale,
+            img_transform.y + y * img_transform.scale,
+        )
+    };
+
+    let mut path = tiny_skia::PathBuilder::new();
+    for (x, y) in measure.points().map(to_surface) {
+        path.move_to(x - 6.0, y);
+        path.line_to(x + 6.0, y);
+        path.move_to(x, y - 6.0);
+        path.line_to(x, y + 6.0);
+    }
+    if let (Some(start), Some(end)) = (measure.start, measure.end) {
+        let (start, end) = (to_surface(start), to_surface(end));
+        path.move_to(start.0, start.1);
+        path.line_to(end.0, end.1);
+    }
+    let Some(path) = path.finish() else { return };
+
+    let mut paint = tiny_skia::Paint::default();
+    paint.anti_alias = true;
+    let mut stroke = tiny_skia::Stroke::default();
+
+    // A dark outline keeps the line visible on light images
+    paint.set_color_rgba8(0, 0, 0, 160);
+    stroke.width = 3.0;
+    canvas.stroke_path(&path, &paint, &stroke, ui_transform, None);
+    paint.set_color_rgba8(255, 210, 0, 255);
+    stroke.width = 1.0;
+    canvas.stroke_path(&path, &paint, &stroke, ui_transform, None);
+}
+
+/// A red dot with a glow around it, visible on any image.
+fn draw_laser(
+    canvas: &mut tiny_skia::PixmapMut,
+    ui_transform: tiny_skia::Transform,
+    point: (f32, f32),
+) {
+    let mut paint = tiny_skia::Paint::default();
+    paint.anti_alias = true;
+    for (radius, alpha) in [(14.0, 60), (9.0, 120), (6.0, 255)] {
+        let Some(path) = tiny_skia::PathBuilder::from_circle(point.0, point.1, radius) else {
+            continue;
+        };
+        paint.set_color_rgba8(255, 30, 30, alpha);
+        canvas.fill_path(
+            &path,
+            &paint,
+            tiny_skia::FillRule::Winding,
+            ui_transform,
+            None,
+        );
+    }
+}
+
+/// Draw a ruler along `edge`. Ruler value `v` is at `offset + v * scale` in surface local
+/// coordinates.
+#[allow(clippy::too_many_arguments)]
+fn draw_ruler(
+    canvas: &mut tiny_skia::PixmapMut,
+    labels: &mut Labels,
+    ui_transform: tiny_skia::Transform,
+    ui_scale: f32,
+    (w, h): (f32, f32),
+    edge: Edge,
+    offset: f32,
+    scale: f32,
+    suffix: &str,
+) {
+    let horizontal = matches!(edge, Edge::Top | Edge::Bottom);
+    let len = if horizontal { w } else { h };
+
+    let bar = match edge {
+        Edge::Top => tiny_skia::Rect::from_xywh(0.0, 0.0, w, RULER_SIZE),
+        Edge::Bottom => tiny_skia::Rect::from_xywh(0.0, h - RULER_SIZE, w, RULER_SIZE),
+        Edge::Left => tiny_skia::Rect::from_xywh(0.0, 0.0, RULER_SIZE, h),
+        Edge::Right => tiny_skia::Rect::from_xywh(w - RULER_SIZE, 0.0, RULER_SIZE, h),
+    };
+    let Some(bar) = bar else { return };
+    let mut paint = tiny_skia::Paint::default();
+    paint.set_color_rgba8(0, 0, 0, 180);
+    canvas.fill_rect(bar, &paint, ui_transform, None);
+    paint.set_color_rgba8(230, 230, 230, 255);
+
+    let step = nice_step(RULER_LABEL_SPACING / scale);
+    let minor_step = step / if leading_digit(step) == 2 { 4.0 } else { 5.0 };
+    let decimals = (-step.log10()).ceil().max(0.0) as usize;
+
+    let thickness = 1.0 / ui_scale;
+    let first = (-offset / scale / minor_step).ceil() as i64;
+    let last = ((len - offset) / scale / minor_step).floor() as i64;
+    for i in first..=last {
+        let value = i as f32 * minor_step;
+        let pos = offset + value * scale;
+        let major = (value / step).round() * step;
+        let is_major = (value - major).abs() < minor_step * 0.5;
+        let tick_len = if is_major { 8.0 } else { 4.0 };
+
+        let tick = match edge {
+            Edge::Top => {
+                tiny_skia::Rect::from_xywh(pos, RULER_SIZE - tick_len, thickness, tick_len)
+            }
+            Edge::Bottom => tiny_skia::Rect::from_xywh(pos, h - RULER_SIZE, thickness, tick_len),
+            Edge::Left => {
+                tiny_skia::Rect::from_xywh(RULER_SIZE - tick_len, pos, tick_len, thickness)
+            }
+            Edge::Right => tiny_skia::Rect::from_xywh(w - RULER_SIZE, pos, tick_len, thickness),
+        };
+        if let Some(tick) = tick {
+            canvas.fill_rect(tick, &paint, ui_transform, None);
+        }
+
+        if is_major {
+            let text = format!("{:.*}{suffix}", decimals, major);
+            match edge {
+                Edge::Top => labels.add(pos + 2.0, FONT_SIZE, false, &text),
+                Edge::Bottom => labels.add(pos + 2.0, h - 2.0, false, &text),
+                Edge::Left => labels.add(FONT_SIZE + 1.0, pos - 2.0, true, &text),
+                Edge::Right => labels.add(w - 1.0, pos - 2.0, true, &text),
+            }
+        }
+    }
+}
+
+/// The smallest number of the form {1, 2, 5} * 10^n which is not less than `x`.
+fn nice_step(x: f32) -> f32 {
+    let pow = 10f32.powf(x.log10().floor());
+    [1.0, 2.0, 5.0, 10.0]
+        .into_iter()
+        .map(|m| m * pow)
+        .find(|&s| s >= x * 0.999)
+        .unwrap_or(10.0 * pow)
+}
+
+fn leading_digit(x: f32) -> u32 {
+    (x / 10f32.powf(x.log10().floor())).round() as u32
+}
+
+impl Labels {
+    /// Add a label with the baseline starting at `(x, y)`. Vertical labels are read bottom to top.
+    fn add(&mut self, x: f32, y: f32, vertical: bool, text: &str) {
+        let rotate = if vertical { " rotate(-90)" } else { "" };
+        let _ = write!(
+            self.svg,
+            r#"<text transform="translate({x} {y}){rotate}">{}</text>"#,
+            escape_xml(text)
+        );
+    }
+
+    /// Add a larger, outlined label with the baseline starting at `(x, y)`.
+    fn add_message(&mut self, x: f32, y: f32, text: &str) {
+        let _ = write!(
+            self.svg,
+            r#"<text x="{x}" y="{y}" font-size="{MESSAGE_FONT_SIZE}" stroke="black" stroke-width="3" stroke-linejoin="round" paint-order="stroke">{}</text>"#,
+            escape_xml(text)
+        );
+    }
+
+    fn render(
+        &self,
+        canvas: &mut tiny_skia::PixmapMut,
+        fontdb: &fontdb::Database,
+        ui_transform: tiny_skia::Transform,
+        w: f32,
+        h: f32,
+    ) {
+        if self.svg.is_empty() {
+            return;
+        }
+        let svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" font-family="sans-serif" font-size="{FONT_SIZE}" fill="white">{}</svg>"#,
+            self.svg
+        );
+        if let Ok(tree) = usvg::Tree::from_str(&svg, &usvg::Options::default(), fontdb) {
+            resvg::render(&tree, ui_transform, canvas);
+        }
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Load system fonts and make sure that the generic sans-serif family resolves to an installed
+/// font.
+fn load_fonts(fixed: bool) -> fontdb::Database {
+    const PREFERRED: &[&str] = &[
+        "DejaVu Sans",
+        "Noto Sans",
+        "Liberation Sans",
+        "Cantarell",
+        "Arial",
+    ];
+
+    let mut db = fontdb::Database::new();
+    db.load_system_fonts();
+
+    let has_family = |name: &str| {
+        db.faces()
+            .any(|face| face.families.iter().any(|(family, _)| family == name))
+    };
+    // Labels must look the same everywhere, if that is possible at all
+    if fixed {
+        if !has_family(PREFERRED[0]) {
+            eprintln!(
+                "reimv: {} is not installed, labels depend on the fonts",
+                PREFERRED[0]
+            );
+        }
+        db.set_sans_serif_family(PREFERRED[0]);
+        return db;
+    }
+    let family = PREFERRED
+        .iter()
+        .map(|f| f.to_string())
+        .find(|f| has_family(f))
+        .or_else(|| {
+            db.faces()
+                .next()
+                .and_then(|face| face.families.first())
+                .map(|(family, _)| family.clone())
+        });
+    if let Some(family) = family {
+        db.set_sans_serif_family(family);
+    }
+
+    db
+}
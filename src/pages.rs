@@ -0,0 +1,296 @@
+//! Files which contain several images: multi-page TIFF files, ICO files with multiple sizes, GIF
+//! files with several frames, and DjVu and PDF documents.
+
+use std::io::Cursor;
+
+use anyhow::{Context, Result};
+use image::codecs::gif::GifDecoder;
+use image::{AnimationDecoder, DynamicImage, ImageDecoder, ImageFormat};
+
+use crate::color;
+use crate::djvu;
+use crate::isolate;
+use crate::limits::Limits;
+use crate::metadata::Tiff;
+use crate::pdf;
+
+const TAG_NEW_SUBFILE_TYPE: u16 = 0xFE;
+
+/// The images contained in a file.
+///
+/// The `image` crate only decodes the first IFD of a TIFF file and the largest image of an ICO
+/// file. To decode image N we hand it a copy of the file which contains only (or starts with)
+/// image N. GIF frames are drawn over the previous ones, so these are decoded up to frame N.
+/// DjVu pages are rendered by djvulibre, see [`crate::djvu`], and PDF pages by Poppler, see
+/// [`crate::pdf`].
+pub struct Pages {
+    data: Vec<u8>,
+    kind: Kind,
+    /// IFD offsets of TIFF pages, or offsets of ICO directory entries. GIF frames, and DjVu and
+    /// PDF pages have no offsets of their own and are numbered instead.
+    offsets: Vec<u32>,
+    current: usize,
+    limits: Limits,
+    color_management: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Tiff,
+    Ico,
+    Gif,
+    Djvu,
+    Pdf,
+}
+
+impl Pages {
+    /// Returns `None` if this is not a multi-page TIFF file.
+    pub fn tiff(data: Vec<u8>, limits: Limits, color_management: bool) -> Option<Self> {
+        let tiff = Tiff::new(&data)?;
+        let offsets: Vec<u32> = tiff
+            .chain()
+            .into_iter()
+            .filter(|&offset| {
+                // Skip reduced-resolution versions of other pages (thumbnails)
+                tiff.parse_ifd(offset as usize).is_some_and(|(ifd, _)| {
+                    tiff.value_of(&ifd, TAG_NEW_SUBFILE_TYPE).unwrap_or(0) & 1 == 0
+                })
+            })
+            .collect();
+        if offsets.len() < 2 {
+            return None;
+        }
+        Some(Self {
+            data,
+            kind: Kind::Tiff,
+            offsets,
+            current: 0,
+            limits,
+            color_management,
+        })
+    }
+
+    /// Returns `None` if this ICO file contains only one image. The images are ordered from the
+    /// smallest to the largest, and the largest one is shown first.
+    pub fn ico(data: Vec<u8>, limits: Limits, color_management: bool) -> Option<Self> {
+        let count = u16::from_le_bytes(data.get(4..6)?.try_into().ok()?) as u32;
+        let mut offsets: Vec<u32> = (0..count)
+            .map(|i| 6 + i * 16)
+            .filter(|&offset| data.len() >= offset as usize + 16)
+            .collect();
+        if offsets.len() < 2 {
+            return None;
+        }
+        offsets.sort_by_key(|&offset| {
+            let entry = &data[offset as usize..];
+            let (width, height) = ico_entry_size(entry);
+            (width * height, entry[6])
+        });
+        Some(Self {
+            data,
+            kind: Kind::Ico,
+            current: offsets.len() - 1,
+            offsets,
+            limits,
+            color_management,
+        })
+    }
+
+    /// Returns `None` if this GIF file contains only one frame. Animations are not played, but
+    /// their frames can be looked at one by one.
+    pub fn gif(data: Vec<u8>, limits: Limits) -> Option<Self> {
+        let count = gif_frame_count(&data);
+        if count < 2 {
+            return None;
+        }
+        Some(Self {
+            data,
+            kind: Kind::Gif,
+            offsets: (0..count as u32).collect(),
+            current: 0,
+            limits,
+            color_management: false,
+        })
+    }
+
+    /// Returns `None` if this DjVu document has only one page.
+    pub fn djvu(data: Vec<u8>, limits: Limits) -> Option<Self> {
+        let count = djvu::page_count(&data);
+        if count < 2 {
+            return None;
+        }
+        Some(Self {
+            data,
+            kind: Kind::Djvu,
+            offsets: (0..count as u32).collect(),
+            current: 0,
+            limits,
+            color_management: false,
+        })
+    }
+
+    /// Returns `None` if this PDF document has only one page.
+    pub fn pdf(data: Vec<u8>, limits: Limits) -> Option<Self> {
+        let count = pdf::page_count(&data);
+        if count < 2 {
+            return None;
+        }
+        Some(Self {
+            data,
+            kind: Kind::Pdf,
+            offsets: (0..count as u32).collect(),
+            current: 0,
+            limits,
+            color_management: false,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// A short description of the current image, like `page 3/12`.
+    pub fn label(&self) -> String {
+        let position = format!("{}/{}", self.current + 1, self.len());
+        match self.kind {
+            Kind::Ico => {
+                let entry = &self.data[self.offsets[self.current] as usize..];
+                let (width, height) = ico_entry_size(entry);
+                format!("{width}x{height}, {position}")
+            }
+            Kind::Gif => format!("frame {position}"),
+            Kind::Tiff | Kind::Djvu | Kind::Pdf => format!("page {position}"),
+        }
+    }
+
+    /// Decode the page which is `delta` pages away from the current one, stopping at the first
+    /// and the last page. Returns `None` if the current page stays.
+    pub fn turn(&mut self, delta: isize) -> Option<Result<DynamicImage>> {
+        let page = self
+            .current
+            .saturating_add_signed(delta)
+            .min(self.len() - 1);
+        if page == self.current && delta != 0 {
+            return None;
+        }
+        self.current = page;
+        Some(self.decode(page))
+    }
+
+    fn decode(&self, page: usize) -> Result<DynamicImage> {
+        if self.kind == Kind::Gif {
+            return isolate::run(&self.limits, || self.decode_gif_frame(page))
+                .with_context(|| format!("could not decode frame {}", page + 1));
+        }
+        if self.kind == Kind::Djvu {
+            return djvu::render(&self.data, page, &self.limits)
+                .with_context(|| format!("could not render page {}", page + 1));
+        }
+        if self.kind == Kind::Pdf {
+            return pdf::render(&self.data, page, &self.limits)
+                .with_context(|| format!("could not render page {}", page + 1));
+        }
+        let offset = self.offsets[page];
+        let (data, format) = match self.kind {
+            Kind::Ico => {
+                // A copy with only this entry in the directory, followed by the image data
+                let entry = &self.data[offset as usize..offset as usize + 16];
+                let len = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as usize;
+                let start = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as usize;
+                let image = self
+                    .data
+                    .get(start..start.saturating_add(len))
+                    .context("image data out of bounds")?;
+                let mut data = Vec::with_capacity(22 + len);
+                data.extend_from_slice(&self.data[..4]);
+                data.extend_from_slice(&1u16.to_le_bytes());
+                data.extend_from_slice(&entry[..12]);
+                data.extend_from_slice(&22u32.to_le_bytes());
+                data.extend_from_slice(image);
+                (data, ImageFormat::Ico)
+            }
+            Kind::Tiff | Kind::Gif | Kind::Djvu | Kind::Pdf => {
+                let mut data = self.data.clone();
+                let offset = if data.starts_with(b"II") {
+                    offset.to_le_bytes()
+                } else {
+                    offset.to_be_bytes()
+                };
+                data[4..8].copy_from_slice(&offset);
+                (data, ImageFormat::Tiff)
+            }
+        };
+        isolate::run(&self.limits, || {
+            let image = self.limits.decode(&data, format)?;
+            // Each TIFF page has its own profile, which the copy has in its first IFD
+            Ok(match self.color_management {
+                true => color::to_srgb(&data, image),
+                false => image,
+            })
+        })
+        .with_context(|| format!("could not decode page {}", page + 1))
+    }
+
+    fn decode_gif_frame(&self, frame: usize) -> Result<DynamicImage> {
+        let mut decoder = GifDecoder::new(Cursor::new(&self.data))?;
+        let (width, height) = decoder.dimensions();
+        self.limits.check(width, height)?;
+        let mut limits = image::io::Limits::default();
+        limits.max_alloc = Some(self.limits.max_bytes);
+        decoder.set_limits(limits)?;
+        let frame = decoder
+            .into_frames()
+            .nth(frame)
+            .context("the frame is missing")??;
+        Ok(DynamicImage::ImageRgba8(frame.into_buffer()))
+    }
+}
+
+/// The number of frames in a GIF file, counted from its blocks without decoding them.
+fn gif_frame_count(data: &[u8]) -> usize {
+    // Skip the sub-blocks of an extension or of image data, returning the position after them
+    let skip_sub_blocks = |mut pos: usize| -> Option<usize> {
+        loop {
+            let len = *data.get(pos)? as usize;
+            pos += 1 + len;
+            if len == 0 {
+                return Some(pos);
+            }
+        }
+    };
+    let color_table_len = |flags: u8| match flags & 0x80 {
+        0 => 0,
+        _ => 3 << ((flags & 7) + 1),
+    };
+
+    let Some(&flags) = data.get(10) else {
+        return 0;
+    };
+    let mut pos = 13 + color_table_len(flags);
+    let mut count = 0;
+    loop {
+        match data.get(pos) {
+            // An extension, with its label
+            Some(0x21) => pos += 2,
+            // An image descriptor, with a local color table and the LZW code size
+            Some(0x2C) => {
+                let Some(&flags) = data.get(pos + 9) else {
+                    return count;
+                };
+                count += 1;
+                pos += 10 + color_table_len(flags) + 1;
+            }
+            _ => return count,
+        }
+        match skip_sub_blocks(pos) {
+            Some(next) => pos = next,
+            None => return count,
+        }
+    }
+}
+
+/// The size of an ICO directory entry's image, where zero stands for 256.
+fn ico_entry_size(entry: &[u8]) -> (u32, u32) {
+    let size = |b: u8| if b == 0 { 256 } else { b as u32 };
+    (size(entry[0]), size(entry[1]))
+}
@@ -0,0 +1,138 @@
+//! Keeping the image within the window while it is panned, with `--clamp-pan`.
+//!
+//! An image smaller than the window stays inside it, and a larger one keeps covering it. Dragging
+//! past an edge does not stop dead: the image follows the pointer less and less, like on a rubber
+//! band, and springs back once it is let go, so that the edge can be felt. With
+//! `--reduced-motion` it stops at the edge instead. Panning with the keys always stops there.
+
+use std::time::Duration;
+
+use crate::animation::ViewAnimation;
+use crate::image::ImageTransform;
+
+/// How long the image takes to spring back after it has been dragged past an edge.
+const BOUNCE_DURATION: Duration = Duration::from_millis(250);
+/// How far past an edge the image can be dragged at most, as a fraction of the window.
+const MAX_STRETCH: f32 = 0.15;
+/// How much of the pointer's movement the image follows right past an edge.
+const RESISTANCE: f32 = 0.5;
+
+/// Where the top left corner of the image may be.
+#[derive(Debug, Clone, Copy)]
+pub struct Edges {
+    /// The ranges of `x` and `y` which keep the image within the window
+    x: (f32, f32),
+    y: (f32, f32),
+    /// How far past the edges the image can be stretched, in each direction
+    stretch: (f32, f32),
+}
+
+impl Edges {
+    /// For an image of natural size `size` shown at `scale` in a window of size `window`.
+    pub fn new(size: (f32, f32), scale: f32, window: (f32, f32)) -> Self {
+        Self {
+            x: range(size.0 * scale, window.0),
+            y: range(size.1 * scale, window.1),
+            stretch: (window.0 * MAX_STRETCH, window.1 * MAX_STRETCH),
+        }
+    }
+
+    /// Move `transform` back within the edges.
+    pub fn clamp(&self, transform: &mut ImageTransform) {
+        transform.x = transform.x.clamp(self.x.0, self.x.1);
+        transform.y = transform.y.clamp(self.y.0, self.y.1);
+    }
+}
+
+/// Move the image by `(dx, dy)` as it is dragged. It goes past the edges only a bit if `springy`,
+/// and not at all otherwise.
+pub fn drag(transform: &mut ImageTransform, (dx, dy): (f32, f32), edges: &Edges, springy: bool) {
+    if !springy {
+        transform.x += dx;
+        transform.y += dy;
+        edges.clamp(transform);
+        return;
+    }
+    transform.x = drag_axis(transform.x, dx, edges.x, edges.stretch.0);
+    transform.y = drag_axis(transform.y, dy, edges.y, edges.stretch.1);
+}
+
+/// The animation which brings the image back to the edges after a drag, if it is past them.
+/// It jumps there if not `animate`.
+pub fn bounce(
+    transform: ImageTransform,
+    edges: &Edges,
+    window: (f32, f32),
+    animate: bool,
+) -> Option<ViewAnimation> {
+    let mut clamped = transform;
+    edges.clamp(&mut clamped);
+    if clamped == transform {
+        return None;
+    }
+    let center = clamped.image_point(window.0 / 2.0, window.1 / 2.0);
+    let duration = match animate {
+        true => BOUNCE_DURATION,
+        false => Duration::ZERO,
+    };
+    Some(ViewAnimation::new(
+        transform,
+        window,
+        Some(center),
+        None,
+        duration,
+    ))
+}
+
+/// The range of the offset which keeps an image of length `len` within a window of length
+/// `win_len`, inside it if it is shorter and covering it otherwise.
+fn range(len: f32, win_len: f32) -> (f32, f32) {
+    match len <= win_len {
+        true => (0.0, win_len - len),
+        false => (win_len - len, 0.0),
+    }
+}
+
+/// The new offset of an image at `shown` which is dragged by `delta`, with the edges at `range`.
+fn drag_axis(shown: f32, delta: f32, range: (f32, f32), limit: f32) -> f32 {
+    let past = (range.0 - shown).max(shown - range.1);
+    // Further out than stretching goes, after zooming or resizing the window, so it only follows
+    // the pointer back in
+    if past >= limit {
+        let inward = (shown < range.0) == (delta > 0.0);
+        return if inward { shown + delta } else { shown };
+    }
+    // Where the pointer has dragged the image to, as if there were no edges. Nothing else is
+    // remembered, so that zooming in the middle of a drag changes nothing.
+    let free = unstretch(shown, range, limit) + delta;
+    stretch(free, range, limit)
+}
+
+/// Where the image is when the pointer has dragged it to `free`.
+fn stretch(free: f32, (min, max): (f32, f32), limit: f32) -> f32 {
+    match free {
+        _ if free < min => min - band(min - free, limit),
+        _ if free > max => max + band(free - max, limit),
+        _ => free,
+    }
+}
+
+/// The inverse of [`stretch`].
+fn unstretch(shown: f32, (min, max): (f32, f32), limit: f32) -> f32 {
+    match shown {
+        _ if shown < min => min - unband(min - shown, limit),
+        _ if shown > max => max + unband(shown - max, limit),
+        _ => shown,
+    }
+}
+
+/// How far the image goes past an edge when the pointer has gone `past` it: a part of the way at
+/// first, and less and less the further it goes, up to `limit`.
+fn band(past: f32, limit: f32) -> f32 {
+    limit * past * RESISTANCE / (past * RESISTANCE + limit)
+}
+
+/// The inverse of [`band`], for `shown` less than `limit`.
+fn unband(shown: f32, limit: f32) -> f32 {
+    limit * shown / (RESISTANCE * (limit - shown))
+}
@@ -0,0 +1,121 @@
+//! PDF documents, rendered page by page by `pdftoppm` from Poppler.
+//!
+//! Pages are drawn from fonts and vector paths, which needs a full PDF renderer, so they are
+//! rendered by Poppler's command line tool into PPM images at [`DPI`]. `pdfinfo` of the same
+//! package counts the pages and gives their size beforehand, to refuse pages beyond the limits
+//! without rendering them. Both read the document from stdin.
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use image::DynamicImage;
+
+use crate::isolate;
+use crate::limits::Limits;
+use crate::pnm;
+
+/// The resolution the pages are rendered at, the default of `pdftoppm`
+pub const DPI: f32 = 150.0;
+
+/// The header may come after other bytes, as long as it is near the start.
+pub fn is_pdf(data: &[u8]) -> bool {
+    data[..data.len().min(1024)]
+        .windows(5)
+        .any(|window| window == b"%PDF-")
+}
+
+/// The number of pages in the document, or 0 if `pdfinfo` cannot read it.
+pub fn page_count(data: &[u8]) -> usize {
+    info(data, 0).map_or(0, |info| info.pages)
+}
+
+/// Render a page at [`DPI`].
+pub fn render(data: &[u8], page: usize, limits: &Limits) -> Result<DynamicImage> {
+    let info = info(data, page)?;
+    // In points of 1/72 inch, and turned by the page's rotation when rendered
+    let pixels = |points: f32| (points / 72.0 * DPI).ceil() as u32 + 1;
+    let (width, height) = (pixels(info.width), pixels(info.height));
+    limits.check(width, height)?;
+
+    let page = (page + 1).to_string();
+    let dpi = DPI.to_string();
+    let args = ["-r", &dpi, "-f", &page, "-l", &page, "-singlefile", "-"];
+    // The header is short
+    let max_len = 64 + width as u64 * height as u64 * 3;
+    let ppm = run("pdftoppm", &args, data, max_len)?;
+    isolate::run(limits, || pnm::decode(&ppm, limits).map(Into::into))
+}
+
+struct Info {
+    pages: usize,
+    /// Of the page asked for, in points
+    width: f32,
+    height: f32,
+}
+
+/// What `pdfinfo` says about the document and one of its pages, in lines like `Pages: 12` and
+/// `Page    3 size: 612 x 792 pts (letter)`.
+fn info(data: &[u8], page: usize) -> Result<Info> {
+    let page = (page + 1).to_string();
+    let output = run("pdfinfo", &["-f", &page, "-l", &page, "-"], data, 1 << 20)?;
+    let output = String::from_utf8_lossy(&output);
+    let mut pages = None;
+    let mut size = None;
+    for line in output.lines() {
+        if let Some(count) = line.strip_prefix("Pages:") {
+            pages = count.trim().parse().ok();
+        }
+        if let Some((_, rest)) = line
+            .split_once(" size:")
+            .filter(|_| line.starts_with("Page "))
+        {
+            let mut numbers = rest
+                .split_whitespace()
+                .filter_map(|n| n.parse::<f32>().ok());
+            size = numbers.next().zip(numbers.next());
+        }
+    }
+    let pages = pages.context("pdfinfo did not count the pages")?;
+    let (width, height) = size.context("the page is missing")?;
+    Ok(Info {
+        pages,
+        width,
+        height,
+    })
+}
+
+/// Run one of the Poppler tools with the document on stdin, and return at most `max_len` bytes
+/// of its output.
+fn run(program: &str, args: &[&str], data: &[u8], max_len: u64) -> Result<Vec<u8>> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| {
+            format!("could not run {program}, which is needed to show PDF documents")
+        })?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    let mut output = Vec::new();
+    let mut errors = String::new();
+    // The pipes are used at the same time, so that none of them fills up
+    let read = std::thread::scope(|scope| {
+        scope.spawn(move || stdin.write_all(data));
+        scope.spawn(|| stderr.read_to_string(&mut errors));
+        stdout.take(max_len).read_to_end(&mut output)
+    });
+    let status = child.wait()?;
+    read.with_context(|| format!("could not read the output of {program}"))?;
+    if !status.success() {
+        match errors.lines().map(str::trim).find(|line| !line.is_empty()) {
+            Some(line) => bail!("{program} failed: {line}"),
+            None => bail!("{program} failed: {status}"),
+        }
+    }
+    Ok(output)
+}
@@ -0,0 +1,216 @@
+//! Per-file state, kept across sessions in `$XDG_STATE_HOME/reimv/files/`, and where each
+//! session left off in `$XDG_STATE_HOME/reimv/sessions/`.
+//!
+//! Every image gets its own small text file, named after a hash of the image's canonical path.
+//! The path itself is stored on the first line to detect hash collisions. Sessions are kept the
+//! same way for the directory of the images they showed, and `sessions/last` names the directory
+//! of the latest one.
+
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::download;
+use crate::files;
+use crate::guides::{Guide, Orientation};
+
+#[derive(Debug, Default)]
+pub struct FileState {
+    pub guides: Vec<Guide>,
+}
+
+impl FileState {
+    /// Load the state of the image at `image_path`. Missing or unreadable state is treated as
+    /// empty.
+    pub fn load(image_path: impl AsRef<Path>) -> Self {
+        let mut state = Self::default();
+        let Some((image_path, file)) = state_file(image_path.as_ref()) else {
+            return state;
+        };
+        let Ok(contents) = std::fs::read_to_string(file) else {
+            return state;
+        };
+
+        let mut lines = contents.lines();
+        if lines.next().and_then(|l| l.strip_prefix("path ")) != image_path.to_str() {
+            return state;
+        }
+        for line in lines {
+            let mut words = line.split(' ');
+            match (words.next(), words.next(), words.next()) {
+                (Some("guide"), Some(orientation), Some(pos)) => {
+                    let orientation = match orientation {
+                        "h" => Orientation::Horizontal,
+                        "v" => Orientation::Vertical,
+                        _ => continue,
+                    };
+                    if let Ok(pos) = pos.parse() {
+                        state.guides.push(Guide { orientation, pos });
+                    }
+                }
+                _ => continue,
+            }
+        }
+
+        state
+    }
+
+    pub fn save(&self, image_path: impl AsRef<Path>) -> io::Result<()> {
+        // Images read from stdin have nowhere to be remembered
+        if image_path.as_ref() == Path::new("-") {
+            return Ok(());
+        }
+        let (image_path, file) = state_file(image_path.as_ref())
+            .ok_or_else(|| io::Error::other("could not determine the state directory"))?;
+
+        if self.guides.is_empty() {
+            return match std::fs::remove_file(file) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+
+        let mut contents = String::new();
+        let _ = writeln!(contents, "path {}", image_path.display());
+        for guide in &self.guides {
+            let orientation = match guide.orientation {
+                Orientation::Horizontal => "h",
+                Orientation::Vertical => "v",
+            };
+            let _ = writeln!(contents, "guide {orientation} {}", guide.pos);
+        }
+
+        std::fs::create_dir_all(file.parent().unwrap())?;
+        std::fs::write(file, contents)
+    }
+}
+
+/// Where a session left off, for `--resume`.
+#[derive(Debug)]
+pub struct Session {
+    /// The last image shown, relative to the directory of the session if it is in it
+    pub file: PathBuf,
+    pub view: Option<View>,
+}
+
+/// How an image was shown, independent of its size, so that it also fits a preview of it.
+#[derive(Debug, Clone, Copy)]
+pub struct View {
+    /// The point of the image at the center of the window, as fractions of its width and height
+    pub center: (f32, f32),
+    /// How wide the image is shown, in surface local coordinates
+    pub width: f32,
+}
+
+impl Session {
+    /// Load the session of the directory `dir`, if there is one.
+    pub fn load(dir: &str) -> Option<Self> {
+        let (dir, file) = session_file(dir)?;
+        let contents = std::fs::read_to_string(file).ok()?;
+
+        let mut lines = contents.lines();
+        if lines.next().and_then(|l| l.strip_prefix("path ")) != dir.to_str() {
+            return None;
+        }
+        let mut session = Self {
+            file: PathBuf::new(),
+            view: None,
+        };
+        for line in lines {
+            match line.split_once(' ') {
+                Some(("file", file)) => session.file = file.into(),
+                Some(("view", view)) => {
+                    let numbers: Vec<f32> =
+                        view.split(' ').filter_map(|n| n.parse().ok()).collect();
+                    if let &[x, y, width] = &numbers[..] {
+                        session.view = Some(View {
+                            center: (x, y),
+                            width,
+                        });
+                    }
+                }
+                _ => continue,
+            }
+        }
+        (!session.file.as_os_str().is_empty()).then_some(session)
+    }
+
+    /// Save the session of the directory `dir`, which becomes the latest one.
+    pub fn save(&self, dir: &str) -> io::Result<()> {
+        let (dir, file) = session_file(dir)
+            .ok_or_else(|| io::Error::other("could not determine the state directory"))?;
+        let (Some(dir), Some(path)) = (dir.to_str(), self.file.to_str()) else {
+            return Err(io::Error::other("the path is not valid UTF-8"));
+        };
+        if dir.contains('\n') || path.contains('\n') {
+            return Err(io::Error::other("the path contains a line break"));
+        }
+
+        let mut contents = String::new();
+        let _ = writeln!(contents, "path {dir}");
+        let _ = writeln!(contents, "file {path}");
+        if let Some(View { center, width }) = self.view {
+            let _ = writeln!(contents, "view {} {} {width}", center.0, center.1);
+        }
+
+        std::fs::create_dir_all(file.parent().unwrap())?;
+        std::fs::write(&file, contents)?;
+        std::fs::write(file.with_file_name("last"), format!("path {dir}\n"))
+    }
+
+    /// The directory of the latest session.
+    pub fn last() -> Option<String> {
+        let contents = std::fs::read_to_string(state_dir()?.join("sessions/last")).ok()?;
+        let dir = contents.lines().next()?.strip_prefix("path ")?;
+        Some(dir.to_owned())
+    }
+}
+
+/// The directory which a session showing `paths` is kept for: the first of them if it is a
+/// directory, or else the one it is in, as it was given. Downloads and stdin have none.
+pub fn session_dir(paths: &[String]) -> Option<String> {
+    let path = paths.first()?;
+    if path == "-" || download::is_url(path) || files::is_data_uri(path) {
+        return None;
+    }
+    if Path::new(path).is_dir() {
+        return Some(path.clone());
+    }
+    match Path::new(path).parent()?.to_str()? {
+        "" => Some(".".to_owned()),
+        dir => Some(dir.to_owned()),
+    }
+}
+
+pub fn state_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("reimv")),
+        _ => {
+            let home = std::env::var_os("HOME")?;
+            Some(PathBuf::from(home).join(".local/state/reimv"))
+        }
+    }
+}
+
+/// The canonical path of the image and the path of its state file.
+fn state_file(image_path: &Path) -> Option<(PathBuf, PathBuf)> {
+    let image_path = std::fs::canonicalize(image_path).ok()?;
+    let hash = fnv1a(image_path.as_os_str().as_encoded_bytes());
+    let file = state_dir()?.join("files").join(format!("{hash:016x}"));
+    Some((image_path, file))
+}
+
+/// The canonical path of the directory and the path of its session file.
+fn session_file(dir: &str) -> Option<(PathBuf, PathBuf)> {
+    let dir = std::fs::canonicalize(dir).ok()?;
+    let hash = fnv1a(dir.as_os_str().as_encoded_bytes());
+    let file = state_dir()?.join("sessions").join(format!("{hash:016x}"));
+    Some((dir, file))
+}
+
+/// A simple hash which, unlike `DefaultHasher`, is stable across Rust releases.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
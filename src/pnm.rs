@@ -0,0 +1,360 @@
+//! The Netpbm family: PBM, PGM, PPM and PAM.
+//!
+//! The `image` crate can decode most of these, but it trips over some of the comment placements
+//! that Netpbm itself accepts and it does not support every PAM tuple type. Since these formats
+//! are trivial, we parse them ourselves.
+
+use anyhow::{bail, ensure, Context, Result};
+use image::RgbaImage;
+
+use crate::limits::Limits;
+
+/// Decode the first image of the file.
+pub fn decode(data: &[u8], limits: &Limits) -> Result<RgbaImage> {
+    let (image, kind, mut header) = parse(data)?;
+    limits.check(image.width, image.height)?;
+    let samples = match kind {
+        b'1'..=b'3' => image.plain_samples(&mut header, kind == b'1')?,
+        b'4' => image.bitmap_samples(&data[header.pos..])?,
+        _ => image.raw_samples(&data[header.pos..])?,
+    };
+    Ok(image.to_rgba(&samples))
+}
+
+/// A raw PGM, PPM or PAM image, of which any part can be read without decoding the rest. With
+/// a memory mapped file, the image can be larger than the memory.
+pub struct Regions<D> {
+    data: D,
+    raster: Raster,
+    /// Where the samples start
+    offset: usize,
+}
+
+impl<D: AsRef<[u8]>> Regions<D> {
+    /// Returns `None` for plain and bitmap files, whose rows cannot be found without reading
+    /// the ones before, and for anything else which is not a complete raw Netpbm file.
+    pub fn new(data: D) -> Option<Self> {
+        let (raster, kind, header) = parse(data.as_ref()).ok()?;
+        let len = raster.len().ok()?.checked_mul(raster.bytes_per_sample())?;
+        let offset = header.pos;
+        if !matches!(kind, b'5'..=b'7') || data.as_ref().len() - offset < len {
+            return None;
+        }
+        Some(Self {
+            data,
+            raster,
+            offset,
+        })
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.raster.width, self.raster.height)
+    }
+
+    /// Read the `width` by `height` pixels at `(x, y)`, keeping only every `step`th pixel of
+    /// every `step`th row. The region must be within the image.
+    pub fn read(&self, (x, y): (u32, u32), (width, height): (u32, u32), step: u32) -> RgbaImage {
+        let raster = &self.raster;
+        let depth = raster.depth as usize;
+        let bytes_per_sample = raster.bytes_per_sample();
+        let pixel_bytes = depth * bytes_per_sample;
+        let data = &self.data.as_ref()[self.offset..];
+        RgbaImage::from_fn(width.div_ceil(step), height.div_ceil(step), |i, j| {
+            let (sx, sy) = ((x + i * step) as usize, (y + j * step) as usize);
+            let start = (sy * raster.width as usize + sx) * pixel_bytes;
+            let mut tuple = [0; 4];
+            for (k, sample) in tuple[..depth].iter_mut().enumerate() {
+                let at = start + k * bytes_per_sample;
+                *sample = match bytes_per_sample {
+                    1 => data[at] as u16,
+                    _ => u16::from_be_bytes([data[at], data[at + 1]]),
+                };
+            }
+            image::Rgba(raster.rgba(&tuple[..depth]))
+        })
+    }
+}
+
+/// Parse the header, returning the image, the digit of its magic number, and the header
+/// positioned at the start of the raster.
+fn parse(data: &[u8]) -> Result<(Raster, u8, Header<'_>)> {
+    let mut header = Header { data, pos: 2 };
+    match data.get(..2) {
+        Some(b"P7") => {
+            let image = parse_pam(&mut header)?;
+            Ok((image, b'7', header))
+        }
+        Some(&[b'P', kind @ b'1'..=b'6']) => {
+            let width = header.number().context("no width")?;
+            let height = header.number().context("no height")?;
+            let bitmap = matches!(kind, b'1' | b'4');
+            let maxval = if bitmap {
+                1
+            } else {
+                header.number().context("no maxval")?
+            };
+            header.end_of_header();
+
+            let depth = match kind {
+                b'3' | b'6' => 3,
+                _ => 1,
+            };
+            let tuple_type = match (bitmap, depth) {
+                (true, _) => TupleType::Bitmap,
+                (false, 1) => TupleType::Gray,
+                (false, _) => TupleType::Rgb,
+            };
+            let image = Raster {
+                width,
+                height,
+                depth,
+                maxval,
+                tuple_type,
+            };
+            Ok((image, kind, header))
+        }
+        _ => bail!("not a Netpbm file"),
+    }
+}
+
+fn parse_pam(header: &mut Header) -> Result<Raster> {
+    let (mut width, mut height, mut depth, mut maxval) = (None, None, None, None);
+    let mut tuple_type = String::new();
+    loop {
+        let line = header.line().context("unterminated PAM header")?;
+        let line = line.trim_ascii();
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+        if line == b"ENDHDR" {
+            break;
+        }
+        let line = std::str::from_utf8(line).context("invalid PAM header")?;
+        let (key, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let value = value.trim();
+        let number = || value.parse::<u32>().context("invalid PAM header value");
+        match key {
+            "WIDTH" => width = Some(number()?),
+            "HEIGHT" => height = Some(number()?),
+            "DEPTH" => depth = Some(number()?),
+            "MAXVAL" => maxval = Some(number()?),
+            // Multiple TUPLTYPE lines are concatenated
+            "TUPLTYPE" => {
+                if !tuple_type.is_empty() {
+                    tuple_type.push(' ');
+                }
+                tuple_type.push_str(value);
+            }
+            _ => bail!("unknown PAM header field {key:?}"),
+        }
+    }
+
+    let depth = depth.context("no DEPTH")?;
+    let tuple_type = match (tuple_type.as_str(), depth) {
+        ("BLACKANDWHITE", 1) => TupleType::BlackAndWhite,
+        ("BLACKANDWHITE_ALPHA", 2) => TupleType::BlackAndWhiteAlpha,
+        ("GRAYSCALE", 1) | ("", 1) => TupleType::Gray,
+        ("GRAYSCALE_ALPHA", 2) | ("", 2) => TupleType::GrayAlpha,
+        ("RGB", 3) | ("", 3) => TupleType::Rgb,
+        ("RGB_ALPHA", 4) | ("", 4) => TupleType::RgbAlpha,
+        (t, d) => bail!("unsupported PAM tuple type {t:?} with depth {d}"),
+    };
+    Ok(Raster {
+        width: width.context("no WIDTH")?,
+        height: height.context("no HEIGHT")?,
+        depth,
+        maxval: maxval.context("no MAXVAL")?,
+        tuple_type,
+    })
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TupleType {
+    /// PBM, where 1 is black
+    Bitmap,
+    /// PAM, where 1 is white
+    BlackAndWhite,
+    BlackAndWhiteAlpha,
+    Gray,
+    GrayAlpha,
+    Rgb,
+    RgbAlpha,
+}
+
+struct Raster {
+    width: u32,
+    height: u32,
+    depth: u32,
+    maxval: u32,
+    tuple_type: TupleType,
+}
+
+impl Raster {
+    fn len(&self) -> Result<usize> {
+        ensure!(
+            (1..=65535).contains(&self.maxval),
+            "invalid maxval {}",
+            self.maxval
+        );
+        (self.width as usize)
+            .checked_mul(self.height as usize)
+            .and_then(|x| x.checked_mul(self.depth as usize))
+            .context("image too large")
+    }
+
+    /// Raw samples take one byte if maxval is below 256, otherwise two bytes.
+    fn bytes_per_sample(&self) -> usize {
+        if self.maxval < 256 {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Samples of P1-P3, as ASCII decimal numbers. Bits of P1 don't have to be separated.
+    fn plain_samples(&self, header: &mut Header, bits: bool) -> Result<Vec<u16>> {
+        let len = self.len()?;
+        let mut samples = Vec::with_capacity(len.min(header.data.len()));
+        while samples.len() < len {
+            let sample = if bits {
+                header.skip_whitespace();
+                match header.data.get(header.pos) {
+                    Some(&c @ (b'0' | b'1')) => {
+                        header.pos += 1;
+                        (c - b'0') as u32
+                    }
+                    _ => bail!("truncated raster"),
+                }
+            } else {
+                header.number().context("truncated raster")?
+            };
+            ensure!(sample <= self.maxval, "sample exceeds maxval");
+            samples.push(sample as u16);
+        }
+        Ok(samples)
+    }
+
+    /// Samples of P4, packed eight pixels per byte, with rows padded to whole bytes.
+    fn bitmap_samples(&self, data: &[u8]) -> Result<Vec<u16>> {
+        let len = self.len()?;
+        let row_bytes = (self.width as usize).div_ceil(8);
+        ensure!(
+            data.len() >= row_bytes * self.height as usize,
+            "truncated raster"
+        );
+        let mut samples = Vec::with_capacity(len);
+        for row in data.chunks_exact(row_bytes).take(self.height as usize) {
+            for x in 0..self.width as usize {
+                samples.push(((row[x / 8] >> (7 - x % 8)) & 1) as u16);
+            }
+        }
+        Ok(samples)
+    }
+
+    /// Samples of P5, P6 and P7: one byte each if maxval is below 256, otherwise two bytes,
+    /// most significant first.
+    fn raw_samples(&self, data: &[u8]) -> Result<Vec<u16>> {
+        let len = self.len()?;
+        let bytes_per_sample = self.bytes_per_sample();
+        let data = data
+            .get(..len * bytes_per_sample)
+            .context("truncated raster")?;
+        Ok(match bytes_per_sample {
+            1 => data.iter().map(|&s| s as u16).collect(),
+            _ => data
+                .chunks_exact(2)
+                .map(|s| u16::from_be_bytes([s[0], s[1]]))
+                .collect(),
+        })
+    }
+
+    fn to_rgba(&self, samples: &[u16]) -> RgbaImage {
+        let mut image = RgbaImage::new(self.width, self.height);
+        let tuples = samples.chunks_exact(self.depth as usize);
+        for (pixel, tuple) in image.pixels_mut().zip(tuples) {
+            pixel.0 = self.rgba(tuple);
+        }
+        image
+    }
+
+    /// The color of a pixel, from its `depth` samples.
+    fn rgba(&self, tuple: &[u16]) -> [u8; 4] {
+        let maxval = self.maxval;
+        let scale = |s: u16| ((s.min(maxval as u16) as u32 * 255 + maxval / 2) / maxval) as u8;
+        match (self.tuple_type, tuple) {
+            (TupleType::Bitmap, &[s]) => {
+                let v = if s == 0 { u8::MAX } else { 0 };
+                [v, v, v, u8::MAX]
+            }
+            (TupleType::BlackAndWhite | TupleType::Gray, &[s]) => {
+                let v = scale(s);
+                [v, v, v, u8::MAX]
+            }
+            (TupleType::BlackAndWhiteAlpha | TupleType::GrayAlpha, &[s, a]) => {
+                let v = scale(s);
+                [v, v, v, scale(a)]
+            }
+            (TupleType::Rgb, &[r, g, b]) => [scale(r), scale(g), scale(b), u8::MAX],
+            (TupleType::RgbAlpha, &[r, g, b, a]) => [scale(r), scale(g), scale(b), scale(a)],
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// A cursor over the header and, for plain formats, the raster.
+struct Header<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Header<'_> {
+    /// Skip whitespace and comments. A comment starts with `#`, which does not have to be
+    /// preceded by whitespace, and extends to the end of the line.
+    fn skip_whitespace(&mut self) {
+        while let Some(&c) = self.data.get(self.pos) {
+            match c {
+                b'#' => self.skip_comment(),
+                b' ' | b'\t' | b'\n' | b'\x0B' | b'\x0C' | b'\r' => self.pos += 1,
+                _ => break,
+            }
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        while let Some(&c) = self.data.get(self.pos) {
+            self.pos += 1;
+            if c == b'\n' || c == b'\r' {
+                break;
+            }
+        }
+    }
+
+    fn number(&mut self) -> Option<u32> {
+        self.skip_whitespace();
+        let start = self.pos;
+        while self.data.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.data[start..self.pos])
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    /// Consume the single whitespace character, or a comment, which separates the header from a
+    /// raw raster.
+    fn end_of_header(&mut self) {
+        match self.data.get(self.pos) {
+            Some(b'#') => self.skip_comment(),
+            Some(_) => self.pos += 1,
+            None => (),
+        }
+    }
+
+    fn line(&mut self) -> Option<&[u8]> {
+        let rest = self.data.get(self.pos..)?;
+        let len = rest.iter().position(|&c| c == b'\n')?;
+        self.pos += len + 1;
+        Some(&rest[..len])
+    }
+}
@@ -0,0 +1,614 @@
+//! Choosing files to open with the FileChooser of xdg-desktop-portal, see the `o` key.
+//!
+//! This is a minimal client of the D-Bus session bus. It authenticates as our user, calls
+//! `OpenFile`, and then reads what arrives while the event loop goes on, until the `Response`
+//! signal of the request says which files were chosen. Only the few messages and types which
+//! this needs are written, and other values are skipped.
+
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+const BUS: &str = "org.freedesktop.DBus";
+/// Names the request object of the call, so that we know it before the call returns
+const TOKEN: &str = "reimv_open";
+/// How long to wait for the bus while connecting
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const SIGNAL: u8 = 4;
+const NO_REPLY_EXPECTED: u8 = 0x1;
+
+/// Header fields, with the type of their value
+const PATH: (u8, &str) = (1, "o");
+const INTERFACE: (u8, &str) = (2, "s");
+const MEMBER: (u8, &str) = (3, "s");
+const DESTINATION: (u8, &str) = (6, "s");
+const SIGNATURE: (u8, &str) = (8, "g");
+
+/// A file chooser which is shown, until the user has chosen.
+pub struct FileChooser {
+    socket: UnixStream,
+    /// What has arrived of messages which are not complete yet
+    received: Vec<u8>,
+    /// The serial of the `OpenFile` call
+    call: u32,
+    /// The object whose `Response` signal has the result
+    request: String,
+}
+
+/// A message from the bus, with the header fields we look at.
+struct Incoming {
+    kind: u8,
+    big_endian: bool,
+    reply_serial: Option<u32>,
+    path: Option<String>,
+    member: Option<String>,
+    error_name: Option<String>,
+    signature: String,
+    body: Vec<u8>,
+}
+
+impl FileChooser {
+    /// Ask the portal to show a file chooser for images, starting in `folder`. This waits for
+    /// the bus to accept the connection, but not for the user.
+    pub fn open(folder: Option<&Path>) -> Result<Self> {
+        let mut socket = connect().context("could not connect to the session bus")?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        authenticate(&mut socket)?;
+        let mut this = Self {
+            socket,
+            received: Vec::new(),
+            call: 3,
+            request: String::new(),
+        };
+
+        let path = "/org/freedesktop/DBus";
+        this.send(1, 0, BUS, path, BUS, "Hello", "", &[])?;
+        let reply = this.reply(1)?;
+        let name = Reader::new(&reply.body, reply.big_endian)
+            .string()
+            .context("invalid reply to Hello")?;
+        // Where the portal puts the request object of our call, since version 0.9
+        let sender = name.trim_start_matches(':').replace('.', "_");
+        this.request = format!("/org/freedesktop/portal/desktop/request/{sender}/{TOKEN}");
+
+        let mut rule = Writer::default();
+        rule.string("type='signal',interface='org.freedesktop.portal.Request',member='Response'");
+        this.send(
+            2,
+            NO_REPLY_EXPECTED,
+            BUS,
+            path,
+            BUS,
+            "AddMatch",
+            "s",
+            &rule.0,
+        )?;
+
+        this.send(
+            this.call,
+            0,
+            "org.freedesktop.portal.Desktop",
+            "/org/freedesktop/portal/desktop",
+            "org.freedesktop.portal.FileChooser",
+            "OpenFile",
+            "ssa{sv}",
+            &open_file_args(folder),
+        )?;
+        this.socket.set_nonblocking(true)?;
+        Ok(this)
+    }
+
+    /// Becomes readable when something has arrived, see [`Self::read`].
+    pub fn fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+
+    /// Read what has arrived. Returns the chosen files once the user has chosen, which are none
+    /// if the file chooser was cancelled.
+    pub fn read(&mut self) -> Option<Result<Vec<String>>> {
+        let mut buf = [0; 4096];
+        loop {
+            match self.socket.read(&mut buf) {
+                Ok(0) => return Some(Err(anyhow!("the session bus has closed the connection"))),
+                Ok(len) => self.received.extend_from_slice(&buf[..len]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+        loop {
+            let message = match self.next_message().transpose()? {
+                Ok(message) => message,
+                Err(e) => return Some(Err(e)),
+            };
+            match message.kind {
+                ERROR if message.reply_serial == Some(self.call) => {
+                    return Some(Err(error(&message)));
+                }
+                METHOD_RETURN if message.reply_serial == Some(self.call) => {
+                    // Older portals put the request object elsewhere
+                    let path = Reader::new(&message.body, message.big_endian).string();
+                    if let Some(path) = path {
+                        self.request = path;
+                    }
+                }
+                SIGNAL
+                    if message.member.as_deref() == Some("Response")
+                        && message.path.as_deref() == Some(&self.request) =>
+                {
+                    return Some(response(&message));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn send(
+        &mut self,
+        serial: u32,
+        flags: u8,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        signature: &str,
+        body: &[u8],
+    ) -> Result<()> {
+        let mut fields = vec![
+            (PATH, path),
+            (INTERFACE, interface),
+            (MEMBER, member),
+            (DESTINATION, destination),
+        ];
+        if !signature.is_empty() {
+            fields.push((SIGNATURE, signature));
+        }
+        let mut message = Writer::default();
+        message.0.extend_from_slice(&[b'l', METHOD_CALL, flags, 1]);
+        message.u32(body.len() as u32);
+        message.u32(serial);
+        message.array(8, |w| {
+            for ((code, kind), value) in fields {
+                w.align(8);
+                w.0.push(code);
+                w.signature(kind);
+                match kind {
+                    "g" => w.signature(value),
+                    _ => w.string(value),
+                }
+            }
+        });
+        message.align(8);
+        message.0.extend_from_slice(body);
+        self.socket
+            .write_all(&message.0)
+            .context("could not write to the session bus")
+    }
+
+    /// Wait for the reply to the call with `serial`, while connecting.
+    fn reply(&mut self, serial: u32) -> Result<Incoming> {
+        let mut buf = [0; 4096];
+        loop {
+            while let Some(message) = self.next_message()? {
+                if message.reply_serial != Some(serial) {
+                    continue;
+                }
+                if message.kind == ERROR {
+                    return Err(error(&message));
+                }
+                return Ok(message);
+            }
+            let len = self
+                .socket
+                .read(&mut buf)
+                .context("could not read from the session bus")?;
+            ensure!(len > 0, "the session bus has closed the connection");
+            self.received.extend_from_slice(&buf[..len]);
+        }
+    }
+
+    /// Take the first message out of `received`, if all of it has arrived.
+    fn next_message(&mut self) -> Result<Option<Incoming>> {
+        if self.received.len() < 16 {
+            return Ok(None);
+        }
+        let big_endian = self.received[0] == b'B';
+        let mut header = Reader::new(&self.received, big_endian);
+        header.pos = 4;
+        let body_len = header.u32().unwrap() as usize;
+        header.pos = 12;
+        let fields_len = header.u32().unwrap() as usize;
+        let body_start = (16 + fields_len).next_multiple_of(8);
+        // The most the specification allows
+        ensure!(
+            body_start + body_len <= 1 << 27,
+            "invalid message from the session bus"
+        );
+        if self.received.len() < body_start + body_len {
+            return Ok(None);
+        }
+
+        let mut message = Incoming {
+            kind: self.received[1],
+            big_endian,
+            reply_serial: None,
+            path: None,
+            member: None,
+            error_name: None,
+            signature: String::new(),
+            body: self.received[body_start..body_start + body_len].to_vec(),
+        };
+        let mut fields = Reader::new(&self.received[..16 + fields_len], big_endian);
+        fields.pos = 16;
+        while fields.pos < 16 + fields_len {
+            fields.align(8);
+            let field = (|| {
+                let code = fields.take(1)?[0];
+                let kind = fields.signature()?;
+                match (code, kind.as_str()) {
+                    (1, "o") => message.path = Some(fields.string()?),
+                    (3, "s") => message.member = Some(fields.string()?),
+                    (4, "s") => message.error_name = Some(fields.string()?),
+                    (5, "u") => message.reply_serial = Some(fields.u32()?),
+                    (8, "g") => message.signature = fields.signature()?,
+                    _ => {
+                        fields.skip(kind.as_bytes())?;
+                    }
+                }
+                Some(())
+            })();
+            field.context("invalid message from the session bus")?;
+        }
+        self.received.drain(..body_start + body_len);
+        Ok(Some(message))
+    }
+}
+
+/// The arguments of `OpenFile`: no parent window, a title and the options.
+fn open_file_args(folder: Option<&Path>) -> Vec<u8> {
+    let mut args = Writer::default();
+    args.string("");
+    args.string("Open images");
+    args.array(8, |w| {
+        w.entry("handle_token", "s", |w| w.string(TOKEN));
+        w.entry("multiple", "b", |w| w.u32(1));
+        w.entry("filters", "a(sa(us))", |w| {
+            w.array(8, |w| {
+                for (name, pattern) in [("Images", "image/*"), ("All files", "*")] {
+                    w.align(8);
+                    w.string(name);
+                    w.array(8, |w| {
+                        w.align(8);
+                        // By MIME type, or by glob pattern
+                        w.u32(if pattern == "*" { 0 } else { 1 });
+                        w.string(pattern);
+                    });
+                }
+            });
+        });
+        if let Some(folder) = folder {
+            w.entry("current_folder", "ay", |w| {
+                use std::os::unix::ffi::OsStrExt;
+                w.array(1, |w| {
+                    w.0.extend_from_slice(folder.as_os_str().as_bytes());
+                    w.0.push(0);
+                });
+            });
+        }
+    });
+    args.0
+}
+
+/// The files of a `Response` signal, whose arguments are a response code and the results.
+fn response(message: &Incoming) -> Result<Vec<String>> {
+    ensure!(
+        message.signature == "ua{sv}",
+        "invalid response of the file chooser"
+    );
+    let mut body = Reader::new(&message.body, message.big_endian);
+    let uris = (|| {
+        let code = body.u32()?;
+        let mut uris = Vec::new();
+        let len = body.u32()? as usize;
+        body.align(8);
+        let end = body.pos + len;
+        while body.pos < end {
+            body.align(8);
+            let key = body.string()?;
+            let kind = body.signature()?;
+            if key != "uris" || kind != "as" {
+                body.skip(kind.as_bytes())?;
+                continue;
+            }
+            let len = body.u32()? as usize;
+            let end = body.pos + len;
+            while body.pos < end {
+                uris.push(body.string()?);
+            }
+        }
+        Some((code, uris))
+    })();
+    match uris.context("invalid response of the file chooser")? {
+        (0, uris) => Ok(uris.iter().map(|uri| path(uri)).collect()),
+        // Cancelled
+        (1, _) => Ok(Vec::new()),
+        _ => bail!("the file chooser has failed"),
+    }
+}
+
+/// The path of a `file:` URI. Other URIs are kept, so that web images are downloaded.
+fn path(uri: &str) -> String {
+    match uri.strip_prefix("file://") {
+        Some(path) => String::from_utf8_lossy(&percent_decode(path)).into_owned(),
+        None => uri.to_owned(),
+    }
+}
+
+fn percent_decode(text: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        let hex = after.get(..2).and_then(|hex| std::str::from_utf8(hex).ok());
+        match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(decoded) if byte == b'%' => {
+                bytes.push(decoded);
+                rest = &after[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = after;
+            }
+        }
+    }
+    bytes
+}
+
+/// An error reply, with the message which comes with it.
+fn error(message: &Incoming) -> anyhow::Error {
+    let name = message.error_name.as_deref().unwrap_or("an error");
+    let text = match message.signature.starts_with('s') {
+        true => Reader::new(&message.body, message.big_endian).string(),
+        false => None,
+    };
+    match text {
+        Some(text) => anyhow!("{name}: {text}"),
+        None => anyhow!("{name}"),
+    }
+}
+
+/// Connect to the first Unix socket of `$DBUS_SESSION_BUS_ADDRESS`, or to
+/// `$XDG_RUNTIME_DIR/bus`.
+fn connect() -> Result<UnixStream> {
+    let address = match std::env::var("DBUS_SESSION_BUS_ADDRESS") {
+        Ok(address) => address,
+        Err(_) => {
+            let runtime_dir = std::env::var("XDG_RUNTIME_DIR")
+                .context("neither DBUS_SESSION_BUS_ADDRESS nor XDG_RUNTIME_DIR is set")?;
+            format!("unix:path={runtime_dir}/bus")
+        }
+    };
+    // Like `unix:path=/run/user/1000/bus;unix:abstract=/tmp/dbus-1234,guid=...`
+    for params in address.split(';').filter_map(|a| a.strip_prefix("unix:")) {
+        for param in params.split(',') {
+            let value = |key| param.strip_prefix(key).map(percent_decode);
+            if let Some(path) = value("path=") {
+                return Ok(UnixStream::connect(
+                    String::from_utf8_lossy(&path).as_ref(),
+                )?);
+            }
+            if let Some(name) = value("abstract=") {
+                return Ok(UnixStream::connect_addr(&SocketAddr::from_abstract_name(
+                    name,
+                )?)?);
+            }
+        }
+    }
+    bail!("there is no Unix socket in {address:?}")
+}
+
+/// Authenticate as the user who runs reimv, whom the bus knows from the socket.
+fn authenticate(socket: &mut UnixStream) -> Result<()> {
+    // SAFETY: getuid cannot fail
+    let uid = unsafe { libc::getuid() }.to_string();
+    let hex: String = uid.bytes().map(|b| format!("{b:02x}")).collect();
+    socket.write_all(format!("\0AUTH EXTERNAL {hex}\r\n").as_bytes())?;
+    // Byte by byte, since messages may follow the line once we have begun
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        let mut byte = [0];
+        socket
+            .read_exact(&mut byte)
+            .context("the session bus did not answer")?;
+        line.push(byte[0]);
+        ensure!(line.len() < 512, "the session bus did not answer");
+    }
+    let line = String::from_utf8_lossy(&line);
+    ensure!(
+        line.starts_with("OK "),
+        "the session bus refused us: {}",
+        line.trim_end()
+    );
+    socket.write_all(b"BEGIN\r\n")?;
+    Ok(())
+}
+
+/// Little-endian values, aligned from the start of the message.
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn align(&mut self, alignment: usize) {
+        let len = self.0.len().next_multiple_of(alignment);
+        self.0.resize(len, 0);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value.as_bytes());
+        self.0.push(0);
+    }
+
+    fn signature(&mut self, value: &str) {
+        self.0.push(value.len() as u8);
+        self.0.extend_from_slice(value.as_bytes());
+        self.0.push(0);
+    }
+
+    /// An array whose elements are aligned to `alignment`, which `elements` writes.
+    fn array(&mut self, alignment: usize, elements: impl FnOnce(&mut Self)) {
+        self.u32(0);
+        let len_at = self.0.len() - 4;
+        self.align(alignment);
+        let start = self.0.len();
+        elements(self);
+        let len = (self.0.len() - start) as u32;
+        self.0[len_at..len_at + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    /// An entry of an `a{sv}` dictionary, whose value of type `kind` `value` writes.
+    fn entry(&mut self, key: &str, kind: &str, value: impl FnOnce(&mut Self)) {
+        self.align(8);
+        self.string(key);
+        self.signature(kind);
+        value(self);
+    }
+}
+
+/// Values in the byte order of the message, aligned from its start or that of its body.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8], big_endian: bool) -> Self {
+        Self {
+            bytes,
+            pos: 0,
+            big_endian,
+        }
+    }
+
+    fn align(&mut self, alignment: usize) {
+        self.pos = self.pos.next_multiple_of(alignment);
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.align(4);
+        let bytes = self.take(4)?.try_into().unwrap();
+        Some(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        let string = String::from_utf8_lossy(self.take(len)?).into_owned();
+        self.take(1)?;
+        Some(string)
+    }
+
+    fn signature(&mut self) -> Option<String> {
+        let len = self.take(1)?[0] as usize;
+        let signature = String::from_utf8_lossy(self.take(len)?).into_owned();
+        self.take(1)?;
+        Some(signature)
+    }
+
+    /// Skip a value of the first complete type of `signature`, and return the rest of it.
+    fn skip<'s>(&mut self, signature: &'s [u8]) -> Option<&'s [u8]> {
+        let (&code, rest) = signature.split_first()?;
+        match code {
+            b'y' => {
+                self.take(1)?;
+            }
+            b'n' | b'q' => {
+                self.align(2);
+                self.take(2)?;
+            }
+            b'b' | b'i' | b'u' | b'h' => {
+                self.u32()?;
+            }
+            b'x' | b't' | b'd' => {
+                self.align(8);
+                self.take(8)?;
+            }
+            b's' | b'o' => {
+                self.string()?;
+            }
+            b'g' => {
+                self.signature()?;
+            }
+            b'v' => {
+                let inner = self.signature()?;
+                if !self.skip(inner.as_bytes())?.is_empty() {
+                    return None;
+                }
+            }
+            b'a' => {
+                let len = self.u32()? as usize;
+                let element = type_len(rest)?;
+                self.align(alignment(rest[0]));
+                self.take(len)?;
+                return Some(&rest[element..]);
+            }
+            b'(' | b'{' => {
+                self.align(8);
+                let mut inner = rest;
+                while !matches!(inner.first()?, b')' | b'}') {
+                    inner = self.skip(inner)?;
+                }
+                return Some(&inner[1..]);
+            }
+            _ => return None,
+        }
+        Some(rest)
+    }
+}
+
+/// The length of the first complete type of `signature`.
+fn type_len(signature: &[u8]) -> Option<usize> {
+    match signature.first()? {
+        b'a' => Some(1 + type_len(&signature[1..])?),
+        b'(' | b'{' => {
+            let mut len = 1;
+            while !matches!(signature.get(len)?, b')' | b'}') {
+                len += type_len(&signature[len..])?;
+            }
+            Some(len + 1)
+        }
+        _ => Some(1),
+    }
+}
+
+fn alignment(code: u8) -> usize {
+    match code {
+        b'n' | b'q' => 2,
+        b'b' | b'i' | b'u' | b'h' | b's' | b'o' | b'a' => 4,
+        b'x' | b't' | b'd' | b'(' | b'{' => 8,
+        _ => 1,
+    }
+}
@@ -0,0 +1,45 @@
+//! Pausing animations while the displays are off, on compositors which support the wlr output
+//! power management protocol.
+//!
+//! Compositors turn the displays off after a while without input, but keep the windows as they
+//! are. Without this, an animation on a kiosk would be drawn all night for nobody.
+
+use wayrs_client::protocol::*;
+use wayrs_client::Connection;
+use wayrs_protocols::wlr_output_power_management_unstable_v1::*;
+
+use crate::globals::Globals;
+use crate::{EventCtx, State};
+
+/// Follow the power mode of `output`, if the compositor supports it.
+pub fn watch(
+    conn: &mut Connection<State>,
+    globals: &Globals,
+    output: WlOutput,
+) -> Option<ZwlrOutputPowerV1> {
+    let manager = globals.output_power_manager?;
+    Some(manager.get_output_power_with_cb(conn, output, output_power_cb))
+}
+
+fn output_power_cb(ctx: EventCtx<ZwlrOutputPowerV1>) {
+    let Some(output) = ctx
+        .state
+        .outputs
+        .iter_mut()
+        .find(|o| o.power == Some(ctx.proxy))
+    else {
+        return;
+    };
+    match ctx.event {
+        zwlr_output_power_v1::Event::Mode(mode) => {
+            output.powered = mode != zwlr_output_power_v1::Mode::Off;
+        }
+        // Another client controls the power mode, or the output is gone
+        zwlr_output_power_v1::Event::Failed => {
+            output.powered = true;
+            output.power = None;
+            ctx.proxy.destroy(ctx.conn);
+        }
+        _ => (),
+    }
+}
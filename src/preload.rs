@@ -0,0 +1,184 @@
+//! Decoding the images next to the current one in the background, so that moving to them shows
+//! them at once. See `--no-preload`.
+//!
+//! A few worker threads decode the files they are given in turn, and skip those which are no
+//! longer wanted by the time they get to them, like while a key is held to move through many
+//! images. Nothing new is started while the user drags or pinches the image. A decoded image is
+//! only shown if its file has not been modified since it was read.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+
+use crate::cache;
+use crate::files::Entry;
+use crate::image::{self, DecodeOptions, Prepared};
+
+/// How many images are decoded at the same time, one in each direction.
+const WORKERS: usize = 2;
+
+pub struct Preload {
+    jobs: mpsc::Sender<Job>,
+    results: mpsc::Receiver<Done>,
+    /// The files to decode, shared with the workers
+    wanted: Arc<Mutex<Vec<String>>>,
+    /// The files given to the workers which have not come back yet
+    pending: Vec<String>,
+    /// The files which have come back
+    ready: Vec<Done>,
+    /// While the system is low on memory
+    paused: bool,
+    /// While the user is dragging or pinching
+    interacting: bool,
+}
+
+struct Job {
+    path: String,
+    options: DecodeOptions,
+}
+
+struct Done {
+    path: String,
+    /// When the file was modified, before it was read
+    modified: Option<SystemTime>,
+    /// Missing if it was no longer wanted
+    result: Option<Result<Prepared>>,
+}
+
+impl Preload {
+    pub fn new() -> Self {
+        let (jobs, job_rx) = mpsc::channel();
+        let (result_tx, results) = mpsc::channel();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let wanted = Arc::new(Mutex::new(Vec::new()));
+        for _ in 0..WORKERS {
+            let job_rx = job_rx.clone();
+            let wanted = wanted.clone();
+            let result_tx = result_tx.clone();
+            std::thread::spawn(move || work(&job_rx, &wanted, &result_tx));
+        }
+        Self {
+            jobs,
+            results,
+            wanted,
+            pending: Vec::new(),
+            ready: Vec::new(),
+            paused: false,
+            interacting: false,
+        }
+    }
+
+    /// Stop decoding and forget the images decoded already, or start again with [`Self::want`].
+    /// Images which are being decoded are finished first.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        if paused {
+            self.wanted.lock().unwrap().clear();
+            self.ready.clear();
+        }
+    }
+
+    /// Start no more decodes while the user is dragging or pinching, so that they do not slow
+    /// the gesture down, but keep the images decoded already. Returns `true` when the gesture is
+    /// over, and [`Self::want`] should be called again.
+    pub fn set_interacting(&mut self, interacting: bool) -> bool {
+        let ended = self.interacting && !interacting;
+        if interacting && !self.interacting {
+            self.wanted.lock().unwrap().clear();
+        }
+        self.interacting = interacting;
+        ended
+    }
+
+    /// Decode these files, the first one first, and forget the others.
+    pub fn want(&mut self, files: Vec<(String, DecodeOptions)>) {
+        if self.paused || self.interacting {
+            return;
+        }
+        *self.wanted.lock().unwrap() = files.iter().map(|(path, _)| path.clone()).collect();
+        self.collect();
+        self.ready
+            .retain(|done| files.iter().any(|(path, _)| *path == done.path));
+        for (path, options) in files {
+            if self.pending.contains(&path) || self.ready.iter().any(|done| done.path == path) {
+                continue;
+            }
+            self.pending.push(path.clone());
+            let _ = self.jobs.send(Job { path, options });
+        }
+    }
+
+    /// The decoded image of the file at `path`, waiting for it if it is being decoded.
+    pub fn take(&mut self, path: &str) -> Option<Result<Prepared>> {
+        self.collect();
+        let done = match self.ready.iter().position(|done| done.path == path) {
+            Some(i) => self.ready.swap_remove(i),
+            None if self.pending.iter().any(|pending| pending == path) => loop {
+                let done = self.results.recv().ok()?;
+                self.pending.retain(|pending| *pending != done.path);
+                if done.path == path {
+                    break done;
+                }
+                if done.result.is_some() {
+                    self.ready.push(done);
+                }
+            },
+            None => return None,
+        };
+        // The file may have changed since, like with `--watch`
+        let result = done.result?;
+        (modified(&done.path) == done.modified).then_some(result)
+    }
+
+    /// Move the files which have come back to `ready`.
+    fn collect(&mut self) {
+        for done in self.results.try_iter() {
+            self.pending.retain(|pending| *pending != done.path);
+            if done.result.is_some() && !self.paused {
+                self.ready.push(done);
+            }
+        }
+    }
+}
+
+fn work(
+    jobs: &Mutex<mpsc::Receiver<Job>>,
+    wanted: &Mutex<Vec<String>>,
+    results: &mpsc::Sender<Done>,
+) {
+    loop {
+        let Ok(Job { path, options }) = jobs.lock().unwrap().recv() else {
+            return;
+        };
+        let modified = modified(&path);
+        let is_wanted = wanted.lock().unwrap().contains(&path);
+        let result = is_wanted.then(|| {
+            let cache_key = options
+                .disk_cache
+                .and_then(|_| cache::Key::new(path.as_ref()));
+            let entry = Entry::File(path.clone());
+            // Tell the viewer, which may be waiting for it
+            panic::catch_unwind(AssertUnwindSafe(|| {
+                image::prepare(&entry, options, cache_key)
+            }))
+            .unwrap_or_else(|_| Err(anyhow!("the decoder crashed")))
+        });
+        if results
+            .send(Done {
+                path,
+                modified,
+                result,
+            })
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
@@ -0,0 +1,44 @@
+//! Presenting slides: a laser pointer and a magnifier, which is shown while the button is held.
+
+use crate::image::ImageTransform;
+
+/// How much the magnifier enlarges the image.
+const MAGNIFICATION: f32 = 2.5;
+
+/// Points are in surface local coordinates, since they follow the pointer and not the image.
+#[derive(Debug, Default)]
+pub struct Present {
+    /// Where the laser pointer is shown, while it is on
+    pub laser: Option<(f32, f32)>,
+    /// The view from before magnifying, while the magnifier is shown
+    unmagnified: Option<ImageTransform>,
+}
+
+impl Present {
+    /// Turn the laser pointer on at `pointer`, or off. Returns whether it is on now.
+    pub fn toggle_laser(&mut self, pointer: (f32, f32)) -> bool {
+        self.laser = match self.laser {
+            Some(_) => None,
+            None => Some(pointer),
+        };
+        self.laser.is_some()
+    }
+
+    pub fn magnifying(&self) -> bool {
+        self.unmagnified.is_some()
+    }
+
+    /// Magnify the image around `point`, or move the magnifier there if it is shown already.
+    pub fn magnify(&mut self, transform: &mut ImageTransform, point: (f32, f32)) {
+        let unmagnified = *self.unmagnified.get_or_insert(*transform);
+        *transform = unmagnified;
+        transform.zoom_to(point.0, point.1, unmagnified.scale * MAGNIFICATION);
+    }
+
+    /// Go back to the view from before magnifying.
+    pub fn release(&mut self, transform: &mut ImageTransform) {
+        if let Some(unmagnified) = self.unmagnified.take() {
+            *transform = unmagnified;
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Noticing when the system runs low on memory, from its pressure stall information (PSI).
+//!
+//! A trigger on the `memory.pressure` file of reimv's cgroup, or on `/proc/pressure/memory`,
+//! wakes reimv up when tasks have been waiting for memory for a while. Until there has been no
+//! such report for [`CALM`], reimv stops preloading and keeping the images shown before, gives
+//! back the memory it has freed and decodes new images at the size of the window. systemd-oomd
+//! kills a whole cgroup once its pressure has stayed high, without warning it first, so using
+//! less memory early is the only way to be spared.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// 200 ms of stalls within 2 s. Triggers set without privileges need a window of a multiple of
+/// 2 s. The kernel replaces the last byte written by a NUL.
+const TRIGGER: &[u8] = b"some 200000 2000000\0";
+/// How long memory is taken to be low after the last report.
+const CALM: Duration = Duration::from_secs(30);
+
+pub struct Pressure {
+    file: File,
+    /// When pressure was last reported, while memory is low
+    since: Option<Instant>,
+}
+
+impl Pressure {
+    /// Returns `None` if the kernel has no PSI, or does not let reimv set a trigger.
+    pub fn new() -> Option<Self> {
+        [cgroup_file(), Some("/proc/pressure/memory".into())]
+            .into_iter()
+            .flatten()
+            .find_map(|path| {
+                let mut file = OpenOptions::new().read(true).write(true).open(path).ok()?;
+                file.write_all(TRIGGER).ok()?;
+                Some(Self { file, since: None })
+            })
+    }
+
+    /// Becomes ready with `POLLPRI` when the trigger fires, which `poll` clears again.
+    pub fn fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+
+    /// Note that the trigger has fired. Returns `true` if memory was not low before.
+    pub fn report(&mut self) -> bool {
+        self.since.replace(Instant::now()).is_none()
+    }
+
+    pub fn low(&self) -> bool {
+        self.since.is_some()
+    }
+
+    /// The duration until memory is no longer taken to be low.
+    pub fn sleep(&self) -> Option<Duration> {
+        self.since
+            .map(|since| (since + CALM).saturating_duration_since(Instant::now()))
+    }
+
+    /// Whether memory has stopped being low just now.
+    pub fn calmed(&mut self) -> bool {
+        let calmed = self.since.is_some_and(|since| since.elapsed() >= CALM);
+        if calmed {
+            self.since = None;
+        }
+        calmed
+    }
+}
+
+/// The pressure file of the cgroup of reimv, with cgroup v2.
+fn cgroup_file() -> Option<PathBuf> {
+    // Like `0::/user.slice/user-1000.slice/...`
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+    let dir = Path::new("/sys/fs/cgroup").join(path.trim_start_matches('/'));
+    Some(dir.join("memory.pressure"))
+}
+
+/// Give the memory which has been freed back to the system, which malloc keeps otherwise.
+pub fn trim() {
+    #[cfg(target_env = "gnu")]
+    // SAFETY: it only releases memory which is not in use
+    unsafe {
+        libc::malloc_trim(0);
+    }
+}
@@ -0,0 +1,93 @@
+//! A line of text typed into the overlay, like the new name of a file.
+//!
+//! Keys have been through the compose table already, so that dead keys and compose sequences type
+//! accented letters, see [`crate::keyboard`]. Keys which are not part of a sequence type what they
+//! type in the layout.
+
+use crate::keyboard::{xkb, KeyboardEvent};
+
+pub struct Prompt {
+    /// What the text is for, like `Rename to`
+    label: &'static str,
+    text: String,
+    /// The byte offset of the cursor in `text`
+    cursor: usize,
+}
+
+/// What a key did to a prompt.
+pub enum Input {
+    /// The key was not for the prompt
+    Ignored,
+    /// The text or the cursor may have moved
+    Edited,
+    /// Enter was pressed, with the text
+    Done(String),
+    /// Escape was pressed
+    Cancelled,
+}
+
+impl Prompt {
+    /// Start with `text`, with the cursor at the byte offset `cursor`.
+    pub fn new(label: &'static str, text: String, cursor: usize) -> Self {
+        Self {
+            label,
+            text,
+            cursor,
+        }
+    }
+
+    pub fn key(&mut self, event: &KeyboardEvent) -> Input {
+        if let Some(text) = &event.composed {
+            self.insert(text);
+            return Input::Edited;
+        }
+        let keysym = event.xkb_state.key_get_one_sym(event.keycode);
+        match keysym {
+            xkb::Keysym::Return | xkb::Keysym::KP_Enter => return Input::Done(self.text.clone()),
+            xkb::Keysym::Escape => return Input::Cancelled,
+            xkb::Keysym::BackSpace => {
+                if let Some(c) = self.text[..self.cursor].chars().next_back() {
+                    self.cursor -= c.len_utf8();
+                    self.text.remove(self.cursor);
+                }
+            }
+            xkb::Keysym::Delete | xkb::Keysym::KP_Delete => {
+                if self.cursor < self.text.len() {
+                    self.text.remove(self.cursor);
+                }
+            }
+            xkb::Keysym::Left | xkb::Keysym::KP_Left => {
+                if let Some(c) = self.text[..self.cursor].chars().next_back() {
+                    self.cursor -= c.len_utf8();
+                }
+            }
+            xkb::Keysym::Right | xkb::Keysym::KP_Right => {
+                if let Some(c) = self.text[self.cursor..].chars().next() {
+                    self.cursor += c.len_utf8();
+                }
+            }
+            xkb::Keysym::Home | xkb::Keysym::KP_Home => self.cursor = 0,
+            xkb::Keysym::End | xkb::Keysym::KP_End => self.cursor = self.text.len(),
+            _ => {
+                // Nothing with Control types text
+                let text = event.xkb_state.key_get_utf8(event.keycode);
+                if text.is_empty() || text.chars().any(char::is_control) {
+                    return Input::Ignored;
+                }
+                self.insert(&text);
+            }
+        }
+        Input::Edited
+    }
+
+    /// The prompt as a message for the overlay, with a bar at the cursor.
+    pub fn message(&self) -> String {
+        let (before, after) = self.text.split_at(self.cursor);
+        format!("{}: {before}|{after}", self.label)
+    }
+
+    fn insert(&mut self, text: &str) {
+        self.text.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+}
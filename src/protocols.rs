@@ -0,0 +1,6 @@
+//! Protocols which `wayrs-protocols` does not include yet.
+
+pub mod color_management_v1 {
+    use wayrs_client::protocol::*;
+    wayrs_client::generate!("protocols/color-management-v1.xml");
+}
@@ -0,0 +1,268 @@
+//! Photoshop documents (PSD and PSB).
+//!
+//! Only the flattened composite is decoded, which Photoshop stores after the layers unless
+//! "Maximize Compatibility" was turned off when saving.
+
+use anyhow::{bail, ensure, Context, Result};
+use image::RgbaImage;
+
+use crate::hdr::linear_to_srgb;
+use crate::limits::Limits;
+
+const MODE_BITMAP: u16 = 0;
+const MODE_GRAYSCALE: u16 = 1;
+const MODE_INDEXED: u16 = 2;
+const MODE_RGB: u16 = 3;
+const MODE_CMYK: u16 = 4;
+const MODE_MULTICHANNEL: u16 = 7;
+const MODE_DUOTONE: u16 = 8;
+
+pub fn is_psd(data: &[u8]) -> bool {
+    data.starts_with(b"8BPS")
+}
+
+/// The sections of a PSD file.
+pub struct Sections<'a> {
+    pub header: Header,
+    pub color_mode_data: &'a [u8],
+    pub image_resources: &'a [u8],
+    pub layer_and_mask_info: &'a [u8],
+    pub image_data: &'a [u8],
+}
+
+pub struct Header {
+    /// PSB files use wider lengths in some places
+    pub big: bool,
+    pub channels: u16,
+    pub height: u32,
+    pub width: u32,
+    pub depth: u16,
+    pub mode: u16,
+}
+
+impl<'a> Sections<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        let mut r = Reader { data, pos: 4 };
+        let big = match r.u16()? {
+            1 => false,
+            2 => true,
+            _ => return None,
+        };
+        r.skip(6)?;
+        let header = Header {
+            big,
+            channels: r.u16()?,
+            height: r.u32()?,
+            width: r.u32()?,
+            depth: r.u16()?,
+            mode: r.u16()?,
+        };
+        let color_mode_data = r.section(false)?;
+        let image_resources = r.section(false)?;
+        let layer_and_mask_info = r.section(big)?;
+        Some(Self {
+            header,
+            color_mode_data,
+            image_resources,
+            layer_and_mask_info,
+            image_data: &data[r.pos..],
+        })
+    }
+}
+
+/// Decode the flattened composite.
+pub fn decode(data: &[u8], limits: &Limits) -> Result<RgbaImage> {
+    let psd = Sections::parse(data).context("invalid PSD header")?;
+    let Header {
+        big,
+        channels,
+        height,
+        width,
+        depth,
+        mode,
+    } = psd.header;
+
+    let color_channels = match mode {
+        MODE_BITMAP | MODE_GRAYSCALE | MODE_INDEXED | MODE_DUOTONE => 1,
+        MODE_RGB => 3,
+        MODE_CMYK => 4,
+        MODE_MULTICHANNEL => channels.min(3),
+        _ => bail!("color mode {mode} is not supported"),
+    };
+    ensure!(channels >= color_channels, "not enough channels");
+    ensure!(
+        matches!(depth, 1 | 8 | 16 | 32),
+        "{depth}-bit channels are not supported"
+    );
+    ensure!((depth == 1) == (mode == MODE_BITMAP), "invalid bit depth");
+    limits.check(width, height)?;
+
+    // The first extra channel is the transparency of the composite if the layer count is
+    // negative. Otherwise extra channels are spot colors or saved selections.
+    let has_alpha = channels > color_channels && layer_count(&psd).is_some_and(|n| n < 0);
+    let used_channels = color_channels as usize + has_alpha as usize;
+
+    let row_bytes = (width as usize * depth as usize).div_ceil(8);
+    let channel_bytes = row_bytes
+        .checked_mul(height as usize)
+        .context("image too large")?;
+
+    let mut r = Reader {
+        data: psd.image_data,
+        pos: 0,
+    };
+    let planes: Vec<Vec<u8>> = match r.u16().context("no image data")? {
+        0 => (0..used_channels)
+            .map(|_| r.bytes(channel_bytes).map(<[u8]>::to_vec))
+            .collect::<Option<_>>()
+            .context("truncated image data")?,
+        1 => {
+            // Byte counts of all rows of all channels, followed by PackBits-compressed rows
+            let rows = channels as usize * height as usize;
+            let counts = (0..rows)
+                .map(|_| {
+                    if big {
+                        r.u32()
+                    } else {
+                        r.u16().map(Into::into)
+                    }
+                })
+                .collect::<Option<Vec<u32>>>()
+                .context("truncated row byte counts")?;
+            let mut planes = Vec::with_capacity(used_channels);
+            for channel in counts.chunks_exact(height as usize).take(used_channels) {
+                let mut plane = Vec::with_capacity(channel_bytes);
+                for &count in channel {
+                    let row = r.bytes(count as usize).context("truncated image data")?;
+                    unpack_bits(row, row_bytes, &mut plane)?;
+                }
+                planes.push(plane);
+            }
+            planes
+        }
+        c => bail!("compression method {c} is not supported"),
+    };
+
+    // Normalized samples, linear for 32-bit images
+    let sample = |channel: usize, x: usize, y: usize| -> f32 {
+        let row = &planes[channel][y * row_bytes..][..row_bytes];
+        match depth {
+            1 => ((row[x / 8] >> (7 - x % 8)) & 1) as f32,
+            8 => row[x] as f32 / 255.0,
+            16 => u16::from_be_bytes([row[2 * x], row[2 * x + 1]]) as f32 / 65535.0,
+            _ => f32::from_be_bytes(row[4 * x..4 * x + 4].try_into().unwrap()).clamp(0.0, 1.0),
+        }
+    };
+    let palette = psd.color_mode_data;
+    ensure!(
+        mode != MODE_INDEXED || palette.len() >= 768,
+        "no color palette"
+    );
+
+    let mut image = RgbaImage::new(width, height);
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let (x, y) = (x as usize, y as usize);
+        let mut rgb = match mode {
+            MODE_BITMAP => [1.0 - sample(0, x, y); 3],
+            MODE_INDEXED => {
+                let i = planes[0][y * row_bytes + x] as usize;
+                [palette[i], palette[256 + i], palette[512 + i]].map(|c| c as f32 / 255.0)
+            }
+            MODE_RGB => [sample(0, x, y), sample(1, x, y), sample(2, x, y)],
+            MODE_CMYK => {
+                // Stored inverted, so that 1 means no ink
+                let k = sample(3, x, y);
+                [0, 1, 2].map(|c| sample(c, x, y) * k)
+            }
+            MODE_MULTICHANNEL if color_channels == 3 => {
+                [sample(0, x, y), sample(1, x, y), sample(2, x, y)]
+            }
+            _ => [sample(0, x, y); 3],
+        };
+        let alpha = if has_alpha {
+            sample(color_channels as usize, x, y)
+        } else {
+            1.0
+        };
+        if alpha > 0.0 && alpha < 1.0 {
+            // The composite is matted against white
+            rgb = rgb.map(|c| ((c - (1.0 - alpha)) / alpha).clamp(0.0, 1.0));
+        }
+        if depth == 32 {
+            rgb = rgb.map(linear_to_srgb);
+        }
+        let to_u8 = |v: f32| (v * 255.0 + 0.5) as u8;
+        pixel.0 = [to_u8(rgb[0]), to_u8(rgb[1]), to_u8(rgb[2]), to_u8(alpha)];
+    }
+    Ok(image)
+}
+
+/// The layer count from the layer info, which is negative if the composite has transparency.
+fn layer_count(psd: &Sections) -> Option<i16> {
+    let mut r = Reader {
+        data: psd.layer_and_mask_info,
+        pos: 0,
+    };
+    let layer_info = r.section(psd.header.big)?;
+    Some(i16::from_be_bytes(layer_info.get(..2)?.try_into().ok()?))
+}
+
+/// Decompress a PackBits-encoded row of `len` bytes.
+fn unpack_bits(mut src: &[u8], len: usize, dst: &mut Vec<u8>) -> Result<()> {
+    let end = dst.len() + len;
+    while dst.len() < end {
+        let (&header, rest) = src.split_first().context("truncated row")?;
+        src = rest;
+        match header as i8 {
+            -128 => (),
+            n @ 0.. => {
+                let n = n as usize + 1;
+                ensure!(src.len() >= n, "truncated row");
+                dst.extend_from_slice(&src[..n]);
+                src = &src[n..];
+            }
+            n => {
+                let (&value, rest) = src.split_first().context("truncated row")?;
+                src = rest;
+                dst.resize(dst.len() + (1 - n as isize) as usize, value);
+            }
+        }
+    }
+    ensure!(dst.len() == end, "row too long");
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.bytes(len).map(drop)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    /// A section prefixed with its length, which is 64-bit in some sections of PSB files.
+    fn section(&mut self, wide: bool) -> Option<&'a [u8]> {
+        let len = if wide {
+            u64::from_be_bytes(self.bytes(8)?.try_into().ok()?) as usize
+        } else {
+            self.u32()? as usize
+        };
+        self.bytes(len)
+    }
+}
@@ -0,0 +1,152 @@
+//! Pyramidal TIFF files, which contain the same image at several resolutions, such as slide
+//! scans from microscopes or the output of `vips tiffsave --pyramid`.
+//!
+//! The levels below the full resolution are either reduced-resolution IFDs following it in the
+//! main chain, or SubIFDs of it. Only the level closest to the resolution it is shown at is
+//! decoded, so that images much larger than the memory can be browsed.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use image::{DynamicImage, ImageFormat};
+
+use crate::color;
+use crate::isolate;
+use crate::limits::Limits;
+use crate::metadata::{Tiff, TAG_SUB_IFDS};
+
+const TAG_NEW_SUBFILE_TYPE: u16 = 0xFE;
+const TAG_IMAGE_WIDTH: u16 = 0x100;
+const TAG_IMAGE_LENGTH: u16 = 0x101;
+
+/// The first level shown has at least this many pixels, until the one fitting the window is known.
+const START_PIXELS: u64 = 4_000_000;
+
+#[derive(Clone)]
+pub struct Pyramid {
+    /// Shared with the decodes running in the background
+    data: Arc<[u8]>,
+    /// From the full resolution to the smallest level
+    levels: Vec<Level>,
+    current: usize,
+    limits: Limits,
+    color_management: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Level {
+    /// The offset of the IFD
+    offset: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Pyramid {
+    /// Returns `None` unless this is a TIFF file with one image at several resolutions.
+    /// Multi-page files are not pyramids, even if their pages have reduced versions.
+    pub fn tiff(data: &[u8], limits: Limits, color_management: bool) -> Option<Self> {
+        let tiff = Tiff::new(data)?;
+        let level = |offset: u32| {
+            let (ifd, _) = tiff.parse_ifd(offset as usize)?;
+            let reduced = tiff.value_of(&ifd, TAG_NEW_SUBFILE_TYPE).unwrap_or(0) & 1 == 1;
+            let width = tiff.value_of(&ifd, TAG_IMAGE_WIDTH)?;
+            let height = tiff.value_of(&ifd, TAG_IMAGE_LENGTH)?;
+            Some((
+                Level {
+                    offset,
+                    width,
+                    height,
+                },
+                reduced,
+            ))
+        };
+
+        let chain = tiff.chain();
+        let (&first, rest) = chain.split_first()?;
+        let (full, false) = level(first)? else {
+            return None;
+        };
+        let mut levels = vec![full];
+        for &offset in rest {
+            match level(offset)? {
+                (level, true) => levels.push(level),
+                (_, false) => return None,
+            }
+        }
+        let (ifd0, _) = tiff.parse_ifd(first as usize)?;
+        if let Some(entry) = ifd0.get(&TAG_SUB_IFDS) {
+            let offsets = (0..entry.count as usize).filter_map(|i| tiff.value(entry, i));
+            levels.extend(offsets.filter_map(level).map(|(level, _)| level));
+        }
+
+        // Thumbnails with another aspect ratio, or cut out, are not levels
+        let aspect = full.width as f64 / full.height as f64;
+        levels.retain(|level| {
+            let scaled_height = level.width as f64 / aspect;
+            level.width <= full.width && (scaled_height - level.height as f64).abs() <= 2.0
+        });
+        levels.sort_by_key(|level| std::cmp::Reverse(level.width));
+        levels.dedup_by_key(|level| level.width);
+        if levels.len() < 2 {
+            return None;
+        }
+
+        let mut pyramid = Self {
+            data: data.into(),
+            levels,
+            current: 0,
+            limits,
+            color_management,
+        };
+        let (width, height) = pyramid.dimensions();
+        let scale = (START_PIXELS as f64 / (width as f64 * height as f64)).sqrt();
+        pyramid.current = pyramid.level_for_width(width as f32 * scale.min(1.0) as f32);
+        Some(pyramid)
+    }
+
+    /// The size of the full resolution.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.levels[0].width, self.levels[0].height)
+    }
+
+    /// The level shown now, 0 being the full resolution.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn set_current(&mut self, level: usize) {
+        self.current = level;
+    }
+
+    /// The smallest level with at least `width` pixels per row, or the largest one within the
+    /// limits.
+    pub fn level_for_width(&self, width: f32) -> usize {
+        let fits = |level: &Level| self.limits.check(level.width, level.height).is_ok();
+        let largest = self.levels.iter().position(fits).unwrap_or(0);
+        (largest..self.levels.len())
+            .rev()
+            .find(|&i| self.levels[i].width as f32 >= width)
+            .unwrap_or(largest)
+    }
+
+    /// Decode a level. This may take a while, and can be done on another thread.
+    pub fn decode(&self, level: usize) -> Result<DynamicImage> {
+        // A copy which starts with the IFD of the level
+        let mut data = self.data.to_vec();
+        let offset = self.levels[level].offset;
+        let offset = if data.starts_with(b"II") {
+            offset.to_le_bytes()
+        } else {
+            offset.to_be_bytes()
+        };
+        data[4..8].copy_from_slice(&offset);
+        isolate::run(&self.limits, || {
+            let image = self.limits.decode(&data, ImageFormat::Tiff)?;
+            Ok(match self.color_management {
+                true => color::to_srgb(&data, image),
+                false => image,
+            })
+        })
+        .with_context(|| format!("could not decode level {}", level + 1))
+    }
+}
@@ -0,0 +1,287 @@
+//! Camera RAW files.
+//!
+//! Almost all RAW formats are TIFF containers which, besides the sensor data, carry one or more
+//! JPEG previews rendered by the camera. We can always show the largest of those previews. The
+//! sensor data itself is decoded only for uncompressed DNG files, which are demosaiced with a
+//! simple bilinear filter and converted to sRGB with the color matrix of the file, preferably the
+//! one for daylight. Files without a color matrix are shown in the colors of the camera.
+
+use anyhow::{ensure, Context, Result};
+use image::RgbaImage;
+
+use crate::color;
+use crate::hdr::linear_to_srgb;
+use crate::limits::Limits;
+use crate::metadata::{Ifd, Tiff};
+
+const TAG_NEW_SUBFILE_TYPE: u16 = 0xFE;
+const TAG_WIDTH: u16 = 0x100;
+const TAG_HEIGHT: u16 = 0x101;
+const TAG_BITS_PER_SAMPLE: u16 = 0x102;
+const TAG_COMPRESSION: u16 = 0x103;
+const TAG_PHOTOMETRIC: u16 = 0x106;
+const TAG_STRIP_OFFSETS: u16 = 0x111;
+const TAG_SAMPLES_PER_PIXEL: u16 = 0x115;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x117;
+const TAG_JPEG_OFFSET: u16 = 0x201;
+const TAG_JPEG_LENGTH: u16 = 0x202;
+const TAG_CFA_REPEAT_PATTERN_DIM: u16 = 0x828D;
+const TAG_CFA_PATTERN: u16 = 0x828E;
+const TAG_DNG_VERSION: u16 = 0xC612;
+const TAG_BLACK_LEVEL: u16 = 0xC61A;
+const TAG_WHITE_LEVEL: u16 = 0xC61D;
+const TAG_COLOR_MATRIX_1: u16 = 0xC621;
+const TAG_COLOR_MATRIX_2: u16 = 0xC622;
+const TAG_AS_SHOT_NEUTRAL: u16 = 0xC628;
+const TAG_CALIBRATION_ILLUMINANT_1: u16 = 0xC65A;
+const TAG_CALIBRATION_ILLUMINANT_2: u16 = 0xC65B;
+
+const PHOTOMETRIC_CFA: u32 = 32803;
+/// The EXIF light source which sRGB is made for.
+const ILLUMINANT_D65: u32 = 21;
+
+/// File extensions of the RAW formats we know to be TIFF-based (or, in case of RAF, to contain a
+/// JPEG preview at a known offset).
+pub fn is_raw_extension(ext: &str) -> bool {
+    matches!(
+        ext.to_ascii_lowercase().as_str(),
+        "dng" | "cr2" | "nef" | "nrw" | "arw" | "srf" | "sr2" | "pef" | "raf" | "rw2" | "orf"
+    )
+}
+
+/// Recognize the RAW formats which have a signature of their own. Others, such as NEF and ARW,
+/// look like plain TIFF files.
+pub fn is_raw(data: &[u8]) -> bool {
+    if data.starts_with(b"FUJIFILMCCD-RAW")
+        || data.starts_with(b"IIRO")
+        || data.starts_with(b"IIRS")
+        || data.starts_with(b"MMOR")
+        || data.starts_with(b"IIU\0")
+    {
+        return true;
+    }
+    let Some(tiff) = Tiff::new(data) else {
+        return false;
+    };
+    data.get(8..10) == Some(b"CR")
+        || tiff
+            .ifd0()
+            .is_some_and(|ifd| ifd.contains_key(&TAG_DNG_VERSION))
+}
+
+/// Find the largest embedded JPEG preview that the `image` crate can decode.
+pub fn embedded_preview(data: &[u8]) -> Option<&[u8]> {
+    if data.starts_with(b"FUJIFILMCCD-RAW") {
+        let offset = u32::from_be_bytes(data.get(84..88)?.try_into().ok()?) as usize;
+        let len = u32::from_be_bytes(data.get(88..92)?.try_into().ok()?) as usize;
+        return data
+            .get(offset..offset.checked_add(len)?)
+            .filter(|jpeg| is_decodable_jpeg(jpeg));
+    }
+
+    let tiff = Tiff::new(data)?;
+    let mut best: Option<&[u8]> = None;
+    for ifd in tiff.ifds() {
+        let mut candidates = Vec::new();
+        if let (Some(offset), Some(len)) = (ifd.get(&TAG_JPEG_OFFSET), ifd.get(&TAG_JPEG_LENGTH)) {
+            candidates.push((tiff.value(offset, 0), tiff.value(len, 0)));
+        }
+        if matches!(tiff.value_of(&ifd, TAG_COMPRESSION), Some(6 | 7)) {
+            if let (Some(offsets), Some(lens)) =
+                (ifd.get(&TAG_STRIP_OFFSETS), ifd.get(&TAG_STRIP_BYTE_COUNTS))
+            {
+                if offsets.count == 1 {
+                    candidates.push((tiff.value(offsets, 0), tiff.value(lens, 0)));
+                }
+            }
+        }
+        for (offset, len) in candidates {
+            let (Some(offset), Some(len)) = (offset, len) else {
+                continue;
+            };
+            let Some(jpeg) =
+                data.get(offset as usize..(offset as usize).saturating_add(len as usize))
+            else {
+                continue;
+            };
+            if is_decodable_jpeg(jpeg) && best.is_none_or(|b| b.len() < jpeg.len()) {
+                best = Some(jpeg);
+            }
+        }
+    }
+    best
+}
+
+/// Decode and demosaic the sensor data.
+pub fn decode(data: &[u8], limits: &Limits) -> Result<RgbaImage> {
+    let tiff = Tiff::new(data).context("not a TIFF-based RAW file")?;
+    let ifds = tiff.ifds();
+    ensure!(
+        ifds.first()
+            .is_some_and(|ifd| ifd.contains_key(&TAG_DNG_VERSION)),
+        "only DNG sensor data is supported"
+    );
+    let root = &ifds[0];
+
+    let raw = ifds
+        .iter()
+        .find(|ifd| {
+            tiff.value_of(ifd, TAG_NEW_SUBFILE_TYPE).unwrap_or(0) == 0
+                && tiff.value_of(ifd, TAG_PHOTOMETRIC) == Some(PHOTOMETRIC_CFA)
+        })
+        .context("no CFA image found")?;
+
+    let get = |tag| {
+        tiff.value_of(raw, tag)
+            .with_context(|| format!("tag {tag:#x} missing"))
+    };
+    let width = get(TAG_WIDTH)? as usize;
+    let height = get(TAG_HEIGHT)? as usize;
+    limits.check(width as u32, height as u32)?;
+    let bits = get(TAG_BITS_PER_SAMPLE)?;
+    ensure!(
+        get(TAG_COMPRESSION)? == 1,
+        "compressed sensor data is not supported"
+    );
+    ensure!(
+        tiff.value_of(raw, TAG_SAMPLES_PER_PIXEL).unwrap_or(1) == 1,
+        "unexpected number of samples per pixel"
+    );
+    ensure!(
+        bits == 8 || bits == 16,
+        "{bits}-bit samples are not supported"
+    );
+    ensure!(
+        raw.get(&TAG_CFA_REPEAT_PATTERN_DIM)
+            .is_some_and(|dim| tiff.value(dim, 0) == Some(2) && tiff.value(dim, 1) == Some(2)),
+        "only 2x2 CFA patterns are supported"
+    );
+    let pattern_entry = raw.get(&TAG_CFA_PATTERN).context("no CFA pattern")?;
+    let mut pattern = [0; 4];
+    for (i, c) in pattern.iter_mut().enumerate() {
+        *c = tiff
+            .value(pattern_entry, i)
+            .context("CFA pattern too short")? as usize;
+        ensure!(*c < 3, "unsupported CFA color");
+    }
+
+    let black = raw
+        .get(&TAG_BLACK_LEVEL)
+        .and_then(|e| tiff.real(e, 0))
+        .unwrap_or(0.0);
+    let white = raw
+        .get(&TAG_WHITE_LEVEL)
+        .and_then(|e| tiff.real(e, 0))
+        .unwrap_or(((1u32 << bits) - 1) as f64);
+    ensure!(white > black, "invalid black/white levels");
+
+    let mut wb = [1.0f32; 3];
+    if let Some(neutral) = root.get(&TAG_AS_SHOT_NEUTRAL) {
+        for (c, wb) in wb.iter_mut().enumerate() {
+            let n = tiff.real(neutral, c).unwrap_or(1.0);
+            if n > 0.0 {
+                *wb = (1.0 / n) as f32;
+            }
+        }
+        let g = wb[1];
+        wb.iter_mut().for_each(|x| *x /= g);
+    }
+    let to_srgb = color_matrix(&tiff, root).and_then(|m| color::camera_to_srgb(&m));
+
+    // Read the samples, normalized to 0..1
+    let offsets = raw.get(&TAG_STRIP_OFFSETS).context("no strips")?;
+    let lens = raw
+        .get(&TAG_STRIP_BYTE_COUNTS)
+        .context("no strip lengths")?;
+    let bytes_per_sample = bits as usize / 8;
+    let mut samples = Vec::with_capacity(width * height);
+    for strip in 0..offsets.count as usize {
+        let offset = tiff.value(offsets, strip).context("bad strip offset")? as usize;
+        let len = tiff.value(lens, strip).context("bad strip length")? as usize;
+        let strip = data
+            .get(offset..offset.saturating_add(len))
+            .context("strip out of bounds")?;
+        for s in strip.chunks_exact(bytes_per_sample) {
+            let v = match s {
+                [v] => *v as u16,
+                [a, b] if tiff.le => u16::from_le_bytes([*a, *b]),
+                [a, b] => u16::from_be_bytes([*a, *b]),
+                _ => unreachable!(),
+            };
+            samples.push(((v as f64 - black) / (white - black)).clamp(0.0, 1.0) as f32);
+        }
+    }
+    ensure!(samples.len() >= width * height, "not enough sensor data");
+
+    let color_at = |x: usize, y: usize| pattern[(y % 2) * 2 + x % 2];
+
+    let mut img = RgbaImage::new(width as u32, height as u32);
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        let (x, y) = (x as usize, y as usize);
+        let mut sum = [0.0f32; 3];
+        let mut cnt = [0u32; 3];
+        for ny in y.saturating_sub(1)..(y + 2).min(height) {
+            for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                let c = color_at(nx, ny);
+                sum[c] += samples[ny * width + nx];
+                cnt[c] += 1;
+            }
+        }
+        let own = color_at(x, y);
+        sum[own] = samples[y * width + x];
+        cnt[own] = 1;
+        let camera: [f32; 3] = std::array::from_fn(|c| match cnt[c] {
+            0 => 0.0,
+            cnt => sum[c] / cnt as f32 * wb[c],
+        });
+        let linear = match &to_srgb {
+            Some(m) => m.map(|row| (0..3).map(|c| row[c] * camera[c]).sum::<f32>()),
+            None => camera,
+        };
+        for c in 0..3 {
+            pixel[c] = (linear_to_srgb(linear[c].clamp(0.0, 1.0)) * 255.0 + 0.5) as u8;
+        }
+        pixel[3] = u8::MAX;
+    }
+
+    Ok(img)
+}
+
+/// The matrix from XYZ to the colors of the camera, the one calibrated for D65 daylight if either
+/// is. Otherwise the second, which is for the cooler of the two illuminants.
+fn color_matrix(tiff: &Tiff, root: &Ifd) -> Option<color::Matrix> {
+    let illuminant = |tag| tiff.value_of(root, tag);
+    let tags = match illuminant(TAG_CALIBRATION_ILLUMINANT_1) == Some(ILLUMINANT_D65)
+        && illuminant(TAG_CALIBRATION_ILLUMINANT_2) != Some(ILLUMINANT_D65)
+    {
+        true => [TAG_COLOR_MATRIX_1, TAG_COLOR_MATRIX_2],
+        false => [TAG_COLOR_MATRIX_2, TAG_COLOR_MATRIX_1],
+    };
+    let entry = tags.iter().find_map(|tag| root.get(tag))?;
+    // A camera with other than three colors has a matrix of a different size
+    if entry.count != 9 {
+        return None;
+    }
+    let mut matrix = [[0.0; 3]; 3];
+    for (i, m) in matrix.iter_mut().flatten().enumerate() {
+        *m = tiff.real(entry, i)? as f32;
+    }
+    Some(matrix)
+}
+
+/// Check that this is a baseline or progressive JPEG. Sensor data is often stored as lossless
+/// JPEG, which we can't decode.
+fn is_decodable_jpeg(data: &[u8]) -> bool {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return false;
+    }
+    let mut i = 2;
+    while let Some(&[0xFF, marker, hi, lo]) = data.get(i..i + 4) {
+        match marker {
+            0xC0..=0xC2 => return true,
+            0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => return false,
+            _ => i += 2 + u16::from_be_bytes([hi, lo]) as usize,
+        }
+    }
+    false
+}
@@ -0,0 +1,84 @@
+//! The images shown last, kept decoded so that going back and forth between them does not
+//! decode them again. See `--memory-cache`.
+//!
+//! What the decoders return is kept, not the buffers the compositor has been given: a buffer is
+//! handed back to the pool once the compositor releases it, and attaching one again which is
+//! still held has no release of its own, so the pool could never reuse it. Taking a new buffer
+//! for an image costs a copy of its pixels, much less than a decode.
+
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+use crate::files::Entry;
+
+/// Which image is kept, so that one whose file has been modified since is decoded again.
+#[derive(PartialEq)]
+pub struct Key {
+    name: String,
+    modified: Option<SystemTime>,
+}
+
+impl Key {
+    pub fn new(entry: &Entry) -> Self {
+        let modified = match entry {
+            Entry::File(path) if path != "-" => {
+                std::fs::metadata(path).and_then(|m| m.modified()).ok()
+            }
+            _ => None,
+        };
+        Self {
+            name: entry.name(),
+            modified,
+        }
+    }
+}
+
+/// Kept values with their size in bytes, of at most `budget` bytes together.
+pub struct Recent<T> {
+    /// The least recently shown first
+    entries: VecDeque<(Key, T, u64)>,
+    bytes: u64,
+    budget: u64,
+}
+
+impl<T> Recent<T> {
+    pub fn new(budget: u64) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            bytes: 0,
+            budget,
+        }
+    }
+
+    pub fn contains(&self, key: &Key) -> bool {
+        self.entries.iter().any(|(k, _, _)| k == key)
+    }
+
+    /// Remove the value of `key`, which is kept again once it is no longer shown.
+    pub fn take(&mut self, key: &Key) -> Option<T> {
+        let i = self.entries.iter().position(|(k, _, _)| k == key)?;
+        let (_, value, bytes) = self.entries.remove(i)?;
+        self.bytes -= bytes;
+        Some(value)
+    }
+
+    /// Keep `value`, forgetting the least recently shown values until they fit into the budget.
+    /// A value larger than the whole budget is not kept.
+    pub fn insert(&mut self, key: Key, value: T, bytes: u64) {
+        if bytes > self.budget {
+            return;
+        }
+        self.take(&key);
+        while self.bytes + bytes > self.budget {
+            let (_, _, oldest) = self.entries.pop_front().unwrap();
+            self.bytes -= oldest;
+        }
+        self.bytes += bytes;
+        self.entries.push_back((key, value, bytes));
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+}
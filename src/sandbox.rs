@@ -0,0 +1,346 @@
+//! Restricting what the process can do once it is connected to the compositor.
+//!
+//! Decoders parse untrusted files, so before any image is decoded we give up everything that is
+//! not needed to show it. Landlock limits the file system to reading the directories of the
+//! images, fonts and cursor themes, and to writing our state directory. A seccomp filter forbids
+//! `exec`, tracing other processes and creating sockets other than Unix sockets, which are still
+//! needed to talk to (and reconnect to) the compositor and the sync group. The directory of the
+//! IPC socket stays writable, which is where snapshots can be saved, and so does that of
+//! `--current-link`.
+//!
+//! The restrictions are inherited by decoder threads and stay in place across reconnections.
+
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{bail, Context, Result};
+
+use crate::cache;
+use crate::current;
+use crate::persist;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+const ACCESS_NET_BIND_TCP: u64 = 1 << 0;
+const ACCESS_NET_CONNECT_TCP: u64 = 1 << 1;
+
+const READ: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+const WRITE: u64 = READ
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_REMOVE_DIR
+    | ACCESS_FS_REMOVE_FILE
+    | ACCESS_FS_MAKE_DIR
+    | ACCESS_FS_MAKE_REG
+    | ACCESS_FS_TRUNCATE;
+const FILE_ACCESS: u64 =
+    ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(target_arch = "riscv64")]
+const AUDIT_ARCH: u32 = 0xc000_00f3;
+
+/// Syscalls with this bit set are x32 syscalls, which would bypass the filter.
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+static ENTERED: AtomicBool = AtomicBool::new(false);
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+    handled_access_net: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Restrict this process for good. Does nothing when called again after reconnecting.
+pub fn enter(
+    image_paths: &[&str],
+    sync_group: bool,
+    ipc_socket: Option<&Path>,
+    disk_cache: bool,
+    current_link: bool,
+) -> Result<()> {
+    if ENTERED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    // SAFETY: PR_SET_NO_NEW_PRIVS takes integer arguments
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error()).context("could not set no_new_privs");
+    }
+    restrict_paths(allowed_paths(
+        image_paths,
+        sync_group,
+        ipc_socket,
+        disk_cache,
+        current_link,
+    ))
+    .context("landlock")?;
+    filter_syscalls().context("seccomp")?;
+
+    ENTERED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Restrict a decoder process, which needs no files at all. It must have set no_new_privs.
+pub fn restrict_decoder() -> Result<()> {
+    restrict_paths(Vec::new()).context("landlock")?;
+    filter_syscalls().context("seccomp")
+}
+
+fn restrict_paths(allowed: Vec<(PathBuf, u64)>) -> Result<()> {
+    // SAFETY: querying the version takes no attribute
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        bail!("not supported by the kernel");
+    }
+
+    let mut handled = (1 << 13) - 1;
+    if abi >= 3 {
+        handled |= ACCESS_FS_TRUNCATE;
+    }
+    if abi >= 5 {
+        handled |= ACCESS_FS_IOCTL_DEV;
+    }
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+        // With no rules for them, TCP sockets can neither bind nor connect
+        handled_access_net: ACCESS_NET_BIND_TCP | ACCESS_NET_CONNECT_TCP,
+    };
+    let attr_size = if abi >= 4 {
+        std::mem::size_of::<RulesetAttr>()
+    } else {
+        std::mem::size_of::<u64>()
+    };
+    // SAFETY: `attr` is valid for reads of `attr_size` bytes
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            attr_size,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("could not create ruleset");
+    }
+    // SAFETY: the ruleset fd was just created and is not owned by anything else
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+    for (path, access) in allowed {
+        // Paths which do not exist are simply not allowed
+        let Ok(file) = File::open(&path) else {
+            continue;
+        };
+        let is_dir = file.metadata().is_ok_and(|m| m.is_dir());
+        let access = if is_dir { access } else { access & FILE_ACCESS };
+        let rule = PathBeneathAttr {
+            allowed_access: access & handled,
+            parent_fd: file.as_raw_fd(),
+        };
+        // SAFETY: `rule` is a valid path beneath attribute
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &rule as *const PathBeneathAttr,
+                0,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("could not allow {}", path.display()));
+        }
+    }
+
+    // SAFETY: the ruleset fd is valid
+    if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } != 0 {
+        return Err(io::Error::last_os_error()).context("could not restrict the process");
+    }
+    Ok(())
+}
+
+fn allowed_paths(
+    image_paths: &[&str],
+    sync_group: bool,
+    ipc_socket: Option<&Path>,
+    disk_cache: bool,
+    current_link: bool,
+) -> Vec<(PathBuf, u64)> {
+    let env_path = |var: &str| std::env::var_os(var).filter(|v| !v.is_empty());
+    let home = env_path("HOME").map(PathBuf::from);
+    let data_home = env_path("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|h| h.join(".local/share")));
+
+    let mut paths = Vec::new();
+
+    // Images are loaded when moving through the list and again after reconnecting, and SVG
+    // files refer to files next to them
+    for image_path in image_paths.iter().filter(|p| **p != "-") {
+        if let Ok(image_path) = std::fs::canonicalize(image_path) {
+            let dir = image_path.parent().unwrap_or(&image_path).to_owned();
+            // Usually all images are in the same directory
+            if !paths.contains(&(dir.clone(), READ)) {
+                paths.push((dir, READ));
+            }
+        }
+    }
+
+    // Fonts, cursor themes and the compositor's name for bug reports
+    for dir in ["/usr", "/etc", "/nix/store", "/run/current-system", "/proc"] {
+        paths.push((PathBuf::from(dir), READ));
+    }
+    if let Some(home) = &home {
+        paths.push((home.join(".fonts"), READ));
+        paths.push((home.join(".icons"), READ));
+    }
+    paths.extend(data_home.map(|dir| (dir, READ)));
+    for var in ["XDG_DATA_DIRS", "XCURSOR_PATH"] {
+        if let Some(dirs) = env_path(var) {
+            paths.extend(std::env::split_paths(&dirs).map(|dir| (dir, READ)));
+        }
+    }
+    // Backtraces read debug information from the executable
+    if let Ok(exe) = std::env::current_exe() {
+        paths.push((exe, READ));
+    }
+
+    // Guides and crash reports
+    if let Some(dir) = persist::state_dir() {
+        let _ = std::fs::create_dir_all(&dir);
+        paths.push((dir, WRITE));
+    }
+    if let Some(dir) = cache::cache_dir().filter(|_| disk_cache) {
+        let _ = std::fs::create_dir_all(&dir);
+        paths.push((dir, WRITE));
+    }
+    if sync_group {
+        if let Some(runtime_dir) = env_path("XDG_RUNTIME_DIR") {
+            let dir = Path::new(&runtime_dir).join("reimv-sync");
+            paths.push((dir, WRITE | ACCESS_FS_MAKE_SOCK));
+        }
+    }
+    // The socket is bound again after reconnecting
+    if let Some(dir) = ipc_socket.and_then(|path| std::path::absolute(path).ok()) {
+        let dir = dir.parent().unwrap_or(&dir).to_owned();
+        paths.push((dir, WRITE | ACCESS_FS_MAKE_SOCK));
+    }
+    if let Some(dir) = current::dir().filter(|_| current_link) {
+        paths.push((dir, WRITE | ACCESS_FS_MAKE_SYM));
+    }
+
+    paths
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn filter_syscalls() -> Result<()> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+    // Offsets into `struct seccomp_data`
+    const NR: u32 = 0;
+    const ARCH: u32 = 4;
+    const ARG0: u32 = 16;
+
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_writev,
+        libc::SYS_io_uring_setup,
+    ];
+
+    let insn = |code: u32, k: u32, jt: u8, jf: u8| libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    };
+    let load = |offset| insn(BPF_LD | BPF_W | BPF_ABS, offset, 0, 0);
+    let ret = |value| insn(BPF_RET | BPF_K, value, 0, 0);
+    let jeq = |k, jt, jf| insn(BPF_JMP | BPF_JEQ | BPF_K, k, jt, jf);
+    let n = DENIED.len() as u8;
+
+    // Everything jumps forward to the last two instructions, which deny or allow the syscall
+    let mut filter = vec![
+        load(ARCH),
+        jeq(AUDIT_ARCH, 1, 0),
+        ret(libc::SECCOMP_RET_KILL_PROCESS),
+        load(NR),
+        insn(BPF_JMP | BPF_JGE | BPF_K, X32_SYSCALL_BIT, n + 3, 0),
+    ];
+    for (i, &nr) in DENIED.iter().enumerate() {
+        filter.push(jeq(nr as u32, n - 1 - i as u8 + 3, 0));
+    }
+    filter.extend([
+        jeq(libc::SYS_socket as u32, 0, 3),
+        load(ARG0),
+        jeq(libc::AF_UNIX as u32, 1, 0),
+        ret(libc::SECCOMP_RET_ERRNO | libc::EACCES as u32),
+        ret(libc::SECCOMP_RET_ALLOW),
+    ]);
+
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    // SAFETY: `prog` points to a valid filter, which the kernel copies
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &prog as *const libc::sock_fprog,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error()).context("could not install the filter");
+    }
+    Ok(())
+}
+
+/// The filter is written for a handful of architectures. Elsewhere only Landlock applies.
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+fn filter_syscalls() -> Result<()> {
+    Ok(())
+}
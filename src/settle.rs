@@ -0,0 +1,99 @@
+//! Waiting for files which are still being written, such as screenshots and exports which are
+//! opened as soon as they appear.
+//!
+//! A file which cannot be decoded is taken to be still being written if it has been modified
+//! just now. Rather than showing the error, or skipping the file, the previous image stays until
+//! the size and modification time of the file have stayed the same for a while, and the file is
+//! tried again. Since the writer may pause halfway, this is done a few times before giving up.
+//! Files which change while they are shown with `--watch` are waited for the same way.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How many times a file is tried again, each time after a longer delay.
+const RETRIES: u32 = 5;
+/// How recently a file which cannot be decoded must have been modified to be waited for.
+const RECENT: Duration = Duration::from_secs(2);
+
+pub struct Settle {
+    path: PathBuf,
+    /// How long the file has to stay the same
+    delay: Duration,
+    /// When to try the file again, unless it has changed since
+    due: Option<Instant>,
+    /// The size and modification time of the file when it was last looked at
+    seen: Option<(u64, SystemTime)>,
+    /// How often the file has been tried again
+    retries: u32,
+}
+
+impl Settle {
+    /// Wait for the file at `path` to stay the same for `delay`.
+    pub fn new(path: &Path, delay: Duration) -> Self {
+        let mut settle = Self {
+            path: path.to_owned(),
+            delay,
+            due: None,
+            seen: None,
+            retries: 0,
+        };
+        settle.changed();
+        settle
+    }
+
+    /// Whether the file at `path`, which could not be decoded, may still be being written.
+    pub fn is_written(path: &Path) -> bool {
+        stat(path)
+            .is_some_and(|(_, modified)| modified.elapsed().is_ok_and(|elapsed| elapsed < RECENT))
+    }
+
+    pub fn is_for(&self, path: &Path) -> bool {
+        self.path == path
+    }
+
+    /// The duration until the file is looked at again, or `None` if it is not waited for.
+    pub fn sleep(&self) -> Option<Duration> {
+        self.due
+            .map(|due| due.saturating_duration_since(Instant::now()))
+    }
+
+    /// Whether the file should be tried now: it has stayed the same since it was last looked at.
+    pub fn due(&mut self) -> bool {
+        if self.due.is_none_or(|due| due > Instant::now()) {
+            return false;
+        }
+        let now = stat(&self.path);
+        // Unless it is gone, and trying it shows why
+        if now.is_some() && self.seen != now {
+            // Still being written
+            self.changed();
+            return false;
+        }
+        self.due = None;
+        true
+    }
+
+    /// Try the file again later, after it still could not be decoded. Returns `false` once
+    /// this has failed too often.
+    pub fn retry(&mut self) -> bool {
+        self.retries += 1;
+        if self.retries > RETRIES {
+            return false;
+        }
+        self.seen = stat(&self.path);
+        self.due = Some(Instant::now() + self.delay * self.retries);
+        true
+    }
+
+    /// The file has changed: wait for it to stay the same again.
+    pub fn changed(&mut self) {
+        self.seen = stat(&self.path);
+        self.due = Some(Instant::now() + self.delay);
+    }
+}
+
+/// The size and modification time of the file at `path`.
+fn stat(path: &Path) -> Option<(u64, SystemTime)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
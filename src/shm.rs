@@ -0,0 +1,390 @@
+//! A shared memory allocator for image and overlay buffers.
+//!
+//! This is a copy of the "free list" allocator from `wayrs-utils`, which does not let us control
+//! how the pool is created. The file backing the pool is a memfd sealed against shrinking, so the
+//! compositor cannot truncate it from under our mapping, and the mapping of a large pool can be
+//! backed by transparent hugepages. Cursors still use the allocator from `wayrs-utils`.
+
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsFd, FromRawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use memmap2::MmapMut;
+use wayrs_client::protocol::*;
+use wayrs_client::Connection;
+use wayrs_utils::shm_alloc::BufferSpec;
+
+use crate::window::Window;
+use crate::{EventCtx, State};
+
+/// Pools smaller than this are never backed by hugepages.
+const HUGEPAGE_THRESHOLD: usize = 32 << 20;
+
+pub struct ShmAlloc {
+    wl_shm: WlShm,
+    hugepages: bool,
+    pool: Option<Pool>,
+    /// Formats the compositor supports besides the two that every compositor has
+    formats: Vec<wl_shm::Format>,
+}
+
+struct Pool {
+    pool: WlShmPool,
+    len: usize,
+    file: File,
+    mmap: MmapMut,
+    segments: Vec<Segment>,
+    /// The serial of the last allocation
+    serial: u64,
+}
+
+struct Segment {
+    offset: usize,
+    len: usize,
+    refcnt: Arc<AtomicU32>,
+    buffer: Option<(WlBuffer, BufferSpec)>,
+    /// The serial of the last allocation of the segment, or 0 if its memory may not hold what
+    /// was written to it then, since it has been split, merged or given another buffer
+    serial: u64,
+}
+
+/// A buffer which must be attached to a surface.
+pub struct Buffer {
+    wl: WlBuffer,
+    refcnt: Arc<AtomicU32>,
+    serial: u64,
+    previous: u64,
+}
+
+impl ShmAlloc {
+    /// `hugepages` advises the kernel to back large pools with transparent hugepages.
+    pub fn new(wl_shm: WlShm, hugepages: bool) -> Self {
+        Self {
+            wl_shm,
+            hugepages,
+            pool: None,
+            formats: Vec::new(),
+        }
+    }
+
+    pub fn supports(&self, format: wl_shm::Format) -> bool {
+        self.formats.contains(&format)
+    }
+
+    /// Allocate a buffer, reusing released ones whenever possible.
+    pub fn alloc_buffer(
+        &mut self,
+        conn: &mut Connection<State>,
+        spec: BufferSpec,
+    ) -> io::Result<(Buffer, &mut [u8])> {
+        if self.pool.is_none() {
+            let pool = Pool::new(conn, self.wl_shm, spec.size(), self.hugepages)?;
+            self.pool = Some(pool);
+        }
+        let hugepages = self.hugepages;
+        self.pool
+            .as_mut()
+            .unwrap()
+            .alloc_buffer(conn, spec, hugepages)
+    }
+}
+
+pub fn wl_shm_cb(ctx: EventCtx<WlShm>) {
+    let wl_shm::Event::Format(format) = ctx.event else {
+        return;
+    };
+    // The same buffers on every compositor, without 16-bit or HDR images
+    if ctx.state.deterministic {
+        return;
+    }
+    let State {
+        backend, shm_alloc, ..
+    } = ctx.state;
+    shm_alloc.formats.push(format);
+    if format == wl_shm::Format::Abgr16161616 && backend.enable_deep_output(ctx.conn, shm_alloc) {
+        Window::frame(ctx.state);
+    }
+}
+
+impl Buffer {
+    #[must_use = "memory is leaked if wl_buffer is not attached"]
+    pub fn into_wl_buffer(self) -> WlBuffer {
+        let wl = self.wl;
+        std::mem::forget(self);
+        wl
+    }
+
+    /// A number which tells this allocation apart from all others.
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    /// The serial of the previous allocation of the same memory, if it still holds what was
+    /// written to it then.
+    pub fn previous(&self) -> Option<u64> {
+        (self.previous != 0).then_some(self.previous)
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        assert!(self.refcnt.fetch_sub(1, Ordering::AcqRel) > 0);
+    }
+}
+
+impl Pool {
+    fn new(
+        conn: &mut Connection<State>,
+        wl_shm: WlShm,
+        len: usize,
+        hugepages: bool,
+    ) -> io::Result<Self> {
+        let file = create_memfd()?;
+        file.set_len(len as u64)?;
+        let mmap = map(&file, len, hugepages)?;
+        let pool = wl_shm.create_pool(conn, file.as_fd().try_clone_to_owned()?, len as i32);
+        Ok(Self {
+            pool,
+            len,
+            file,
+            mmap,
+            segments: vec![Segment {
+                offset: 0,
+                len,
+                refcnt: Arc::new(AtomicU32::new(0)),
+                buffer: None,
+                serial: 0,
+            }],
+            serial: 0,
+        })
+    }
+
+    fn alloc_buffer(
+        &mut self,
+        conn: &mut Connection<State>,
+        spec: BufferSpec,
+        hugepages: bool,
+    ) -> io::Result<(Buffer, &mut [u8])> {
+        let index = self.alloc_segment(conn, spec, hugepages)?;
+        self.serial += 1;
+        let segment = &mut self.segments[index];
+        let previous = std::mem::replace(&mut segment.serial, self.serial);
+
+        let (wl, _) = *segment.buffer.get_or_insert_with(|| {
+            let refcnt = Arc::clone(&segment.refcnt);
+            let wl = self.pool.create_buffer_with_cb(
+                conn,
+                segment.offset as i32,
+                spec.width as i32,
+                spec.height as i32,
+                spec.stride as i32,
+                spec.format,
+                move |_| {
+                    // The buffer is not destroyed, so that it can be reused later
+                    assert!(refcnt.fetch_sub(1, Ordering::AcqRel) > 0);
+                },
+            );
+            (wl, spec)
+        });
+
+        Ok((
+            Buffer {
+                wl,
+                refcnt: Arc::clone(&segment.refcnt),
+                serial: segment.serial,
+                previous,
+            },
+            &mut self.mmap[segment.offset..][..segment.len],
+        ))
+    }
+
+    /// Merge adjacent free segments.
+    fn defragment(&mut self, conn: &mut Connection<State>) {
+        let mut i = 0;
+        while i + 1 < self.segments.len() {
+            // A free segment cannot become used behind our back
+            if self.segments[i].refcnt.load(Ordering::Acquire) != 0
+                || self.segments[i + 1].refcnt.load(Ordering::Acquire) != 0
+            {
+                i += 1;
+                continue;
+            }
+            for segment in &mut self.segments[i..i + 2] {
+                if let Some((wl, _)) = segment.buffer.take() {
+                    wl.destroy(conn);
+                }
+            }
+            self.segments[i].len += self.segments[i + 1].len;
+            self.segments[i].serial = 0;
+            self.segments.remove(i + 1);
+        }
+    }
+
+    /// Grow the pool to at least `new_len` bytes, at least doubling its size.
+    fn resize(
+        &mut self,
+        conn: &mut Connection<State>,
+        new_len: usize,
+        hugepages: bool,
+    ) -> io::Result<()> {
+        if new_len > self.len {
+            self.len = usize::max(self.len * 2, new_len);
+            self.file.set_len(self.len as u64)?;
+            self.pool.resize(conn, self.len as i32);
+            self.mmap = map(&self.file, self.len, hugepages)?;
+        }
+        Ok(())
+    }
+
+    /// Find a free segment of at least `len` bytes without growing the pool.
+    fn try_alloc_in_place(
+        &mut self,
+        conn: &mut Connection<State>,
+        len: usize,
+        spec: BufferSpec,
+    ) -> Option<usize> {
+        fn take_if_free(s: &Segment) -> bool {
+            s.refcnt
+                .compare_exchange(0, 1, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        }
+
+        // A segment of the exact size can keep its buffer if the spec matches
+        if let Some((i, segment)) = self
+            .segments
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, s)| s.len == len)
+            .find(|(_, s)| take_if_free(s))
+        {
+            if segment.buffer.is_some_and(|(_, s)| s != spec) {
+                segment.buffer.take().unwrap().0.destroy(conn);
+                segment.serial = 0;
+            }
+            return Some(i);
+        }
+
+        let (i, segment) = self
+            .segments
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, s)| s.len > len)
+            .find(|(_, s)| take_if_free(s))?;
+        if let Some((wl, _)) = segment.buffer.take() {
+            wl.destroy(conn);
+        }
+        let rest = Segment {
+            offset: segment.offset + len,
+            len: segment.len - len,
+            refcnt: Arc::new(AtomicU32::new(0)),
+            buffer: None,
+            serial: 0,
+        };
+        segment.len = len;
+        segment.serial = 0;
+        self.segments.insert(i + 1, rest);
+        Some(i)
+    }
+
+    /// Returns the index of the allocated segment.
+    fn alloc_segment(
+        &mut self,
+        conn: &mut Connection<State>,
+        spec: BufferSpec,
+        hugepages: bool,
+    ) -> io::Result<usize> {
+        let len = spec.size();
+
+        if let Some(index) = self.try_alloc_in_place(conn, len, spec) {
+            return Ok(index);
+        }
+        self.defragment(conn);
+        if let Some(index) = self.try_alloc_in_place(conn, len, spec) {
+            return Ok(index);
+        }
+
+        // Grow the last segment if it is free, otherwise append a new one
+        let end = match self.segments.last_mut() {
+            Some(segment)
+                if segment
+                    .refcnt
+                    .compare_exchange(0, 1, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok() =>
+            {
+                if let Some((wl, _)) = segment.buffer.take() {
+                    wl.destroy(conn);
+                }
+                segment.len = len;
+                segment.serial = 0;
+                let end = segment.offset + len;
+                self.resize(conn, end, hugepages)?;
+                end
+            }
+            _ => {
+                let offset = self.len;
+                self.resize(conn, offset + len, hugepages)?;
+                self.segments.push(Segment {
+                    offset,
+                    len,
+                    refcnt: Arc::new(AtomicU32::new(1)),
+                    buffer: None,
+                    serial: 0,
+                });
+                offset + len
+            }
+        };
+        let index = self.segments.len() - 1;
+
+        // The pool may have grown more than needed
+        if self.len > end {
+            self.segments.push(Segment {
+                offset: end,
+                len: self.len - end,
+                refcnt: Arc::new(AtomicU32::new(0)),
+                buffer: None,
+                serial: 0,
+            });
+        }
+
+        Ok(index)
+    }
+}
+
+/// Create a memfd which can grow but not shrink.
+fn create_memfd() -> io::Result<File> {
+    let name = c"reimv-shm-pool";
+    // SAFETY: the name is a valid C string
+    let fd =
+        unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the fd was just created and is not owned by anything else
+    let file = unsafe { File::from_raw_fd(fd) };
+    // Sealing further seals prevents the compositor from forbidding growth
+    // SAFETY: F_ADD_SEALS takes an integer argument
+    if unsafe {
+        libc::fcntl(
+            fd,
+            libc::F_ADD_SEALS,
+            libc::F_SEAL_SHRINK | libc::F_SEAL_SEAL,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+fn map(file: &File, len: usize, hugepages: bool) -> io::Result<MmapMut> {
+    // SAFETY: the file cannot shrink, and nobody but the compositor, which only reads from
+    // buffers, has access to it
+    let mmap = unsafe { MmapMut::map_mut(file)? };
+    if hugepages && len >= HUGEPAGE_THRESHOLD {
+        // Only a hint, which fails if the kernel lacks transparent hugepage support
+        let _ = mmap.advise(memmap2::Advice::HugePage);
+    }
+    Ok(mmap)
+}
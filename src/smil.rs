@@ -0,0 +1,486 @@
+//! SMIL animations of SVG documents, which usvg does not support.
+//!
+//! The animation elements are collected once. For every frame we write a copy of the document in
+//! which the animated attributes have their values at that time, and usvg parses that copy like
+//! any other document. Only what simple animations use is supported: `<animate>`, `<set>` and
+//! `<animateTransform>` with offset `begin` and `end` times, `dur`, `repeatCount`, `repeatDur`,
+//! `fill`, `additive`, `values`, `from`, `to`, `by`, `keyTimes` and linear or discrete
+//! interpolation. Attributes with non-numeric values, such as colors, change in steps. CSS
+//! animations are not supported.
+
+use std::ops::Range;
+
+use resvg::usvg::roxmltree;
+
+use crate::limits::Limits;
+
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+const XLINK_NS: &str = "http://www.w3.org/1999/xlink";
+
+pub struct Animation {
+    text: String,
+    targets: Vec<Target>,
+}
+
+/// An element with animated attributes.
+struct Target {
+    /// Where new attributes are inserted, right after the element name
+    insert_at: usize,
+    /// Animated attributes the element already has, which are left out of the copy
+    remove: Vec<Range<usize>>,
+    /// In document order, where later animations override earlier ones
+    animations: Vec<Anim>,
+    /// The value of each animated attribute without animations, in the order of `attributes`
+    base: Vec<Option<String>>,
+    attributes: Vec<String>,
+}
+
+struct Anim {
+    /// Index into [`Target::attributes`]
+    attribute: usize,
+    begin: f32,
+    /// Duration of one iteration
+    dur: f32,
+    /// Duration of all iterations together
+    active: f32,
+    freeze: bool,
+    additive: bool,
+    kind: Kind,
+    values: Vec<String>,
+    key_times: Option<Vec<f32>>,
+    discrete: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Animate,
+    Set,
+    /// `<animateTransform>` with its `type`
+    Transform(&'static str),
+}
+
+impl Animation {
+    /// Returns `None` if the document has no animations.
+    pub fn parse(text: &str, limits: &Limits) -> Option<Self> {
+        let opt = roxmltree::ParsingOptions {
+            allow_dtd: true,
+            nodes_limit: limits.max_svg_nodes,
+        };
+        let doc = roxmltree::Document::parse_with_options(text, opt).ok()?;
+
+        let mut targets: Vec<(roxmltree::NodeId, Target)> = Vec::new();
+        for node in doc.descendants().filter(|n| n.is_element()) {
+            let tag = node.tag_name();
+            if tag.namespace().is_some_and(|ns| ns != SVG_NS) {
+                continue;
+            }
+            let kind = match tag.name() {
+                "animate" => Kind::Animate,
+                "set" => Kind::Set,
+                "animateTransform" => match node.attribute("type").unwrap_or("translate") {
+                    "translate" => Kind::Transform("translate"),
+                    "scale" => Kind::Transform("scale"),
+                    "rotate" => Kind::Transform("rotate"),
+                    "skewX" => Kind::Transform("skewX"),
+                    "skewY" => Kind::Transform("skewY"),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            let Some(target) = target_of(&doc, node) else {
+                continue;
+            };
+            let attribute = match kind {
+                Kind::Transform(_) => "transform",
+                _ => match node.attribute("attributeName") {
+                    Some(name) => name,
+                    None => continue,
+                },
+            };
+
+            let index = match targets.iter().position(|(id, _)| *id == target.id()) {
+                Some(index) => index,
+                None => {
+                    targets.push((target.id(), Target::new(text, target)?));
+                    targets.len() - 1
+                }
+            };
+            let target_info = &mut targets[index].1;
+            let attribute = target_info.attribute_index(text, target, attribute);
+            let base = target_info.base[attribute].as_deref();
+            if let Some(anim) = Anim::parse(node, kind, attribute, base) {
+                target_info.animations.push(anim);
+            }
+        }
+
+        targets.retain(|(_, target)| !target.animations.is_empty());
+        if targets.is_empty() {
+            return None;
+        }
+        Some(Self {
+            text: text.to_owned(),
+            targets: targets.into_iter().map(|(_, target)| target).collect(),
+        })
+    }
+
+    /// The time in seconds after which nothing changes anymore, or `None` if the animation runs
+    /// forever.
+    pub fn end(&self) -> Option<f32> {
+        let end = self
+            .targets
+            .iter()
+            .flat_map(|target| &target.animations)
+            .map(|anim| match (anim.kind, anim.active.is_finite()) {
+                // Setting a value forever changes nothing after the start
+                (Kind::Set, false) => anim.begin,
+                _ => anim.begin + anim.active,
+            })
+            .fold(0.0, f32::max);
+        end.is_finite().then_some(end)
+    }
+
+    /// The document at `t` seconds.
+    pub fn frame(&self, t: f32) -> String {
+        let mut edits: Vec<(Range<usize>, String)> = Vec::new();
+        for target in &self.targets {
+            let mut inserted = String::new();
+            for (attribute, name) in target.attributes.iter().enumerate() {
+                let value = target.value_at(attribute, t);
+                if let Some(value) = value.or_else(|| target.base[attribute].clone()) {
+                    inserted.push_str(&format!(" {name}=\"{}\"", escape(&value)));
+                }
+            }
+            edits.push((target.insert_at..target.insert_at, inserted));
+            edits.extend(
+                target
+                    .remove
+                    .iter()
+                    .map(|range| (range.clone(), String::new())),
+            );
+        }
+        edits.sort_by_key(|(range, _)| range.start);
+
+        let mut out = String::with_capacity(self.text.len());
+        let mut pos = 0;
+        for (range, replacement) in edits {
+            out.push_str(&self.text[pos..range.start]);
+            out.push_str(&replacement);
+            pos = range.end;
+        }
+        out.push_str(&self.text[pos..]);
+        out
+    }
+}
+
+/// The element an animation element applies to: the one it refers to or its parent.
+fn target_of<'a, 'input>(
+    doc: &'a roxmltree::Document<'input>,
+    node: roxmltree::Node<'a, 'input>,
+) -> Option<roxmltree::Node<'a, 'input>> {
+    let href = node
+        .attribute((XLINK_NS, "href"))
+        .or_else(|| node.attribute("href"));
+    match href {
+        Some(href) => {
+            let id = href.strip_prefix('#')?;
+            doc.descendants().find(|n| n.attribute("id") == Some(id))
+        }
+        None => node.parent_element(),
+    }
+}
+
+impl Target {
+    fn new(text: &str, node: roxmltree::Node) -> Option<Self> {
+        let start = node.range().start;
+        // `<` followed by the name, which ends at whitespace, `/` or `>`
+        let name_len =
+            text[start + 1..].find(|c: char| c.is_ascii_whitespace() || c == '/' || c == '>')?;
+        Some(Self {
+            insert_at: start + 1 + name_len,
+            remove: Vec::new(),
+            animations: Vec::new(),
+            base: Vec::new(),
+            attributes: Vec::new(),
+        })
+    }
+
+    fn attribute_index(&mut self, text: &str, node: roxmltree::Node, name: &str) -> usize {
+        if let Some(index) = self.attributes.iter().position(|a| a == name) {
+            return index;
+        }
+        let existing = node
+            .attributes()
+            .find(|a| a.namespace().is_none() && a.name() == name);
+        if let Some(range) = existing.and_then(|a| attribute_range(text, a.position())) {
+            self.remove.push(range);
+        }
+        self.attributes.push(name.to_owned());
+        self.base.push(existing.map(|a| a.value().to_owned()));
+        self.attributes.len() - 1
+    }
+
+    /// The animated value of an attribute, or `None` if no animation of it is in effect.
+    fn value_at(&self, attribute: usize, t: f32) -> Option<String> {
+        let base = self.base[attribute].as_deref();
+        let mut value: Option<String> = None;
+        for anim in self.animations.iter().filter(|a| a.attribute == attribute) {
+            let Some(own) = anim.value_at(t) else {
+                continue;
+            };
+            value = Some(match (anim.additive, anim.kind) {
+                (true, Kind::Transform(_)) => {
+                    let under = value.as_deref().or(base).unwrap_or("");
+                    format!("{under} {own}")
+                }
+                (true, _) => {
+                    let under = value.as_deref().or(base).and_then(number);
+                    match (under, number(&own)) {
+                        (Some(under), Some(own)) => (under + own).to_string(),
+                        _ => own,
+                    }
+                }
+                (false, _) => own,
+            });
+        }
+        value
+    }
+}
+
+/// The byte range of an attribute, from its name to its closing quote.
+fn attribute_range(text: &str, start: usize) -> Option<Range<usize>> {
+    let eq = start + text[start..].find('=')?;
+    let open = eq + 1 + text[eq + 1..].find(['"', '\''])?;
+    let quote = &text[open..open + 1];
+    let close = open + 1 + text[open + 1..].find(quote)?;
+    Some(start..close + 1)
+}
+
+impl Anim {
+    fn parse(
+        node: roxmltree::Node,
+        kind: Kind,
+        attribute: usize,
+        base: Option<&str>,
+    ) -> Option<Self> {
+        // Only offsets, not event or sync base timing
+        let begin = match node.attribute("begin") {
+            Some(begin) => clock_value(begin.split(';').next()?)?,
+            None => 0.0,
+        };
+        let dur = match node.attribute("dur") {
+            Some("indefinite") | None => f32::INFINITY,
+            Some(dur) => clock_value(dur).filter(|&d| d > 0.0)?,
+        };
+        if dur.is_infinite() && kind != Kind::Set {
+            return None;
+        }
+        let repeat_count = match node.attribute("repeatCount") {
+            Some("indefinite") => Some(f32::INFINITY),
+            Some(count) => count.parse().ok().filter(|&c: &f32| c > 0.0),
+            None => None,
+        };
+        let repeat_dur = match node.attribute("repeatDur") {
+            Some("indefinite") => Some(f32::INFINITY),
+            Some(dur) => clock_value(dur),
+            None => None,
+        };
+        let mut active = match (repeat_count, repeat_dur) {
+            (None, None) => dur,
+            (Some(count), None) => dur * count,
+            (None, Some(repeat_dur)) => repeat_dur,
+            (Some(count), Some(repeat_dur)) => (dur * count).min(repeat_dur),
+        };
+        if let Some(end) = node.attribute("end").and_then(clock_value) {
+            active = active.min((end - begin).max(0.0));
+        }
+
+        let attr = |name| node.attribute(name).map(str::trim);
+        let values: Vec<String> = if kind == Kind::Set {
+            vec![attr("to")?.to_owned()]
+        } else if let Some(values) = attr("values") {
+            values
+                .split(';')
+                .map(|v| v.trim().to_owned())
+                .filter(|v| !v.is_empty())
+                .collect()
+        } else {
+            let from = attr("from").map(str::to_owned).or_else(|| match kind {
+                // Transforms are relative to the identity, other attributes to their base value
+                Kind::Transform(_) => None,
+                _ => base.map(str::to_owned),
+            });
+            match (attr("to"), attr("by")) {
+                (Some(to), _) => vec![from.unwrap_or_else(|| identity(kind)), to.to_owned()],
+                (None, Some(by)) => {
+                    let from = from.unwrap_or_else(|| identity(kind));
+                    let to = add(&from, by)?;
+                    vec![from, to]
+                }
+                (None, None) => return None,
+            }
+        };
+        if values.is_empty() {
+            return None;
+        }
+
+        let key_times = match attr("keyTimes") {
+            Some(times) => {
+                let times: Vec<f32> = times
+                    .split(';')
+                    .map(|t| t.trim().parse().ok())
+                    .collect::<Option<_>>()?;
+                (times.len() == values.len()).then_some(times)
+            }
+            None => None,
+        };
+
+        Some(Self {
+            attribute,
+            begin,
+            dur,
+            active,
+            freeze: node.attribute("fill") == Some("freeze"),
+            additive: node.attribute("additive") == Some("sum"),
+            kind,
+            key_times,
+            discrete: kind == Kind::Set
+                || node.attribute("calcMode") == Some("discrete")
+                || !values.iter().all(|v| numbers(v).is_some()),
+            values,
+        })
+    }
+
+    /// The value of this animation at `t`, or `None` if it has no effect then.
+    fn value_at(&self, t: f32) -> Option<String> {
+        let t = t - self.begin;
+        if t < 0.0 {
+            return None;
+        }
+        let progress = if t < self.active {
+            (t / self.dur).fract()
+        } else if self.freeze {
+            // The value at the end of the active duration, which is the end of the last
+            // iteration if it was complete
+            match (self.active / self.dur).fract() {
+                0.0 => 1.0,
+                rest => rest,
+            }
+        } else {
+            return None;
+        };
+
+        let value = if self.kind == Kind::Set {
+            self.values[0].clone()
+        } else {
+            self.sample(progress)
+        };
+        Some(match self.kind {
+            Kind::Transform(kind) => format!("{kind}({value})"),
+            _ => value,
+        })
+    }
+
+    /// The value at `progress` through one iteration, from 0 to 1.
+    fn sample(&self, progress: f32) -> String {
+        let n = self.values.len();
+        if n == 1 {
+            return self.values[0].clone();
+        }
+        let segments = if self.discrete { n } else { n - 1 };
+        let key_time = |i: usize| match &self.key_times {
+            Some(times) => times[i],
+            None => i as f32 / segments as f32,
+        };
+        let i = (0..n).rev().find(|&i| key_time(i) <= progress).unwrap_or(0);
+        if self.discrete || i + 1 >= n {
+            return self.values[i].clone();
+        }
+        let span = key_time(i + 1) - key_time(i);
+        let local = if span > 0.0 {
+            (progress - key_time(i)) / span
+        } else {
+            1.0
+        };
+        interpolate(&self.values[i], &self.values[i + 1], local)
+    }
+}
+
+/// The value which changes nothing, for `by` animations without `from`.
+fn identity(kind: Kind) -> String {
+    match kind {
+        Kind::Transform("scale") => "1".to_owned(),
+        _ => "0".to_owned(),
+    }
+}
+
+/// Interpolate two lists of numbers. Units of the first value are kept.
+fn interpolate(from: &str, to: &str, t: f32) -> String {
+    let (Some(a), Some(b)) = (numbers(from), numbers(to)) else {
+        return from.to_owned();
+    };
+    let unit = unit(from);
+    let values: Vec<String> = (0..a.len().max(b.len()))
+        .map(|i| {
+            let a = a.get(i).or(a.last()).copied().unwrap_or(0.0);
+            let b = b.get(i).or(b.last()).copied().unwrap_or(0.0);
+            format!("{}{unit}", a + (b - a) * t)
+        })
+        .collect();
+    values.join(" ")
+}
+
+fn add(a: &str, b: &str) -> Option<String> {
+    let (a, b) = (numbers(a)?, numbers(b)?);
+    let sum: Vec<String> = (0..a.len().max(b.len()))
+        .map(|i| (a.get(i).unwrap_or(&0.0) + b.get(i).unwrap_or(&0.0)).to_string())
+        .collect();
+    Some(sum.join(" "))
+}
+
+/// The numbers of a value such as `10`, `10px`, `0.5` or `45 50 50`, or `None` if there are
+/// other things in it.
+fn numbers(value: &str) -> Option<Vec<f32>> {
+    let unit = unit(value);
+    value
+        .split(|c: char| c.is_ascii_whitespace() || c == ',')
+        .filter(|part| !part.is_empty())
+        .map(|part| part.strip_suffix(unit).unwrap_or(part).parse().ok())
+        .collect::<Option<Vec<f32>>>()
+        .filter(|numbers| !numbers.is_empty())
+}
+
+fn number(value: &str) -> Option<f32> {
+    numbers(value).and_then(|numbers| numbers.first().copied())
+}
+
+/// A length unit after a single number, which interpolation keeps.
+fn unit(value: &str) -> &'static str {
+    ["px", "%", "em", "deg"]
+        .into_iter()
+        .find(|unit| value.trim_end().ends_with(unit))
+        .unwrap_or("")
+}
+
+/// Parse a clock value such as `2s`, `500ms`, `1.5min`, `00:01.5` or just `2`, in seconds.
+fn clock_value(value: &str) -> Option<f32> {
+    let value = value.trim();
+    if value.contains(':') {
+        return value.split(':').try_fold(0.0, |total, part| {
+            Some(total * 60.0 + part.parse::<f32>().ok()?)
+        });
+    }
+    let units = [("ms", 0.001), ("min", 60.0), ("h", 3600.0), ("s", 1.0)];
+    let (number, scale) = units
+        .into_iter()
+        .find_map(|(unit, scale)| Some((value.strip_suffix(unit)?, scale)))
+        .unwrap_or((value, 1.0));
+    let seconds = number.trim().parse::<f32>().ok()? * scale;
+    seconds.is_finite().then_some(seconds)
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+}
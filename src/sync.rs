@@ -0,0 +1,142 @@
+use std::io::{self, ErrorKind};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+
+use crate::image::ImageTransform;
+
+/// A group of reimv instances that mirror each other's view, and move through their file lists
+/// together.
+///
+/// Every member binds a datagram socket in a per-group directory under `$XDG_RUNTIME_DIR`.
+/// Broadcasting is simply sending a datagram to every other socket in that directory.
+pub struct SyncGroup {
+    socket: UnixDatagram,
+    dir: PathBuf,
+    path: PathBuf,
+    last_sent: Option<ImageTransform>,
+}
+
+impl SyncGroup {
+    pub fn join(name: &str) -> Result<Self> {
+        if name.is_empty() || name.contains('/') {
+            bail!("invalid sync group name {name:?}");
+        }
+
+        let runtime_dir =
+            std::env::var_os("XDG_RUNTIME_DIR").context("XDG_RUNTIME_DIR is not set")?;
+        let dir = PathBuf::from(runtime_dir).join("reimv-sync").join(name);
+        std::fs::create_dir_all(&dir).context("could not create sync group directory")?;
+
+        let path = dir.join(format!("{}.sock", std::process::id()));
+        // A socket left behind by a crashed process which happened to have the same pid
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).context("could not bind sync socket")?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket,
+            dir,
+            path,
+            last_sent: None,
+        })
+    }
+
+    /// Send the transform to other members of the group, unless it has not changed since the last
+    /// broadcast or since it was received.
+    pub fn broadcast(&mut self, transform: &ImageTransform) {
+        if self.last_sent.as_ref() == Some(transform) {
+            return;
+        }
+        self.last_sent = Some(*transform);
+
+        self.send(&format!(
+            "transform {} {} {}",
+            transform.x, transform.y, transform.scale
+        ));
+    }
+
+    /// Tell the other members of the group that the image at `index` of the file list is shown
+    /// now, after moving through the list.
+    pub fn broadcast_index(&self, index: usize) {
+        self.send(&format!("show {index}"));
+    }
+
+    fn send(&self, msg: &str) {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let peer = entry.path();
+            if peer == self.path {
+                continue;
+            }
+            match self.socket.send_to(msg.as_bytes(), &peer) {
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                    // Nobody is listening, the owner must have died
+                    let _ = std::fs::remove_file(&peer);
+                }
+                Err(_) => (),
+            }
+        }
+    }
+
+    /// Receive all pending messages.
+    pub fn recv(&mut self) -> io::Result<Received> {
+        let mut buf = [0; 256];
+        let mut received = Received::default();
+        loop {
+            let len = match self.socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            };
+            let Ok(msg) = std::str::from_utf8(&buf[..len]) else {
+                continue;
+            };
+            if let Some(transform) = parse_transform(msg) {
+                received.transform = Some(transform);
+            }
+            if let Some(index) = msg.strip_prefix("show ").and_then(|i| i.parse().ok()) {
+                received.index = Some(index);
+            }
+        }
+        if received.transform.is_some() {
+            self.last_sent = received.transform;
+        }
+        Ok(received)
+    }
+}
+
+/// The most recent messages of each kind from the other members.
+#[derive(Default)]
+pub struct Received {
+    pub transform: Option<ImageTransform>,
+    /// Of the image shown in the file list
+    pub index: Option<usize>,
+}
+
+fn parse_transform(msg: &str) -> Option<ImageTransform> {
+    let mut parts = msg.strip_prefix("transform ")?.split(' ');
+    let transform = ImageTransform {
+        x: parts.next()?.parse().ok()?,
+        y: parts.next()?.parse().ok()?,
+        scale: parts.next()?.parse().ok()?,
+    };
+    parts.next().is_none().then_some(transform)
+}
+
+impl AsRawFd for SyncGroup {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl Drop for SyncGroup {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,158 @@
+//! Templates for the window title and the on-screen display, such as `{name} {date}`.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error, Result};
+
+use reimv::metadata::Exif;
+
+/// A text with `{variable}` placeholders. `{{` and `}}` stand for literal braces.
+#[derive(Debug, Clone)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Var(Var),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Var {
+    Name,
+    Page,
+    Index,
+    Count,
+    Size,
+    Date,
+    Camera,
+    Gps,
+    Marked,
+}
+
+const VARS: &[(&str, Var)] = &[
+    ("name", Var::Name),
+    ("page", Var::Page),
+    ("index", Var::Index),
+    ("count", Var::Count),
+    ("size", Var::Size),
+    ("date", Var::Date),
+    ("camera", Var::Camera),
+    ("gps", Var::Gps),
+    ("marked", Var::Marked),
+];
+
+/// What the variables stand for.
+pub struct Info<'a> {
+    pub name: String,
+    /// Which page is shown, if this is a multi-page image
+    pub page: Option<String>,
+    /// The position in the file list, counting from 1, and its length
+    pub position: (usize, usize),
+    /// The size of the file in bytes, if an image is shown
+    pub size: Option<u64>,
+    pub exif: &'a Exif,
+    /// Whether the image has been marked with `m`
+    pub marked: bool,
+}
+
+impl FromStr for Template {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = text;
+        while let Some(i) = rest.find(['{', '}']) {
+            literal.push_str(&rest[..i]);
+            let (brace, after) = rest[i..].split_at(1);
+            if let Some(after) = after.strip_prefix(brace) {
+                literal.push_str(brace);
+                rest = after;
+                continue;
+            }
+            if brace == "}" {
+                bail!("unmatched `}}`, write `}}}}` for a literal brace");
+            }
+            let (name, after) = after
+                .split_once('}')
+                .context("unclosed `{`, write `{{` for a literal brace")?;
+            let Some(&(_, var)) = VARS.iter().find(|(n, _)| *n == name) else {
+                let known: Vec<&str> = VARS.iter().map(|(n, _)| *n).collect();
+                bail!(
+                    "unknown variable `{name}`, expected one of {}",
+                    known.join(", ")
+                );
+            };
+            if !literal.is_empty() {
+                parts.push(Part::Text(std::mem::take(&mut literal)));
+            }
+            parts.push(Part::Var(var));
+            rest = after;
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Text(literal));
+        }
+        Ok(Self { parts })
+    }
+}
+
+impl Template {
+    /// Fill in the variables. Unknown values, such as the date of a photo without EXIF data,
+    /// are left empty.
+    pub fn expand(&self, info: &Info) -> String {
+        let mut text = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(literal) => text.push_str(literal),
+                Part::Var(Var::Name) => text.push_str(&info.name),
+                Part::Var(Var::Page) => text.push_str(info.page.as_deref().unwrap_or_default()),
+                Part::Var(Var::Index) => text.push_str(&info.position.0.to_string()),
+                Part::Var(Var::Count) => text.push_str(&info.position.1.to_string()),
+                Part::Var(Var::Size) => {
+                    if let Some(size) = info.size {
+                        text.push_str(&format_size(size));
+                    }
+                }
+                Part::Var(Var::Date) => {
+                    text.push_str(info.exif.date.as_deref().unwrap_or_default())
+                }
+                Part::Var(Var::Camera) => {
+                    text.push_str(info.exif.camera.as_deref().unwrap_or_default())
+                }
+                Part::Var(Var::Gps) => {
+                    if info.exif.gps {
+                        text.push_str("GPS");
+                    }
+                }
+                Part::Var(Var::Marked) => {
+                    if info.marked {
+                        text.push_str("marked");
+                    }
+                }
+            }
+        }
+        text
+    }
+}
+
+/// Format a size in bytes with binary units, e.g. `2.4 MiB`.
+pub fn format_size(size: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if size < 1024 {
+        return format!("{size} B");
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if value < 10.0 {
+        format!("{value:.1} {}", UNITS[unit])
+    } else {
+        format!("{value:.0} {}", UNITS[unit])
+    }
+}
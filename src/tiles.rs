@@ -0,0 +1,256 @@
+//! Images too large to decode at full resolution, read tile by tile.
+//!
+//! The whole image is shown at a reduced resolution, like with `--downscale-above`. Once it is
+//! zoomed in further, the part in the window is read from the file at about the resolution it is
+//! shown at, in squares of [`TILE_SIZE`] pixels, each a sub-surface above the reduced image. Tiles
+//! which leave the window are dropped, so the memory needed depends on the window and not on the
+//! image. Only raw Netpbm files can be read in parts, straight from the memory mapped file.
+
+use std::fs::File;
+use std::path::Path;
+
+use wayrs_client::protocol::*;
+use wayrs_client::Connection;
+use wayrs_protocols::viewporter::*;
+use wayrs_utils::shm_alloc::BufferSpec;
+
+use anyhow::{Context, Result};
+use image::RgbaImage;
+use memmap2::Mmap;
+
+use crate::backend::{Region, View};
+use crate::convert;
+use crate::globals::Globals;
+use crate::limits::Limits;
+use crate::pnm::Regions;
+use crate::shm::ShmAlloc;
+use crate::State;
+
+/// The width and height of a tile, in buffer pixels.
+const TILE_SIZE: u32 = 512;
+
+/// What the surfaces of tiles are created with.
+#[derive(Clone, Copy)]
+pub struct Layer {
+    compositor: WlCompositor,
+    subcompositor: WlSubcompositor,
+    viewporter: WpViewporter,
+    /// The window's surface
+    parent: WlSurface,
+    /// The surface of the reduced image, which the tiles are placed above
+    below: WlSurface,
+}
+
+pub struct Tiles {
+    source: Regions<Mmap>,
+    /// How many pixels of the file one pixel of the reduced image stands for, in each direction
+    step: u32,
+    tiles: Vec<Tile>,
+}
+
+struct Tile {
+    /// Only every `level`th pixel of every `level`th row of the file is shown
+    level: u32,
+    /// The position and size in the file, in its pixels
+    region: Region,
+    surface: WlSurface,
+    subsurface: WlSubsurface,
+    viewport: WpViewport,
+}
+
+impl Layer {
+    pub fn new(globals: &Globals, parent: WlSurface, below: WlSurface) -> Self {
+        Self {
+            compositor: globals.wl_compositor,
+            subcompositor: globals.wl_subcompositor,
+            viewporter: globals.wp_viewporter,
+            parent,
+            below,
+        }
+    }
+}
+
+impl Tiles {
+    /// Open `path` tile by tile if it is a raw Netpbm file with more than `max_pixels` pixels.
+    /// Returns the image with the whole file at a reduced resolution, to show when it is not
+    /// zoomed in, and the size of the file.
+    pub fn open(
+        path: &Path,
+        max_pixels: u64,
+        limits: &Limits,
+    ) -> Result<Option<(Self, RgbaImage, u64)>> {
+        let file = File::open(path).context("could not read file")?;
+        // SAFETY: the file is only read. If it is truncated while it is shown, reading the
+        // missing part kills the process with SIGBUS, as for any program which maps files.
+        let mmap = unsafe { Mmap::map(&file) }.context("could not read file")?;
+        let file_size = mmap.len() as u64;
+        let Some(source) = Regions::new(mmap) else {
+            return Ok(None);
+        };
+        let (width, height) = source.dimensions();
+        let pixels = width as u64 * height as u64;
+        if pixels <= max_pixels {
+            return Ok(None);
+        }
+        let step = (pixels as f64 / max_pixels as f64).sqrt().ceil() as u32;
+        limits.check(width.div_ceil(step), height.div_ceil(step))?;
+        let reduced = source.read((0, 0), (width, height), step);
+        let tiles = Self {
+            source,
+            step,
+            tiles: Vec::new(),
+        };
+        Ok(Some((tiles, reduced, file_size)))
+    }
+
+    pub fn surfaces(&self) -> impl Iterator<Item = WlSurface> + '_ {
+        self.tiles.iter().map(|tile| tile.surface)
+    }
+
+    /// Show the tiles in the window for `view`, which is the view of the reduced image.
+    /// Nothing is shown unless it is zoomed in beyond the reduced resolution.
+    pub fn render(
+        &mut self,
+        conn: &mut Connection<State>,
+        shm: &mut ShmAlloc,
+        layer: &Layer,
+        view: &View,
+    ) {
+        let size = self.source.dimensions();
+        let view = view.of_larger(self.step);
+        let visible = view.visible(size);
+        let Some(visible) = visible.filter(|_| view.density() * self.step as f32 > 1.0) else {
+            self.clear(conn);
+            return;
+        };
+
+        let level = view.level(self.step);
+        let wanted: Vec<Region> = visible.tiles(size, TILE_SIZE * level).collect();
+
+        self.tiles.retain(|tile| {
+            let keep = tile.level == level && wanted.contains(&tile.region);
+            if !keep {
+                tile.destroy(conn);
+            }
+            keep
+        });
+        for region in wanted {
+            if self.tiles.iter().all(|tile| tile.region != region) {
+                let pixels =
+                    self.source
+                        .read((region.x, region.y), (region.width, region.height), level);
+                self.tiles
+                    .push(Tile::new(conn, shm, layer, region, level, pixels));
+            }
+        }
+
+        for tile in &self.tiles {
+            tile.place(conn, &view);
+        }
+    }
+
+    /// Destroy all tiles, before the image is replaced or while it is not zoomed in.
+    pub fn clear(&mut self, conn: &mut Connection<State>) {
+        for tile in self.tiles.drain(..) {
+            tile.destroy(conn);
+        }
+    }
+}
+
+impl Tile {
+    fn new(
+        conn: &mut Connection<State>,
+        shm: &mut ShmAlloc,
+        layer: &Layer,
+        region: Region,
+        level: u32,
+        pixels: RgbaImage,
+    ) -> Self {
+        let surface = layer.compositor.create_surface(conn);
+        let subsurface = layer
+            .subcompositor
+            .get_subsurface(conn, surface, layer.parent);
+        subsurface.place_above(conn, layer.below);
+        let viewport = layer.viewporter.get_viewport(conn, surface);
+
+        let empty_reg = layer.compositor.create_region(conn);
+        surface.set_input_region(conn, Some(empty_reg));
+        empty_reg.destroy(conn);
+
+        let (buffer, canvas) = shm
+            .alloc_buffer(
+                conn,
+                BufferSpec {
+                    width: pixels.width(),
+                    height: pixels.height(),
+                    stride: pixels.width() * 4,
+                    format: wl_shm::Format::Abgr8888,
+                },
+            )
+            .unwrap();
+        canvas.copy_from_slice(pixels.as_raw());
+        convert::premultiply(canvas);
+        surface.attach(conn, Some(buffer.into_wl_buffer()), 0, 0);
+        surface.damage(conn, 0, 0, i32::MAX, i32::MAX);
+
+        Self {
+            level,
+            region,
+            surface,
+            subsurface,
+            viewport,
+        }
+    }
+
+    /// Position the tile, cut at the edges of the window. The edges are rounded the same way
+    /// for all tiles, so that neighbours meet without gaps.
+    fn place(&self, conn: &mut Connection<State>, view: &View) {
+        let Region {
+            x,
+            y,
+            width,
+            height,
+        } = self.region;
+        let (window, scale) = (view.window, view.transform.scale);
+        let left = view.transform.x + x as f32 * scale;
+        let top = view.transform.y + y as f32 * scale;
+        let right = left + width as f32 * scale;
+        let bottom = top + height as f32 * scale;
+        let (x0, x1) = (left.max(0.0).round(), right.min(window.0).round());
+        let (y0, y1) = (top.max(0.0).round(), bottom.min(window.1).round());
+        if x1 - x0 < 1.0 || y1 - y0 < 1.0 {
+            // HACK
+            self.subsurface.set_position(conn, 0, 0);
+            self.viewport.set_destination(conn, 1, 1);
+            return;
+        }
+        // Surface local coordinates per buffer pixel
+        let buffer_scale = scale * self.level as f32;
+        let (buffer_width, buffer_height) = (
+            width.div_ceil(self.level) as f32,
+            height.div_ceil(self.level) as f32,
+        );
+        let src_x = ((x0 - left) / buffer_scale).clamp(0.0, buffer_width - 1.0);
+        let src_y = ((y0 - top) / buffer_scale).clamp(0.0, buffer_height - 1.0);
+        self.subsurface.set_position(conn, x0 as i32, y0 as i32);
+        self.viewport
+            .set_destination(conn, (x1 - x0) as i32, (y1 - y0) as i32);
+        self.viewport.set_source(
+            conn,
+            src_x.into(),
+            src_y.into(),
+            ((x1 - x0) / buffer_scale)
+                .clamp(1.0, buffer_width - src_x)
+                .into(),
+            ((y1 - y0) / buffer_scale)
+                .clamp(1.0, buffer_height - src_y)
+                .into(),
+        );
+    }
+
+    fn destroy(&self, conn: &mut Connection<State>) {
+        self.viewport.destroy(conn);
+        self.subsurface.destroy(conn);
+        self.surface.destroy(conn);
+    }
+}
@@ -0,0 +1,432 @@
+//! Printing the messages between reimv and the compositor, see `--protocol-trace`.
+//!
+//! reimv connects to a socket of its own instead of the compositor's, and a thread passes the
+//! bytes and file descriptors on both ways. It follows which object is of which interface, from
+//! the new objects in the messages, so that each message is printed as a line of JSON with its
+//! arguments and the time since reimv started. Unlike `WAYLAND_DEBUG`, this leaves out the
+//! programs which reimv runs, and can be narrowed down to some interfaces.
+//!
+//! The thread is started before the sandbox is entered, so Landlock does not restrict it, but it
+//! only ever connects to the compositor and writes to stderr.
+
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use wayrs_client::interface::{Interface, MessageDesc};
+use wayrs_client::protocol::*;
+use wayrs_client::proxy::Proxy;
+use wayrs_client::wire::ArgType;
+
+use reimv::json::Value;
+
+use crate::current;
+use crate::globals;
+
+/// The most file descriptors which libwayland sends with one message.
+const MAX_FDS: usize = 28;
+
+/// The interface of object 1, which wayrs-client keeps to itself.
+static WL_DISPLAY: Interface = Interface {
+    name: c"wl_display",
+    version: 1,
+    events: &[
+        MessageDesc {
+            name: "error",
+            is_destructor: false,
+            signature: &[ArgType::Object, ArgType::Uint, ArgType::String],
+        },
+        MessageDesc {
+            name: "delete_id",
+            is_destructor: false,
+            signature: &[ArgType::Uint],
+        },
+    ],
+    requests: &[
+        MessageDesc {
+            name: "sync",
+            is_destructor: false,
+            signature: &[ArgType::NewId(WlCallback::INTERFACE)],
+        },
+        MessageDesc {
+            name: "get_registry",
+            is_destructor: false,
+            signature: &[ArgType::NewId(WlRegistry::INTERFACE)],
+        },
+    ],
+};
+
+pub struct Trace {
+    /// The socket which reimv connects to
+    path: PathBuf,
+}
+
+impl Trace {
+    /// Start passing messages on to the compositor of `$WAYLAND_DISPLAY`, which is pointed to our
+    /// socket. Only messages on objects of `interfaces` are printed, or all if it is empty.
+    pub fn start(interfaces: Vec<String>) -> Result<Self> {
+        let runtime_dir =
+            std::env::var_os("XDG_RUNTIME_DIR").context("XDG_RUNTIME_DIR is not set")?;
+        let display = std::env::var_os("WAYLAND_DISPLAY").context("WAYLAND_DISPLAY is not set")?;
+        // Like a name in $XDG_RUNTIME_DIR or an absolute path
+        let compositor = Path::new(&runtime_dir).join(display);
+
+        let dir = current::dir().context("XDG_RUNTIME_DIR is not set")?;
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("could not create {}", dir.display()))?;
+        let path = dir.join(format!("trace.{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("could not bind {}", path.display()))?;
+        std::env::set_var("WAYLAND_DISPLAY", &path);
+
+        let started = Instant::now();
+        std::thread::spawn(move || {
+            // A new connection after the compositor has restarted
+            for client in listener.incoming() {
+                let result = client.and_then(|client| {
+                    let server = UnixStream::connect(&compositor)?;
+                    relay(&client, &server, &interfaces, started)
+                });
+                if let Err(e) = result {
+                    eprintln!("reimv: protocol trace: {e}");
+                }
+            }
+        });
+        Ok(Self { path })
+    }
+}
+
+impl Drop for Trace {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Pass messages between reimv and the compositor until either closes the connection.
+fn relay(
+    client: &UnixStream,
+    server: &UnixStream,
+    interfaces: &[String],
+    started: Instant,
+) -> io::Result<()> {
+    let mut objects = Objects::new();
+    let mut requests = Vec::new();
+    let mut events = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let mut fds = [client, server].map(|socket| libc::pollfd {
+            fd: socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        // SAFETY: the pointer and the length are those of `fds`
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, -1) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        for (i, fd) in fds.iter().enumerate() {
+            if fd.revents == 0 {
+                continue;
+            }
+            let (from, to, pending, is_event) = match i {
+                0 => (client, server, &mut requests, false),
+                _ => (server, client, &mut events, true),
+            };
+            let (len, passed) = recv(from, &mut buf)?;
+            if len == 0 {
+                return Ok(());
+            }
+            send(to, &buf[..len], &passed)?;
+            pending.extend_from_slice(&buf[..len]);
+            while let Some(size) = message_size(pending) {
+                let message = objects.decode(&pending[..size], is_event);
+                if interfaces.is_empty() || interfaces.contains(&message.interface) {
+                    let time = (started.elapsed().as_secs_f64() * 1e6).round() / 1e6;
+                    eprintln!("{}", message.to_json(time, is_event));
+                }
+                pending.drain(..size);
+            }
+        }
+    }
+}
+
+/// The size of the first message in `bytes`, if all of it is there.
+fn message_size(bytes: &[u8]) -> Option<usize> {
+    // A message is at least its header
+    let size = ((word(bytes, 4)? >> 16) as usize).max(8);
+    (bytes.len() >= size).then_some(size)
+}
+
+fn word(bytes: &[u8], at: usize) -> Option<u32> {
+    let word = bytes.get(at..at + 4)?;
+    Some(u32::from_ne_bytes(word.try_into().unwrap()))
+}
+
+/// The interfaces of the objects of a connection, by ID.
+struct Objects(HashMap<u32, Object>);
+
+struct Object {
+    name: String,
+    /// Missing for globals which reimv does not know, whose messages cannot be decoded
+    interface: Option<&'static Interface>,
+}
+
+struct Message {
+    object: String,
+    interface: String,
+    name: String,
+    args: Vec<Value>,
+}
+
+impl Objects {
+    fn new() -> Self {
+        Self(HashMap::from([(1, Object::new(&WL_DISPLAY))]))
+    }
+
+    fn name(&self, id: u32) -> Value {
+        match (id, self.0.get(&id)) {
+            (0, _) => Value::Null,
+            (_, Some(object)) => Value::String(format!("{}@{id}", object.name)),
+            (_, None) => Value::String(format!("unknown@{id}")),
+        }
+    }
+
+    /// Decode a whole message, and note the objects it creates and deletes.
+    fn decode(&mut self, message: &[u8], is_event: bool) -> Message {
+        let id = word(message, 0).unwrap();
+        let opcode = word(message, 4).unwrap() as u16 as usize;
+        let object = self.0.get(&id);
+        let interface = object.map_or("unknown".to_owned(), |o| o.name.clone());
+        let desc = object
+            .and_then(|o| o.interface)
+            .and_then(|i| match is_event {
+                true => i.events.get(opcode),
+                false => i.requests.get(opcode),
+            });
+        let Some(desc) = desc else {
+            return Message {
+                object: format!("{interface}@{id}"),
+                interface,
+                name: format!("opcode {opcode}"),
+                args: Vec::new(),
+            };
+        };
+
+        let mut args = Vec::new();
+        let mut at = 8;
+        for arg in desc.signature {
+            let Some(value) = self.decode_arg(message, &mut at, arg) else {
+                // Shorter than its signature, which the compositor will complain about
+                break;
+            };
+            args.push(value);
+        }
+        // The IDs of deleted objects may be used again
+        if id == 1 && is_event && desc.name == "delete_id" {
+            if let Some(deleted) = word(message, 8) {
+                self.0.remove(&deleted);
+            }
+        }
+        Message {
+            object: format!("{interface}@{id}"),
+            interface,
+            name: desc.name.to_owned(),
+            args,
+        }
+    }
+
+    fn decode_arg(&mut self, message: &[u8], at: &mut usize, arg: &ArgType) -> Option<Value> {
+        let next = |at: &mut usize| {
+            let word = word(message, *at);
+            *at += 4;
+            word
+        };
+        Some(match arg {
+            ArgType::Int => Value::Number(next(at)? as i32 as f64),
+            ArgType::Uint => Value::Number(next(at)? as f64),
+            ArgType::Fixed => Value::Number(next(at)? as i32 as f64 / 256.0),
+            ArgType::Object | ArgType::OptObject => self.name(next(at)?),
+            ArgType::NewId(interface) => {
+                let id = next(at)?;
+                self.0.insert(id, Object::new(interface));
+                self.name(id)
+            }
+            ArgType::AnyNewId => {
+                // Like `wl_registry.bind`
+                let name = string(message, at)?;
+                let _version = next(at)?;
+                let id = next(at)?;
+                let name = name.unwrap_or_default();
+                let interface = globals::interfaces()
+                    .into_iter()
+                    .find(|i| i.name.to_bytes() == name.as_bytes());
+                self.0.insert(id, Object { name, interface });
+                self.name(id)
+            }
+            ArgType::String | ArgType::OptString => match string(message, at)? {
+                Some(string) => Value::String(string),
+                None => Value::Null,
+            },
+            ArgType::Array => {
+                let bytes = array(message, at)?;
+                // Arrays hold 32-bit values, like the keys of `wl_keyboard.enter`
+                let values = bytes.chunks_exact(4).map(|w| word(w, 0).unwrap() as f64);
+                Value::Array(values.map(Value::Number).collect())
+            }
+            // Passed beside the bytes
+            ArgType::Fd => Value::String("fd".to_owned()),
+        })
+    }
+}
+
+impl Object {
+    fn new(interface: &'static Interface) -> Self {
+        Self {
+            name: interface.name.to_string_lossy().into_owned(),
+            interface: Some(interface),
+        }
+    }
+}
+
+impl Message {
+    fn to_json(&self, time: f64, is_event: bool) -> Value {
+        let direction = match is_event {
+            true => "event",
+            false => "request",
+        };
+        Value::object([
+            ("time", Value::Number(time)),
+            ("direction", Value::String(direction.to_owned())),
+            ("object", Value::String(self.object.clone())),
+            ("message", Value::String(self.name.clone())),
+            ("args", Value::Array(self.args.clone())),
+        ])
+    }
+}
+
+/// A string argument, which is `None` if it is null.
+fn string(message: &[u8], at: &mut usize) -> Option<Option<String>> {
+    let bytes = array(message, at)?;
+    // Without the terminating NUL
+    let string = bytes
+        .split_last()
+        .map(|(_, s)| String::from_utf8_lossy(s).into_owned());
+    Some(string)
+}
+
+/// The bytes of an array argument, which is padded to 32 bits.
+fn array<'a>(message: &'a [u8], at: &mut usize) -> Option<&'a [u8]> {
+    let len = word(message, *at)? as usize;
+    let bytes = message.get(*at + 4..*at + 4 + len)?;
+    *at += 4 + len.next_multiple_of(4);
+    Some(bytes)
+}
+
+/// Receive bytes, and the file descriptors which come with them.
+fn recv(socket: &UnixStream, buf: &mut [u8]) -> io::Result<(usize, Vec<OwnedFd>)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut cmsg = [0u64; cmsg_words()];
+    // SAFETY: all zeros is a valid msghdr
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(&cmsg) as _;
+    let len = loop {
+        // SAFETY: `msg` points to `buf` and `cmsg`, which outlive the call
+        let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if len >= 0 {
+            break len as usize;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    };
+
+    let mut fds = Vec::new();
+    // SAFETY: the control messages are those the kernel has written into `cmsg`
+    unsafe {
+        let mut header = libc::CMSG_FIRSTHDR(&msg);
+        while !header.is_null() {
+            if (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(header).cast::<RawFd>();
+                let count = ((*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / mem::size_of::<RawFd>();
+                for i in 0..count {
+                    fds.push(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            header = libc::CMSG_NXTHDR(&msg, header);
+        }
+    }
+    Ok((len, fds))
+}
+
+/// Send all of `bytes`, with `fds` beside the first of them.
+fn send(socket: &UnixStream, mut bytes: &[u8], fds: &[OwnedFd]) -> io::Result<()> {
+    let mut cmsg = [0u64; cmsg_words()];
+    let mut fds = fds;
+    while !bytes.is_empty() {
+        let mut iov = libc::iovec {
+            iov_base: bytes.as_ptr().cast_mut().cast(),
+            iov_len: bytes.len(),
+        };
+        // SAFETY: all zeros is a valid msghdr
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if !fds.is_empty() {
+            let fds_len = mem::size_of_val(fds) as u32;
+            msg.msg_control = cmsg.as_mut_ptr().cast();
+            // SAFETY: `cmsg` has room for `MAX_FDS`, and a socket never passes on more
+            unsafe {
+                msg.msg_controllen = libc::CMSG_SPACE(fds_len) as _;
+                let header = libc::CMSG_FIRSTHDR(&msg);
+                (*header).cmsg_level = libc::SOL_SOCKET;
+                (*header).cmsg_type = libc::SCM_RIGHTS;
+                (*header).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+                let data = libc::CMSG_DATA(header).cast::<RawFd>();
+                for (i, fd) in fds.iter().enumerate() {
+                    data.add(i).write_unaligned(fd.as_raw_fd());
+                }
+            }
+        }
+        // SAFETY: `msg` points to `bytes` and `cmsg`, which outlive the call
+        let len = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+        if len < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        bytes = &bytes[len as usize..];
+        fds = &[];
+    }
+    Ok(())
+}
+
+/// The size of a buffer for `MAX_FDS` file descriptors, in aligned words.
+const fn cmsg_words() -> usize {
+    // SAFETY: CMSG_SPACE only computes a size
+    let space = unsafe { libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32) } as usize;
+    space.div_ceil(8)
+}
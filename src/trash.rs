@@ -0,0 +1,207 @@
+//! Moving files to the trash, the way file managers do, following the FreeDesktop.org trash
+//! specification.
+//!
+//! Files go to the trash in `$XDG_DATA_HOME/Trash` if it is on the same file system, and to the
+//! one at the top of their own file system otherwise, since moving them would copy them:
+//! `.Trash/$UID` if the administrator has set up `.Trash`, or `.Trash-$UID`. An info file next to
+//! each file records where it came from and when, so that file managers can restore it.
+
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+/// Move the file at `path` to the trash.
+pub fn trash(path: &Path) -> Result<()> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        bail!("{} is not a file", path.display());
+    };
+    // Links are trashed themselves, so only the directory is resolved
+    let dir = match dir {
+        dir if dir == Path::new("") => Path::new("."),
+        dir => dir,
+    };
+    let dir = fs::canonicalize(dir).context("could not find the file")?;
+    let path = dir.join(name);
+    let device = fs::symlink_metadata(&path)
+        .context("could not find the file")?
+        .dev();
+
+    let home = home_trash().context("could not find the trash")?;
+    let (trash, original) = match existing_ancestor(&home).dev() == device {
+        true => (home, path.clone()),
+        false => {
+            let top = top_dir(&dir, device);
+            let trash = top_trash(&top).context("could not create a trash on its file system")?;
+            let original = path.strip_prefix(&top).unwrap().to_owned();
+            (trash, original)
+        }
+    };
+
+    let mut builder = DirBuilder::new();
+    builder.recursive(true).mode(0o700);
+    for sub in ["files", "info"] {
+        builder
+            .create(trash.join(sub))
+            .with_context(|| format!("could not create {}", trash.join(sub).display()))?;
+    }
+
+    let info = format!(
+        "[Trash Info]\nPath={}\nDeletionDate={}\n",
+        percent_encode(original.as_os_str().as_bytes()),
+        local_time()
+    );
+    for n in 1.. {
+        let name = numbered(Path::new(name), n);
+        let target = trash.join("files").join(&name);
+        if fs::symlink_metadata(&target).is_ok() {
+            continue;
+        }
+        let mut info_name = name;
+        info_name.push(".trashinfo");
+        let info_path = trash.join("info").join(info_name);
+        // The info file claims the name, which other programs may be trashing files under too
+        let mut file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&info_path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).context("could not write to the trash"),
+        };
+        let result = file
+            .write_all(info.as_bytes())
+            .context("could not write to the trash")
+            .and_then(|()| {
+                fs::rename(&path, &target).context("could not move the file to the trash")
+            });
+        if result.is_err() {
+            let _ = fs::remove_file(&info_path);
+        }
+        return result;
+    }
+    unreachable!()
+}
+
+/// `$XDG_DATA_HOME/Trash`.
+fn home_trash() -> Option<PathBuf> {
+    match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("Trash")),
+        _ => {
+            let home = std::env::var_os("HOME")?;
+            Some(PathBuf::from(home).join(".local/share/Trash"))
+        }
+    }
+}
+
+/// The metadata of `path` or of the closest directory above it which exists.
+fn existing_ancestor(path: &Path) -> fs::Metadata {
+    path.ancestors()
+        .find_map(|path| fs::metadata(path).ok())
+        .unwrap_or_else(|| fs::metadata("/").unwrap())
+}
+
+/// The directory at the top of the file system of `dir`, which is on `device`.
+fn top_dir(dir: &Path, device: u64) -> PathBuf {
+    let mut top = dir;
+    while let Some(parent) = top.parent() {
+        match fs::metadata(parent) {
+            Ok(metadata) if metadata.dev() == device => top = parent,
+            _ => break,
+        }
+    }
+    top.to_owned()
+}
+
+/// The trash of the user at the top of a file system, which is created if needed.
+fn top_trash(top: &Path) -> io::Result<PathBuf> {
+    // SAFETY: getuid cannot fail
+    let uid = unsafe { libc::getuid() };
+    // Shared by all users, and only used if it is a real directory with the sticky bit, so that
+    // nobody can replace the directories of others
+    let shared = top.join(".Trash");
+    let sticky =
+        fs::symlink_metadata(&shared).is_ok_and(|m| m.is_dir() && m.mode() & libc::S_ISVTX != 0);
+    if sticky {
+        let trash = shared.join(uid.to_string());
+        match DirBuilder::new().mode(0o700).create(&trash) {
+            Ok(()) => return Ok(trash),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if is_own_dir(&trash, uid) {
+                    return Ok(trash);
+                }
+            }
+            Err(_) => (),
+        }
+    }
+    let trash = top.join(format!(".Trash-{uid}"));
+    match DirBuilder::new().mode(0o700).create(&trash) {
+        Ok(()) => Ok(trash),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists && is_own_dir(&trash, uid) => Ok(trash),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} belongs to someone else", trash.display()),
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+fn is_own_dir(path: &Path, uid: u32) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|m| m.is_dir() && m.uid() == uid)
+}
+
+/// The name of the `n`th file called `name` in the trash, like `photo.2.jpg` for the second one.
+fn numbered(name: &Path, n: u32) -> OsString {
+    if n == 1 {
+        return name.as_os_str().to_owned();
+    }
+    let stem = name.file_stem().unwrap_or(name.as_os_str());
+    let mut numbered = stem.as_bytes().to_vec();
+    numbered.extend_from_slice(format!(".{n}").as_bytes());
+    if let Some(extension) = name.extension() {
+        numbered.push(b'.');
+        numbered.extend_from_slice(extension.as_bytes());
+    }
+    OsString::from_vec(numbered)
+}
+
+/// Percent-encode a path for an info file, like in a `file:` URL.
+fn percent_encode(path: &[u8]) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for &byte in path {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => write!(encoded, "%{byte:02X}").unwrap(),
+        }
+    }
+    encoded
+}
+
+/// The local time in the form of info files, like `2024-05-01T13:45:02`.
+fn local_time() -> String {
+    // SAFETY: all zeros is a valid tm
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: time may be given a null pointer, and localtime_r only writes to `tm`, which it may
+    // leave as it is if it fails
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        libc::localtime_r(&now, &mut tm);
+    }
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
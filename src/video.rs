@@ -0,0 +1,206 @@
+//! Short videos, like the MP4 and WebM files which are shared in place of GIF animations, played
+//! without sound with `ffmpeg`.
+//!
+//! `ffprobe` reads the size and the frame rate, and `ffmpeg` decodes the frames to raw RGBA on its
+//! stdout. A thread reads them a few frames ahead of the playback and starts `ffmpeg` over after
+//! the last one, so only these frames are kept in memory however long the video is. It wakes the
+//! event loop up when a frame is ready, and a frame which is late leaves the current one shown
+//! rather than making the viewer wait.
+//!
+//! Like JPEG 2000 images, the video is copied into a temporary directory first: MP4 files can
+//! only be decoded from a pipe if their index comes before the frames, which it often does not.
+
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use image::RgbaImage;
+
+use crate::limits::Limits;
+use crate::temp::TempDir;
+
+/// How many frames are decoded ahead of the one shown.
+const FRAMES_AHEAD: usize = 4;
+/// The frame rate of videos which do not say.
+const DEFAULT_FPS: f64 = 25.0;
+/// The brands of the ISO base media format which are still images, like AVIF and HEIC.
+const IMAGE_BRANDS: &[&[u8]] = &[
+    b"avif", b"avis", b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"msf1",
+];
+
+/// Whether `data` is a WebM or Matroska file, or an MP4 or QuickTime file.
+pub fn is_video(data: &[u8]) -> bool {
+    if data.starts_with(b"\x1a\x45\xdf\xa3") {
+        return true;
+    }
+    match data.get(4..12) {
+        Some([b'f', b't', b'y', b'p', brand @ ..]) => !IMAGE_BRANDS.contains(&brand),
+        _ => false,
+    }
+}
+
+/// A video which is being decoded.
+pub struct Video {
+    frames: mpsc::Receiver<Result<RgbaImage>>,
+    /// Becomes readable when a frame has been decoded
+    wakeup: UnixStream,
+    /// How long each frame is shown
+    pub interval: Duration,
+    /// Where the video is, until it is no longer played
+    _dir: TempDir,
+}
+
+impl Video {
+    /// Start decoding the video in `data`, and return its first frame together with it.
+    pub fn open(data: &[u8], limits: &Limits) -> Result<(RgbaImage, Self)> {
+        let dir = TempDir::new().context("could not create a temporary directory")?;
+        let input = dir.path().join("video");
+        std::fs::write(&input, data).context("could not write a temporary file")?;
+
+        let (width, height, rate) = probe(&input)?;
+        limits.check(width, height)?;
+        let fps = match rate.split_once('/') {
+            Some((num, den)) => num.parse::<f64>().ok().zip(den.parse::<f64>().ok()),
+            None => rate.parse().ok().map(|fps| (fps, 1.0)),
+        }
+        .map(|(num, den)| num / den)
+        .filter(|fps| fps.is_finite() && *fps > 0.0)
+        .unwrap_or(DEFAULT_FPS);
+
+        let (tx, frames) = mpsc::sync_channel(FRAMES_AHEAD);
+        let (wakeup, wakeup_tx) = UnixStream::pair().context("could not create a socket")?;
+        wakeup
+            .set_nonblocking(true)
+            .context("could not create a socket")?;
+        std::thread::spawn(move || stream(input, (width, height), fps, tx, wakeup_tx));
+        let video = Self {
+            frames,
+            wakeup,
+            interval: Duration::from_secs_f64(fps.recip()),
+            _dir: dir,
+        };
+        // This runs in the background already, so it may wait
+        let first = match video.frames.recv() {
+            Ok(frame) => frame?,
+            Err(mpsc::RecvError) => bail!("the video could not be decoded"),
+        };
+        Ok((first, video))
+    }
+
+    /// The next frame, after the last one the first again, or `None` if it has not been
+    /// decoded yet.
+    pub fn try_next_frame(&self) -> Option<Result<RgbaImage>> {
+        // The bytes only wake the event loop up
+        while (&self.wakeup).read(&mut [0; 64]).is_ok_and(|len| len > 0) {}
+        match self.frames.try_recv() {
+            Ok(frame) => Some(frame),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                Some(Err(anyhow!("the video could not be decoded again")))
+            }
+        }
+    }
+
+    /// A file descriptor which becomes readable when [`Self::try_next_frame`] may have a frame.
+    pub fn wakeup_fd(&self) -> RawFd {
+        self.wakeup.as_raw_fd()
+    }
+}
+
+/// The size and frame rate of the first video stream of the file at `path`.
+fn probe(path: &Path) -> Result<(u32, u32, String)> {
+    let result = Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "v:0", "-show_entries"])
+        .arg("stream=width,height,r_frame_rate")
+        .args(["-of", "default=noprint_wrappers=1"])
+        .arg(path)
+        .output()
+        .context("could not run ffprobe, which comes with ffmpeg and is needed to play videos")?;
+    if !result.status.success() {
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        match stderr.lines().map(str::trim).find(|l| !l.is_empty()) {
+            Some(line) => bail!("ffprobe failed: {line}"),
+            None => bail!("ffprobe failed: {}", result.status),
+        }
+    }
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let value = |key: &str| {
+        stdout
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(key)?.strip_prefix('='))
+    };
+    let width = value("width").and_then(|w| w.parse().ok());
+    let height = value("height").and_then(|h| h.parse().ok());
+    let (Some(width), Some(height)) = (width, height) else {
+        bail!("the file has no video stream");
+    };
+    let rate = value("r_frame_rate").unwrap_or_default().to_owned();
+    Ok((width, height, rate))
+}
+
+/// Send the frames of the video at `path` to `tx` over and over, until it is dropped, with a byte
+/// to `wakeup` after each one.
+fn stream(
+    path: PathBuf,
+    (width, height): (u32, u32),
+    fps: f64,
+    tx: mpsc::SyncSender<Result<RgbaImage>>,
+    wakeup: UnixStream,
+) {
+    let send = |frame| {
+        let sent = tx.send(frame).is_ok();
+        let _ = (&wakeup).write_all(&[0]);
+        sent
+    };
+    let frame_len = width as usize * height as usize * 4;
+    loop {
+        let child = Command::new("ffmpeg")
+            .args(["-v", "error", "-nostdin"])
+            // The frames are sent as they are stored, since their size is from ffprobe
+            .arg("-noautorotate")
+            .arg("-i")
+            .arg(&path)
+            // Frames which are shown longer are repeated, so that each one takes the same time
+            .args(["-an", "-vf", &format!("fps={fps}")])
+            .args(["-f", "rawvideo", "-pix_fmt", "rgba", "-"])
+            .stdout(Stdio::piped())
+            // Nothing reads them, and a video without frames is reported as such
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                let e = anyhow::Error::new(e)
+                    .context("could not run ffmpeg, which is needed to play videos");
+                send(Err(e));
+                return;
+            }
+        };
+        let mut stdout = child.stdout.take().unwrap();
+        let mut frames = 0;
+        loop {
+            let mut frame = vec![0; frame_len];
+            if stdout.read_exact(&mut frame).is_err() {
+                break;
+            }
+            frames += 1;
+            let frame = RgbaImage::from_raw(width, height, frame).unwrap();
+            if !send(Ok(frame)) {
+                // The video is no longer played
+                let _ = child.kill();
+                let _ = child.wait();
+                return;
+            }
+        }
+        let _ = child.wait();
+        if frames == 0 {
+            send(Err(anyhow!("ffmpeg decoded no frames")));
+            return;
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! Showing the image again when its file changes, and following the files which are added to and
+//! removed from the directories, see `--watch`.
+//!
+//! The directory of the file is watched with inotify rather than the file itself, so that files
+//! which are replaced by renaming another one over them, as editors and many exporters do, are
+//! still followed. A change is only shown once the file has settled, like files which are
+//! opened while they are still being written, see [`crate::settle`].
+//!
+//! The directories have an inotify instance of their own, since watching the same directory
+//! twice in one would share the watch. New files are only added once they have been closed after
+//! writing or moved in complete, so screenshots and downloads are not added halfway.
+
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::files;
+use crate::settle::Settle;
+
+const EVENT_SIZE: usize = std::mem::size_of::<libc::inotify_event>();
+/// The changes which are of interest, in the directory of the file.
+const MASK: u32 = libc::IN_CLOSE_WRITE | libc::IN_MODIFY | libc::IN_CREATE | libc::IN_MOVED_TO;
+/// Files being added to and removed from the directories.
+const DIRECTORY_MASK: u32 = libc::IN_CLOSE_WRITE
+    | libc::IN_MOVED_TO
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_ONLYDIR;
+
+pub struct Watch {
+    inotify: OwnedFd,
+    /// The watched file, and the watch of its directory
+    watched: Option<(PathBuf, i32)>,
+    /// How long the file has to stay the same
+    delay: Duration,
+    /// The change of the file, until it has been shown
+    settle: Option<Settle>,
+}
+
+impl Watch {
+    pub fn new(delay: Duration) -> io::Result<Self> {
+        Ok(Self {
+            inotify: inotify()?,
+            watched: None,
+            delay,
+            settle: None,
+        })
+    }
+
+    /// Watch the file at `path` instead of the one watched so far, or none if `None`.
+    pub fn follow(&mut self, path: Option<&Path>) {
+        if self.watched.as_ref().map(|(watched, _)| watched.as_path()) == path {
+            return;
+        }
+        if let Some((_, wd)) = self.watched.take() {
+            unsafe { libc::inotify_rm_watch(self.inotify.as_raw_fd(), wd) };
+        }
+        self.settle = None;
+        let Some(path) = path else {
+            return;
+        };
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        match add_watch(&self.inotify, dir, MASK) {
+            Ok(wd) => self.watched = Some((path.to_owned(), wd)),
+            Err(e) => eprintln!("reimv: cannot watch {} for changes: {e}", path.display()),
+        }
+    }
+
+    /// Read the pending events, and show the file again after the delay if it has changed.
+    pub fn read_events(&mut self) {
+        let mut changed = false;
+        read_events(&self.inotify, |event, name| {
+            changed |= self.is_watched(event.wd, name);
+        });
+        if changed {
+            self.changed();
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+
+    /// The duration until the file is looked at again, or `None` if it has not changed.
+    pub fn sleep(&self) -> Option<Duration> {
+        self.settle.as_ref()?.sleep()
+    }
+
+    /// Whether the file should be shown again now: it has changed, and stayed the same since.
+    pub fn due(&mut self) -> bool {
+        if !self.settle.as_mut().is_some_and(Settle::due) {
+            return false;
+        }
+        let Some((path, _)) = &self.watched else {
+            return false;
+        };
+        if !path.exists() {
+            // Replaced, and the new file is not there yet
+            self.settle = None;
+            return false;
+        }
+        true
+    }
+
+    /// The file has been shown again.
+    pub fn reloaded(&mut self) {
+        self.settle = None;
+    }
+
+    /// Try to show the file again later, after it could not be decoded. Returns `false` once
+    /// this has failed too often.
+    pub fn retry(&mut self) -> bool {
+        if self.settle.as_mut().is_some_and(Settle::retry) {
+            return true;
+        }
+        self.settle = None;
+        false
+    }
+
+    fn is_watched(&self, wd: i32, name: &[u8]) -> bool {
+        self.watched.as_ref().is_some_and(|(path, watched)| {
+            *watched == wd && path.file_name().is_some_and(|n| n.as_bytes() == name)
+        })
+    }
+
+    fn changed(&mut self) {
+        match (&mut self.settle, &self.watched) {
+            (Some(settle), _) => settle.changed(),
+            (None, Some((path, _))) => self.settle = Some(Settle::new(path, self.delay)),
+            (None, None) => (),
+        }
+    }
+}
+
+/// A change to the files of the watched directories.
+pub enum Change {
+    /// A file has been written, or moved into the directory
+    Added(String),
+    /// A file has been deleted, or moved out of the directory
+    Removed(String),
+}
+
+/// The directories the files were found in.
+pub struct Directories {
+    inotify: OwnedFd,
+    /// The watches, and the paths of their directories
+    watched: Vec<(i32, String)>,
+}
+
+impl Directories {
+    /// Watch the directories at `paths`. Those which cannot be watched are reported and skipped.
+    pub fn new(paths: &[String]) -> io::Result<Self> {
+        let inotify = inotify()?;
+        let mut watched = Vec::new();
+        for path in paths {
+            match add_watch(&inotify, Path::new(path), DIRECTORY_MASK) {
+                Ok(wd) => watched.push((wd, path.clone())),
+                Err(e) => eprintln!("reimv: cannot watch {path} for new files: {e}"),
+            }
+        }
+        Ok(Self { inotify, watched })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+
+    /// The changes since they were last read, in the order they happened.
+    pub fn read_events(&mut self) -> Vec<Change> {
+        let mut changes = Vec::new();
+        read_events(&self.inotify, |event, name| {
+            if event.mask & libc::IN_ISDIR != 0 {
+                return;
+            }
+            let Some((_, dir)) = self.watched.iter().find(|(wd, _)| *wd == event.wd) else {
+                return;
+            };
+            // Such files are not in the list either
+            let Ok(name) = std::str::from_utf8(name) else {
+                return;
+            };
+            let path = files::join(dir, name);
+            changes.push(match event.mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) {
+                0 => Change::Added(path),
+                _ => Change::Removed(path),
+            });
+        });
+        changes
+    }
+}
+
+fn inotify() -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Watch the directory at `dir` for the events of `mask`, returning the watch.
+fn add_watch(inotify: &OwnedFd, dir: &Path, mask: u32) -> io::Result<i32> {
+    let dir = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let wd = unsafe { libc::inotify_add_watch(inotify.as_raw_fd(), dir.as_ptr(), mask) };
+    if wd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(wd)
+}
+
+/// Call `f` with each pending event and the name of its file, until there are no more.
+fn read_events(inotify: &OwnedFd, mut f: impl FnMut(&libc::inotify_event, &[u8])) {
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = unsafe { libc::read(inotify.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        let Ok(len) = usize::try_from(len) else {
+            // Nothing more to read
+            return;
+        };
+        let mut events = &buf[..len];
+        while events.len() >= EVENT_SIZE {
+            let event: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(events.as_ptr().cast()) };
+            let end = EVENT_SIZE + event.len as usize;
+            let Some(name) = events.get(EVENT_SIZE..end) else {
+                break;
+            };
+            // The name is padded with NUL bytes
+            let name = name.split(|&byte| byte == 0).next().unwrap_or_default();
+            f(&event, name);
+            events = &events[end..];
+        }
+    }
+}
@@ -11,8 +11,13 @@ use wayrs_protocols::xdg_shell::*;
 use wayrs_client::protocol::*;
 use wayrs_protocols::fractional_scale_v1::*;
 use wayrs_protocols::xdg_decoration_unstable_v1::*;
+use wayrs_utils::shm_alloc::BufferSpec;
 
+use crate::backend;
+use crate::config::Background;
+use crate::frame::FrameScheduler;
 use crate::globals::Globals;
+use crate::overlay::Overlay;
 use crate::EventCtx;
 use crate::State;
 
@@ -20,7 +25,8 @@ pub struct Window {
     pub surface: WlSurface,
     pub xdg_surface: XdgSurface,
     pub xdg_toplevel: XdgToplevel,
-    pub wl_buffer: WlBuffer,
+    /// The single pixel buffer of a background color
+    single_pixel: Option<WlBuffer>,
     pub viewport: WpViewport,
     pub fractional_scale: Option<WpFractionalScaleV1>,
 
@@ -28,16 +34,29 @@ pub struct Window {
     pub scale120: Option<u32>,
 
     pub mapped: bool,
-    pub throttle: Option<WlCallback>,
-    pub throttled: bool,
+    pub frames: FrameScheduler,
     pub width: u32,
     pub height: u32,
     pub fullscreen: bool,
     pub closed: bool,
+    /// What is around and behind the image
+    background: Background,
+    /// The size in buffer pixels of the checkerboard attached to the surface
+    checkerboard_size: Option<(u32, u32)>,
+    /// Whether the background must be opaque
+    opaque: bool,
 }
 
+/// A dark gray, which is translucent unless it has to be opaque.
+const DEFAULT_BACKGROUND: [u8; 4] = [20, 20, 20, 20];
+
+/// The size of the squares of the checkerboard, in surface local coordinates.
+const CHECKER_SIZE: u32 = 8;
+const CHECKER_COLORS: [[u8; 4]; 2] = [[102, 102, 102, 255], [153, 153, 153, 255]];
+
 impl Window {
-    pub fn new(conn: &mut Connection<State>, globals: &Globals, title: String) -> Self {
+    /// The background is translucent unless it has to be `opaque`.
+    pub fn new(conn: &mut Connection<State>, globals: &Globals, opaque: bool) -> Self {
         let surface = globals
             .wl_compositor
             .create_surface_with_cb(conn, wl_surface_cb);
@@ -53,14 +72,11 @@ impl Window {
                 .xdg_wm_base
                 .get_xdg_surface_with_cb(conn, surface, xdg_surface_cb);
 
-        let bg_pix = u32::MAX / 256 * 20;
-        let wl_buffer = globals
-            .single_pixel_buffer_manager
-            .create_u32_rgba_buffer(conn, bg_pix, bg_pix, bg_pix, bg_pix);
+        let background = background_color(DEFAULT_BACKGROUND, opaque);
+        let single_pixel = background_buffer(conn, globals, background);
 
         let xdg_toplevel = xdg_surface.get_toplevel_with_cb(conn, xdg_toplevel_cb);
         xdg_toplevel.set_app_id(conn, cstr!("reimv").into());
-        xdg_toplevel.set_title(conn, CString::new(title).expect("title has nul bytes"));
 
         // We don't care what the compositor prefers, thus no callback. There are no plans to
         // implement CSD.
@@ -77,7 +93,7 @@ impl Window {
             surface,
             xdg_surface,
             xdg_toplevel,
-            wl_buffer,
+            single_pixel: Some(single_pixel),
             viewport,
             fractional_scale,
 
@@ -85,30 +101,114 @@ impl Window {
             outputs: HashSet::new(),
 
             mapped: false,
-            throttle: None,
-            throttled: false,
+            frames: FrameScheduler::default(),
             width: 400,
             height: 300,
             fullscreen: false,
             closed: false,
+            background: Background::Color(background),
+            checkerboard_size: None,
+            opaque,
         }
     }
 
-    pub fn frame(state: &mut State, conn: &mut Connection<State>) {
-        if !state.window.mapped {
+    /// Change what is around the image, or go back to the default color.
+    pub fn set_background(
+        &mut self,
+        conn: &mut Connection<State>,
+        globals: &Globals,
+        background: Option<Background>,
+    ) {
+        let background = match background.unwrap_or(Background::Color(DEFAULT_BACKGROUND)) {
+            Background::Color(color) => Background::Color(background_color(color, self.opaque)),
+            Background::Checkerboard => Background::Checkerboard,
+        };
+        if background == self.background {
             return;
         }
+        self.background = background;
+        if let Some(buffer) = self.single_pixel.take() {
+            buffer.destroy(conn);
+        }
+        // Drawn at the size of the window in the next frame
+        self.checkerboard_size = None;
+        if let Background::Color(color) = background {
+            self.single_pixel = Some(background_buffer(conn, globals, color));
+            self.attach_single_pixel(conn);
+        }
+    }
+
+    fn attach_single_pixel(&self, conn: &mut Connection<State>) {
+        if let (true, Some(buffer)) = (self.mapped, self.single_pixel) {
+            self.surface.attach(conn, Some(buffer), 0, 0);
+            self.surface.damage(conn, 0, 0, 1, 1);
+        }
+    }
+
+    /// Fill `canvas`, RGBA pixels in rows of `width`, with the background at `ui_scale120`.
+    pub fn draw_background(&self, canvas: &mut [u8], width: u32, ui_scale120: u32) {
+        let pixels = canvas.chunks_exact_mut(4);
+        match self.background {
+            Background::Color(color) => pixels.for_each(|pixel| pixel.copy_from_slice(&color)),
+            Background::Checkerboard => {
+                // Whole buffer pixels, so that the squares stay sharp at fractional scales
+                let (square, _) = backend::buffer_size(CHECKER_SIZE, CHECKER_SIZE, ui_scale120);
+                let square = square.max(1);
+                for (i, pixel) in pixels.enumerate() {
+                    let (x, y) = (i as u32 % width, i as u32 / width);
+                    let color = CHECKER_COLORS[((x / square + y / square) % 2) as usize];
+                    pixel.copy_from_slice(&color);
+                }
+            }
+        }
+    }
 
-        if state.window.throttle.is_some() {
-            state.window.throttled = true;
+    /// Draw the checkerboard again for the size of the window in buffer pixels, which only
+    /// changes with the window or its scale.
+    fn update_checkerboard(state: &mut State, conn: &mut Connection<State>, ui_scale120: u32) {
+        let this = &state.window;
+        if this.background != Background::Checkerboard {
+            return;
+        }
+        let size = backend::buffer_size(this.width, this.height, ui_scale120);
+        if this.checkerboard_size == Some(size) || size.0 == 0 || size.1 == 0 {
             return;
         }
+        let (buffer, canvas) = state
+            .shm_alloc
+            .alloc_buffer(
+                conn,
+                BufferSpec {
+                    width: size.0,
+                    height: size.1,
+                    stride: size.0 * 4,
+                    format: wl_shm::Format::Abgr8888,
+                },
+            )
+            .unwrap();
+        state.window.draw_background(canvas, size.0, ui_scale120);
+        let this = &mut state.window;
+        this.surface
+            .attach(conn, Some(buffer.into_wl_buffer()), 0, 0);
+        this.surface.damage(conn, 0, 0, i32::MAX, i32::MAX);
+        this.checkerboard_size = Some(size);
+    }
 
-        let scale120 = state
-            .window
-            .scale120
-            .unwrap_or_else(|| state.window.get_int_scale(state) * 120);
+    /// Ask for a frame, which [`Self::render`] draws.
+    pub fn frame(state: &mut State) {
+        state.window.frames.request();
+    }
 
+    /// Draw the frame which has been asked for, once the window is mapped and the compositor is
+    /// ready for it. Called by the event loop after each round of events.
+    pub fn render(state: &mut State, conn: &mut Connection<State>) {
+        if !state.window.mapped || !state.window.frames.due() {
+            return;
+        }
+
+        let scale120 = state.window.ui_scale120(state);
+
+        Self::update_checkerboard(state, conn, scale120);
         state.backend.render(
             conn,
             &mut state.shm_alloc,
@@ -118,27 +218,39 @@ impl Window {
             &state.img_transform,
         );
 
+        Overlay::render(state, conn, scale120);
+
         state.window.viewport.set_destination(
             conn,
             state.window.width as i32,
             state.window.height as i32,
         );
 
-        state.window.throttle = Some(state.window.surface.frame_with_cb(conn, |ctx| {
-            assert_eq!(ctx.state.window.throttle, Some(ctx.proxy));
-            ctx.state.window.throttle = None;
-            if ctx.state.window.throttled {
-                ctx.state.window.throttled = false;
-                Self::frame(ctx.state, ctx.conn);
-            }
-        }));
+        // The preview of a pending decode does not count as the first frame
+        if state.exit_after_first_frame && state.backend.pending_fd().is_none() {
+            state.window.frames.close_after = true;
+        }
 
-        state.window.surface.commit(conn);
+        let mut surfaces = state.backend.surfaces();
+        surfaces.push(state.overlay.surface);
+        state
+            .window
+            .frames
+            .present(conn, state.window.surface, &surfaces);
+    }
+
+    /// The scale of buffers, times 120.
+    pub fn ui_scale120(&self, state: &State) -> u32 {
+        if state.deterministic {
+            return 120;
+        }
+        self.scale120
+            .unwrap_or_else(|| self.get_int_scale(state) * 120)
     }
 
     pub fn get_int_scale(&self, state: &State) -> u32 {
         match self.scale120 {
-            Some(scale120) => (scale120 + 119) / 120,
+            Some(scale120) => scale120.div_ceil(120),
             None => state
                 .outputs
                 .iter()
@@ -149,6 +261,12 @@ impl Window {
         }
     }
 
+    pub fn set_title(&self, conn: &mut Connection<State>, title: String) {
+        // Names from archives and templates can contain NUL, which a C string cannot
+        let title = CString::new(title.replace('\0', "")).unwrap();
+        self.xdg_toplevel.set_title(conn, title);
+    }
+
     pub fn toggle_fullscreen(&self, conn: &mut Connection<State>) {
         if self.fullscreen {
             self.xdg_toplevel.unset_fullscreen(conn);
@@ -158,6 +276,23 @@ impl Window {
     }
 }
 
+/// Make a premultiplied color opaque if it has to be, as if it was drawn over black.
+fn background_color(background: [u8; 4], opaque: bool) -> [u8; 4] {
+    let [r, g, b, a] = background;
+    [r, g, b, if opaque { 255 } else { a }]
+}
+
+fn background_buffer(
+    conn: &mut Connection<State>,
+    globals: &Globals,
+    background: [u8; 4],
+) -> WlBuffer {
+    let [r, g, b, a] = background.map(|c| u32::MAX / 255 * c as u32);
+    globals
+        .single_pixel_buffer_manager
+        .create_u32_rgba_buffer(conn, r, g, b, a)
+}
+
 fn wl_surface_cb(ctx: EventCtx<WlSurface>) {
     assert_eq!(ctx.state.window.surface, ctx.proxy);
     match ctx.event {
@@ -172,7 +307,7 @@ fn wl_surface_cb(ctx: EventCtx<WlSurface>) {
         }
         _ => (),
     }
-    Window::frame(ctx.state, ctx.conn);
+    Window::frame(ctx.state);
 }
 
 fn xdg_surface_cb(ctx: EventCtx<XdgSurface>) {
@@ -183,13 +318,11 @@ fn xdg_surface_cb(ctx: EventCtx<XdgSurface>) {
     ctx.proxy.ack_configure(ctx.conn, serial);
     if !ctx.state.window.mapped {
         ctx.state.window.mapped = true;
-        ctx.state
-            .window
-            .surface
-            .attach(ctx.conn, Some(ctx.state.window.wl_buffer), 0, 0);
-        ctx.state.window.surface.damage(ctx.conn, 0, 0, 1, 1);
+        ctx.state.window.attach_single_pixel(ctx.conn);
+        // The size of the window is only known now
+        ctx.state.reset_view(ctx.conn);
     }
-    Window::frame(ctx.state, ctx.conn);
+    Window::frame(ctx.state);
 }
 
 fn fractional_scale_cb(ctx: EventCtx<WpFractionalScaleV1>) {
@@ -199,7 +332,7 @@ fn fractional_scale_cb(ctx: EventCtx<WpFractionalScaleV1>) {
     };
     if ctx.state.window.scale120 != Some(scale120) {
         ctx.state.window.scale120 = Some(scale120);
-        Window::frame(ctx.state, ctx.conn);
+        Window::frame(ctx.state);
     }
 }
 
@@ -0,0 +1,89 @@
+//! X bitmaps: C source with the size in `#define` lines and the bits in an array, as written by
+//! `bitmap` and used for X11 cursors and icons.
+//!
+//! ```c
+//! #define icon_width 16
+//! #define icon_height 16
+//! static unsigned char icon_bits[] = { 0x00, 0x00, 0xfc, 0x3f, ... };
+//! ```
+//!
+//! Every row starts on a new byte, with the leftmost pixel in the lowest bit. The older X10
+//! format has `short` arrays with 16-bit words instead. Set bits are drawn black on white.
+
+use anyhow::{bail, ensure, Context, Result};
+use image::{Rgba, RgbaImage};
+
+use crate::limits::Limits;
+
+/// Whether this looks like an X bitmap, from the `#define` lines at its start.
+pub fn is_xbm(data: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&data[..data.len().min(1024)]);
+    let mut defines = head
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("/*"));
+    let mut is_define = |suffix: &str| {
+        defines.next().is_some_and(|line| {
+            let mut words = line.split_whitespace();
+            words.next() == Some("#define")
+                && words.next().is_some_and(|name| name.ends_with(suffix))
+        })
+    };
+    is_define("width") && is_define("height")
+}
+
+pub fn decode(data: &[u8], limits: &Limits) -> Result<RgbaImage> {
+    let text = std::str::from_utf8(data).context("the bitmap is not text")?;
+    let (mut width, mut height) = (None, None);
+    for line in text.lines() {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("#define") {
+            continue;
+        }
+        let (Some(name), Some(value)) = (words.next(), words.next()) else {
+            continue;
+        };
+        if name.ends_with("width") {
+            width = Some(value.parse::<u32>().context("invalid width")?);
+        } else if name.ends_with("height") {
+            height = Some(value.parse::<u32>().context("invalid height")?);
+        }
+    }
+    let width = width.context("the width is missing")?;
+    let height = height.context("the height is missing")?;
+    ensure!(width > 0 && height > 0, "the bitmap is empty");
+    limits.check(width, height)?;
+
+    let (start, array) = text
+        .split_once('{')
+        .context("the array of bits is missing")?;
+    let short = start
+        .rsplit_once("static")
+        .is_some_and(|(_, declaration)| declaration.contains("short"));
+    let array = array.split('}').next().unwrap_or_default();
+    let word_bits = if short { 16 } else { 8 };
+    let words_per_row = width.div_ceil(word_bits) as usize;
+
+    let mut image = RgbaImage::from_pixel(width, height, Rgba([255; 4]));
+    let mut words = array
+        .split(',')
+        .map(str::trim)
+        .filter(|word| !word.is_empty());
+    for y in 0..height {
+        for i in 0..words_per_row {
+            let word = words.next().context("the bitmap is truncated")?;
+            let Some(hex) = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) else {
+                bail!("invalid word {word:?}");
+            };
+            let word =
+                u16::from_str_radix(hex, 16).with_context(|| format!("invalid word {word:?}"))?;
+            for bit in 0..word_bits {
+                let x = i as u32 * word_bits + bit;
+                if x < width && word >> bit & 1 == 1 {
+                    image.put_pixel(x, y, Rgba([0, 0, 0, 255]));
+                }
+            }
+        }
+    }
+    Ok(image)
+}
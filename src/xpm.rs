@@ -0,0 +1,202 @@
+//! X pixmaps: C source with an array of strings, used for icons in older X11 applications.
+//!
+//! ```c
+//! /* XPM */
+//! static char *icon[] = {
+//! "16 16 2 1",
+//! ". c None",
+//! "# c #204a87",
+//! "................",
+//! ...
+//! };
+//! ```
+//!
+//! The first string holds the width, the height, the number of colors and the number of
+//! characters per pixel. Each color is given by its characters followed by pairs of a key and a
+//! value, where `c` is the color for color displays and `m`, `g4`, `g` are for monochrome and
+//! grayscale ones. Then come the rows of pixels. XPM2 files have the same lines without the C
+//! syntax, after a `! XPM2` line.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, ensure, Context, Result};
+use image::{Rgba, RgbaImage};
+
+use crate::limits::Limits;
+
+/// The keys of colors, from the most preferred one.
+const KEYS: &[&str] = &["c", "g", "g4", "m"];
+
+/// X11 color names which are common in icons. The shades of gray in `gray0` to `gray100` are
+/// computed.
+const COLOR_NAMES: &[(&str, [u8; 3])] = &[
+    ("black", [0, 0, 0]),
+    ("white", [255, 255, 255]),
+    ("red", [255, 0, 0]),
+    ("green", [0, 255, 0]),
+    ("blue", [0, 0, 255]),
+    ("yellow", [255, 255, 0]),
+    ("cyan", [0, 255, 255]),
+    ("magenta", [255, 0, 255]),
+    ("gray", [190, 190, 190]),
+    ("grey", [190, 190, 190]),
+    ("lightgray", [211, 211, 211]),
+    ("lightgrey", [211, 211, 211]),
+    ("darkgray", [169, 169, 169]),
+    ("darkgrey", [169, 169, 169]),
+    ("dimgray", [105, 105, 105]),
+    ("dimgrey", [105, 105, 105]),
+    ("orange", [255, 165, 0]),
+    ("brown", [165, 42, 42]),
+    ("navy", [0, 0, 128]),
+    ("darkgreen", [0, 100, 0]),
+    ("darkred", [139, 0, 0]),
+    ("darkblue", [0, 0, 139]),
+    ("gold", [255, 215, 0]),
+    ("pink", [255, 192, 203]),
+    ("purple", [160, 32, 240]),
+    ("violet", [238, 130, 238]),
+];
+
+pub fn is_xpm(data: &[u8]) -> bool {
+    data.starts_with(b"/* XPM */") || data.starts_with(b"! XPM2")
+}
+
+pub fn decode(data: &[u8], limits: &Limits) -> Result<RgbaImage> {
+    let text = std::str::from_utf8(data).context("the pixmap is not text")?;
+    let strings = match text.strip_prefix("! XPM2") {
+        Some(rest) => rest.lines().filter(|line| !line.is_empty()).collect(),
+        None => c_strings(text),
+    };
+    let mut strings = strings.into_iter();
+
+    let values = strings.next().context("the values are missing")?;
+    let mut values = values.split_whitespace().map(str::parse::<u32>);
+    let mut value = |name: &str| -> Result<u32> {
+        values
+            .next()
+            .with_context(|| format!("the {name} is missing"))?
+            .with_context(|| format!("invalid {name}"))
+    };
+    let width = value("width")?;
+    let height = value("height")?;
+    let colors = value("number of colors")?;
+    let chars = value("number of characters per pixel")? as usize;
+    ensure!(width > 0 && height > 0, "the pixmap is empty");
+    ensure!(
+        (1..=8).contains(&chars),
+        "{chars} characters per pixel are not supported"
+    );
+    limits.check(width, height)?;
+
+    let mut palette = HashMap::new();
+    for _ in 0..colors {
+        let line = strings.next().context("the colors are truncated")?;
+        let (pixel, rest) = split_at_chars(line, chars).context("invalid color")?;
+        palette.insert(pixel, parse_color_line(rest)?);
+    }
+
+    let mut image = RgbaImage::new(width, height);
+    for y in 0..height {
+        let mut row = strings.next().context("the pixels are truncated")?;
+        for x in 0..width {
+            let (pixel, rest) = split_at_chars(row, chars).context("the row is too short")?;
+            let color = palette
+                .get(pixel)
+                .with_context(|| format!("the pixel {pixel:?} has no color"))?;
+            image.put_pixel(x, y, Rgba(*color));
+            row = rest;
+        }
+    }
+    Ok(image)
+}
+
+/// The string literals of C source, outside of comments.
+fn c_strings(text: &str) -> Vec<&str> {
+    let mut strings = Vec::new();
+    let mut rest = text;
+    loop {
+        let comment = rest.find("/*");
+        let quote = rest.find('"');
+        match (comment, quote) {
+            (Some(comment), Some(quote)) if comment < quote => match rest[comment..].find("*/") {
+                Some(end) => rest = &rest[comment + end + 2..],
+                None => return strings,
+            },
+            (_, Some(quote)) => {
+                let string = &rest[quote + 1..];
+                // Pixel characters are printable, so strings end at the next quote
+                let Some(end) = string.find('"') else {
+                    return strings;
+                };
+                strings.push(&string[..end]);
+                rest = &string[end + 1..];
+            }
+            (_, None) => return strings,
+        }
+    }
+}
+
+/// Split off the characters of one pixel, which are `chars` characters and not bytes.
+fn split_at_chars(text: &str, chars: usize) -> Option<(&str, &str)> {
+    let end = text
+        .char_indices()
+        .nth(chars)
+        .map_or(text.len(), |(i, _)| i);
+    (text[..end].chars().count() == chars).then(|| text.split_at(end))
+}
+
+/// Parse the keys and values after the characters of a color, using the preferred key.
+fn parse_color_line(line: &str) -> Result<[u8; 4]> {
+    // Symbolic names, with the key `s`, only mean something to the application
+    let is_key = |word: &str| KEYS.contains(&word) || word == "s";
+    let mut values: Vec<(&str, String)> = Vec::new();
+    for word in line.split_whitespace() {
+        match values.last_mut() {
+            Some((_, value)) if value.is_empty() => value.push_str(word),
+            // Color names can have spaces, like `light grey`
+            Some((_, value)) if !is_key(word) => value.push_str(word),
+            _ if is_key(word) => values.push((word, String::new())),
+            _ => bail!("invalid color {line:?}"),
+        }
+    }
+    let key = KEYS
+        .iter()
+        .find_map(|key| values.iter().find(|(k, _)| k == key))
+        .with_context(|| format!("invalid color {line:?}"))?;
+    parse_color(&key.1)
+}
+
+fn parse_color(value: &str) -> Result<[u8; 4]> {
+    let name = value.to_ascii_lowercase();
+    if name == "none" {
+        return Ok([0; 4]);
+    }
+    if let Some(hex) = name.strip_prefix('#') {
+        let digits = hex.len() / 3;
+        ensure!(
+            hex.len() % 3 == 0 && (1..=4).contains(&digits) && hex.is_ascii(),
+            "invalid color {value:?}"
+        );
+        let mut rgb = [0; 3];
+        for (i, c) in rgb.iter_mut().enumerate() {
+            let channel = u16::from_str_radix(&hex[i * digits..(i + 1) * digits], 16)
+                .with_context(|| format!("invalid color {value:?}"))?;
+            let max = (1u32 << (4 * digits)) - 1;
+            *c = ((channel as u32 * 255 + max / 2) / max) as u8;
+        }
+        return Ok([rgb[0], rgb[1], rgb[2], 255]);
+    }
+    let gray = name
+        .strip_prefix("gray")
+        .or_else(|| name.strip_prefix("grey"));
+    if let Some(percent) = gray.and_then(|level| level.parse::<u32>().ok()) {
+        ensure!(percent <= 100, "invalid color {value:?}");
+        let level = ((percent * 255 + 50) / 100) as u8;
+        return Ok([level, level, level, 255]);
+    }
+    match COLOR_NAMES.iter().find(|(known, _)| *known == name) {
+        Some(&(_, [r, g, b])) => Ok([r, g, b, 255]),
+        None => bail!("unknown color {value:?}"),
+    }
+}